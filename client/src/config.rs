@@ -1,24 +1,37 @@
+use std::ascii::AsciiExt;
 use std::fs::File;
 use std::io::Read;
+use std::path::PathBuf;
 use xdg::BaseDirectories;
 use toml;
 use toml::Parser;
 use rustc_serialize::Decodable;
+use time;
+
+use common::conn::DEFAULT_MAX_MSG_SIZE;
 
 pub type UserId = String;
 
+/// Timestamp format used when none is configured.
+const DEFAULT_TIME_FORMAT: &'static str = "%H:%M:%S";
+
 
 /// Loads the client's config file. Panics on error.
 pub fn read_config() -> Config {
-    let dirs = BaseDirectories::with_prefix("distirc-client").unwrap();
-    let path = dirs.find_config_file("config.toml")
-        .expect("Missing configuration file");
+    load_config().unwrap_or_else(|e| panic!("{}", e))
+}
+
+/// Loads the client's config file.
+pub fn load_config() -> Result<Config, String> {
+    let dirs = try!(BaseDirectories::with_prefix("distirc-client").map_err(|e| e.to_string()));
+    let path = try!(dirs.find_config_file("config.toml")
+        .ok_or_else(|| "Missing configuration file".to_owned()));
 
     info!("Reading config file from {}", path.display());
     let mut s = String::new();
 
-    let mut f = File::open(path).expect("Failed to open config file");
-    f.read_to_string(&mut s).expect("Failed to read config file");
+    let mut f = try!(File::open(path).map_err(|e| format!("Failed to open config file: {}", e)));
+    try!(f.read_to_string(&mut s).map_err(|e| format!("Failed to read config file: {}", e)));
     debug!("Read config");
 
     let mut parser = Parser::new(&s);
@@ -26,13 +39,33 @@ pub fn read_config() -> Config {
         debug!("Parsed config");
 
         let mut dec = toml::Decoder::new(toml::Value::Table(table));
-        Config::decode(&mut dec).expect("Invalid config file")
+        Config::decode(&mut dec).map_err(|e| format!("Invalid config file: {}", e))
     } else {
         error!("Failed to parse config file. Error list:");
         for e in parser.errors {
             error!("{}", e);
         }
-        panic!("Failed to parse configuration file.");
+        Err("Failed to parse configuration file.".to_owned())
+    }
+}
+
+
+/// Gets the path of the text entry history file, creating its directory if
+/// needed.
+pub fn history_path() -> Option<PathBuf> {
+    let dirs = match BaseDirectories::with_prefix("distirc-client") {
+        Ok(dirs) => dirs,
+        Err(e) => {
+            warn!("Couldn't find data directory for history: {}", e);
+            return None;
+        },
+    };
+    match dirs.place_data_file("history") {
+        Ok(path) => Some(path),
+        Err(e) => {
+            warn!("Couldn't create data directory for history: {}", e);
+            None
+        },
     }
 }
 
@@ -40,6 +73,14 @@ pub fn read_config() -> Config {
 #[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
 pub struct Config {
     pub core: CoreConfig,
+    pub ui: Option<UiConfig>,
+}
+
+impl Config {
+    /// Returns the UI settings, or the defaults if there's no `[ui]` section.
+    pub fn ui(&self) -> UiConfig {
+        self.ui.clone().unwrap_or_default()
+    }
 }
 
 #[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
@@ -48,4 +89,160 @@ pub struct CoreConfig {
     pub port: u16,
     pub user: String,
     pub pass: String,
+    /// Largest message in bytes we'll send to or accept from the core.
+    pub max_message_size: Option<usize>,
+}
+
+impl CoreConfig {
+    pub fn max_message_size(&self) -> usize {
+        self.max_message_size.unwrap_or(DEFAULT_MAX_MSG_SIZE)
+    }
+}
+
+#[derive(Debug, Clone, Default, RustcEncodable, RustcDecodable)]
+pub struct UiConfig {
+    /// Whether to color nicks. Defaults to true.
+    pub nick_colors: Option<bool>,
+    /// Colors to pick nick colors from. Either color names like "red" or
+    /// 256-color palette indices.
+    pub nick_palette: Option<Vec<String>>,
+    /// Whether to show bold, colors and other formatting in messages. If
+    /// false, formatting codes are stripped. Defaults to true.
+    pub formatting: Option<bool>,
+    /// Number of entered lines to remember across restarts. Defaults to 1000.
+    pub history_size: Option<usize>,
+    /// Whether to hide joins, parts and quits by default. They can be shown
+    /// again per buffer with `/filter jpq`. Defaults to false.
+    pub hide_jpq: Option<bool>,
+    /// Seconds within which alerts from the same place are collapsed into
+    /// one. Defaults to 300.
+    pub alert_window: Option<u64>,
+    /// strftime format for line timestamps. An empty string hides them.
+    /// Defaults to `%H:%M:%S`.
+    pub time_format: Option<String>,
+    /// Whether to draw a line with the date above the first line of each
+    /// day. Defaults to true.
+    pub date_separators: Option<bool>,
+    /// Timezone to show times in. Either "local", "UTC" or an offset like
+    /// "+02:00". Defaults to "local".
+    pub timezone: Option<String>,
+    /// Number of spaces to expand tabs in messages to. If 0, tabs are shown
+    /// as `^I`. Defaults to 4.
+    pub tab_width: Option<usize>,
+    /// Which log messages to show in the status buffer, like
+    /// `info,client::conn=trace`. The `DISTIRC_LOG` environment variable
+    /// overrides this. Defaults to everything from distirc's own crates.
+    pub log_filter: Option<String>,
+    /// Whether to show the list of users beside channel buffers. It can be
+    /// toggled with F2. Defaults to true.
+    pub nick_list: Option<bool>,
+    /// Number of columns the user list takes up. Defaults to 20.
+    pub nick_list_width: Option<usize>,
+    /// Whether to show runs of joins, parts or quits as a single line, like
+    /// "5 users quit: a, b, c". Only the display changes. Defaults to false.
+    pub compact_jpq: Option<bool>,
+    /// Most seconds apart two joins, parts or quits can be to be compacted
+    /// into the same line. Defaults to 10.
+    pub compact_window: Option<u64>,
+    /// Key that quits the client, like "ctrl-q" or "f10". Ctrl-C always
+    /// quits too, once the text entry is empty. Defaults to "ctrl-c".
+    pub quit_key: Option<String>,
+    /// Whether the quit key has to be pressed twice to quit. Defaults to
+    /// true.
+    pub confirm_quit: Option<bool>,
+    /// Whether to color the whole of messages that mention your nick, rather
+    /// than just the mentions. Defaults to false.
+    pub tint_highlights: Option<bool>,
+}
+
+impl UiConfig {
+    pub fn nick_colors(&self) -> bool { self.nick_colors.unwrap_or(true) }
+    pub fn formatting(&self) -> bool { self.formatting.unwrap_or(true) }
+    pub fn history_size(&self) -> usize { self.history_size.unwrap_or(1000) }
+    pub fn hide_jpq(&self) -> bool { self.hide_jpq.unwrap_or(false) }
+    pub fn alert_window(&self) -> u64 { self.alert_window.unwrap_or(300) }
+    pub fn date_separators(&self) -> bool { self.date_separators.unwrap_or(true) }
+    pub fn tab_width(&self) -> usize { self.tab_width.unwrap_or(4) }
+    pub fn nick_list(&self) -> bool { self.nick_list.unwrap_or(true) }
+    pub fn nick_list_width(&self) -> usize { self.nick_list_width.unwrap_or(20) }
+    pub fn compact_jpq(&self) -> bool { self.compact_jpq.unwrap_or(false) }
+    pub fn compact_window(&self) -> u64 { self.compact_window.unwrap_or(10) }
+    pub fn confirm_quit(&self) -> bool { self.confirm_quit.unwrap_or(true) }
+    pub fn tint_highlights(&self) -> bool { self.tint_highlights.unwrap_or(false) }
+
+    /// Returns the timestamp format, falling back to the default if the
+    /// configured one is invalid.
+    pub fn time_format(&self) -> String {
+        match self.time_format {
+            Some(ref fmt) if time::now().strftime(fmt).is_ok() => fmt.clone(),
+            Some(ref fmt) => {
+                warn!("Invalid time format {:?}. Using the default", fmt);
+                DEFAULT_TIME_FORMAT.to_owned()
+            },
+            None => DEFAULT_TIME_FORMAT.to_owned(),
+        }
+    }
+
+    /// Returns the configured UTC offset in seconds, or `None` to use local
+    /// time.
+    pub fn utc_offset(&self) -> Option<i32> {
+        match self.timezone {
+            Some(ref tz) => match parse_utc_offset(tz) {
+                Ok(off) => off,
+                Err(()) => {
+                    warn!("Invalid timezone {:?}. Using local time", tz);
+                    None
+                },
+            },
+            None => None,
+        }
+    }
+}
+
+/// Parses a timezone setting into a UTC offset in seconds. `None` means
+/// local time.
+fn parse_utc_offset(tz: &str) -> Result<Option<i32>, ()> {
+    let tz = tz.trim();
+    if tz.eq_ignore_ascii_case("local") {
+        return Ok(None);
+    } else if tz.eq_ignore_ascii_case("utc") || tz.eq_ignore_ascii_case("z") {
+        return Ok(Some(0));
+    }
+    let sign = match tz.chars().next() {
+        Some('+') => 1,
+        Some('-') => -1,
+        _ => return Err(()),
+    };
+    let digits = tz[1..].replace(":", "");
+    if digits.is_empty() || !digits.chars().all(|c| c.is_digit(10)) {
+        return Err(());
+    }
+    let (h, m) = match digits.len() {
+        1 | 2 => (&digits[..], "0"),
+        4 => digits.split_at(2),
+        _ => return Err(()),
+    };
+    let (h, m) = (h.parse::<i32>().unwrap(), m.parse::<i32>().unwrap());
+    if h > 14 || m > 59 {
+        return Err(());
+    }
+    Ok(Some(sign * (h * 3600 + m * 60)))
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::parse_utc_offset;
+
+    #[test]
+    fn utc_offsets() {
+        assert_eq!(parse_utc_offset("local"), Ok(None));
+        assert_eq!(parse_utc_offset("UTC"), Ok(Some(0)));
+        assert_eq!(parse_utc_offset("+02:00"), Ok(Some(7200)));
+        assert_eq!(parse_utc_offset("-0530"), Ok(Some(-19800)));
+        assert_eq!(parse_utc_offset("+9"), Ok(Some(32400)));
+        assert_eq!(parse_utc_offset("Europe/Berlin"), Err(()));
+        assert_eq!(parse_utc_offset("+25:00"), Err(()));
+    }
 }
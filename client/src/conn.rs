@@ -1,17 +1,66 @@
+use std::cmp;
+use std::io;
 use std::thread;
+use std::time::Duration;
 use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
 use std::net::SocketAddr;
-use rotor::{Scope, Loop, Config as LoopCfg, Notifier};
-use rotor_stream::Persistent;
+use rotor::{Machine, Response, Scope, GenericScope, EventSet, PollOpt, Time};
+use rotor::{Loop, Config as LoopCfg, Notifier};
+use rotor::mio::tcp::TcpStream;
+use rotor::void::{unreachable, Void};
+use rotor_stream::Stream;
 
-use common::conn::{Action, Handler, Connection};
-use common::messages::{CoreMsg, ClientMsg, Password};
+use common::conn::{Action, Handler, Connection, ProtocolError};
+use common::messages::{CoreMsg, ClientMsg, Password, COMPRESSION_FEATURE};
+
+
+/// Time to wait before the first reconnect attempt, in milliseconds.
+const MIN_RECONNECT_DELAY: u64 = 500;
+/// The reconnect delay doubles after each failed attempt up to this many
+/// milliseconds.
+const MAX_RECONNECT_DELAY: u64 = 30_000;
+/// Time to wait for a TCP connection to the core to be established, in
+/// milliseconds.
+const CONNECT_TIMEOUT: u64 = 5_000;
+
+
+/// Events sent from the connection thread to the model.
+#[derive(Debug)]
+pub enum ConnEvent {
+    /// A message from the core.
+    Msg(CoreMsg),
+    /// We've (re)connected and authenticated with the core.
+    Connected,
+    /// The core rejected our username or password. We won't try again until
+    /// `ConnThread::retry` is called.
+    AuthFailed,
+    /// We lost our connection to the core, or failed to make one, and will
+    /// try again after the given number of milliseconds.
+    Reconnecting(u64),
+    /// The core answered a heartbeat ping after the given number of
+    /// milliseconds.
+    Latency(u64),
+}
+
+/// State of our connection to the core, for showing in the UI.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnState {
+    /// Connecting for the first time.
+    Connecting,
+    Connected,
+    /// Waiting to reconnect after losing the connection.
+    Reconnecting,
+    /// The core rejected our login, so we've stopped trying to connect.
+    AuthFailed,
+}
 
 
 /// Handle for communicating with the connection thread.
 pub struct ConnThread {
-    rx: Receiver<CoreMsg>,
+    rx: Receiver<ConnEvent>,
     tx: Sender<ClientMsg>,
+    /// Sends new login details to the connection thread.
+    retry_tx: Sender<(String, Password)>,
     /// Notifier to wake up the connection machine when we have messages to
     /// send.
     notif: Notifier,
@@ -19,21 +68,29 @@ pub struct ConnThread {
 
 impl ConnThread {
     /// Spawns a connection to the given address.
-    pub fn spawn(addr: SocketAddr, user: String, pass: Password) -> ConnThread {
+    ///
+    /// The connection thread reconnects and authenticates again whenever the
+    /// connection to the core is lost.
+    pub fn spawn(addr: SocketAddr, user: String, pass: Password, max_msg_size: usize) -> ConnThread {
         // sender/receiver for messages to the server
         let (txs, txr) = channel();
         // sender/receiver for messages from the server
         let (rxs, rxr) = channel();
+        let (retry_tx, retry_rx) = channel();
 
         let ctx = ConnCtx {
             rxs: rxs,
             txr: txr,
+            retry_rx: retry_rx,
+            max_msg_size: max_msg_size,
+            authed: false,
+            auth_failed: false,
         };
         let mut notif = None;
         let mut mkloop = Loop::new(&LoopCfg::new()).unwrap();
         mkloop.add_machine_with(|scope| {
             notif = Some(scope.notifier());
-            Persistent::<Connection<Conn>>::connect(scope, addr, (user, pass))
+            Reconnect::new(scope, addr, (user, pass))
         }).expect("Failed to add connection state machine");
 
         thread::Builder::new()
@@ -44,17 +101,30 @@ impl ConnThread {
         ConnThread {
             rx: rxr,
             tx: txs,
+            retry_tx: retry_tx,
             notif: notif.expect("Notifier was not set."),
         }
     }
 
+    /// Connects again with the given login details.
+    ///
+    /// If we're connected, the new details are used the next time we
+    /// reconnect. If we stopped after the core rejected our login, we
+    /// connect right away.
+    pub fn retry(&mut self, user: String, pass: Password) {
+        self.retry_tx.send((user, pass)).expect("Failed to send login to connection thread");
+        self.notif.wakeup().expect("Failed to wake up connection thread");
+    }
+
     /// Sends a message to the server.
+    ///
+    /// If we're not connected, the message is sent once we reconnect.
     pub fn send(&mut self, msg: ClientMsg) {
         self.tx.send(msg).expect("Failed to send message to connection thread");
         self.notif.wakeup().expect("Failed to wake up connection thread");
     }
 
-    pub fn recv(&mut self) -> Option<CoreMsg> {
+    pub fn recv(&mut self) -> Option<ConnEvent> {
         self.rx.try_recv().ok()
     }
 }
@@ -62,29 +132,241 @@ impl ConnThread {
 
 /// Context object for the connection.
 struct ConnCtx {
-    rxs: Sender<CoreMsg>,
+    rxs: Sender<ConnEvent>,
     txr: Receiver<ClientMsg>,
+    retry_rx: Receiver<(String, Password)>,
+    max_msg_size: usize,
+    /// Set by the handler when it authenticates so that the reconnect delay
+    /// can be reset.
+    authed: bool,
+    /// Set by the handler when the core rejects our login, so that we stop
+    /// reconnecting with the same details.
+    auth_failed: bool,
+}
+
+
+type ConnStream = Stream<Connection<Conn>>;
+
+/// State machine which keeps a connection to the core open, reconnecting with
+/// exponential backoff when it's lost.
+///
+/// This is like `rotor_stream::Persistent`, which always waits the same short
+/// time between attempts.
+struct Reconnect {
+    info: ConnInfo,
+    state: ReconnectState,
+}
+
+/// Everything needed to make a new connection.
+struct ConnInfo {
+    addr: SocketAddr,
+    seed: (String, Password),
+    /// Time to wait before the next reconnect attempt, in milliseconds.
+    delay: u64,
+}
+
+enum ReconnectState {
+    Connecting(TcpStream, Time),
+    Established(ConnStream),
+    Sleeping(Time),
+    /// The core rejected our login. Waiting for `ConnThread::retry`.
+    Stopped,
+}
+
+impl Reconnect {
+    fn new<S: GenericScope>(scope: &mut S, addr: SocketAddr, seed: (String, Password))
+                            -> Response<Reconnect, Void> {
+        let info = ConnInfo {
+            addr: addr,
+            seed: seed,
+            delay: MIN_RECONNECT_DELAY,
+        };
+        // Connect on the first timeout, since we can't send events to the
+        // model from here.
+        info.respond(ReconnectState::Sleeping(scope.now()))
+    }
+}
+
+impl ConnInfo {
+    /// Starts connecting to the core.
+    fn connect(self, scope: &mut Scope<ConnCtx>) -> Response<Reconnect, Void> {
+        info!("Connecting to core at {}", self.addr);
+        match TcpStream::connect(&self.addr) {
+            Ok(sock) => {
+                scope.register(&sock, EventSet::writable(), PollOpt::level())
+                    .expect("Failed to register socket");
+                let dline = scope.now() + Duration::from_millis(CONNECT_TIMEOUT);
+                self.respond(ReconnectState::Connecting(sock, dline))
+            },
+            Err(e) => {
+                warn!("Failed to connect to core at {}: {}", self.addr, e);
+                self.sleep(scope)
+            },
+        }
+    }
+
+    /// Waits before connecting again and doubles the delay for the next
+    /// attempt.
+    fn sleep(mut self, scope: &mut Scope<ConnCtx>) -> Response<Reconnect, Void> {
+        info!("Reconnecting to core in {} ms", self.delay);
+        let _ = scope.rxs.send(ConnEvent::Reconnecting(self.delay));
+        let dline = scope.now() + Duration::from_millis(self.delay);
+        self.delay = cmp::min(self.delay * 2, MAX_RECONNECT_DELAY);
+        self.respond(ReconnectState::Sleeping(dline))
+    }
+
+    /// Handles the stream's response to an event.
+    fn stream(mut self, resp: Response<ConnStream, Void>, scope: &mut Scope<ConnCtx>)
+              -> Response<Reconnect, Void> {
+        if resp.is_stopped() {
+            match resp.cause() {
+                Some(e) => warn!("Lost connection to core: {}", e),
+                None => warn!("Connection to core closed"),
+            }
+            if scope.auth_failed {
+                // Trying the same login again won't help.
+                info!("Not reconnecting until the login changes");
+                return Response::ok(Reconnect { info: self, state: ReconnectState::Stopped });
+            }
+            self.sleep(scope)
+        } else {
+            if scope.authed {
+                scope.authed = false;
+                self.delay = MIN_RECONNECT_DELAY;
+            }
+            resp.wrap(|s| Reconnect { info: self, state: ReconnectState::Established(s) })
+        }
+    }
+
+    fn respond(self, state: ReconnectState) -> Response<Reconnect, Void> {
+        let dline = match state {
+            ReconnectState::Connecting(_, dline) => dline,
+            ReconnectState::Sleeping(dline) => dline,
+            // The stream sets its own deadlines, and we wait for a wakeup
+            // when stopped.
+            ReconnectState::Established(_) | ReconnectState::Stopped => unreachable!(),
+        };
+        Response::ok(Reconnect { info: self, state: state }).deadline(dline)
+    }
 }
 
+impl Machine for Reconnect {
+    type Context = ConnCtx;
+    type Seed = Void;
+
+    fn create(seed: Void, _scope: &mut Scope<ConnCtx>) -> Response<Self, Void> {
+        unreachable(seed)
+    }
+
+    fn ready(self, events: EventSet, scope: &mut Scope<ConnCtx>) -> Response<Self, Void> {
+        let Reconnect { info, state } = self;
+        match state {
+            ReconnectState::Connecting(sock, dline) => {
+                if events.is_writable() {
+                    let resp = Stream::connected(sock, info.seed.clone(), scope);
+                    info.stream(resp, scope)
+                } else if events.is_hup() {
+                    warn!("Connection to core closed immediately");
+                    info.sleep(scope)
+                } else {
+                    info.respond(ReconnectState::Connecting(sock, dline))
+                }
+            },
+            ReconnectState::Established(s) => {
+                let resp = s.ready(events, scope);
+                info.stream(resp, scope)
+            },
+            ReconnectState::Stopped => Response::ok(Reconnect { info: info, state: state }),
+            state => info.respond(state),
+        }
+    }
+
+    fn spawned(self, _scope: &mut Scope<ConnCtx>) -> Response<Self, Void> {
+        unreachable!()
+    }
+
+    fn timeout(self, scope: &mut Scope<ConnCtx>) -> Response<Self, Void> {
+        let Reconnect { info, state } = self;
+        match state {
+            ReconnectState::Connecting(sock, dline) => {
+                if scope.now() >= dline {
+                    warn!("Timed out connecting to core");
+                    info.sleep(scope)
+                } else {
+                    info.respond(ReconnectState::Connecting(sock, dline))
+                }
+            },
+            ReconnectState::Established(s) => {
+                let resp = s.timeout(scope);
+                info.stream(resp, scope)
+            },
+            ReconnectState::Sleeping(dline) => {
+                if scope.now() >= dline {
+                    info.connect(scope)
+                } else {
+                    info.respond(ReconnectState::Sleeping(dline))
+                }
+            },
+            ReconnectState::Stopped => Response::ok(Reconnect { info: info, state: state }),
+        }
+    }
+
+    fn wakeup(self, scope: &mut Scope<ConnCtx>) -> Response<Self, Void> {
+        let Reconnect { mut info, state } = self;
+        let mut retry = false;
+        while let Ok(seed) = scope.retry_rx.try_recv() {
+            info.seed = seed;
+            retry = true;
+        }
+        match state {
+            ReconnectState::Stopped if retry => {
+                scope.auth_failed = false;
+                info.delay = MIN_RECONNECT_DELAY;
+                info.connect(scope)
+            },
+            ReconnectState::Stopped => Response::ok(Reconnect { info: info, state: state }),
+            ReconnectState::Established(s) => {
+                let resp = s.wakeup(scope);
+                info.stream(resp, scope)
+            },
+            // Messages wait in the channel until we're connected again.
+            state => info.respond(state),
+        }
+    }
+}
+
+
 enum Conn {
     Auth,
     Conn,
 }
 
 impl Conn {
-    fn handle_auth_reply(msg: &CoreMsg, _s: &mut Scope<ConnCtx>) -> Action<Self> {
+    fn handle_auth_reply(msg: &CoreMsg, scope: &mut Scope<ConnCtx>) -> Action<Self> {
         match *msg {
-            CoreMsg::AuthOk => {
+            CoreMsg::AuthOk { ref features } => {
                 info!("Authenticated successfully");
-                Action::ok(Conn::Conn)
+                scope.authed = true;
+                // The core sends the network and global buffer lists along
+                // with `AuthOk`, so the model resyncs on every reconnect.
+                let _ = scope.rxs.send(ConnEvent::Connected);
+                // Send anything the UI queued up while we were disconnected.
+                let act = Conn::Conn.send_messages(scope);
+                if features.iter().any(|f| f == COMPRESSION_FEATURE) {
+                    act.send(ClientMsg::EnableCompression)
+                } else {
+                    act
+                }
             },
             CoreMsg::AuthErr => {
                 error!("Failed to authenticate");
+                scope.auth_failed = true;
+                let _ = scope.rxs.send(ConnEvent::AuthFailed);
                 Action::done()
             },
             ref m => {
-                error!("Received invalid message during auth phase: {:?}", m);
-                Action::done()
+                let e = ProtocolError(format!("Received invalid message during auth phase: {:?}", m));
+                Action::error(Box::new(e))
             }
         }
     }
@@ -97,8 +379,8 @@ impl Conn {
                 Ok(msg) => act = act.send(msg),
                 Err(TryRecvError::Empty) => break 'recv,
                 Err(_) => {
-                    error!("Outbound message channel closed");
-                    return Action::done() // TODO: Implement Action::error()
+                    let e = io::Error::new(io::ErrorKind::BrokenPipe, "Outbound message channel closed");
+                    return Action::error(Box::new(e));
                 },
             }
         }
@@ -120,7 +402,11 @@ impl Handler for Conn {
     fn msg_recv(self, msg: &Self::Recv, scope: &mut Scope<Self::Context>) -> Action<Self> {
         match self {
             Conn::Conn => {
-                scope.rxs.send(msg.clone()).unwrap();
+                if let CoreMsg::CompressionEnabled = *msg {
+                    debug!("Core enabled compression");
+                    return Action::ok(self).compress();
+                }
+                scope.rxs.send(ConnEvent::Msg(msg.clone())).unwrap();
                 Action::ok(self)
             },
             Conn::Auth => {
@@ -133,12 +419,23 @@ impl Handler for Conn {
         unreachable!("Unexpected connection handler timeout")
     }
 
+    fn max_msg_size(scope: &mut Scope<Self::Context>) -> usize {
+        scope.max_msg_size
+    }
+
+    fn pong(self, rtt: u64, scope: &mut Scope<Self::Context>) -> Action<Self> {
+        let _ = scope.rxs.send(ConnEvent::Latency(rtt));
+        Action::ok(self)
+    }
+
     fn wakeup(self, scope: &mut Scope<Self::Context>) -> Action<Self> {
         if let Conn::Conn = self {
             // On wakeup, check for any messages to send and send them.
             self.send_messages(scope)
         } else {
-            unreachable!("Woke to send messages up during auth phase");
+            // Messages queued before we're authenticated are sent once we
+            // get `AuthOk`.
+            Action::ok(self)
         }
     }
 }
@@ -0,0 +1,161 @@
+//! Logging to the `*status*` buffer.
+
+use std::env;
+use std::sync::Mutex;
+use log::{self, Log, LogLevelFilter, LogRecord, LogMetadata};
+use time;
+
+use common::line::{BufferLine, LineData, MsgKind};
+
+use model::BufSender;
+
+/// Environment variable which overrides the configured log filter.
+pub const LOG_ENV_VAR: &'static str = "DISTIRC_LOG";
+/// Log filter used when none is configured. This shows everything from our
+/// own crates and nothing from our dependencies.
+pub const DEFAULT_LOG_FILTER: &'static str = "client,common";
+
+
+/// Decides which log messages to show, based on a `RUST_LOG`-style list like
+/// `info,client::conn=trace,rotor=off`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogFilter {
+    /// Target prefixes and their levels, longest prefix first. The empty
+    /// prefix matches every target.
+    directives: Vec<(String, LogLevelFilter)>,
+}
+
+impl LogFilter {
+    /// Parses a comma separated list of directives. Each is either
+    /// `target=level`, a bare `target` to show all of its messages, or a bare
+    /// `level` which applies to all targets.
+    ///
+    /// Returns the filter along with any directives that couldn't be parsed.
+    pub fn parse(spec: &str) -> (LogFilter, Vec<String>) {
+        let mut directives: Vec<(String, LogLevelFilter)> = vec![];
+        let mut errors = vec![];
+        for dir in spec.split(',').map(|d| d.trim()).filter(|d| !d.is_empty()) {
+            let mut parts = dir.splitn(2, '=');
+            let name = parts.next().unwrap().trim();
+            let (name, level) = match parts.next().map(|l| l.trim()) {
+                Some(level) => match level.parse() {
+                    Ok(level) => (name.to_owned(), level),
+                    Err(_) => {
+                        errors.push(dir.to_owned());
+                        continue;
+                    },
+                },
+                None => match name.parse() {
+                    Ok(level) => (String::new(), level),
+                    Err(_) => (name.to_owned(), LogLevelFilter::Trace),
+                },
+            };
+            // Later directives for the same target replace earlier ones.
+            directives.retain(|d| d.0 != name);
+            directives.push((name, level));
+        }
+        // The most specific target that matches is the one that applies.
+        directives.sort_by(|a, b| b.0.len().cmp(&a.0.len()));
+        (LogFilter { directives: directives }, errors)
+    }
+
+    /// Returns the most verbose level shown for `target`.
+    pub fn level_for(&self, target: &str) -> LogLevelFilter {
+        self.directives.iter()
+            .find(|d| target.starts_with(&d.0[..]))
+            .map_or(LogLevelFilter::Off, |d| d.1)
+    }
+
+    /// Returns the most verbose level shown for any target.
+    pub fn max_level(&self) -> LogLevelFilter {
+        self.directives.iter().map(|d| d.1).max().unwrap_or(LogLevelFilter::Off)
+    }
+}
+
+
+/// A logger that writes to a buffer handle.
+pub struct ClientLogger {
+    bs: Mutex<BufSender>,
+    filter: LogFilter,
+}
+
+impl ClientLogger {
+    /// Sets up logging to `bs`. The filter is read from `DISTIRC_LOG` if it's
+    /// set, and from `cfg_filter` otherwise.
+    pub fn init(bs: BufSender, cfg_filter: Option<String>) {
+        let spec = env::var(LOG_ENV_VAR).ok()
+            .or(cfg_filter)
+            .unwrap_or(DEFAULT_LOG_FILTER.to_owned());
+        let (filter, errors) = LogFilter::parse(&spec);
+        log::set_logger(move |max| {
+            max.set(filter.max_level());
+            let l = ClientLogger {
+                bs: Mutex::new(bs),
+                filter: filter,
+            };
+            Box::new(l) as Box<Log>
+        }).expect("Failed to initialize logging system");
+        for e in errors {
+            warn!("Ignoring invalid log filter directive {:?}", e);
+        }
+    }
+}
+
+impl Log for ClientLogger {
+    fn enabled(&self, meta: &LogMetadata) -> bool {
+        meta.level() <= self.filter.level_for(meta.target())
+    }
+
+    fn log(&self, log: &LogRecord) {
+        if self.enabled(&log.metadata()) {
+            let msg = format!(
+                "{0: >5} {1} {2}",
+                log.level(),
+                log.location().module_path(),
+                log.args());
+            let data = LineData::Message {
+                from: "status".to_owned(),
+                msg: msg,
+                kind: MsgKind::Status,
+            };
+
+            let line = BufferLine::new(time::now(), data);
+
+            let mut bs = self.bs.lock().expect("Failed to lock log destination mutex");
+            bs.send_front(line);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use log::LogLevelFilter;
+    use super::LogFilter;
+
+    #[test]
+    fn per_target_levels() {
+        let (filter, errors) = LogFilter::parse("info, client::conn=trace, rotor=off");
+        assert!(errors.is_empty());
+        assert_eq!(filter.level_for("client::ui"), LogLevelFilter::Info);
+        assert_eq!(filter.level_for("client::conn"), LogLevelFilter::Trace);
+        assert_eq!(filter.level_for("rotor_stream"), LogLevelFilter::Off);
+        assert_eq!(filter.max_level(), LogLevelFilter::Trace);
+    }
+
+    #[test]
+    fn bare_targets() {
+        let (filter, _) = LogFilter::parse(super::DEFAULT_LOG_FILTER);
+        assert_eq!(filter.level_for("client::model"), LogLevelFilter::Trace);
+        assert_eq!(filter.level_for("common::conn"), LogLevelFilter::Trace);
+        assert_eq!(filter.level_for("rotor::loop_api"), LogLevelFilter::Off);
+    }
+
+    #[test]
+    fn last_directive_wins() {
+        let (filter, errors) = LogFilter::parse("client=debug,client=warn,common=loud");
+        assert_eq!(errors, vec!["common=loud".to_owned()]);
+        assert_eq!(filter.level_for("client"), LogLevelFilter::Warn);
+        assert_eq!(filter.level_for("common"), LogLevelFilter::Off);
+    }
+}
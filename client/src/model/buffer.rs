@@ -1,9 +1,17 @@
 use std::fmt;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::sync::mpsc::{channel, Sender, Receiver};
 use common::messages::BufferLine;
+use common::line::{ChannelUser, sort_users};
 
 use common::messages::{BufId, NetId, BufTarget};
 
+use url::line_urls;
+
+
+/// Number of recent URLs each buffer remembers for `/url`.
+const URL_RING_SIZE: usize = 20;
+
 
 /// Sends lines to a `Buffer` in a thread-safe manner.
 #[derive(Debug)]
@@ -49,10 +57,31 @@ pub struct Buffer {
     front: Vec<BufferLine>,
     /// Scrollback lines in reverse order. The first of these is at index -1.
     back: Vec<BufferLine>,
-    /// True if we've joined this channel.
-    ///
-    /// For private message buffers, this indicates whether the user is online.
+    /// True if we've joined this channel. Private message buffers are always
+    /// joined.
     joined: bool,
+    /// For private message buffers, whether the other user is online.
+    online: bool,
+    /// Users in this channel, keyed by nick.
+    users: HashMap<String, ChannelUser>,
+    /// The channel's topic. Empty if there's no topic.
+    topic: String,
+    /// Who set the topic and when, as a Unix timestamp, if we know.
+    topic_set: Option<(String, i64)>,
+    /// Number of lines we haven't read.
+    unread: usize,
+    /// True if there's an unread ping or private message in this buffer.
+    highlight: bool,
+    /// Whether joins, parts and quits are hidden in this buffer. If `None`,
+    /// the UI's default is used.
+    hide_jpq: Option<bool>,
+    /// If true, the buffer doesn't raise alerts or show up as active.
+    muted: bool,
+    /// URLs mentioned in this buffer, most recent first.
+    urls: VecDeque<String>,
+    /// IDs of the lines we have, so lines the core sends twice, like
+    /// scrollback that overlaps replayed lines, are only shown once.
+    ids: HashSet<u64>,
 }
 
 impl Buffer {
@@ -74,6 +103,16 @@ impl Buffer {
             front: vec![],
             back: vec![],
             joined: false,
+            online: true,
+            users: HashMap::new(),
+            topic: String::new(),
+            topic_set: None,
+            unread: 0,
+            highlight: false,
+            hide_jpq: None,
+            muted: false,
+            urls: VecDeque::new(),
+            ids: HashSet::new(),
         };
         (buf, sender)
     }
@@ -91,16 +130,167 @@ impl Buffer {
         self.joined = j;
     }
 
+    /// For private message buffers, true if the other user is online.
+    pub fn online(&self) -> bool {
+        self.online
+    }
+
+    pub fn set_online(&mut self, online: bool) {
+        self.online = online;
+    }
+
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    /// Sets the topic, forgetting who set the old one.
+    pub fn set_topic(&mut self, topic: String) {
+        self.topic = topic;
+        self.topic_set = None;
+    }
+
+    /// Who set the topic and when, if we know.
+    pub fn topic_set(&self) -> Option<&(String, i64)> {
+        self.topic_set.as_ref()
+    }
+
+    pub fn set_topic_by(&mut self, by: String, at: i64) {
+        self.topic_set = Some((by, at));
+    }
+
+    pub fn unread(&self) -> usize {
+        self.unread
+    }
+
+    /// Sets the unread line count. Setting it to 0 also clears the highlight.
+    pub fn set_unread(&mut self, unread: usize) {
+        self.unread = unread;
+        if unread == 0 {
+            self.highlight = false;
+        }
+    }
+
+    /// Returns the ID of the newest line we have which has one.
+    pub fn newest_id(&self) -> Option<u64> {
+        self.front.iter().rev().chain(self.back.iter()).filter_map(|l| l.id()).next()
+    }
+
+    pub fn highlight(&self) -> bool {
+        self.highlight
+    }
+
+    pub fn set_highlight(&mut self, h: bool) {
+        self.highlight = h;
+    }
+
+    pub fn hide_jpq(&self) -> Option<bool> {
+        self.hide_jpq
+    }
+
+    pub fn set_hide_jpq(&mut self, hide: bool) {
+        self.hide_jpq = Some(hide);
+    }
+
+    pub fn muted(&self) -> bool {
+        self.muted
+    }
+
+    pub fn set_muted(&mut self, muted: bool) {
+        self.muted = muted;
+        if muted {
+            self.highlight = false;
+        }
+    }
+
+    /// Returns the users in this channel with ops first, then voiced users,
+    /// then everyone else.
+    pub fn users(&self) -> Vec<ChannelUser> {
+        let mut users = self.users.values().cloned().collect::<Vec<_>>();
+        sort_users(&mut users);
+        users
+    }
+
+    /// Replaces the user list with the given users.
+    pub fn set_users(&mut self, users: Vec<ChannelUser>) {
+        self.users = users.into_iter().map(|u| (u.nick.clone(), u)).collect();
+    }
+
+    /// Adds a user or updates their prefixes.
+    pub fn update_user(&mut self, user: ChannelUser) {
+        self.users.insert(user.nick.clone(), user);
+    }
+
+    /// Removes the user with the given nick.
+    pub fn remove_user(&mut self, nick: &str) {
+        self.users.remove(nick);
+    }
+
+    /// Describes the user list as text for `/names`.
+    ///
+    /// The first line counts the users, and each line after it lists the
+    /// users with one prefix, highest rank first.
+    pub fn names_lines(&self) -> Vec<String> {
+        let users = self.users();
+        let chan = match self.key {
+            BufKey::Channel(_, ref chan) => &chan[..],
+            _ => &self.name[..],
+        };
+        let mut lines = vec![format!("{} users in {}", users.len(), chan)];
+        let mut groups: Vec<(Option<char>, Vec<String>)> = vec![];
+        for user in users {
+            let pfx = user.prefix();
+            let name = match pfx {
+                Some(p) => format!("{}{}", p, user.nick),
+                None => user.nick,
+            };
+            if groups.last().map_or(false, |g| g.0 == pfx) {
+                groups.last_mut().unwrap().1.push(name);
+            } else {
+                groups.push((pfx, vec![name]));
+            }
+        }
+        lines.extend(groups.into_iter().map(|(_, names)| names.join(" ")));
+        lines
+    }
+
+    /// Gets the `n`th most recent URL in this buffer, starting from 1.
+    pub fn url(&self, n: usize) -> Option<&str> {
+        if n == 0 { return None; }
+        self.urls.get(n - 1).map(|u| &u[..])
+    }
+
     /// Receives new messages from the sender.
     pub fn update(&mut self) {
         while let Ok(line) = self.front_rx.try_recv() {
-            self.front.push(line)
+            if !self.is_new(&line) { continue; }
+            for url in line_urls(&line.data) {
+                self.urls.push_front(url);
+            }
+            self.urls.truncate(URL_RING_SIZE);
+            self.front.push(line);
+            self.unread += 1;
         }
         while let Ok(line) = self.back_rx.try_recv() {
+            if !self.is_new(&line) { continue; }
+            // Scrollback is older than anything we have, so its URLs go at
+            // the back of the ring if there's room.
+            for url in line_urls(&line.data).into_iter().rev() {
+                if self.urls.len() < URL_RING_SIZE {
+                    self.urls.push_back(url);
+                }
+            }
             self.back.push(line)
         }
     }
 
+    /// Checks whether we don't have `line` yet, and remembers its ID if so.
+    fn is_new(&mut self, line: &BufferLine) -> bool {
+        match line.id() {
+            Some(id) => self.ids.insert(id),
+            None => true,
+        }
+    }
+
     pub fn get(&self, idx: isize) -> &BufferLine {
         if idx < 0 {
             &self.back[(-idx) as usize - 1]
@@ -157,6 +347,17 @@ impl Buffer {
         self.front.len() + self.back.len()
     }
 
+    /// Drops every line we have, leaving the logs alone.
+    ///
+    /// The IDs of the dropped lines are kept, so the core sending them again
+    /// doesn't bring them back.
+    pub fn clear(&mut self) {
+        self.front.clear();
+        self.back.clear();
+        self.unread = 0;
+        self.highlight = false;
+    }
+
     /// Tells the client to request more backlogs from the server.
     pub fn request_logs(&mut self, count: usize) {
         self.log_req += count;
@@ -191,6 +392,10 @@ pub enum BufKey {
     Channel(NetId, BufId),
     /// A private message buffer.
     Private(NetId, BufId),
+    /// Results of searching the logs of the given buffer.
+    Search(Box<BufKey>),
+    /// The channel list of the given network.
+    ChanList(NetId),
 }
 
 impl BufKey {
@@ -202,6 +407,18 @@ impl BufKey {
             BufTarget::Private(bid) => BufKey::Private(nid.clone(), bid),
         }
     }
+
+    /// Gets the ID of the network this buffer belongs to, if any.
+    pub fn nid(&self) -> Option<&NetId> {
+        match *self {
+            BufKey::Status | BufKey::Global(_) => None,
+            BufKey::Network(ref nid) => Some(nid),
+            BufKey::Channel(ref nid, _) => Some(nid),
+            BufKey::Private(ref nid, _) => Some(nid),
+            BufKey::Search(ref key) => key.nid(),
+            BufKey::ChanList(ref nid) => Some(nid),
+        }
+    }
 }
 
 
@@ -213,6 +430,79 @@ impl fmt::Display for BufKey {
             BufKey::Network(ref nid) => write!(f, ".{}", nid),
             BufKey::Channel(ref nid, ref bid) => write!(f, "{}<{}>", bid, nid),
             BufKey::Private(ref nid, ref bid) => write!(f, "{}<{}>", bid, nid),
+            BufKey::Search(ref key) => write!(f, "?{}", key),
+            BufKey::ChanList(ref nid) => write!(f, "*list*<{}>", nid),
         }
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use time;
+    use common::line::{BufferLine, LineData, MsgKind, ChannelUser, USER_PREFIXES};
+    use super::{Buffer, BufKey};
+
+    fn line(id: u64) -> BufferLine {
+        BufferLine::with_id(id, time::now(), LineData::Message {
+            kind: MsgKind::PrivMsg,
+            from: "Forkk".to_owned(),
+            msg: format!("{}", id),
+        })
+    }
+
+    #[test]
+    fn drop_duplicate_lines() {
+        let (mut buf, mut bs) = Buffer::new(BufKey::Network("net".to_owned()));
+        bs.send_front(line(4));
+        bs.send_front(line(5));
+        // Scrollback overlapping the lines we already have.
+        bs.send_back(line(5));
+        bs.send_back(line(4));
+        bs.send_back(line(3));
+        // Lines without IDs are always kept.
+        bs.send_front(BufferLine::new(time::now(), line(0).data));
+        bs.send_front(BufferLine::new(time::now(), line(0).data));
+        buf.update();
+
+        assert_eq!(buf.len(), 5);
+        assert_eq!(buf.get(-1).id(), Some(3));
+        assert_eq!(buf.unread(), 4);
+        assert_eq!(buf.newest_id(), Some(5));
+    }
+
+    #[test]
+    fn names_grouped_by_prefix() {
+        let (mut buf, _) = Buffer::new(BufKey::Channel("net".to_owned(), "#chan".to_owned()));
+        buf.set_users(vec![
+            ChannelUser::parse_names_entry("+bob", USER_PREFIXES),
+            ChannelUser::parse_names_entry("@alice", USER_PREFIXES),
+            ChannelUser::parse_names_entry("dave", USER_PREFIXES),
+            ChannelUser::parse_names_entry("@carol", USER_PREFIXES),
+        ]);
+        assert_eq!(buf.names_lines(), vec![
+            "4 users in #chan".to_owned(),
+            "@alice @carol".to_owned(),
+            "+bob".to_owned(),
+            "dave".to_owned(),
+        ]);
+    }
+
+    #[test]
+    fn clear_lines() {
+        let (mut buf, mut bs) = Buffer::new(BufKey::Network("net".to_owned()));
+        bs.send_front(line(1));
+        bs.send_back(line(0));
+        buf.update();
+        buf.clear();
+        assert!(buf.is_empty());
+        assert_eq!(buf.unread(), 0);
+
+        // Lines we cleared stay gone, but new ones still show up.
+        bs.send_back(line(0));
+        bs.send_front(line(2));
+        buf.update();
+        assert_eq!(buf.len(), 1);
+        assert_eq!(buf.get(0).id(), Some(2));
+    }
+}
@@ -3,15 +3,20 @@
 
 use std::rc::Rc;
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use time;
 use common::messages::{
     BufTarget, NetId, BufInfo,
     CoreMsg, CoreBufMsg, CoreNetMsg,
     ClientMsg, ClientNetMsg, ClientBufMsg,
-    Alert, SendMsgKind,
+    Alert, SendMsgKind, BufferLine, Password,
 };
+use common::alert::AlertKind;
+use common::casemap::CaseMapping;
+use common::line::{LineData, MsgKind};
 
-use conn::ConnThread;
+use conn::{ConnThread, ConnEvent, ConnState};
 
 mod buffer;
 
@@ -26,10 +31,43 @@ pub struct CoreModel {
     // buffer. When the `BufId` is none and the `NetId` isn't, the buffer is the
     // network's status buffer.
     pub bufs: HashMap<BufKey, BufEntry>,
+    /// State of the networks we know about.
+    pub nets: HashMap<NetId, NetState>,
     conn: ConnThread,
+    conn_state: ConnState,
+    /// Round trip time of the last heartbeat ping to the core, in
+    /// milliseconds.
+    latency: Option<u64>,
     status: Option<String>,
+    /// A buffer the UI should switch to, such as new search results.
+    opened: Option<BufKey>,
     // List of new alerts.
     alerts: Vec<Alert>,
+    /// Buffer keys in window number order. Numbers are handed out as buffers
+    /// are created and don't change for the rest of the session, even if the
+    /// buffer is removed.
+    windows: Vec<BufKey>,
+    /// Channel lists we've asked for, by network. Results come in batches,
+    /// so they're collected here.
+    chan_lists: HashMap<NetId, Vec<(String, usize, String)>>,
+    /// Channels whose user list we've asked the core to refresh. Their lists
+    /// are shown with `/names` once they arrive.
+    names_pending: HashSet<BufKey>,
+}
+
+/// Client-side state for a network.
+pub struct NetState {
+    pub nick: String,
+    /// The network's name as advertised by the server.
+    pub name: Option<String>,
+    /// Our away message if we're marked as away.
+    pub away: Option<String>,
+    /// True if the core is connected to the network.
+    pub connected: bool,
+    /// True if the user disconnected from the network on purpose.
+    pub offline: bool,
+    /// The network's casemapping, for matching our nick in messages.
+    pub casemap: CaseMapping,
 }
 
 /// Type for storing buffers in the model.
@@ -49,18 +87,61 @@ impl CoreModel {
 
         CoreModel {
             bufs: bufs,
+            nets: HashMap::new(),
             conn: conn,
+            conn_state: ConnState::Connecting,
+            latency: None,
             alerts: vec![],
+            windows: vec![],
+            chan_lists: HashMap::new(),
+            names_pending: HashSet::new(),
             status: None,
+            opened: None,
         }
     }
 
 
+    /// Gets the state of our connection to the core.
+    pub fn conn_state(&self) -> ConnState {
+        self.conn_state
+    }
+
+    /// Gets the latency to the core in milliseconds, if we've measured it
+    /// since we last connected.
+    pub fn latency(&self) -> Option<u64> {
+        self.latency
+    }
+
     /// Gets the given buffer if it exists.
     pub fn get(&self, key: &BufKey) -> Option<&Rc<RefCell<Buffer>>> {
         self.bufs.get(key).map(|&BufEntry { ref buf, .. }| buf)
     }
 
+    /// Lists buffer keys in window order. A buffer's window number is its
+    /// position in this list, starting from 1. The status buffer is window 0
+    /// and isn't in the list.
+    pub fn window_keys(&self) -> &[BufKey] {
+        &self.windows
+    }
+
+    /// Gets the key of the buffer with the given window number.
+    pub fn window_key(&self, num: usize) -> Option<BufKey> {
+        if num == 0 {
+            Some(BufKey::Status)
+        } else {
+            self.windows.get(num - 1).cloned()
+        }
+    }
+
+    /// Gets the window number of the given buffer.
+    pub fn window_num(&self, key: &BufKey) -> Option<usize> {
+        if *key == BufKey::Status {
+            Some(0)
+        } else {
+            self.windows.iter().position(|k| k == key).map(|i| i + 1)
+        }
+    }
+
     /// Gets the buffer with the given key. Creates one if it doesn't exist.
     pub fn get_or_create(&mut self, key: BufKey) -> Rc<RefCell<Buffer>> {
         if let Some(buf) = self.get(&key) {
@@ -69,6 +150,9 @@ impl CoreModel {
         let (buf, bs) = Buffer::new(key.clone());
         let buf = Rc::new(RefCell::new(buf));
         debug!("Created client buffer {:?}", &key);
+        if !self.windows.contains(&key) {
+            self.windows.push(key.clone());
+        }
         self.bufs.insert(key, BufEntry {
             buf: buf.clone(),
             sender: Some(bs)
@@ -76,11 +160,62 @@ impl CoreModel {
         buf
     }
 
+    /// Gets or creates the private message buffer for `nick` on the given
+    /// network and returns its key.
+    pub fn open_private(&mut self, nid: NetId, nick: String) -> BufKey {
+        let key = BufKey::Private(nid, nick);
+        if self.get(&key).is_none() {
+            // Like the core, assume the user is online until we hear
+            // otherwise.
+            self.get_or_create(key.clone()).borrow_mut().set_joined(true);
+        }
+        key
+    }
+
+    /// Removes the buffer with the given key, parting it first if it's a
+    /// channel we're in. The status buffer can't be closed.
+    ///
+    /// The core drops private buffers and channels we're not in too, so new
+    /// messages for them open a fresh buffer instead of going to one we no
+    /// longer have.
+    ///
+    /// Returns false if there was no such buffer.
+    pub fn close_buf(&mut self, key: &BufKey) -> bool {
+        if *key == BufKey::Status { return false; }
+        let joined = match self.get(key) {
+            Some(buf) => buf.borrow().joined(),
+            None => return false,
+        };
+        match *key {
+            BufKey::Channel(ref nid, ref chan) if joined => {
+                self.send_net(nid, ClientNetMsg::PartChan(chan.clone(), None));
+            },
+            BufKey::Channel(ref nid, ref chan) => {
+                self.send_net(nid, ClientNetMsg::CloseBuf(BufTarget::Channel(chan.clone())));
+            },
+            BufKey::Private(ref nid, ref nick) => {
+                self.send_net(nid, ClientNetMsg::CloseBuf(BufTarget::Private(nick.clone())));
+            },
+            _ => {},
+        }
+        debug!("Closed client buffer {:?}", key);
+        // The buffer keeps its window number in case it comes back.
+        self.bufs.remove(key);
+        true
+    }
+
     /// Creates a buffer for the given `NetId` and `BufInfo`.
     fn create_remote_buf(&mut self, nid: NetId, info: BufInfo) {
         let key = BufKey::from_targ(nid, info.id);
         let buf = self.get_or_create(key);
-        buf.borrow_mut().set_joined(info.joined);
+        let mut buf = buf.borrow_mut();
+        buf.set_joined(info.joined);
+        buf.set_online(info.online);
+        buf.set_topic(info.topic);
+        if let Some((by, at)) = info.topic_set {
+            buf.set_topic_by(by, at);
+        }
+        buf.set_unread(info.unread);
     }
 
 
@@ -94,6 +229,11 @@ impl CoreModel {
         self.status.take()
     }
 
+    /// Takes the buffer the UI should switch to, if any.
+    pub fn take_opened(&mut self) -> Option<BufKey> {
+        self.opened.take()
+    }
+
 
     /// Returns a vector of new alerts.
     pub fn take_alerts(&mut self) -> Vec<Alert> {
@@ -134,9 +274,9 @@ impl CoreModel {
         }
     }
 
-    /// Asks the core to join the given channel
-    pub fn send_join(&mut self, netid: String, chan: String) {
-        self.send_net(&netid, ClientNetMsg::JoinChan(chan));
+    /// Asks the core to join the given channel, using the given key if any.
+    pub fn send_join(&mut self, netid: String, chan: String, key: Option<String>) {
+        self.send_net(&netid, ClientNetMsg::JoinChan { chan: chan, key: key });
     }
 
     /// Asks the core to part from the given channel
@@ -149,11 +289,85 @@ impl CoreModel {
         self.send_buf(key, ClientBufMsg::FetchLogs(count));
     }
 
+    /// Asks the core to search the given buffer's logs.
+    pub fn send_search(&mut self, key: &BufKey, query: String, limit: usize) {
+        self.send_buf(key, ClientBufMsg::SearchLogs { query: query, limit: limit });
+    }
+
+    /// Tells the core we've read every line in the given buffer up to and
+    /// including the line with ID `id`.
+    pub fn send_mark_read(&mut self, key: &BufKey, id: u64) {
+        self.send_buf(key, ClientBufMsg::MarkRead(id));
+    }
+
     /// Asks the core to change our nick.
     pub fn send_nick(&mut self, netid: String, new: String) {
         self.send_net(&netid, ClientNetMsg::ChangeNick(new));
     }
 
+    /// Asks the core to disconnect from the given network.
+    pub fn send_disconnect(&mut self, netid: &NetId, msg: Option<String>) {
+        self.send_net(netid, ClientNetMsg::Disconnect(msg));
+    }
+
+    /// Asks the core to connect to the given network.
+    pub fn send_connect(&mut self, netid: &NetId) {
+        self.send_net(netid, ClientNetMsg::Connect);
+    }
+
+    /// Asks the core to add a network. `cfg` is the network's settings as an
+    /// inline TOML table.
+    pub fn send_add_network(&mut self, netid: NetId, cfg: String) {
+        self.send(ClientMsg::AddNetwork(netid, cfg));
+    }
+
+    /// Asks the core to remove the given network.
+    pub fn send_remove_network(&mut self, netid: NetId) {
+        self.send(ClientMsg::RemoveNetwork(netid));
+    }
+
+    /// Asks the core to drop its connection to the given network and connect
+    /// again.
+    pub fn send_reconnect(&mut self, netid: &NetId, msg: Option<String>) {
+        self.send_net(netid, ClientNetMsg::Reconnect(msg));
+    }
+
+    /// Asks the core to send a `WHOIS` query for the given nick.
+    pub fn send_whois(&mut self, netid: &NetId, nick: String) {
+        self.send_net(netid, ClientNetMsg::Whois(nick));
+    }
+
+    /// Asks the core to set the topic of the channel `chan`.
+    pub fn send_topic(&mut self, netid: &NetId, chan: String, topic: String) {
+        self.send_net(netid, ClientNetMsg::SetTopic(BufTarget::Channel(chan), topic));
+    }
+
+    /// Asks the core to refresh the user list of the channel `key`, and shows
+    /// the list in the channel once it arrives.
+    pub fn send_names(&mut self, key: &BufKey) {
+        if let BufKey::Channel(ref nid, ref chan) = *key {
+            self.names_pending.insert(key.clone());
+            self.send_net(nid, ClientNetMsg::RefreshNames(BufTarget::Channel(chan.clone())));
+        }
+    }
+
+    /// Asks the core for the given network's channel list, optionally
+    /// filtered by `filter`.
+    pub fn send_list(&mut self, netid: &NetId, filter: Option<String>) {
+        self.chan_lists.insert(netid.clone(), vec![]);
+        self.send_net(netid, ClientNetMsg::ListChannels(filter));
+    }
+
+    /// Asks the core to send a raw IRC line to the given network.
+    pub fn send_raw(&mut self, netid: &NetId, line: String) {
+        self.send_net(netid, ClientNetMsg::RawLine(line));
+    }
+
+    /// Asks the core to mark us as away, or as back if `msg` is `None`.
+    pub fn send_away(&mut self, netid: &NetId, msg: Option<String>) {
+        self.send_net(netid, ClientNetMsg::SetAway(msg));
+    }
+
 
     /// Sends log requests for buffers that need it.
     pub fn send_log_reqs(&mut self) {
@@ -190,6 +404,10 @@ impl CoreModel {
             BufKey::Global(ref bid) => {
                 self.send(ClientMsg::BufMsg(bid.clone(), msg));
             },
+            BufKey::Search(_) | BufKey::ChanList(_) => {
+                // Search results and channel lists only exist on the client.
+                trace!("Not sending message to client-side buffer {:?}", key);
+            },
         }
     }
 
@@ -204,11 +422,17 @@ impl CoreModel {
         self.conn.send(msg)
     }
 
+    /// Connects to the core again with the given login details, even if it
+    /// rejected the last ones.
+    pub fn retry_core(&mut self, user: String, pass: Password) {
+        self.conn.retry(user, pass)
+    }
+
 
     /// Handles messages and updates the model's state.
     pub fn update(&mut self) {
-        while let Some(msg) = self.conn.recv() {
-            self.handle_msg(msg);
+        while let Some(ev) = self.conn.recv() {
+            self.handle_conn_event(ev);
         }
         for (_, &mut BufEntry { ref mut buf, .. }) in self.bufs.iter_mut() {
             buf.borrow_mut().update();
@@ -216,41 +440,118 @@ impl CoreModel {
         self.send_log_reqs();
     }
 
+    fn handle_conn_event(&mut self, ev: ConnEvent) {
+        match ev {
+            ConnEvent::Msg(msg) => self.handle_msg(msg),
+            ConnEvent::Connected => {
+                if self.conn_state == ConnState::Reconnecting {
+                    self.status("Reconnected to core".to_owned());
+                }
+                self.conn_state = ConnState::Connected;
+            },
+            ConnEvent::Latency(rtt) => self.latency = Some(rtt),
+            ConnEvent::AuthFailed => {
+                self.conn_state = ConnState::AuthFailed;
+                self.latency = None;
+                self.status("Failed to authenticate with core. Check your username and password, \
+                             then use /reconnect to try again".to_owned());
+            },
+            ConnEvent::Reconnecting(delay) => {
+                if self.conn_state == ConnState::Connected {
+                    warn!("Lost connection to core");
+                }
+                self.conn_state = ConnState::Reconnecting;
+                self.latency = None;
+                self.status(format!("Not connected to core. Reconnecting in {} seconds", (delay + 999) / 1000));
+            },
+        }
+    }
+
     fn handle_msg(&mut self, msg: CoreMsg) {
         match msg {
-            CoreMsg::Networks(nets) => {
+            CoreMsg::Networks(mut nets) => {
                 info!("Adding networks: {:?}", nets);
-                for net in nets {
+                // Sort so window numbers go by network, then buffer name.
+                nets.sort_by(|a, b| a.id.cmp(&b.id));
+                for mut net in nets {
+                    net.buffers.sort_by(|a, b| a.id.cmp(&b.id));
+                    self.nets.insert(net.id.clone(), NetState {
+                        nick: net.nick,
+                        name: net.name,
+                        away: net.away,
+                        connected: net.connected,
+                        offline: net.offline,
+                        casemap: CaseMapping::from_name(&net.casemapping).unwrap_or(CaseMapping::default()),
+                    });
                     for buf in net.buffers {
                         self.create_remote_buf(net.id.clone(), buf);
                     }
                 }
             },
-            CoreMsg::GlobalBufs(bufs) => {
+            CoreMsg::NetworkRemoved(nid) => {
+                info!("Removing network {}", nid);
+                self.nets.remove(&nid);
+                self.chan_lists.remove(&nid);
+                let keys = self.bufs.keys().filter(|k| k.nid() == Some(&nid)).cloned().collect::<Vec<_>>();
+                for key in keys {
+                    self.bufs.remove(&key);
+                }
+                self.status(format!("Removed network {}", nid));
+            },
+            CoreMsg::GlobalBufs(mut bufs) => {
                 debug!("New global buffers: {:?}", bufs);
+                bufs.sort_by(|a, b| a.name().cmp(b.name()));
                 for buf in bufs {
-                    self.get_or_create(BufKey::Global(buf.name().to_owned()));
+                    let gbuf = self.get_or_create(BufKey::Global(buf.name().to_owned()));
+                    let mut gbuf = gbuf.borrow_mut();
+                    gbuf.set_joined(buf.joined);
+                    gbuf.set_unread(buf.unread);
                 }
             },
             CoreMsg::NetMsg(nid, nmsg) => self.handle_net_msg(nid, nmsg),
             CoreMsg::BufMsg(bid, bmsg) => self.handle_buf_msg(BufKey::Global(bid), bmsg),
-            CoreMsg::Alerts(mut alerts) => self.alerts.append(&mut alerts),
+            CoreMsg::Alerts(alerts) => {
+                for alert in alerts {
+                    if let Some(key) = alert_buf(&alert.kind) {
+                        if let Some(buf) = self.get(&key) {
+                            let mut buf = buf.borrow_mut();
+                            // Muted buffers don't alert at all.
+                            if buf.muted() { continue; }
+                            buf.set_highlight(true);
+                        }
+                    }
+                    self.alerts.push(alert);
+                }
+            },
             CoreMsg::Status(msg) => self.status(msg),
-            CoreMsg::AuthOk | CoreMsg::AuthErr => {
+            CoreMsg::AuthOk { .. } | CoreMsg::AuthErr => {
                 error!("Got unexpected authentication response message during connection");
-            }
+            },
+            // The connection thread handles these itself.
+            CoreMsg::CompressionEnabled | CoreMsg::Ping(_) | CoreMsg::Pong(_) => {},
         }
     }
 
     fn handle_net_msg(&mut self, nid: NetId, msg: CoreNetMsg) {
         match msg {
             CoreNetMsg::Connection(state) => {
+                if let Some(net) = self.nets.get_mut(&nid) {
+                    net.connected = state;
+                }
                 if state {
                     self.status(format!("Core connected to network {}", nid));
                 } else {
                     self.status(format!("Core disconnected from network {}", nid));
                 }
             },
+            CoreNetMsg::Offline(offline) => {
+                if let Some(net) = self.nets.get_mut(&nid) {
+                    net.offline = offline;
+                }
+                if offline {
+                    self.status(format!("Network {} is offline until you /connect", nid));
+                }
+            },
             CoreNetMsg::Buffers(bufs) => {
                 for buf in bufs {
                     self.status(format!("Added buffer {}", BufKey::from_targ(nid.clone(), buf.id.clone())));
@@ -259,42 +560,221 @@ impl CoreModel {
             },
             CoreNetMsg::BufMsg(targ, bmsg) =>
                 self.handle_buf_msg(BufKey::from_targ(nid, targ), bmsg),
-            CoreNetMsg::Joined(_) => unimplemented!(),
-            CoreNetMsg::NickChanged(new) =>
-                self.status(format!("You are now known as {}", new)),
-        }
-    }
-
-    fn handle_buf_msg(&mut self, key: BufKey, msg: CoreBufMsg) {
-        let (buf, bs) = match self.bufs.get_mut(&key) {
-            Some(&mut BufEntry { ref mut buf, sender: Some(ref mut bs)}) => (buf, bs),
-            _ => {
-                error!("Ignoring message for unknown buffer: {:?}", key);
-                return;
+            CoreNetMsg::Joined(info) => {
+                let key = BufKey::from_targ(nid.clone(), info.id.clone());
+                self.status(format!("Joined {}", key));
+                self.create_remote_buf(nid, info);
+                self.opened = Some(key);
             },
-        };
-
-        match msg {
-            CoreBufMsg::State { joined } => {
-                buf.borrow_mut().set_joined(joined);
-                if joined {
-                    self.status = Some(format!("Joined channel {}", key));
-                } else {
-                    self.status = Some(format!("Parted channel {}", key));
+            CoreNetMsg::NickChanged(new) => {
+                if let Some(net) = self.nets.get_mut(&nid) {
+                    net.nick = new.clone();
+                }
+                self.status(format!("You are now known as {}", new))
+            },
+            CoreNetMsg::CaseMapping(name) => {
+                if let Some(net) = self.nets.get_mut(&nid) {
+                    net.casemap = CaseMapping::from_name(&name).unwrap_or(CaseMapping::default());
                 }
             },
-            CoreBufMsg::NewLines(lines) => {
-                for line in lines {
-                    trace!("Sending line {:?} to front", line);
-                    bs.send_front(line);
+            CoreNetMsg::NetName(name) => {
+                if let Some(net) = self.nets.get_mut(&nid) {
+                    net.name = Some(name);
                 }
             },
-            CoreBufMsg::Scrollback(lines) => {
-                for line in lines {
-                    trace!("Sending line {:?} to back", line);
-                    bs.send_back(line);
+            CoreNetMsg::Away(msg) => {
+                if let Some(net) = self.nets.get_mut(&nid) {
+                    net.away = msg;
                 }
             },
+            CoreNetMsg::ChannelList { chans, end } => self.show_chan_list(nid, chans, end),
         }
     }
+
+    fn handle_buf_msg(&mut self, key: BufKey, msg: CoreBufMsg) {
+        if let CoreBufMsg::SearchResults(lines) = msg {
+            self.show_search_results(key, lines);
+            return;
+        }
+
+        let show_names = match msg {
+            CoreBufMsg::Users(_) => self.names_pending.remove(&key),
+            _ => false,
+        };
+        {
+            let (buf, bs) = match self.bufs.get_mut(&key) {
+                Some(&mut BufEntry { ref mut buf, sender: Some(ref mut bs)}) => (buf, bs),
+                _ => {
+                    error!("Ignoring message for unknown buffer: {:?}", key);
+                    return;
+                },
+            };
+
+            match msg {
+                CoreBufMsg::State { joined } => {
+                    buf.borrow_mut().set_joined(joined);
+                    if joined {
+                        self.status = Some(format!("Joined channel {}", key));
+                    } else {
+                        self.status = Some(format!("Parted channel {}", key));
+                    }
+                },
+                CoreBufMsg::Online(online) => {
+                    buf.borrow_mut().set_online(online);
+                    if let BufKey::Private(_, ref nick) = key {
+                        let state = if online { "online" } else { "offline" };
+                        self.status = Some(format!("{} is now {}", nick, state));
+                    }
+                },
+                CoreBufMsg::NewLines(lines) => {
+                    for line in lines {
+                        trace!("Sending line {:?} to front", line);
+                        bs.send_front(line);
+                    }
+                },
+                CoreBufMsg::Scrollback(lines) => {
+                    for line in lines {
+                        trace!("Sending line {:?} to back", line);
+                        bs.send_back(line);
+                    }
+                },
+                CoreBufMsg::Users(users) => buf.borrow_mut().set_users(users),
+                CoreBufMsg::UserUpdate(user) => buf.borrow_mut().update_user(user),
+                CoreBufMsg::UserRemoved(nick) => buf.borrow_mut().remove_user(&nick),
+                CoreBufMsg::Topic(topic) => buf.borrow_mut().set_topic(topic),
+                CoreBufMsg::TopicSetBy(by, at) => buf.borrow_mut().set_topic_by(by, at),
+                CoreBufMsg::Unread(count) => buf.borrow_mut().set_unread(count),
+                CoreBufMsg::SearchResults(_) => unreachable!(),
+            }
+        }
+        if show_names {
+            self.show_names(&key);
+        }
+    }
+
+    /// Lists the users of the channel `key` in the channel's buffer.
+    pub fn show_names(&mut self, key: &BufKey) {
+        let lines = match self.get(key) {
+            Some(buf) => buf.borrow().names_lines(),
+            None => return,
+        };
+        if let Some(&mut BufEntry { sender: Some(ref mut bs), .. }) = self.bufs.get_mut(key) {
+            for msg in lines {
+                bs.send_front(BufferLine::new(time::now(), LineData::Message {
+                    kind: MsgKind::Status,
+                    from: "names".to_owned(),
+                    msg: msg,
+                }));
+            }
+        }
+    }
+
+    /// Puts search results for the buffer `key` in a fresh search buffer and
+    /// tells the UI to open it.
+    fn show_search_results(&mut self, key: BufKey, lines: Vec<BufferLine>) {
+        if lines.is_empty() {
+            self.status(format!("No matching lines in {}", key));
+            return;
+        }
+        self.status(format!("Found {} lines in {}", lines.len(), key));
+        let skey = BufKey::Search(Box::new(key));
+        // Replace the results of any previous search of this buffer.
+        self.bufs.remove(&skey);
+        self.get_or_create(skey.clone());
+        if let Some(&mut BufEntry { sender: Some(ref mut bs), .. }) = self.bufs.get_mut(&skey) {
+            for line in lines {
+                bs.send_back(line);
+            }
+        }
+        self.opened = Some(skey);
+    }
+
+    /// Adds a batch of channels to the channel list for `nid`. Once the last
+    /// batch arrives, the list is shown in its buffer and the UI is told to
+    /// open it. Lists we didn't ask for are ignored.
+    fn show_chan_list(&mut self, nid: NetId, batch: Vec<(String, usize, String)>, end: bool) {
+        match self.chan_lists.get_mut(&nid) {
+            Some(list) => list.extend(batch),
+            None => {
+                debug!("Ignoring channel list for {} we didn't ask for", nid);
+                return;
+            },
+        }
+        if !end { return; }
+        let mut chans = self.chan_lists.remove(&nid).unwrap_or_default();
+        // Busiest channels first.
+        chans.sort_by(|a, b| match b.1.cmp(&a.1) {
+            Ordering::Equal => a.0.cmp(&b.0),
+            ord => ord,
+        });
+        if chans.is_empty() {
+            self.status(format!("No channels found on {}", nid));
+            return;
+        }
+        self.status(format!("Found {} channels on {}", chans.len(), nid));
+
+        let key = BufKey::ChanList(nid);
+        // Replace any list from an earlier request.
+        self.bufs.remove(&key);
+        self.get_or_create(key.clone());
+        if let Some(&mut BufEntry { sender: Some(ref mut bs), .. }) = self.bufs.get_mut(&key) {
+            // Each line goes above the ones before it, so the busiest channels
+            // end up at the bottom where the view starts.
+            for (chan, users, topic) in chans {
+                bs.send_back(BufferLine::new(time::now(), LineData::Message {
+                    kind: MsgKind::Status,
+                    from: chan,
+                    msg: format!("{} users  {}", users, topic),
+                }));
+            }
+        }
+        self.opened = Some(key);
+    }
+}
+
+
+/// Gets the key of the buffer an alert happened in, if it's in one.
+fn alert_buf(kind: &AlertKind) -> Option<BufKey> {
+    match *kind {
+        AlertKind::Ping(ref nid, ref bid) => Some(BufKey::Channel(nid.clone(), bid.clone())),
+        AlertKind::PrivateMsg(ref nid, ref bid) => Some(BufKey::Private(nid.clone(), bid.clone())),
+        _ => None,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use common::alert::Alert;
+    use common::messages::{CoreMsg, Password};
+    use conn::ConnThread;
+    use super::{CoreModel, Buffer, BufKey};
+
+    /// Makes a model whose connection thread tries a port nothing listens
+    /// on, so it never hears from a core.
+    fn test_model() -> CoreModel {
+        let (status, _) = Buffer::new(BufKey::Status);
+        let addr = "127.0.0.1:9".parse().unwrap();
+        let conn = ConnThread::spawn(addr, "user".to_owned(), Password("pass".to_owned()), 1024);
+        CoreModel::new(status, conn)
+    }
+
+    #[test]
+    fn muted_buffer_no_alerts() {
+        let mut model = test_model();
+        let chan = BufKey::Channel("net".to_owned(), "#chan".to_owned());
+        let bob = BufKey::Private("net".to_owned(), "bob".to_owned());
+        model.get_or_create(chan.clone()).borrow_mut().set_muted(true);
+        model.get_or_create(bob.clone());
+
+        model.handle_msg(CoreMsg::Alerts(vec![
+            Alert::ping("net".to_owned(), "#chan".to_owned(), "bob".to_owned(), "me: hi".to_owned()),
+            Alert::privmsg("net".to_owned(), "bob".to_owned(), "bob".to_owned(), "hi".to_owned()),
+        ]));
+        assert!(!model.get(&chan).unwrap().borrow().highlight());
+        assert!(model.get(&bob).unwrap().borrow().highlight());
+        let alerts = model.take_alerts();
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].msg, "hi");
+    }
 }
@@ -59,26 +59,47 @@ impl ClientAlert {
 struct AlertState {
     def: ClientAlert,
     shown_at: Tm,
+    /// When an alert was last collapsed into this one.
+    last: Tm,
 }
 
 
 /// UI component for storing alerts and showing them on screen.
 pub struct AlertList {
     alerts: Vec<AlertState>,
+    /// Alerts from the same place are collapsed into one if they arrive
+    /// within this long of each other.
+    window: Duration,
 }
 
 impl AlertList {
-    pub fn new() -> AlertList {
+    pub fn new(window: Duration) -> AlertList {
         AlertList {
             alerts: vec![],
+            window: window,
         }
     }
 
     /// Pushes a new alert into the list.
+    ///
+    /// If there's already an alert from the same place, the new one is
+    /// collapsed into it if it's recent enough, or replaces it otherwise.
     pub fn push(&mut self, alert: ClientAlert) {
+        let now = time::now();
+        let existing = self.alerts.iter().position(|a| a.def.info.same_source(&alert.info));
+        if let Some(i) = existing {
+            if now - self.alerts[i].last < self.window {
+                let state = &mut self.alerts[i];
+                state.def.info.merge(alert.info);
+                state.last = now;
+                return;
+            }
+            self.alerts.remove(i);
+        }
         let state = AlertState {
             def: alert,
-            shown_at: time::now(),
+            shown_at: now,
+            last: now,
         };
         self.alerts.push(state);
         self.alerts.sort_by_key(|a| a.def.info.kind.clone());
@@ -8,7 +8,15 @@
 use std::cmp;
 
 use super::TermUi;
-use super::util::{RustBoxExt, LineBuilder};
+use rustbox::{Color, Style};
+
+use common::format::strip_formatting;
+
+use conn::ConnState;
+use model::BufKey;
+
+use super::util::{RustBoxExt, LineBuilder, Segment};
+use super::format::sanitize;
 
 pub trait StatusBar {
     /// Updates the status bar's state.
@@ -34,9 +42,8 @@ impl StatusBar for MainBar {
     fn height(&self, _ui: &TermUi) -> usize { 1 }
 
     fn render(&mut self, y: usize, ui: &mut TermUi) {
-        use rustbox::{RB_NORMAL};
+        use rustbox::{RB_NORMAL, RB_BOLD};
         use rustbox::Color::*;
-        // use super::util::AlignCol::*;
 
         let buf = ui.view.buf.borrow();
         let buf_name = buf.name();
@@ -49,13 +56,141 @@ impl StatusBar for MainBar {
 
         ui.rb.blank_line(y, RB_NORMAL, Default, Black);
 
+        let mut segs = vec![];
+        if let Some(num) = ui.model.window_num(&ui.key) {
+            segs.push(segment(format!("[{}] ", num), RB_BOLD, None));
+        }
+        segs.push(segment(buf_name.to_owned(), RB_NORMAL, None));
+        // Show whether the other user is online in private buffers.
+        if let BufKey::Private(_, _) = ui.key {
+            if buf.online() {
+                segs.push(segment(" (online)".to_owned(), RB_NORMAL, Some(Green)));
+            } else {
+                segs.push(segment(" (offline)".to_owned(), RB_NORMAL, Some(Red)));
+            }
+        }
+        if buf.muted() {
+            segs.push(segment(" (muted)".to_owned(), RB_NORMAL, Some(Blue)));
+        }
+        segs.push(segment(" | ".to_owned(), RB_NORMAL, None));
+        segs.push(segment(buf_scroll, RB_NORMAL, None));
+
+        let net = ui.key.nid().and_then(|nid| ui.model.nets.get(nid));
+        if let Some(name) = net.and_then(|net| net.name.clone()) {
+            segs.push(segment(" | ".to_owned(), RB_NORMAL, None));
+            segs.push(segment(name, RB_NORMAL, None));
+        }
+
+        // Networks the user disconnected from on purpose are shown
+        // differently from ones we lost.
+        let net_state = match net {
+            Some(net) if net.offline => Some(("offline", Default)),
+            Some(net) if !net.connected => Some(("disconnected", Red)),
+            _ => None,
+        };
+        if let Some((state, color)) = net_state {
+            segs.push(segment(" | ".to_owned(), RB_NORMAL, None));
+            segs.push(segment(state.to_owned(), RB_BOLD, Some(color)));
+        }
+
+        let away = net.map_or(false, |net| net.away.is_some());
+        if away {
+            segs.push(segment(" | ".to_owned(), RB_NORMAL, None));
+            segs.push(segment("away".to_owned(), RB_NORMAL, Some(Yellow)));
+        }
+
+        if let Some(lag) = ui.model.latency() {
+            segs.push(segment(format!(" | lag {}ms", lag), RB_NORMAL, None));
+        }
+
+        let conn = match ui.model.conn_state() {
+            ConnState::Connected => None,
+            ConnState::Connecting => Some("connecting..."),
+            ConnState::Reconnecting => Some("reconnecting..."),
+            ConnState::AuthFailed => Some("login failed"),
+        };
+        if let Some(conn) = conn {
+            segs.push(segment(" | ".to_owned(), RB_NORMAL, None));
+            segs.push(segment(conn.to_owned(), RB_BOLD, Some(Yellow)));
+        }
+
+        // List other buffers with activity by window number. Buffers with
+        // unread pings or private messages stand out.
+        let mut active = vec![];
+        for (i, key) in ui.model.window_keys().iter().enumerate() {
+            if *key == ui.key { continue; }
+            let buf = match ui.model.get(key) {
+                Some(buf) => buf.borrow(),
+                None => continue,
+            };
+            if buf.muted() {
+                continue;
+            } else if buf.highlight() {
+                active.push(segment(format!("{}:{}({})", i + 1, key, buf.unread()),
+                                    RB_BOLD, Some(Red)));
+            } else if buf.unread() > 0 {
+                active.push(segment(format!("{}:{}({})", i + 1, key, buf.unread()),
+                                    RB_NORMAL, Some(Cyan)));
+            }
+        }
+        if !active.is_empty() {
+            segs.push(segment(" | Act: ".to_owned(), RB_NORMAL, None));
+            for (i, seg) in active.into_iter().enumerate() {
+                if i > 0 {
+                    segs.push(segment(" ".to_owned(), RB_NORMAL, None));
+                }
+                segs.push(seg);
+            }
+        }
+
         let mut lb = LineBuilder::new();
         lb.skip(1);
-        lb.add_column(buf_name.to_owned()).fgcolor(White).bgcolor(Black);
-        lb.skip(1);
-        lb.add_column(" | ".to_owned());
-        lb.add_column(buf_scroll).fgcolor(White).bgcolor(Black);
+        lb.add_segments(segs).fgcolor(White).bgcolor(Black);
+        lb.print(y, &mut ui.rb);
+    }
+}
+
+/// Makes a segment for a status bar with the bar's background color.
+fn segment(text: String, style: Style, fgcolor: Option<Color>) -> Segment {
+    Segment {
+        text: text,
+        style: style,
+        fgcolor: fgcolor,
+        bgcolor: None,
+    }
+}
+
 
+/// Shows the current buffer's topic. Hidden when there's no topic.
+pub struct TopicBar;
+
+impl StatusBar for TopicBar {
+    fn update(&mut self, _: &mut TermUi) {
+    }
+
+    fn height(&self, ui: &TermUi) -> usize {
+        if ui.view.buf.borrow().topic().is_empty() { 0 } else { 1 }
+    }
+
+    fn render(&mut self, y: usize, ui: &mut TermUi) {
+        use rustbox::{RB_NORMAL};
+        use rustbox::Color::*;
+
+        let (topic, set_by) = {
+            let buf = ui.view.buf.borrow();
+            (buf.topic().to_owned(), buf.topic_set().map(|s| s.0.clone()))
+        };
+        if topic.is_empty() { return; }
+        let mut topic = sanitize(&strip_formatting(&topic), 1);
+        if let Some(by) = set_by {
+            topic.push_str(&format!(" (set by {})", sanitize(&by, 1)));
+        }
+
+        ui.rb.blank_line(y, RB_NORMAL, Default, Black);
+
+        let mut lb = LineBuilder::new();
+        lb.skip(1);
+        lb.add_column(topic).fgcolor(White).bgcolor(Black);
         lb.print(y, &mut ui.rb);
     }
 }
@@ -85,6 +220,9 @@ impl StatusBar for AlertBar {
             let mut lb = LineBuilder::new();
             lb.add_column(format!("{}: ", i+1)).fgcolor(White).bgcolor(Black).pad_left(4);
             lb.add_column(alert.info.msg.clone()).fgcolor(White).bgcolor(Black);
+            if alert.info.count > 1 {
+                lb.add_column(format!(" ({}\u{d7})", alert.info.count)).fgcolor(Yellow).bgcolor(Black);
+            }
 
             lb.print(y+i, &mut ui.rb);
         }
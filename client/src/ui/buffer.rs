@@ -1,12 +1,99 @@
 //! This module implements the buffer display widget.
 
+use std::cmp;
 use std::rc::Rc;
 use std::cell::RefCell;
-use rustbox::RustBox;
+use rustbox::{RustBox, Color};
+use time::{self, Tm, Timespec};
 
-use common::line::{LineData, MsgKind};
+use common::line::{BufferLine, LineData, MsgKind};
+use common::casemap::CaseMapping;
 
 use model::Buffer;
+use config::UiConfig;
+use super::color::NickColors;
+use super::format::{format_segments, highlight_nick, sanitize};
+use super::util::Segment;
+
+/// Display settings shared by all buffer views.
+#[derive(Debug, Clone)]
+pub struct ViewOpts {
+    pub nick_colors: NickColors,
+    /// If false, formatting codes are stripped from messages.
+    pub formatting: bool,
+    /// Whether to hide joins, parts and quits in buffers which haven't been
+    /// toggled with `/filter`.
+    pub hide_jpq: bool,
+    /// strftime format for timestamps. Empty if they're hidden.
+    pub time_format: String,
+    /// Whether to draw the date above the first line of each day.
+    pub date_separators: bool,
+    /// UTC offset in seconds to show times in. `None` for local time.
+    pub utc_offset: Option<i32>,
+    /// Number of spaces tabs are expanded to.
+    pub tab_width: usize,
+    /// If set, runs of joins, parts or quits at most this many seconds apart
+    /// are shown as a single line.
+    pub compact_window: Option<i64>,
+    /// Whether to color the whole of lines that mention our nick, not just
+    /// the mentions.
+    pub tint_highlights: bool,
+}
+
+impl ViewOpts {
+    pub fn from_cfg(cfg: &UiConfig) -> ViewOpts {
+        ViewOpts {
+            nick_colors: NickColors::from_cfg(cfg),
+            formatting: cfg.formatting(),
+            hide_jpq: cfg.hide_jpq(),
+            time_format: cfg.time_format(),
+            date_separators: cfg.date_separators(),
+            utc_offset: cfg.utc_offset(),
+            tab_width: cfg.tab_width(),
+            compact_window: if cfg.compact_jpq() { Some(cfg.compact_window() as i64) } else { None },
+            tint_highlights: cfg.tint_highlights(),
+        }
+    }
+
+    /// Returns the time a line was sent in the configured timezone.
+    fn line_time(&self, line: &BufferLine) -> Tm {
+        self.local_time(line.time().to_timespec().sec)
+    }
+
+    /// Converts a Unix timestamp to a time in the configured timezone.
+    pub fn local_time(&self, secs: i64) -> Tm {
+        match self.utc_offset {
+            Some(off) => {
+                let mut tm = time::at_utc(Timespec::new(secs + off as i64, 0));
+                tm.tm_utcoff = off;
+                tm
+            },
+            None => time::at(Timespec::new(secs, 0)),
+        }
+    }
+
+    /// Formats a line's timestamp.
+    fn format_time(&self, tm: &Tm) -> String {
+        if self.time_format.is_empty() {
+            String::new()
+        } else {
+            // The format was checked when the config was loaded.
+            tm.strftime(&self.time_format).map(|t| t.to_string()).unwrap_or(String::new())
+        }
+    }
+
+    /// Number of columns timestamps take up.
+    fn time_width(&self) -> usize {
+        // Use a time where most fields are at their widest.
+        let tm = Tm {
+            tm_sec: 59, tm_min: 59, tm_hour: 23, tm_mday: 28, tm_mon: 11,
+            tm_year: 100, tm_wday: 3, tm_yday: 362, tm_isdst: 0,
+            tm_utcoff: 0, tm_nsec: 0,
+        };
+        self.format_time(&tm).chars().count()
+    }
+}
+
 
 #[derive(Debug)]
 pub struct BufferView {
@@ -19,6 +106,17 @@ pub struct BufferView {
     time_col_w: usize,
     /// Number of columns reserved for sender names.
     name_col_w: usize,
+    /// Index of the first line that was unread when we opened the buffer.
+    /// A divider is drawn above it.
+    divider: Option<isize>,
+    /// Number of columns the view was last rendered in.
+    width: usize,
+    /// Our nick on the buffer's network, if it has one. Mentions of it are
+    /// highlighted.
+    nick: Option<String>,
+    /// The casemapping of the buffer's network.
+    casemap: CaseMapping,
+    opts: ViewOpts,
 }
 
 impl BufferView {
@@ -26,60 +124,112 @@ impl BufferView {
     ///
     /// The view maintains ownership over the buffer during its lifetime.
     /// To get the buffer back, call `into_buf`.
-    pub fn new(bh: Rc<RefCell<Buffer>>, rb: &mut RustBox) -> Self {
-        {
+    pub fn new(bh: Rc<RefCell<Buffer>>, opts: ViewOpts, rb: &mut RustBox) -> Self {
+        let divider = {
             let mut buf = bh.borrow_mut();
             if rb.height() > buf.len() {
                 buf.request_logs(rb.height());
             }
-        }
+            if buf.unread() > 0 {
+                Some(buf.front_len() - buf.unread() as isize)
+            } else { None }
+        };
         BufferView {
             buf: bh,
             scroll: None,
-            time_col_w: 8,
+            time_col_w: opts.time_width(),
             name_col_w: 16,
+            divider: divider,
+            width: rb.width(),
+            nick: None,
+            casemap: CaseMapping::default(),
+            opts: opts,
         }
     }
 
+    /// Sets our nick on the buffer's network and the network's casemapping.
+    pub fn set_nick(&mut self, nick: Option<String>, cm: CaseMapping) {
+        self.nick = nick;
+        self.casemap = cm;
+    }
+
 
     /// Displays the buffer on the terminal.
     ///
-    /// The buffer is rendered between rows `y1` and `y2` in the terminal.
-    pub fn render(&mut self, rb: &mut RustBox, y1: usize, y2: usize) {
+    /// The buffer is rendered between rows `y1` and `y2` in the first `w`
+    /// columns of the terminal.
+    pub fn render(&mut self, rb: &mut RustBox, y1: usize, y2: usize, w: usize) {
         debug_assert!(y1 < y2);
         debug_assert!(y1 < rb.height());
+        self.width = w;
         let buf = self.buf.borrow();
         if buf.is_empty() { return; }
+        let hide_jpq = buf.hide_jpq().unwrap_or(self.opts.hide_jpq);
         let mut y = y2;
         let mut i = self.scroll.unwrap_or(buf.first_idx());
         while y > y1 && i >= buf.last_idx() {
+            let idx = i;
             let ref line = buf.get(i);
 
             i -= 1;
-            let tm = line.time();
-            let timefmt = tm.strftime("%H:%M:%S").expect("Failed to format time");
-            let time = format!("{0: >1$}", timefmt, self.time_col_w);
+            let tm = self.opts.line_time(line);
+            let time = format!("{0: >1$}", self.opts.format_time(&tm), self.time_col_w);
 
+            let run = if hide_jpq { None } else { self.jpq_run(&buf, idx) };
+            // Index of the oldest line this row shows.
+            let oldest = run.as_ref().map_or(idx, |r| r.0);
             let dy = match line.data {
+                _ if oldest != idx => {
+                    let (_, verb, ref nicks) = *run.as_ref().unwrap();
+                    i = oldest - 1;
+                    let arrow = if verb == "joined" { "-->" } else { "<--" };
+                    self.render_line(y, rb, &time, arrow, &summarize_jpq(verb, nicks))
+                },
+                LineData::Join { .. } |
+                LineData::Part { .. } |
+                LineData::Quit { .. } if hide_jpq => 0,
                 LineData::Message { ref kind, ref from, ref msg, .. } => {
-                    let (from, msg) = match *kind {
+                    let color = self.opts.nick_colors.nick_color(from);
+                    let mentions = match *kind {
+                        MsgKind::PrivMsg | MsgKind::Notice | MsgKind::Action => self.mentions_nick(from),
+                        _ => None,
+                    };
+                    let (from, msg, color) = match *kind {
                         MsgKind::PrivMsg =>
-                            (format!("<{}>", from), msg.to_owned()),
+                            (format!("<{}>", from), msg.to_owned(), color),
                         MsgKind::Notice =>
-                            (format!("[{}]", from), msg.to_owned()),
+                            (format!("[{}]", from), msg.to_owned(), Color::Default),
                         MsgKind::Action =>
-                            (format!(" * "), format!("{} {}", from, msg)),
+                            (format!(" * "), format!("{} {}", from, msg), color),
                         MsgKind::Response(_) =>
-                            (format!("{}", from), msg.to_owned()),
+                            (format!("{}", from), msg.to_owned(), Color::Default),
                         MsgKind::Status =>
-                            (format!("*{}*", from), msg.to_owned()),
+                            (format!("*{}*", from), msg.to_owned(), Color::Default),
                     };
-                    self.render_line(y, rb, &time, &from, &msg)
+                    let mut segs = format_segments(&msg, self.opts.formatting, self.opts.tab_width);
+                    let mut tint = None;
+                    if let Some(nick) = mentions {
+                        if self.opts.tint_highlights && self.casemap.contains_word(&msg, nick) {
+                            tint = Some(Color::Yellow);
+                        }
+                        segs = highlight_nick(segs, nick, self.casemap);
+                    }
+                    self.render_segments(y, rb, &time, &from, tint.unwrap_or(color), segs, tint)
                 },
-                LineData::Topic { ref by, ref topic } => {
-                    let user = by.clone().unwrap_or("*".to_owned());
-                    let line = format!("set topic to: {}", topic);
-                    self.render_line(y, rb, &time, &user, &line)
+                LineData::Topic { ref by, ref topic, ref set } => {
+                    if let Some(ref by) = *by {
+                        let line = format!("{} set topic to: {}", by, topic);
+                        self.render_line(y, rb, &time, "***", &line)
+                    } else if let Some((ref by, at)) = *set {
+                        let at = self.opts.local_time(at);
+                        let at = at.strftime("%Y-%m-%d %H:%M").map(|t| t.to_string())
+                            .unwrap_or(String::new());
+                        let line = format!("Topic is: {} (set by {} at {})", topic, by, at);
+                        self.render_line(y, rb, &time, "***", &line)
+                    } else {
+                        let line = format!("Topic is: {}", topic);
+                        self.render_line(y, rb, &time, "***", &line)
+                    }
                 },
                 LineData::Join { ref user } => {
                     let line = format!("{0} ({1}@{2}) has joined {3}",
@@ -111,29 +261,126 @@ impl BufferView {
                     let line = format!("{} is now known as {}", user, new);
                     self.render_line(y, rb, &time, "***", &line)
                 },
+                LineData::Mode { ref by, ref modes, ref args } => {
+                    let line = if args.is_empty() {
+                        format!("{} sets mode {}", by.nick, modes)
+                    } else {
+                        format!("{} sets mode {} {}", by.nick, modes, args.join(" "))
+                    };
+                    self.render_line(y, rb, &time, "***", &line)
+                },
+                LineData::Whois { ref info, .. } => {
+                    // Render the lines bottom up so they appear in order.
+                    let mut dy = 0;
+                    for line in info.iter().rev() {
+                        if dy >= y { break; }
+                        dy += self.render_line(y - dy, rb, &time, "whois", line);
+                    }
+                    dy
+                },
             };
             if y > dy {
                 y -= dy;
             } else { break; }
+
+            if self.divider.map_or(false, |d| oldest <= d && d <= idx) && y > y1 {
+                y -= 1;
+                self.render_divider(y, rb);
+            }
+            // Lines before the ones we have might be from the same day, so
+            // only mark days that start within the buffer.
+            if self.opts.date_separators && oldest > buf.last_idx() && y > y1 {
+                let tm = self.opts.line_time(buf.get(oldest));
+                let prev = self.opts.line_time(buf.get(oldest - 1));
+                if prev.tm_year != tm.tm_year || prev.tm_yday != tm.tm_yday {
+                    y -= 1;
+                    self.render_date(y, rb, &tm);
+                }
+            }
+        }
+    }
+
+    /// Finds the run of joins, parts or quits that ends with the line at
+    /// `idx`, if compacting is on and there's more than one.
+    ///
+    /// Lines in a run are the same kind of event and each comes within the
+    /// compacting window of the next. Returns the index of the oldest line in
+    /// the run, a verb for the event and the users' nicks, oldest first.
+    fn jpq_run(&self, buf: &Buffer, idx: isize) -> Option<(isize, &'static str, Vec<String>)> {
+        let window = match self.opts.compact_window {
+            Some(window) => window,
+            None => return None,
+        };
+        let (verb, nick) = match jpq_event(buf.get(idx)) {
+            Some(ev) => ev,
+            None => return None,
+        };
+        let mut nicks = vec![nick.to_owned()];
+        let mut oldest = idx;
+        while oldest > buf.last_idx() {
+            let (newer, older) = (buf.get(oldest), buf.get(oldest - 1));
+            let gap = newer.time().to_timespec().sec - older.time().to_timespec().sec;
+            match jpq_event(older) {
+                Some((v, nick)) if v == verb && gap <= window => nicks.push(nick.to_owned()),
+                _ => break,
+            }
+            oldest -= 1;
         }
+        if nicks.len() < 2 { return None; }
+        nicks.reverse();
+        Some((oldest, verb, nicks))
+    }
+
+    fn render_date(&self, y: usize, rb: &mut RustBox, tm: &Tm) {
+        use rustbox::RB_NORMAL;
+        use rustbox::Color::*;
+
+        let date = tm.strftime("%Y-%m-%d").map(|d| format!(" {} ", d)).unwrap_or(String::new());
+        let line = format!("{:─^1$}", date, self.width);
+        rb.print(0, y, RB_NORMAL, Cyan, Default, &line);
+    }
+
+    fn render_divider(&self, y: usize, rb: &mut RustBox) {
+        use rustbox::RB_NORMAL;
+        use rustbox::Color::*;
+
+        let line = format!("{:-^1$}", " new messages ", self.width);
+        rb.print(0, y, RB_NORMAL, Red, Default, &line);
+    }
+
+    fn render_line(&self, y: usize, rb: &mut RustBox, time: &str, from: &str, line: &str) -> usize {
+        let segs = format_segments(line, self.opts.formatting, self.opts.tab_width);
+        self.render_segments(y, rb, time, from, Color::Default, segs, None)
     }
 
-    fn render_line(&self, mut y: usize, rb: &mut RustBox, time: &str, from: &str, line: &str) -> usize {
+    /// Draws a line made of already formatted segments, with `from` in the
+    /// given color. Segments without a color of their own are drawn in
+    /// `color` if it's given.
+    fn render_segments(&self, mut y: usize, rb: &mut RustBox, time: &str, from: &str,
+                       from_color: Color, segs: Vec<Segment>, color: Option<Color>) -> usize {
         use rustbox::RB_BOLD;
         use super::util::LineBuilder;
 
         let mut lb = LineBuilder::new();
+        lb.max_width(self.width);
 
         lb.skip(1);
-        lb.add_column(time.to_owned())
-            .pad_right(self.time_col_w);
-        lb.skip(1);
-        lb.add_column(from.to_owned())
+        if self.time_col_w > 0 {
+            lb.add_column(time.to_owned())
+                .pad_right(self.time_col_w);
+            lb.skip(1);
+        }
+        lb.add_column(sanitize(from, 1))
             .style(RB_BOLD)
+            .fgcolor(from_color)
             .pad_left(self.name_col_w);
         lb.skip(1);
-        lb.add_column(line.to_owned())
-            .wrap();
+        {
+            let col = lb.add_segments(segs).wrap();
+            if let Some(color) = color {
+                col.fgcolor(color);
+            }
+        }
 
         let h = lb.height(rb);
         if y > h {
@@ -144,6 +391,16 @@ impl BufferView {
     }
 
 
+    /// Returns our nick if a message from `from` could mention it. Our own
+    /// messages never do.
+    fn mentions_nick(&self, from: &str) -> Option<&str> {
+        match self.nick {
+            Some(ref nick) if !self.casemap.eq(nick, from) => Some(nick),
+            _ => None,
+        }
+    }
+
+
     /// Scrolls by the given number of lines and fetches backlog from the server
     /// if we've scrolled to the top.
     pub fn scroll_and_fetch(&mut self, by: isize, rb: &mut RustBox) {
@@ -189,3 +446,51 @@ impl BufferView {
         }
     }
 }
+
+
+/// If `line` is a join, part or quit, returns a verb for it and the user's
+/// nick.
+fn jpq_event(line: &BufferLine) -> Option<(&'static str, &str)> {
+    match line.data {
+        LineData::Join { ref user } => Some(("joined", &user.nick)),
+        LineData::Part { ref user, .. } => Some(("left", &user.nick)),
+        LineData::Quit { ref user, .. } => Some(("quit", &user.nick)),
+        _ => None,
+    }
+}
+
+/// Most nicks listed in a summary of joins, parts or quits. The rest are
+/// only counted.
+const MAX_SUMMARY_NICKS: usize = 8;
+
+/// Describes a run of joins, parts or quits, like "3 users quit: a, b, c".
+fn summarize_jpq(verb: &str, nicks: &[String]) -> String {
+    let shown = &nicks[..cmp::min(nicks.len(), MAX_SUMMARY_NICKS)];
+    let mut summary = format!("{} users {}: {}", nicks.len(), verb, shown.join(", "));
+    if nicks.len() > shown.len() {
+        summary.push_str(&format!(" and {} more", nicks.len() - shown.len()));
+    }
+    summary
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::summarize_jpq;
+
+    fn nicks(count: usize) -> Vec<String> {
+        (0..count).map(|i| format!("user{}", i)).collect()
+    }
+
+    #[test]
+    fn summary_lists_nicks() {
+        assert_eq!(summarize_jpq("quit", &nicks(3)), "3 users quit: user0, user1, user2");
+    }
+
+    #[test]
+    fn summary_capped() {
+        let summary = summarize_jpq("joined", &nicks(200));
+        assert_eq!(summary, "200 users joined: user0, user1, user2, user3, user4, \
+                             user5, user6, user7 and 192 more");
+    }
+}
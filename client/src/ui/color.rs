@@ -0,0 +1,95 @@
+//! Picks colors for nicks so conversations are easier to follow.
+
+use std::ascii::AsciiExt;
+use rustbox::Color;
+
+use config::UiConfig;
+
+/// Colors nicks are picked from if the config doesn't list any.
+const DEFAULT_PALETTE: &'static [Color] = &[
+    Color::Red, Color::Green, Color::Yellow, Color::Blue, Color::Magenta, Color::Cyan,
+];
+
+
+/// Maps nicks to colors from a palette.
+#[derive(Debug, Clone)]
+pub struct NickColors {
+    /// Colors to pick from. Nicks aren't colored if this is empty.
+    palette: Vec<Color>,
+}
+
+impl NickColors {
+    pub fn from_cfg(cfg: &UiConfig) -> NickColors {
+        if !cfg.nick_colors() {
+            return NickColors { palette: vec![] };
+        }
+        let palette = match cfg.nick_palette {
+            Some(ref names) => names.iter().filter_map(|name| {
+                let color = parse_color(name);
+                if color.is_none() {
+                    warn!("Ignoring unknown color {} in nick palette", name);
+                }
+                color
+            }).collect(),
+            None => DEFAULT_PALETTE.to_vec(),
+        };
+        NickColors { palette: palette }
+    }
+
+    /// Gets the color for the given nick. A nick always gets the same color,
+    /// regardless of case.
+    pub fn nick_color(&self, nick: &str) -> Color {
+        if self.palette.is_empty() {
+            Color::Default
+        } else {
+            self.palette[nick_hash(nick) as usize % self.palette.len()]
+        }
+    }
+}
+
+
+/// FNV-1a hash of the lowercased nick.
+///
+/// We don't use `std`'s hasher since its output isn't guaranteed to stay the
+/// same between versions, and nicks shouldn't change color after an upgrade.
+fn nick_hash(nick: &str) -> u32 {
+    nick.bytes().fold(2166136261, |h, b| {
+        (h ^ b.to_ascii_lowercase() as u32).wrapping_mul(16777619)
+    })
+}
+
+/// Parses a color name from the config. Numbers are taken as 256-color
+/// palette indices.
+fn parse_color(name: &str) -> Option<Color> {
+    match &name.to_lowercase()[..] {
+        "black" => Some(Color::Black),
+        "red" => Some(Color::Red),
+        "green" => Some(Color::Green),
+        "yellow" => Some(Color::Yellow),
+        "blue" => Some(Color::Blue),
+        "magenta" => Some(Color::Magenta),
+        "cyan" => Some(Color::Cyan),
+        "white" => Some(Color::White),
+        n => n.parse::<u8>().ok().map(|n| Color::Byte(n as u16)),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use rustbox::Color;
+    use super::{nick_hash, parse_color};
+
+    #[test]
+    fn hash_ignores_case() {
+        assert_eq!(nick_hash("Forkk"), nick_hash("forkk"));
+        assert!(nick_hash("forkk") != nick_hash("forkk_"));
+    }
+
+    #[test]
+    fn color_names() {
+        assert!(parse_color("Cyan") == Some(Color::Cyan));
+        assert!(parse_color("202") == Some(Color::Byte(202)));
+        assert!(parse_color("mauve").is_none());
+    }
+}
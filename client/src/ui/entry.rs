@@ -1,9 +1,13 @@
 //! This module implements the text entry widget.
 
 use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
 use rustbox::{ RustBox, Event, Key, Style, Color };
 
 use super::wrap::StringWrap;
+use super::util::RustBoxExt;
 
 /// The IRC client's text box.
 pub struct TextEntry {
@@ -14,9 +18,40 @@ pub struct TextEntry {
     hist_pos: usize,
     /// Queue of entries that haven't been processed yet.
     cmds: VecDeque<String>,
+    /// File entries are saved to, if any.
+    hist_file: Option<PathBuf>,
+    /// Maximum number of entries to keep in the history.
+    hist_max: usize,
+    /// The reverse history search in progress, if any.
+    search: Option<HistSearch>,
+    /// What's followed an escape we think starts a bracketed paste marker.
+    paste_marker: Option<String>,
+    /// Lines pasted so far, while a bracketed paste is coming in.
+    pasting: Option<Vec<String>>,
+    /// A multi-line paste waiting to be confirmed.
+    pasted: Option<Vec<String>>,
 }
 
+/// State of a Ctrl-R reverse incremental history search.
+struct HistSearch {
+    /// What's been typed so far.
+    query: String,
+    /// Index of the history entry that matches the query, if any.
+    found: Option<usize>,
+    /// True if the query stopped matching anything. The last match is kept.
+    failed: bool,
+}
+
+/// Shown before the query while searching the history.
+const SEARCH_PROMPT: &'static str = "(reverse-i-search)`";
+
+/// What follows `ESC [` at the start of a bracketed paste.
+const PASTE_START: &'static str = "200~";
+/// What follows `ESC [` at the end of a bracketed paste.
+const PASTE_END: &'static str = "201~";
+
 impl TextEntry {
+    /// Creates a text entry whose history isn't saved.
     pub fn new() -> TextEntry {
         let mut hist = VecDeque::new();
         hist.push_front(String::new());
@@ -26,34 +61,80 @@ impl TextEntry {
             hist: hist,
             hist_pos: 0,
             cmds: VecDeque::new(),
+            hist_file: None,
+            hist_max: 1000,
+            search: None,
+            paste_marker: None,
+            pasting: None,
+            pasted: None,
         }
     }
 
+    /// Creates a text entry which loads its history from `path` and saves new
+    /// entries to it, keeping at most `max` entries.
+    pub fn with_history(path: PathBuf, max: usize) -> TextEntry {
+        let mut entry = TextEntry::new();
+        let mut lines = match File::open(&path) {
+            Ok(f) => BufReader::new(f).lines().filter_map(|l| l.ok()).collect::<Vec<_>>(),
+            Err(_) => vec![],
+        };
+        if lines.len() > max {
+            // Drop the oldest entries and rewrite the file so it doesn't grow
+            // forever.
+            let extra = lines.len() - max;
+            lines.drain(..extra);
+            if let Err(e) = write_hist_file(&path, &lines) {
+                warn!("Failed to rewrite history file {}: {}", path.display(), e);
+            }
+        }
+        for line in lines {
+            entry.hist.insert(1, line);
+        }
+        entry.hist_file = Some(path);
+        entry.hist_max = max;
+        entry
+    }
+
     /// Returns the next entry in the list of things the user has typed.
     pub fn next_entry(&mut self) -> Option<String> {
         self.cmds.pop_front()
     }
 
-
-    /// Queries the height of the entry in the given terminal.
-    pub fn height(&self, rb: &mut RustBox) -> usize {
-        StringWrap::new(self.get_text(), rb.width()).line_count()
+    /// Takes the lines of a multi-line paste, which should be confirmed
+    /// before they're sent.
+    pub fn take_paste(&mut self) -> Option<Vec<String>> {
+        self.pasted.take()
     }
 
-    /// Renders the text entry in the given terminal.
-    pub fn render(&self, rb: &mut RustBox) {
+
+    /// Renders the text entry in the given terminal and returns its height.
+    pub fn render(&self, rb: &mut RustBox) -> usize {
         let w = rb.width();
         let h = rb.height();
 
-        let wrap = StringWrap::new(self.get_text(), w);
+        // While searching, show the search prompt with the cursor after the
+        // query.
+        let (text, cursor_idx) = match self.search {
+            Some(ref search) => {
+                let found = search.found.map_or("", |i| &self.hist[i][..]);
+                let prompt = if search.failed { "(failed " } else { "(" };
+                let text = format!("{}{}{}': {}", prompt, &SEARCH_PROMPT[1..], search.query, found);
+                let idx = text.len() - found.len() - 3;
+                (text, idx)
+            },
+            None => (self.get_text().to_owned(), self.cursor_idx),
+        };
+
+        let wrap = StringWrap::new(&text, w);
         let ent_y = h - wrap.line_count();
 
-        for (i, line) in wrap.iter_lines(self.get_text()).enumerate() {
-            rb.print(0, ent_y + i - 1, Style::empty(), Color::Default, Color::Default, line);
+        for (i, line) in wrap.iter_lines(&text).enumerate() {
+            rb.print_wide(0, ent_y + i - 1, Style::empty(), Color::Default, Color::Default, line);
         }
 
-        let (x, y) = wrap.idx_pos(self.cursor_col() as usize);
+        let (x, y) = wrap.idx_pos(&text, cursor_idx);
         rb.set_cursor(x, ent_y as isize + y);
+        wrap.line_count()
     }
 
     /// Handles an event
@@ -80,7 +161,29 @@ impl TextEntry {
     }
 
     fn handle_key(&mut self, key: &Key) -> bool {
+        // With the terminal in bracketed paste mode, pastes are wrapped in
+        // `ESC [200~` and `ESC [201~`, which reach us as Alt-[ and then
+        // the rest of the marker.
+        if self.paste_marker.is_some() {
+            return self.handle_paste_marker(key);
+        } else if *key == Key::Alt('[') {
+            self.paste_marker = Some(String::new());
+            return true;
+        } else if self.pasting.is_some() {
+            return self.handle_paste_key(key);
+        }
+        if self.search.is_some() {
+            return self.handle_search_key(key);
+        }
         match *key {
+            Key::Ctrl('r') => {
+                self.search = Some(HistSearch {
+                    query: String::new(),
+                    found: None,
+                    failed: false,
+                });
+                true
+            },
             Key::Char(ch) => {
                 self.hist[self.hist_pos].insert(self.cursor_idx as usize, ch);
                 self.move_cursor_by(1);
@@ -95,8 +198,20 @@ impl TextEntry {
             },
             Key::Left => { self.move_cursor_by(-1); true },
             Key::Right => { self.move_cursor_by(1); true },
-            Key::Home => { self.move_cursor_home(); true },
-            Key::End => { self.move_cursor_end(); true },
+            Key::Home | Key::Ctrl('a') => { self.move_cursor_home(); true },
+            Key::End | Key::Ctrl('e') => { self.move_cursor_end(); true },
+            Key::Ctrl('u') => {
+                let idx = self.cursor_idx;
+                self.hist[self.hist_pos].drain(..idx);
+                self.move_cursor_home();
+                true
+            },
+            Key::Ctrl('k') => {
+                let idx = self.cursor_idx;
+                self.hist[self.hist_pos].truncate(idx);
+                true
+            },
+            Key::Ctrl('w') => { self.delete_word(); true },
             Key::Enter => {
                 let text = self.get_text().to_owned();
                 if !text.is_empty() {
@@ -125,15 +240,138 @@ impl TextEntry {
     }
 
 
+    /// Handles a key after what might be the start of a paste marker. If it
+    /// turns out not to be one, the keys are handled as if they were typed.
+    fn handle_paste_marker(&mut self, key: &Key) -> bool {
+        let mut marker = self.paste_marker.take().unwrap();
+        if let Key::Char(ch) = *key {
+            marker.push(ch);
+            if marker == PASTE_START {
+                self.pasting = Some(vec![String::new()]);
+                return true;
+            } else if marker == PASTE_END {
+                self.end_paste();
+                return true;
+            } else if PASTE_START.starts_with(&marker[..]) || PASTE_END.starts_with(&marker[..]) {
+                self.paste_marker = Some(marker);
+                return true;
+            }
+            marker.pop();
+        }
+        for ch in marker.chars() {
+            self.handle_key(&Key::Char(ch));
+        }
+        self.handle_key(key)
+    }
+
+    /// Handles a key that's part of a paste.
+    fn handle_paste_key(&mut self, key: &Key) -> bool {
+        let lines = self.pasting.as_mut().unwrap();
+        match *key {
+            Key::Char(ch) => lines.last_mut().unwrap().push(ch),
+            Key::Tab => lines.last_mut().unwrap().push('\t'),
+            Key::Enter => lines.push(String::new()),
+            _ => {},
+        }
+        true
+    }
+
+    /// Finishes a paste. A single line goes into the entry as if it were
+    /// typed, but several lines are held until they're confirmed.
+    fn end_paste(&mut self) {
+        let mut lines = match self.pasting.take() {
+            Some(lines) => lines,
+            None => return,
+        };
+        // Most pastes end with a line break.
+        if lines.len() > 1 && lines.last().map_or(false, |l| l.is_empty()) {
+            lines.pop();
+        }
+        if lines.len() == 1 {
+            for ch in lines[0].chars() {
+                self.handle_key(&Key::Char(ch));
+            }
+        } else {
+            self.pasted = Some(lines);
+        }
+    }
+
+    /// Handles a key while searching the history.
+    ///
+    /// Typing narrows the search, Ctrl-R looks for an older match, Enter
+    /// puts the match in the entry and Esc or Ctrl-G gives up. Any other key
+    /// ends the search as Enter does and is then handled as usual.
+    fn handle_search_key(&mut self, key: &Key) -> bool {
+        match *key {
+            Key::Char(_) | Key::Backspace | Key::Ctrl('r') => {},
+            Key::Esc | Key::Ctrl('g') => {
+                self.search = None;
+                return true;
+            },
+            Key::Enter => {
+                self.accept_search();
+                return true;
+            },
+            _ => {
+                self.accept_search();
+                return self.handle_key(key);
+            },
+        }
+        let (query, start) = {
+            let search = self.search.as_mut().unwrap();
+            match *key {
+                Key::Char(ch) => {
+                    search.query.push(ch);
+                    (search.query.clone(), search.found.unwrap_or(1))
+                },
+                Key::Backspace => {
+                    search.query.pop();
+                    (search.query.clone(), 1)
+                },
+                // Ctrl-R looks further back.
+                _ => (search.query.clone(), search.found.map_or(1, |i| i + 1)),
+            }
+        };
+        let found = self.search_hist(&query, start);
+        let search = self.search.as_mut().unwrap();
+        search.failed = found.is_none() && !query.is_empty();
+        if found.is_some() || query.is_empty() {
+            search.found = found;
+        }
+        true
+    }
+
+    /// Finds the most recent history entry containing `query`, starting from
+    /// index `start`.
+    fn search_hist(&self, query: &str, start: usize) -> Option<usize> {
+        if query.is_empty() { return None; }
+        (start..self.hist.len()).find(|&i| self.hist[i].contains(query))
+    }
+
+    /// Ends the search, moving to the matching history entry as if we'd
+    /// scrolled up to it.
+    fn accept_search(&mut self) {
+        if let Some(HistSearch { found: Some(i), .. }) = self.search.take() {
+            self.hist_pos = i;
+            self.move_cursor_end();
+        }
+    }
+
+
     pub fn get_text(&self) -> &str {
         &self.hist[self.hist_pos]
     }
 
-
-    fn cursor_col(&self) -> isize {
-        self.cursor_col
+    /// Throws away the current line, leaving the history alone.
+    pub fn clear(&mut self) {
+        self.search = None;
+        self.pasted = None;
+        self.hist_pos = 0;
+        self.hist[0].clear();
+        self.move_cursor_home();
     }
 
+
     fn move_cursor_by(&mut self, by: isize) {
         let ref text = self.hist[self.hist_pos];
         if by > 0 {
@@ -160,6 +398,20 @@ impl TextEntry {
         }
     }
 
+    /// Deletes the word before the cursor, along with any spaces between it
+    /// and the cursor.
+    fn delete_word(&mut self) {
+        let end = self.cursor_idx;
+        let start = {
+            let before = self.get_text()[..end].trim_right_matches(' ');
+            before.rfind(' ').map(|i| i + 1).unwrap_or(0)
+        };
+        let removed = self.get_text()[start..end].chars().count();
+        self.hist[self.hist_pos].drain(start..end);
+        self.cursor_idx = start;
+        self.cursor_col -= removed as isize;
+    }
+
     fn move_cursor_home(&mut self) {
         self.cursor_idx = 0;
         self.cursor_col = 0;
@@ -176,14 +428,45 @@ impl TextEntry {
     /// This has the effect of pushing the current line up into the history and
     /// clearing the line. If hist_pos is greater than 0, pushes the current
     /// line to the front of the history.
+    ///
+    /// Entries identical to the previous one aren't added again.
     fn push_hist(&mut self) {
         if self.hist_pos > 0 {
             let ent = self.hist[self.hist_pos].clone();
             self.hist[0] = ent;
         }
-        self.hist.push_front(String::new());
+        if self.hist.len() > 1 && self.hist[0] == self.hist[1] {
+            self.hist[0].clear();
+        } else {
+            self.save_hist(0);
+            self.hist.push_front(String::new());
+            // The first entry is the current line, so it doesn't count.
+            while self.hist.len() > self.hist_max + 1 {
+                self.hist.pop_back();
+            }
+        }
         self.hist_pos = 0;
     }
+
+    /// Appends the history entry at `idx` to the history file.
+    fn save_hist(&self, idx: usize) {
+        if let Some(ref path) = self.hist_file {
+            let res = OpenOptions::new().create(true).append(true).open(path)
+                .and_then(|mut f| writeln!(f, "{}", self.hist[idx]));
+            if let Err(e) = res {
+                warn!("Failed to write history file {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+/// Replaces the contents of the history file at `path` with `lines`.
+fn write_hist_file(path: &PathBuf, lines: &[String]) -> ::std::io::Result<()> {
+    let mut f = try!(File::create(path));
+    for line in lines {
+        try!(writeln!(f, "{}", line));
+    }
+    Ok(())
 }
 
 #[cfg(test)]
@@ -326,6 +609,94 @@ mod test {
         assert_eq!("", entry.get_text());
     }
 
+    #[test]
+    fn kill_to_start() {
+        let mut entry = TextEntry::new();
+        press_chars(&mut entry, "これは test");
+        press_times(&mut entry, Key::Left, 4);
+        press_key(&mut entry, Key::Ctrl('u'));
+        assert_eq!("test", entry.get_text());
+        press_chars(&mut entry, "a ");
+        assert_eq!("a test", entry.get_text());
+    }
+
+    #[test]
+    fn kill_to_end() {
+        let mut entry = TextEntry::new();
+        press_chars(&mut entry, "これは test");
+        press_key(&mut entry, Key::Ctrl('a'));
+        press_times(&mut entry, Key::Right, 3);
+        press_key(&mut entry, Key::Ctrl('k'));
+        assert_eq!("これは", entry.get_text());
+        press_key(&mut entry, Key::Ctrl('e'));
+        press_chars(&mut entry, "テスト");
+        assert_eq!("これはテスト", entry.get_text());
+    }
+
+    #[test]
+    fn delete_word() {
+        let mut entry = TextEntry::new();
+        press_chars(&mut entry, "IRC is テスト  ");
+        press_key(&mut entry, Key::Ctrl('w'));
+        assert_eq!("IRC is ", entry.get_text());
+        press_key(&mut entry, Key::Ctrl('w'));
+        assert_eq!("IRC ", entry.get_text());
+        press_chars(&mut entry, "rocks");
+        assert_eq!("IRC rocks", entry.get_text());
+    }
+
+    #[test]
+    fn delete_word_mid_line() {
+        let mut entry = TextEntry::new();
+        press_chars(&mut entry, "one two three");
+        press_times(&mut entry, Key::Left, 5);
+        press_key(&mut entry, Key::Ctrl('w'));
+        assert_eq!("one three", entry.get_text());
+        press_key(&mut entry, Key::Ctrl('w'));
+        press_key(&mut entry, Key::Ctrl('w'));
+        assert_eq!("three", entry.get_text());
+    }
+
+    #[test]
+    fn skip_duplicate_history() {
+        let mut entry = TextEntry::new();
+        press_chars(&mut entry, "same");
+        press_key(&mut entry, Key::Enter);
+        press_chars(&mut entry, "same");
+        press_key(&mut entry, Key::Enter);
+        assert_eq!(Some("same".to_owned()), entry.next_entry());
+        assert_eq!(Some("same".to_owned()), entry.next_entry());
+        press_key(&mut entry, Key::Up);
+        assert_eq!("same", entry.get_text());
+        press_key(&mut entry, Key::Up);
+        assert_eq!("same", entry.get_text());
+        // There's only one entry, so we can't go any further up.
+        assert_eq!(entry.hist.len(), 2);
+    }
+
+    #[test]
+    fn history_file() {
+        use common::tempdir::TempDir;
+
+        let dir = TempDir::new("history");
+        let path = dir.join("history");
+        {
+            let mut entry = TextEntry::with_history(path.clone(), 2);
+            for line in &["one", "two", "three"] {
+                press_chars(&mut entry, line);
+                press_key(&mut entry, Key::Enter);
+            }
+        }
+
+        let mut entry = TextEntry::with_history(path.clone(), 2);
+        press_key(&mut entry, Key::Up);
+        assert_eq!("three", entry.get_text());
+        press_key(&mut entry, Key::Up);
+        assert_eq!("two", entry.get_text());
+        press_key(&mut entry, Key::Up);
+        assert_eq!("two", entry.get_text());
+    }
+
     #[test]
     fn enter_from_history() {
         // This checks that when we enter a line from our entry history, it gets
@@ -352,4 +723,97 @@ mod test {
         press_key(&mut entry, Key::Up);
         assert_eq!("Another entry 2", entry.get_text());
     }
+
+    #[test]
+    fn clear_line() {
+        let mut entry = TextEntry::new();
+        press_chars(&mut entry, "first");
+        press_key(&mut entry, Key::Enter);
+        press_chars(&mut entry, "second");
+        entry.clear();
+        assert_eq!(entry.get_text(), "");
+        press_chars(&mut entry, "x");
+        assert_eq!(entry.get_text(), "x");
+        press_key(&mut entry, Key::Up);
+        assert_eq!(entry.get_text(), "first");
+    }
+
+    #[test]
+    fn reverse_search() {
+        let mut entry = TextEntry::new();
+        for line in &["/join net #rust", "hello", "/join net #irc"] {
+            press_chars(&mut entry, line);
+            press_key(&mut entry, Key::Enter);
+            entry.next_entry();
+        }
+        press_key(&mut entry, Key::Ctrl('r'));
+        press_chars(&mut entry, "join");
+        press_key(&mut entry, Key::Ctrl('r'));
+        press_key(&mut entry, Key::Enter);
+        assert_eq!(entry.get_text(), "/join net #rust");
+        assert_eq!(entry.next_entry(), None);
+
+        // Accepting works like scrolling up through the history.
+        press_key(&mut entry, Key::Enter);
+        assert_eq!(entry.next_entry(), Some("/join net #rust".to_owned()));
+        press_key(&mut entry, Key::Up);
+        assert_eq!(entry.get_text(), "/join net #rust");
+    }
+
+    #[test]
+    fn cancel_search() {
+        let mut entry = TextEntry::new();
+        press_chars(&mut entry, "hello");
+        press_key(&mut entry, Key::Enter);
+        press_chars(&mut entry, "draft");
+        press_key(&mut entry, Key::Ctrl('r'));
+        press_chars(&mut entry, "hel");
+        press_key(&mut entry, Key::Esc);
+        assert_eq!(entry.get_text(), "draft");
+
+        // A query with no matches keeps the last match.
+        press_key(&mut entry, Key::Ctrl('r'));
+        press_chars(&mut entry, "hex");
+        press_key(&mut entry, Key::Left);
+        assert_eq!(entry.get_text(), "hello");
+    }
+
+    fn paste(entry: &mut TextEntry, text: &str) {
+        press_key(entry, Key::Alt('['));
+        press_chars(entry, "200~");
+        for ch in text.chars() {
+            match ch {
+                '\n' => press_key(entry, Key::Enter),
+                ch => press_key(entry, Key::Char(ch)),
+            }
+        }
+        press_key(entry, Key::Alt('['));
+        press_chars(entry, "201~");
+    }
+
+    #[test]
+    fn multi_line_paste() {
+        let mut entry = TextEntry::new();
+        paste(&mut entry, "one\ntwo\n");
+        assert_eq!(entry.next_entry(), None);
+        assert_eq!(entry.get_text(), "");
+        assert_eq!(entry.take_paste(), Some(vec!["one".to_owned(), "two".to_owned()]));
+    }
+
+    #[test]
+    fn single_line_paste() {
+        let mut entry = TextEntry::new();
+        press_chars(&mut entry, "a ");
+        paste(&mut entry, "pasted\n");
+        assert_eq!(entry.take_paste(), None);
+        assert_eq!(entry.get_text(), "a pasted");
+    }
+
+    #[test]
+    fn not_a_paste_marker() {
+        let mut entry = TextEntry::new();
+        press_key(&mut entry, Key::Alt('['));
+        press_chars(&mut entry, "20x");
+        assert_eq!(entry.get_text(), "20x");
+    }
 }
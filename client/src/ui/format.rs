@@ -0,0 +1,239 @@
+//! Turns IRC formatting codes and URLs into styled text segments.
+
+use rustbox::Color;
+
+use common::format::{parse_formatting, strip_formatting};
+use common::casemap::CaseMapping;
+
+use url::find_urls;
+use super::util::Segment;
+
+
+/// Splits message text into styled segments, with URLs underlined.
+///
+/// If `formatting` is false, formatting codes are stripped and the only style
+/// applied is URL underlining. Any other control characters are escaped, and
+/// tabs are expanded to `tab_width` spaces.
+pub fn format_segments(text: &str, formatting: bool, tab_width: usize) -> Vec<Segment> {
+    use rustbox::RB_NORMAL;
+
+    let segs = if formatting {
+        irc_segments(text)
+    } else {
+        vec![Segment {
+            text: strip_formatting(text),
+            style: RB_NORMAL,
+            fgcolor: None,
+            bgcolor: None,
+        }]
+    };
+    let segs = segs.into_iter().map(|seg| {
+        Segment { text: sanitize(&seg.text, tab_width), ..seg }
+    }).collect();
+    underline_urls(segs)
+}
+
+/// Makes `text` safe to print on the terminal.
+///
+/// Control characters are replaced by a visible escape, like `^[` for escape,
+/// so they can't move the cursor or change colors. Tabs are expanded to
+/// `tab_width` spaces, or escaped as `^I` if `tab_width` is 0.
+pub fn sanitize(text: &str, tab_width: usize) -> String {
+    let mut out = String::with_capacity(text.len());
+    for ch in text.chars() {
+        match ch {
+            '\t' if tab_width > 0 => {
+                for _ in 0..tab_width { out.push(' '); }
+            },
+            '\x00'...'\x1f' | '\x7f' => {
+                out.push('^');
+                out.push((ch as u8 ^ 0x40) as char);
+            },
+            _ if ch.is_control() => out.extend(ch.escape_unicode()),
+            _ => out.push(ch),
+        }
+    }
+    out
+}
+
+/// Converts IRC formatting codes in `text` into styled segments.
+fn irc_segments(text: &str) -> Vec<Segment> {
+    use rustbox::{RB_NORMAL, RB_BOLD, RB_UNDERLINE, RB_REVERSE};
+
+    parse_formatting(text).into_iter().map(|span| {
+        let mut style = RB_NORMAL;
+        if span.bold { style = style | RB_BOLD; }
+        if span.underline { style = style | RB_UNDERLINE; }
+        if span.reverse { style = style | RB_REVERSE; }
+        // The terminal can't do italics, so we underline instead.
+        if span.italic { style = style | RB_UNDERLINE; }
+        Segment {
+            style: style,
+            fgcolor: span.fg.and_then(irc_color),
+            bgcolor: span.bg.and_then(irc_color),
+            text: span.text,
+        }
+    }).collect::<Vec<_>>()
+}
+
+/// Splits segments so that URLs within them are underlined.
+fn underline_urls(segs: Vec<Segment>) -> Vec<Segment> {
+    use rustbox::RB_UNDERLINE;
+
+    let mut out = vec![];
+    for seg in segs {
+        let urls = find_urls(&seg.text);
+        if urls.is_empty() {
+            out.push(seg);
+            continue;
+        }
+        let mut pos = 0;
+        for (start, end) in urls {
+            if start > pos {
+                out.push(Segment { text: seg.text[pos..start].to_owned(), ..seg.clone() });
+            }
+            out.push(Segment {
+                text: seg.text[start..end].to_owned(),
+                style: seg.style | RB_UNDERLINE,
+                ..seg.clone()
+            });
+            pos = end;
+        }
+        if pos < seg.text.len() {
+            out.push(Segment { text: seg.text[pos..].to_owned(), ..seg });
+        }
+    }
+    out
+}
+
+/// Splits segments so that mentions of `nick` stand out.
+///
+/// Mentions are found the same way the core looks for highlights, using the
+/// network's casemapping `cm`.
+pub fn highlight_nick(segs: Vec<Segment>, nick: &str, cm: CaseMapping) -> Vec<Segment> {
+    use rustbox::RB_BOLD;
+
+    let mut out = vec![];
+    for seg in segs {
+        let found = cm.find_words(&seg.text, nick);
+        if found.is_empty() {
+            out.push(seg);
+            continue;
+        }
+        let mut pos = 0;
+        for (start, end) in found {
+            if start > pos {
+                out.push(Segment { text: seg.text[pos..start].to_owned(), ..seg.clone() });
+            }
+            out.push(Segment {
+                text: seg.text[start..end].to_owned(),
+                style: seg.style | RB_BOLD,
+                fgcolor: Some(Color::Yellow),
+                ..seg.clone()
+            });
+            pos = end;
+        }
+        if pos < seg.text.len() {
+            out.push(Segment { text: seg.text[pos..].to_owned(), ..seg });
+        }
+    }
+    out
+}
+
+/// Maps an mIRC color number to the closest terminal color.
+///
+/// Returns `None` for numbers outside the standard 16 colors.
+pub fn irc_color(num: u8) -> Option<Color> {
+    use rustbox::Color::*;
+    match num {
+        0 | 15 => Some(White),
+        1 | 14 => Some(Black),
+        2 | 12 => Some(Blue),
+        3 | 9 => Some(Green),
+        4 | 5 => Some(Red),
+        6 | 13 => Some(Magenta),
+        7 | 8 => Some(Yellow),
+        10 | 11 => Some(Cyan),
+        _ => None,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use rustbox::{Color, RB_BOLD, RB_NORMAL};
+    use common::casemap::CaseMapping;
+    use super::{format_segments, sanitize, highlight_nick};
+
+    #[test]
+    fn styled_segments() {
+        let segs = format_segments("hi \x02\x034there", true, 4);
+        assert_eq!(segs.len(), 2);
+        assert_eq!(segs[0].text, "hi ");
+        assert!(segs[0].style == RB_NORMAL);
+        assert_eq!(segs[1].text, "there");
+        assert!(segs[1].style == RB_BOLD);
+        assert!(segs[1].fgcolor == Some(Color::Red));
+    }
+
+    #[test]
+    fn underlined_urls() {
+        use rustbox::RB_UNDERLINE;
+        let segs = format_segments("see http://example.com now", false, 4);
+        let texts = segs.iter().map(|s| &s.text[..]).collect::<Vec<_>>();
+        assert_eq!(texts, vec!["see ", "http://example.com", " now"]);
+        assert!(segs[1].style == RB_UNDERLINE);
+        assert!(segs[2].style == RB_NORMAL);
+    }
+
+    #[test]
+    fn highlighted_nicks() {
+        let segs = highlight_nick(format_segments("forkk: ping Forkk[m]", false, 4), "Forkk{M}",
+                                  CaseMapping::Rfc1459);
+        let texts = segs.iter().map(|s| &s.text[..]).collect::<Vec<_>>();
+        assert_eq!(texts, vec!["forkk: ping ", "Forkk[m]"]);
+        assert!(segs[1].style == RB_BOLD);
+        assert!(segs[1].fgcolor == Some(Color::Yellow));
+        assert!(segs[0].fgcolor.is_none());
+    }
+
+    #[test]
+    fn highlighted_nicks_ascii() {
+        // Under ascii, `[m]` and `{m}` are different nicks.
+        let segs = highlight_nick(format_segments("ping Forkk[m]", false, 4), "Forkk{M}",
+                                  CaseMapping::Ascii);
+        assert_eq!(segs.len(), 1);
+        let segs = highlight_nick(format_segments("ping Forkk{m}", false, 4), "Forkk{M}",
+                                  CaseMapping::Ascii);
+        assert_eq!(segs[1].text, "Forkk{m}");
+    }
+
+    #[test]
+    fn stripped_segments() {
+        let segs = format_segments("hi \x02\x034there", false, 4);
+        assert_eq!(segs.len(), 1);
+        assert_eq!(segs[0].text, "hi there");
+    }
+
+    #[test]
+    fn control_chars() {
+        assert_eq!(sanitize("ding\x07", 4), "ding^G");
+        assert_eq!(sanitize("\x1b[2Jgone", 4), "^[[2Jgone");
+        assert_eq!(sanitize("a\x08\x7fb", 4), "a^H^?b");
+        assert_eq!(sanitize("\u{9b}31m", 4), "\\u{9b}31m");
+        assert_eq!(sanitize("ünïcode ✓", 4), "ünïcode ✓");
+    }
+
+    #[test]
+    fn tabs() {
+        assert_eq!(sanitize("a\tb", 2), "a  b");
+        assert_eq!(sanitize("a\tb", 0), "a^Ib");
+    }
+
+    #[test]
+    fn escaped_segments() {
+        let segs = format_segments("\x02bold\x1b[0m", true, 4);
+        assert_eq!(segs.len(), 1);
+        assert_eq!(segs[0].text, "bold^[[0m");
+    }
+}
@@ -6,6 +6,10 @@ use rustbox::{ RustBox, Event, Key };
 
 use model::{CoreModel, Buffer, BufKey};
 use conn::ConnThread;
+use common::casemap::CaseMapping;
+use common::messages::Password;
+use config::{self, UiConfig};
+use url::open_url;
 
 mod buffer;
 mod entry;
@@ -13,14 +17,25 @@ mod bar;
 mod alert;
 mod wrap;
 mod util;
+mod color;
+mod format;
+mod nicklist;
+mod quit;
+mod switcher;
 
 use self::entry::TextEntry;
-use self::buffer::BufferView;
-use self::bar::{StatusBar, MainBar, AlertBar};
+use self::buffer::{BufferView, ViewOpts};
+use self::bar::{StatusBar, MainBar, AlertBar, TopicBar};
 use self::alert::{AlertList, ClientAlert, AlertKind};
+use self::nicklist::NickList;
+use self::quit::QuitKey;
+use self::switcher::{Switcher, SwitcherAction, SWITCHER_KEY};
 use self::util::RustBoxExt;
 
 
+/// Maximum number of lines to ask for when searching logs.
+const SEARCH_LIMIT: usize = 200;
+
 /// Stores the terminal UI's state.
 pub struct TermUi {
     rb: RustBox,
@@ -29,6 +44,15 @@ pub struct TermUi {
     pub alerts: AlertList,
     pub view: BufferView,
     key: BufKey,
+    view_opts: ViewOpts,
+    /// The list of users shown beside channel buffers.
+    nick_list: NickList,
+    quit_key: QuitKey,
+    /// Lines pasted at once and the buffer they were pasted into, waiting
+    /// for `/ok` before they're sent.
+    paste: Option<(BufKey, Vec<String>)>,
+    /// The buffer switcher, if it's open.
+    switcher: Option<Switcher>,
     quit: bool,
     /// Status message shown at the bottom of the screen.
     status: Vec<StatusMsg>,
@@ -40,24 +64,36 @@ struct StatusMsg {
 }
 
 impl TermUi {
-    pub fn new(status: Buffer, conn: ConnThread) -> Result<TermUi, rustbox::InitError> {
+    pub fn new(status: Buffer, conn: ConnThread, cfg: &UiConfig) -> Result<TermUi, rustbox::InitError> {
         let mut rb = try!(RustBox::init(rustbox::InitOptions {
-            input_mode: rustbox::InputMode::Current,
+            // Alt mode lets us use Alt+digit to switch windows.
+            input_mode: rustbox::InputMode::Alt,
             buffer_stderr: true,
         }));
 
+        set_bracketed_paste(true);
+
         let model = CoreModel::new(status, conn);
 
         let key = BufKey::Status;
         let buf = model.get(&key).unwrap().clone();
+        let view_opts = ViewOpts::from_cfg(cfg);
 
         Ok(TermUi {
-            view: BufferView::new(buf, &mut rb),
+            view: BufferView::new(buf, view_opts.clone(), &mut rb),
+            view_opts: view_opts,
+            nick_list: NickList::from_cfg(cfg),
+            quit_key: QuitKey::from_cfg(cfg),
+            paste: None,
+            switcher: None,
             rb: rb,
-            entry: TextEntry::new(),
+            entry: match config::history_path() {
+                Some(path) => TextEntry::with_history(path, cfg.history_size()),
+                None => TextEntry::new(),
+            },
             key: key,
             model: model,
-            alerts: AlertList::new(),
+            alerts: AlertList::new(Duration::seconds(cfg.alert_window() as i64)),
             quit: false,
             status: vec![],
         })
@@ -67,6 +103,7 @@ impl TermUi {
     pub fn main(&mut self) {
         // Status bars below the buffer.
         let mut upper_bars: Vec<Box<StatusBar>> = vec![
+            Box::new(TopicBar) as Box<StatusBar>,
             Box::new(AlertBar) as Box<StatusBar>,
         ];
         // Status bars above the buffer.
@@ -82,6 +119,19 @@ impl TermUi {
             if let Some(status) = self.model.take_status() {
                 self.status(status);
             }
+            if let Some(key) = self.model.take_opened() {
+                self.switch_buf(key);
+            }
+            if self.model.get(&self.key).is_none() {
+                // The buffer we were looking at went away.
+                self.switch_buf(BufKey::Status);
+            }
+            self.mark_read();
+            let (nick, cm) = match self.key.nid().and_then(|nid| self.model.nets.get(nid)) {
+                Some(net) => (Some(net.nick.clone()), net.casemap),
+                None => (None, CaseMapping::default()),
+            };
+            self.view.set_nick(nick, cm);
 
             let now = time::now();
             self.status.retain(|s| now - s.time < Duration::seconds(5));
@@ -91,8 +141,26 @@ impl TermUi {
             self.render(&mut upper_bars, &mut lower_bars);
 
             if let Ok(e) = self.rb.peek_event(std::time::Duration::from_millis(200), false) {
-                if let Event::KeyEvent(Key::Ctrl('c')) = e {
-                    break 'main;
+                if let Event::KeyEvent(key) = e {
+                    if self.quit_key.matches(&key) {
+                        // Ctrl-C throws away what's been typed before it
+                        // quits anything.
+                        if key == Key::Ctrl('c') && !self.entry.get_text().is_empty() {
+                            self.entry.clear();
+                        } else if self.quit_key.press(time::now()) {
+                            break 'main;
+                        } else if key == Key::Ctrl('c') {
+                            self.status(format!("Press Ctrl-C again to quit"));
+                        } else {
+                            self.status(format!("Press the quit key again to quit"));
+                        }
+                        continue 'main;
+                    }
+                    // The switcher takes every key while it's open.
+                    if self.switcher.is_some() {
+                        self.handle_switcher_key(&key);
+                        continue 'main;
+                    }
                 }
 
                 if !self.entry.handle(&e) {
@@ -101,6 +169,14 @@ impl TermUi {
                     if let Some(line) = self.entry.next_entry() {
                         self.handle_input(line);
                     }
+                    if let Some(lines) = self.entry.take_paste() {
+                        if let Some((_, old)) = self.paste.take() {
+                            self.status(format!("Dropped {} pasted lines for the new paste", old.len()));
+                        }
+                        self.status(format!("Paste {} lines into {}? /ok to send, /cancel to drop",
+                                            lines.len(), self.key));
+                        self.paste = Some((self.key.clone(), lines));
+                    }
                 }
             }
 
@@ -121,6 +197,13 @@ impl TermUi {
                         ui.switch_buf(BufKey::Channel(nid.clone(), bid.clone()));
                     });
                 },
+                AlertKind::Invite(ref nid, ref chan) => {
+                    let nid = nid.clone();
+                    let chan = chan.clone();
+                    a = a.action(move |ui| {
+                        ui.model.send_join(nid.clone(), chan.clone(), None);
+                    });
+                },
                 _ => {},
             }
             self.alerts.push(a);
@@ -149,6 +232,8 @@ impl TermUi {
             "s" | "switch" => {
                 if args == "" {
                     self.switch_buf(BufKey::Status);
+                } else if let Ok(num) = args.parse::<usize>() {
+                    self.switch_window(num);
                 } else {
                     if let Some(key) = self.model.bufs.iter()
                         .map(|(key, _)| key.clone())
@@ -162,10 +247,11 @@ impl TermUi {
             },
             "j" | "join" => {
                 let args = args.split(' ').collect::<Vec<_>>();
-                if args.len() == 2 {
-                    self.model.send_join(args[0].to_owned(), args[1].to_owned());
+                if args.len() == 2 || args.len() == 3 {
+                    let key = args.get(2).map(|k| (*k).to_owned());
+                    self.model.send_join(args[0].to_owned(), args[1].to_owned(), key);
                 } else {
-                    self.status(format!("Usage: /join [network] [channel]"));
+                    self.status(format!("Usage: /join [network] [channel] [key]"));
                 }
             },
             "p" | "part" => {
@@ -194,15 +280,242 @@ impl TermUi {
                 }
             },
             "nick" => {
-                let args = args.split(' ').collect::<Vec<_>>();
-                if args.len() == 2 {
-                    self.model.send_nick(args[0].to_owned(), args[1].to_owned());
-                } else {
-                    self.status(format!("Usage: /nick [network] [new nick]"));
+                let args = args.split(' ').filter(|a| !a.is_empty()).collect::<Vec<_>>();
+                let nid = self.key.nid().cloned();
+                match (args.len(), nid) {
+                    (1, Some(nid)) => self.model.send_nick(nid, args[0].to_owned()),
+                    (1, None) => self.status(format!("Can't change nick from a non-network buffer")),
+                    (2, _) => self.model.send_nick(args[0].to_owned(), args[1].to_owned()),
+                    _ => self.status(format!("Usage: /nick [network] [new nick]")),
                 }
             },
             "me" => {
-                self.model.send_action(&self.key, args.to_owned());
+                if args.is_empty() {
+                    self.status(format!("Usage: /me [text..]"));
+                } else {
+                    self.model.send_action(&self.key, args.to_owned());
+                }
+            },
+            "connect" => {
+                if args.is_empty() {
+                    self.status(format!("Usage: /connect [network]"));
+                } else {
+                    self.model.send_connect(&args.to_owned());
+                }
+            },
+            "disconnect" => {
+                let mut args = args.splitn(2, ' ');
+                match args.next() {
+                    Some(nid) if !nid.is_empty() => {
+                        let msg = args.next().map(|m| m.to_owned());
+                        self.model.send_disconnect(&nid.to_owned(), msg);
+                    },
+                    _ => self.status(format!("Usage: /disconnect [network] [message..]")),
+                }
+            },
+            "reconnect" => {
+                let mut args = args.splitn(2, ' ');
+                match args.next() {
+                    Some(nid) if !nid.is_empty() => {
+                        let msg = args.next().map(|m| m.to_owned());
+                        self.model.send_reconnect(&nid.to_owned(), msg);
+                    },
+                    // With no network, reconnect to the core, picking up
+                    // login changes from the config.
+                    _ => match config::load_config() {
+                        Ok(cfg) => {
+                            self.status(format!("Reconnecting to core as {}", cfg.core.user));
+                            self.model.retry_core(cfg.core.user, Password(cfg.core.pass));
+                        },
+                        Err(e) => self.status(format!("Can't reconnect to core: {}", e)),
+                    },
+                }
+            },
+            "addnet" => {
+                let mut args = args.splitn(2, ' ');
+                match (args.next(), args.next()) {
+                    (Some(nid), Some(cfg)) if !nid.is_empty() && !cfg.is_empty() =>
+                        self.model.send_add_network(nid.to_owned(), cfg.to_owned()),
+                    _ => self.status(format!("Usage: /addnet [network] [settings..]")),
+                }
+            },
+            "removenet" => {
+                if args.is_empty() || args.contains(' ') {
+                    self.status(format!("Usage: /removenet [network]"));
+                } else {
+                    self.model.send_remove_network(args.to_owned());
+                }
+            },
+            "whois" => {
+                let nid = self.key.nid().cloned();
+                match nid {
+                    Some(ref nid) if !args.is_empty() && !args.contains(' ') =>
+                        self.model.send_whois(nid, args.to_owned()),
+                    Some(_) => self.status(format!("Usage: /whois [nick]")),
+                    None => self.status(format!("Can't send whois from a non-network buffer")),
+                }
+            },
+            "search" => {
+                match self.key.clone() {
+                    BufKey::Status | BufKey::Search(_) | BufKey::ChanList(_) =>
+                        self.status(format!("Can't search this buffer's logs")),
+                    _ if args.is_empty() => self.status(format!("Usage: /search [text..]")),
+                    key => self.model.send_search(&key, args.to_owned(), SEARCH_LIMIT),
+                }
+            },
+            "query" => {
+                let nid = self.key.nid().cloned();
+                match nid {
+                    Some(nid) if !args.is_empty() && !args.contains(' ') => {
+                        let key = self.model.open_private(nid, args.to_owned());
+                        self.switch_buf(key);
+                    },
+                    Some(_) => self.status(format!("Usage: /query [nick]")),
+                    None => self.status(format!("Can't open a query from a non-network buffer")),
+                }
+            },
+            "msg" => {
+                let nid = self.key.nid().cloned();
+                let mut args = args.splitn(2, ' ');
+                match (nid, args.next(), args.next()) {
+                    (Some(nid), Some(nick), Some(msg)) if !nick.is_empty() && !msg.is_empty() => {
+                        let key = self.model.open_private(nid, nick.to_owned());
+                        self.switch_buf(key.clone());
+                        self.model.send_privmsg(&key, msg.to_owned());
+                    },
+                    (Some(_), _, _) => self.status(format!("Usage: /msg [nick] [text..]")),
+                    (None, _, _) => self.status(format!("Can't send a private message from a non-network buffer")),
+                }
+            },
+            "list" => {
+                let nid = self.key.nid().cloned();
+                match nid {
+                    Some(ref nid) => {
+                        let filter = if args.is_empty() { None } else { Some(args.to_owned()) };
+                        self.model.send_list(nid, filter);
+                    },
+                    None => self.status(format!("Can't list channels from a non-network buffer")),
+                }
+            },
+            "raw" | "quote" => {
+                let nid = self.key.nid().cloned();
+                match nid {
+                    Some(ref nid) if !args.is_empty() => self.model.send_raw(nid, args.to_owned()),
+                    Some(_) => self.status(format!("Usage: /raw [line..]")),
+                    None => self.status(format!("Can't send raw lines from a non-network buffer")),
+                }
+            },
+            "away" => {
+                if let Some(nid) = self.key.nid().cloned() {
+                    let msg = if args.is_empty() { None } else { Some(args.to_owned()) };
+                    self.model.send_away(&nid, msg);
+                } else {
+                    self.status(format!("Can't set away status from a non-network buffer"));
+                }
+            },
+            "topic" => {
+                match self.key.clone() {
+                    BufKey::Channel(_, ref chan) if args.is_empty() => {
+                        let topic = self.view.buf.borrow().topic().to_owned();
+                        if topic.is_empty() {
+                            self.status(format!("No topic is set for {}", chan));
+                        } else {
+                            self.status(format!("Topic for {}: {}", chan, topic));
+                        }
+                    },
+                    BufKey::Channel(nid, chan) => self.model.send_topic(&nid, chan, args.to_owned()),
+                    _ => self.status(format!("Can't set the topic of a non-channel buffer")),
+                }
+            },
+            "names" => {
+                let key = self.key.clone();
+                let stale = self.view.buf.borrow().users().is_empty();
+                match key {
+                    BufKey::Channel(_, _) if args == "refresh" => self.model.send_names(&key),
+                    // We don't have a list, perhaps because we just
+                    // reconnected, so ask for one.
+                    BufKey::Channel(_, _) if args.is_empty() && stale => self.model.send_names(&key),
+                    BufKey::Channel(_, _) if args.is_empty() => self.model.show_names(&key),
+                    BufKey::Channel(_, _) => self.status(format!("Usage: /names [refresh]")),
+                    _ => self.status(format!("Can't list users of a non-channel buffer")),
+                }
+            },
+            "url" => {
+                let n = if args.is_empty() { Ok(1) } else { args.parse::<usize>() };
+                let url = match n {
+                    Ok(n) => self.view.buf.borrow().url(n).map(|u| u.to_owned()),
+                    Err(_) => {
+                        self.status(format!("Usage: /url [n]"));
+                        return;
+                    },
+                };
+                match url {
+                    Some(url) => {
+                        if let Err(e) = open_url(&url) {
+                            self.status(format!("Failed to open {}: {}", url, e));
+                        }
+                    },
+                    None => self.status(format!("No such URL in this buffer")),
+                }
+            },
+            "clear" => {
+                self.view.buf.borrow_mut().clear();
+                // Start the view over, since its scroll position is gone.
+                let key = self.key.clone();
+                self.switch_buf(key);
+            },
+            "close" => {
+                let key = self.key.clone();
+                if key == BufKey::Status {
+                    self.status(format!("Can't close the status buffer"));
+                } else if self.model.close_buf(&key) {
+                    self.switch_buf(BufKey::Status);
+                }
+            },
+            "ok" => {
+                match self.paste.take() {
+                    // The lines go where they were pasted, even if we've
+                    // switched buffers since.
+                    Some((key, lines)) => {
+                        // The core throttles these like any other messages.
+                        for line in lines.into_iter().filter(|l| !l.is_empty()) {
+                            self.model.send_privmsg(&key, line);
+                        }
+                    },
+                    None => self.status(format!("Nothing to send")),
+                }
+            },
+            "cancel" => {
+                if self.paste.take().is_some() {
+                    self.status(format!("Dropped pasted lines"));
+                }
+            },
+            "mute" | "unmute" => {
+                let mute = cmd == "mute";
+                let key = self.key.clone();
+                self.view.buf.borrow_mut().set_muted(mute);
+                if mute {
+                    self.status(format!("Muted {}", key));
+                } else {
+                    self.status(format!("Unmuted {}", key));
+                }
+            },
+            "filter" => {
+                if args == "jpq" {
+                    let hide = {
+                        let mut buf = self.view.buf.borrow_mut();
+                        let hide = !buf.hide_jpq().unwrap_or(self.view_opts.hide_jpq);
+                        buf.set_hide_jpq(hide);
+                        hide
+                    };
+                    if hide {
+                        self.status(format!("Hiding joins, parts and quits in this buffer"));
+                    } else {
+                        self.status(format!("Showing joins, parts and quits in this buffer"));
+                    }
+                } else {
+                    self.status(format!("Usage: /filter jpq"));
+                }
             },
             _ => {
                 self.status(format!("Unrecognized command: {}", cmd));
@@ -219,12 +532,38 @@ impl TermUi {
     }
 
 
+    /// Marks the current buffer as read if we're looking at its newest lines.
+    fn mark_read(&mut self) {
+        if self.view.scroll.is_some() {
+            return;
+        }
+        let (unread, newest) = {
+            let mut buf = self.view.buf.borrow_mut();
+            let unread = buf.unread();
+            // This also clears highlights that arrive after their lines.
+            buf.set_unread(0);
+            (unread, buf.newest_id())
+        };
+        if unread == 0 { return; }
+        // Only mark what we've actually shown, since the core may have
+        // received more lines that haven't reached us yet.
+        let id = match newest {
+            Some(id) => id,
+            None => return,
+        };
+        match self.key {
+            BufKey::Status | BufKey::Search(_) | BufKey::ChanList(_) => {},
+            ref key => self.model.send_mark_read(key, id),
+        }
+    }
+
+
     /// Switches to the buffer with the given key.
     pub fn switch_buf(&mut self, key: BufKey) {
         if let Some(ref mut buf) = self.model.get(&key) {
             info!("Switched buffer to {:?}", key);
             self.key = key;
-            self.view = BufferView::new(buf.clone(), &mut self.rb);
+            self.view = BufferView::new(buf.clone(), self.view_opts.clone(), &mut self.rb);
             return;
         }
         self.status(format!("No such buffer: {:?}", key));
@@ -242,10 +581,75 @@ impl TermUi {
         match *key {
             Key::PageUp => self.view.scroll_and_fetch(-10, &mut self.rb),
             Key::PageDown => self.view.scroll_by(10),
+            Key::Ctrl('n') => self.next_unread(),
+            Key::F(2) => self.nick_list.shown = !self.nick_list.shown,
+            _ if *key == SWITCHER_KEY => self.switcher = Some(Switcher::new()),
+            Key::Alt(ch) if ch.is_digit(10) => {
+                let num = ch.to_digit(10).unwrap() as usize;
+                self.switch_window(num);
+            },
             _ => {},
         }
     }
 
+    /// Passes a key press on to the open buffer switcher.
+    fn handle_switcher_key(&mut self, key: &Key) {
+        let action = {
+            let keys: Vec<BufKey> = self.model.bufs.keys().cloned().collect();
+            match self.switcher {
+                Some(ref mut switcher) => switcher.handle_key(key, &keys),
+                None => return,
+            }
+        };
+        match action {
+            Some(SwitcherAction::Close) => self.switcher = None,
+            Some(SwitcherAction::Switch(key)) => {
+                self.switcher = None;
+                self.switch_buf(key);
+            },
+            None => {},
+        }
+    }
+
+    /// Switches to the next buffer with unread lines after the current one,
+    /// preferring buffers with highlights. Does nothing if everything's read.
+    pub fn next_unread(&mut self) {
+        let found = {
+            let keys = self.model.window_keys();
+            // Start looking after the current buffer and wrap around.
+            let start = keys.iter().position(|k| *k == self.key).map_or(0, |i| i + 1);
+            let ordered = keys[start..].iter().chain(keys[..start].iter());
+            let mut unread = None;
+            let mut highlight = None;
+            for key in ordered {
+                if *key == self.key { continue; }
+                let buf = match self.model.get(key) {
+                    Some(buf) => buf.borrow(),
+                    None => continue,
+                };
+                if buf.muted() { continue; }
+                if buf.highlight() {
+                    highlight = Some(key.clone());
+                    break;
+                } else if buf.unread() > 0 && unread.is_none() {
+                    unread = Some(key.clone());
+                }
+            }
+            highlight.or(unread)
+        };
+        if let Some(key) = found {
+            self.switch_buf(key);
+        }
+    }
+
+    /// Switches to the buffer with the given window number.
+    pub fn switch_window(&mut self, num: usize) {
+        match self.model.window_key(num) {
+            Some(key) => self.switch_buf(key),
+            None => self.status(format!("No window {}", num)),
+        }
+    }
+
 
     /// Renders the UI.
     ///
@@ -257,9 +661,7 @@ impl TermUi {
         let top_height = btop.iter().fold(0, |acc, bar| acc + bar.height(&self));
         let bot_height = bbot.iter().fold(0, |acc, bar| acc + bar.height(&self));
 
-        // FIXME: This computes wrapping twice.
-        let ent_h = self.entry.height(&mut self.rb);
-        self.entry.render(&mut self.rb);
+        let ent_h = self.entry.render(&mut self.rb);
 
         let y1 = top_height;
         let mut y2 = self.rb.height() - ent_h - bot_height;
@@ -272,10 +674,22 @@ impl TermUi {
             y2 -= 1;
         }
 
-        self.view.render(&mut self.rb, y1, y2);
+        let nick_w = self.nick_list.width(&self.key, self.rb.width());
+        let view_w = self.rb.width() - nick_w;
+        self.view.render(&mut self.rb, y1, y2, view_w);
+        if nick_w > 0 {
+            let buf = self.view.buf.borrow();
+            self.nick_list.render(&mut self.rb, &buf, view_w, y1, y2);
+        }
+        if let Some(ref switcher) = self.switcher {
+            switcher.render(&mut self.rb, &self.model, y1, y2);
+        }
 
-        for (y, bar) in btop.iter_mut().enumerate() {
+        let mut y = 0;
+        for bar in btop.iter_mut() {
+            let h = bar.height(self);
             bar.render(y, self);
+            y += h;
         }
         let h = self.rb.height();
         for (y, bar) in bbot.iter_mut().rev().enumerate() {
@@ -285,3 +699,19 @@ impl TermUi {
         self.rb.present();
     }
 }
+
+
+impl Drop for TermUi {
+    fn drop(&mut self) {
+        set_bracketed_paste(false);
+    }
+}
+
+/// Turns the terminal's bracketed paste mode on or off. While it's on,
+/// pasted text is marked so `TextEntry` can tell it from typing.
+fn set_bracketed_paste(on: bool) {
+    use std::io::Write;
+    let seq = if on { "\x1b[?2004h" } else { "\x1b[?2004l" };
+    let mut out = std::io::stdout();
+    let _ = out.write_all(seq.as_bytes()).and_then(|_| out.flush());
+}
@@ -0,0 +1,98 @@
+//! A pane along the right side of the buffer view which lists the users in
+//! the current channel.
+
+use std::cmp;
+use rustbox::{RustBox, Color, RB_NORMAL};
+use unicode_width::UnicodeWidthChar;
+
+use model::{Buffer, BufKey};
+use config::UiConfig;
+
+use super::util::RustBoxExt;
+use super::format::sanitize;
+
+
+pub struct NickList {
+    /// Whether the user wants the list shown. It's still hidden in buffers
+    /// that aren't channels.
+    pub shown: bool,
+    /// Number of columns the list takes up, including its border.
+    width: usize,
+}
+
+impl NickList {
+    pub fn from_cfg(cfg: &UiConfig) -> NickList {
+        NickList {
+            shown: cfg.nick_list(),
+            width: cfg.nick_list_width(),
+        }
+    }
+
+    /// Returns the number of columns to take from the buffer view in a
+    /// terminal `term_w` columns wide while looking at buffer `key`.
+    ///
+    /// This is 0 if the list is hidden. The list never takes more than half
+    /// the terminal.
+    pub fn width(&self, key: &BufKey, term_w: usize) -> usize {
+        match *key {
+            BufKey::Channel(_, _) if self.shown => cmp::min(self.width, term_w / 2),
+            _ => 0,
+        }
+    }
+
+    /// Draws the users in `buf` between rows `y1` and `y2`, starting at
+    /// column `x`.
+    ///
+    /// Users are ordered by rank and then by nick. If they don't all fit, the
+    /// last row says how many were left out.
+    pub fn render(&self, rb: &mut RustBox, buf: &Buffer, x: usize, y1: usize, y2: usize) {
+        use rustbox::Color::*;
+
+        for y in y1..y2 {
+            rb.print_char(x, y, RB_NORMAL, Default, Default, '│');
+        }
+        // Leave room for the border and a space on each side.
+        let name_w = rb.width().saturating_sub(x + 3);
+        let users = buf.users();
+        let rows = y2.saturating_sub(y1);
+        for (i, user) in users.iter().enumerate().take(rows) {
+            let y = y1 + i;
+            if i + 1 == rows && users.len() > rows {
+                let more = format!("({} more)", users.len() - i);
+                rb.print_wide(x + 2, y, RB_NORMAL, Default, Default, &truncate(&more, name_w));
+                break;
+            }
+            let (pfx, pfx_color) = match user.prefix() {
+                Some(pfx) => (pfx, prefix_color(pfx)),
+                None => (' ', Default),
+            };
+            rb.print_char(x + 2, y, RB_NORMAL, pfx_color, Default, pfx);
+            // Away users are dimmed.
+            let color = if user.away { Blue } else { Default };
+            let nick = truncate(&sanitize(&user.nick, 1), name_w.saturating_sub(1));
+            rb.print_wide(x + 3, y, RB_NORMAL, color, Default, &nick);
+        }
+    }
+}
+
+/// Picks the color to draw a user's prefix in.
+fn prefix_color(pfx: char) -> Color {
+    match pfx {
+        '~' | '&' | '@' => Color::Green,
+        '%' => Color::Magenta,
+        '+' => Color::Yellow,
+        _ => Color::Default,
+    }
+}
+
+/// Cuts `text` down to at most `w` columns wide.
+fn truncate(text: &str, w: usize) -> String {
+    let mut out = String::new();
+    let mut x = 0;
+    for ch in text.chars() {
+        x += ch.width().unwrap_or(0);
+        if x > w { break; }
+        out.push(ch);
+    }
+    out
+}
@@ -0,0 +1,120 @@
+//! Decides when a key press should quit the client, so that quitting by
+//! accident takes more than one stray Ctrl-C.
+
+use time::{Tm, Duration};
+use rustbox::Key;
+
+use config::UiConfig;
+
+
+/// Seconds the quit key has to be pressed again within to quit.
+const CONFIRM_SECS: i64 = 3;
+
+
+pub struct QuitKey {
+    /// The configured quit key. Ctrl-C works as well.
+    key: Key,
+    /// Whether quitting takes two presses.
+    confirm: bool,
+    /// When the quit key was last pressed without quitting.
+    pressed: Option<Tm>,
+}
+
+impl QuitKey {
+    pub fn from_cfg(cfg: &UiConfig) -> QuitKey {
+        let key = match cfg.quit_key {
+            Some(ref name) => parse_key(name).unwrap_or_else(|| {
+                warn!("Unknown quit key {}. Using Ctrl-C", name);
+                Key::Ctrl('c')
+            }),
+            None => Key::Ctrl('c'),
+        };
+        QuitKey {
+            key: key,
+            confirm: cfg.confirm_quit(),
+            pressed: None,
+        }
+    }
+
+    /// True if `key` is the quit key or Ctrl-C.
+    pub fn matches(&self, key: &Key) -> bool {
+        *key == self.key || *key == Key::Ctrl('c')
+    }
+
+    /// Handles a press of the quit key at `now`. Returns true if the client
+    /// should quit, or false if the press needs confirming first.
+    pub fn press(&mut self, now: Tm) -> bool {
+        if !self.confirm {
+            return true;
+        }
+        match self.pressed.take() {
+            Some(t) if now - t <= Duration::seconds(CONFIRM_SECS) => true,
+            _ => {
+                self.pressed = Some(now);
+                false
+            },
+        }
+    }
+}
+
+
+/// Parses a key name from the config, like "ctrl-q", "f10" or "esc".
+fn parse_key(name: &str) -> Option<Key> {
+    let name = name.trim().to_lowercase();
+    if name.starts_with("ctrl-") && name.chars().count() == 6 {
+        return name.chars().last().map(Key::Ctrl);
+    }
+    if name.starts_with('f') {
+        if let Ok(n) = name[1..].parse::<u32>() {
+            if n >= 1 && n <= 12 {
+                return Some(Key::F(n));
+            }
+        }
+    }
+    match &name[..] {
+        "esc" | "escape" => Some(Key::Esc),
+        "tab" => Some(Key::Tab),
+        _ => None,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use time::{self, Duration};
+    use rustbox::Key;
+    use config::UiConfig;
+    use super::{QuitKey, parse_key};
+
+    #[test]
+    fn key_names() {
+        assert_eq!(parse_key("ctrl-q"), Some(Key::Ctrl('q')));
+        assert_eq!(parse_key("F10"), Some(Key::F(10)));
+        assert_eq!(parse_key("esc"), Some(Key::Esc));
+        assert_eq!(parse_key("f13"), None);
+        assert_eq!(parse_key("ctrl-"), None);
+    }
+
+    #[test]
+    fn confirm_within_timeout() {
+        let mut quit = QuitKey::from_cfg(&UiConfig::default());
+        let now = time::now();
+        assert!(!quit.press(now));
+        assert!(quit.press(now + Duration::seconds(1)));
+
+        // Too slow, so the second press asks again.
+        assert!(!quit.press(now));
+        assert!(!quit.press(now + Duration::seconds(10)));
+    }
+
+    #[test]
+    fn no_confirm() {
+        let mut cfg = UiConfig::default();
+        cfg.confirm_quit = Some(false);
+        cfg.quit_key = Some("ctrl-q".to_owned());
+        let mut quit = QuitKey::from_cfg(&cfg);
+        assert!(quit.matches(&Key::Ctrl('q')));
+        assert!(quit.matches(&Key::Ctrl('c')));
+        assert!(quit.press(time::now()));
+    }
+}
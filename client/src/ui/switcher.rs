@@ -0,0 +1,286 @@
+//! An overlay listing every buffer, grouped by network, which narrows down as
+//! you type. It's quicker than `/switch` when there are dozens of buffers.
+
+use rustbox::{RustBox, Key, RB_NORMAL, RB_BOLD, RB_REVERSE};
+
+use common::messages::NetId;
+
+use model::{CoreModel, BufKey};
+
+use super::util::RustBoxExt;
+use super::format::sanitize;
+
+
+/// The key which opens and closes the switcher.
+pub const SWITCHER_KEY: Key = Key::Ctrl('b');
+
+
+/// What the UI should do after a key press in the switcher.
+pub enum SwitcherAction {
+    /// Close the switcher without switching.
+    Close,
+    /// Close the switcher and switch to the given buffer.
+    Switch(BufKey),
+}
+
+/// A row of the switcher's list.
+#[derive(PartialEq)]
+enum Row {
+    /// The name of a network, or `None` for global buffers.
+    Header(Option<NetId>),
+    /// The buffer at this index in the list of matches.
+    Buf(usize),
+}
+
+pub struct Switcher {
+    /// What's been typed to filter the list.
+    query: String,
+    /// Index of the selected buffer among those matching the query.
+    selected: usize,
+}
+
+impl Switcher {
+    pub fn new() -> Switcher {
+        Switcher {
+            query: String::new(),
+            selected: 0,
+        }
+    }
+
+    /// Handles a key press given the keys of every buffer.
+    pub fn handle_key(&mut self, key: &Key, keys: &[BufKey]) -> Option<SwitcherAction> {
+        if *key == SWITCHER_KEY {
+            return Some(SwitcherAction::Close);
+        }
+        match *key {
+            Key::Esc | Key::Ctrl('g') => return Some(SwitcherAction::Close),
+            Key::Enter => {
+                return self.matches(keys).into_iter().nth(self.selected)
+                    .map(SwitcherAction::Switch);
+            },
+            Key::Up | Key::Ctrl('p') => {
+                self.selected = self.selected.saturating_sub(1);
+            },
+            Key::Down | Key::Ctrl('n') => {
+                if self.selected + 1 < self.matches(keys).len() {
+                    self.selected += 1;
+                }
+            },
+            Key::Backspace => {
+                self.query.pop();
+                self.select_best(keys);
+            },
+            Key::Char(ch) => {
+                self.query.push(ch);
+                self.select_best(keys);
+            },
+            _ => {},
+        }
+        None
+    }
+
+    /// Selects the buffer which best matches the query.
+    fn select_best(&mut self, keys: &[BufKey]) {
+        let best = self.scored(keys).into_iter().enumerate()
+            .max_by_key(|&(i, (score, _))| (score, -(i as isize)))
+            .map(|(i, _)| i);
+        self.selected = best.unwrap_or(0);
+    }
+
+    /// Lists the buffers matching the query in the order they're shown.
+    pub fn matches(&self, keys: &[BufKey]) -> Vec<BufKey> {
+        self.scored(keys).into_iter().map(|(_, key)| key).collect()
+    }
+
+    /// Lists the buffers matching the query with their scores.
+    ///
+    /// Buffers are grouped by network, with global buffers first. Within a
+    /// network, its status buffer comes first and the rest are ordered by how
+    /// well they match and then by name.
+    fn scored(&self, keys: &[BufKey]) -> Vec<(i32, BufKey)> {
+        let mut found: Vec<_> = keys.iter()
+            .filter_map(|key| fuzzy_score(&key.to_string(), &self.query).map(|s| (s, key.clone())))
+            .collect();
+        found.sort_by_key(|&(score, ref key)| {
+            (key.nid().cloned(), kind_rank(key), -score, key.to_string().to_lowercase())
+        });
+        found
+    }
+
+    /// Draws the switcher over the rows between `y1` and `y2`.
+    pub fn render(&self, rb: &mut RustBox, model: &CoreModel, y1: usize, y2: usize) {
+        use rustbox::Color::*;
+
+        if y2 <= y1 { return; }
+        for y in y1..y2 {
+            rb.blank_line(y, RB_NORMAL, Default, Black);
+        }
+        let prompt = format!("Switch to: {}", self.query);
+        let w = rb.print_wide(1, y1, RB_BOLD, White, Black, &prompt);
+        rb.set_cursor(1 + w as isize, y1 as isize);
+
+        let keys: Vec<BufKey> = model.bufs.keys().cloned().collect();
+        let matches = self.matches(&keys);
+        if matches.is_empty() {
+            rb.print(1, y1 + 1, RB_NORMAL, Blue, Black, "No matching buffers");
+            return;
+        }
+
+        // Each network gets a header above its buffers.
+        let mut rows = vec![];
+        for (i, key) in matches.iter().enumerate() {
+            if i == 0 || key.nid() != matches[i - 1].nid() {
+                rows.push(Row::Header(key.nid().cloned()));
+            }
+            rows.push(Row::Buf(i));
+        }
+
+        // Scroll so the selection stays in view.
+        let h = y2 - y1 - 1;
+        let sel_row = rows.iter().position(|r| *r == Row::Buf(self.selected)).unwrap_or(0);
+        let skip = if sel_row >= h { sel_row + 1 - h } else { 0 };
+        for (y, row) in (y1 + 1..y2).zip(rows.into_iter().skip(skip)) {
+            let i = match row {
+                Row::Buf(i) => i,
+                Row::Header(nid) => {
+                    let title = match nid {
+                        Some(nid) => match model.nets.get(&nid).and_then(|n| n.name.clone()) {
+                            Some(name) => format!("{} ({})", nid, name),
+                            None => nid,
+                        },
+                        None => "Global".to_owned(),
+                    };
+                    rb.print_wide(1, y, RB_BOLD, Yellow, Black, &sanitize(&title, 1));
+                    continue;
+                },
+            };
+            let key = &matches[i];
+            let style = if i == self.selected { RB_REVERSE } else { RB_NORMAL };
+            let num = match model.window_num(key) {
+                Some(num) => format!("{:>3} ", num),
+                None => "    ".to_owned(),
+            };
+            let mut x = 3;
+            x += rb.print_wide(x, y, style, White, Black, &num);
+            x += rb.print_wide(x, y, style, White, Black, &sanitize(&key.to_string(), 1));
+
+            let buf = match model.get(key) {
+                Some(buf) => buf.borrow(),
+                None => continue,
+            };
+            if buf.muted() {
+                rb.print_wide(x, y, RB_NORMAL, Blue, Black, " (muted)");
+            } else if buf.highlight() {
+                rb.print_wide(x, y, RB_BOLD, Red, Black, &format!(" ({})", buf.unread()));
+            } else if buf.unread() > 0 {
+                rb.print_wide(x, y, RB_NORMAL, Cyan, Black, &format!(" ({})", buf.unread()));
+            }
+        }
+    }
+}
+
+
+/// Orders the buffers of a network so its status buffer comes first, then
+/// channels, then private messages.
+fn kind_rank(key: &BufKey) -> u8 {
+    match *key {
+        BufKey::Status | BufKey::Network(_) => 0,
+        BufKey::Global(_) | BufKey::Channel(_, _) => 1,
+        BufKey::Private(_, _) => 2,
+        BufKey::ChanList(_) => 3,
+        BufKey::Search(_) => 4,
+    }
+}
+
+/// Checks whether the characters of `query` appear in order in `text`,
+/// ignoring case, and scores how well they match.
+///
+/// Runs of consecutive characters and characters at the start of a word score
+/// higher, while gaps between them score lower. Returns `None` if `text`
+/// doesn't match.
+fn fuzzy_score(text: &str, query: &str) -> Option<i32> {
+    let text: Vec<char> = text.chars().flat_map(|c| c.to_lowercase()).collect();
+    let mut score = 0;
+    let mut pos = 0;
+    for (n, q) in query.chars().flat_map(|c| c.to_lowercase()).enumerate() {
+        let i = match text[pos..].iter().position(|c| *c == q) {
+            Some(i) => pos + i,
+            None => return None,
+        };
+        if n > 0 && i == pos {
+            score += 3;
+        }
+        if i == 0 || !text[i - 1].is_alphanumeric() {
+            score += 2;
+        }
+        score -= (i - pos) as i32;
+        pos = i + 1;
+    }
+    Some(score)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use rustbox::Key;
+    use model::BufKey;
+    use super::{Switcher, SwitcherAction, fuzzy_score};
+
+    fn chan(nid: &str, chan: &str) -> BufKey {
+        BufKey::Channel(nid.to_owned(), chan.to_owned())
+    }
+
+    #[test]
+    fn fuzzy_matches() {
+        assert!(fuzzy_score("#rust<freenode>", "rust").is_some());
+        assert!(fuzzy_score("#rust<freenode>", "RsFn").is_some());
+        assert_eq!(fuzzy_score("#rust<freenode>", "xyz"), None);
+        assert_eq!(fuzzy_score("#rust", "tsur"), None);
+        assert_eq!(fuzzy_score("anything", ""), Some(0));
+        // Consecutive and word start matches beat scattered ones.
+        assert!(fuzzy_score("#rust<a>", "rust") > fuzzy_score("#r-u-s-t<a>", "rust"));
+        assert!(fuzzy_score("#rust<a>", "rust") > fuzzy_score("#trust<a>", "rust"));
+    }
+
+    #[test]
+    fn grouped_by_network() {
+        let keys = vec![
+            chan("b", "#zed"),
+            BufKey::Private("a".to_owned(), "bob".to_owned()),
+            chan("a", "#rust"),
+            BufKey::Network("a".to_owned()),
+            BufKey::Status,
+        ];
+        let sw = Switcher::new();
+        assert_eq!(sw.matches(&keys), vec![
+            BufKey::Status,
+            BufKey::Network("a".to_owned()),
+            chan("a", "#rust"),
+            BufKey::Private("a".to_owned(), "bob".to_owned()),
+            chan("b", "#zed"),
+        ]);
+    }
+
+    #[test]
+    fn type_and_pick() {
+        let keys = vec![chan("a", "#rust"), chan("a", "#trust"), chan("b", "#rustaceans")];
+        let mut sw = Switcher::new();
+        for ch in "rust".chars() {
+            assert!(sw.handle_key(&Key::Char(ch), &keys).is_none());
+        }
+        // The best match is picked, not the first one listed.
+        match sw.handle_key(&Key::Enter, &keys) {
+            Some(SwitcherAction::Switch(key)) => assert_eq!(key, chan("a", "#rust")),
+            _ => panic!("Didn't switch to the best match"),
+        }
+        sw.handle_key(&Key::Down, &keys);
+        match sw.handle_key(&Key::Enter, &keys) {
+            Some(SwitcherAction::Switch(key)) => assert_eq!(key, chan("a", "#trust")),
+            _ => panic!("Didn't move the selection down"),
+        }
+        match sw.handle_key(&Key::Esc, &keys) {
+            Some(SwitcherAction::Close) => {},
+            _ => panic!("Escape didn't close the switcher"),
+        }
+    }
+}
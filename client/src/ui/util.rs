@@ -1,7 +1,9 @@
 //! Utilities for rendering to the terminal
 
 use std::cmp;
+use std::iter;
 use rustbox::{RustBox, Color, Style};
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 
 use super::wrap::StringWrap;
 
@@ -12,14 +14,18 @@ pub trait RustBoxExt : Sized {
 
     /// Renders blank spaces of the given style across the line at `y`.
     fn blank_line(&mut self, y: usize, style: Style, fgcolor: Color, bgcolor: Color);
+
+    /// Prints `s` at `x`, `y`, giving wide characters two columns.
+    ///
+    /// `RustBox::print` puts each char in its own cell, so wide characters end
+    /// up overlapping the ones after them. Returns the printed text's width.
+    fn print_wide(&mut self, x: usize, y: usize, style: Style,
+                  fgcolor: Color, bgcolor: Color, s: &str) -> usize;
 }
 
 impl RustBoxExt for RustBox {
     fn text_width(&self, s: &str) -> usize {
-        // FIXME: This isn't even remotely accurate, especially not for
-        // multi-byte unicode chars, but RustBox doesn't handle those correctly
-        // anyway, so we won't worry about it for now.
-        s.len()
+        s.width()
     }
 
     fn blank_line(&mut self, y: usize, style: Style, fgcolor: Color, bgcolor: Color) {
@@ -28,26 +34,46 @@ impl RustBoxExt for RustBox {
         let blank = String::from_utf8(vec![b' '; w]).unwrap();
         self.print(0, y, style, fgcolor, bgcolor, &blank);
     }
+
+    fn print_wide(&mut self, x: usize, y: usize, style: Style,
+                  fgcolor: Color, bgcolor: Color, s: &str) -> usize {
+        let mut cx = x;
+        for ch in s.chars() {
+            self.print_char(cx, y, style, fgcolor, bgcolor, ch);
+            cx += ch.width().unwrap_or(0);
+        }
+        cx - x
+    }
 }
 
 
 /// Builder pattern type for rendering with columns and line wrapping.
 pub struct LineBuilder {
     cols: Vec<ColBuilder>,
+    /// Number of columns the line may take up. If `None`, the line takes up
+    /// the terminal's full width.
+    max_w: Option<usize>,
 }
 
 impl LineBuilder {
     pub fn new() -> LineBuilder {
         LineBuilder {
             cols: vec![],
+            max_w: None,
         }
     }
 
+    /// Limits the line to the first `w` columns of the terminal.
+    pub fn max_width(&mut self, w: usize) {
+        self.max_w = Some(w);
+    }
+
     /// Adds a column and returns a `&mut` reference to it.
     pub fn add_column(&mut self, text: String) -> &mut ColBuilder {
         use rustbox::RB_NORMAL;
         self.cols.push(ColBuilder {
             text: text,
+            segments: vec![],
             wrap: false,
             wrapping: None,
             pad: None,
@@ -59,6 +85,18 @@ impl LineBuilder {
         &mut self.cols[idx]
     }
 
+    /// Adds a column made up of differently styled segments and returns a
+    /// `&mut` reference to it.
+    ///
+    /// Each segment's style is added to the column's, and its colors override
+    /// the column's if set. Segment styles are ignored in padded columns.
+    pub fn add_segments(&mut self, segments: Vec<Segment>) -> &mut ColBuilder {
+        let text = segments.iter().map(|s| &s.text[..]).collect::<String>();
+        let col = self.add_column(text);
+        col.segments = segments;
+        col
+    }
+
     /// Skips a space of the given width.
     pub fn skip(&mut self, w: usize) {
         self.add_column(String::new()).pad_right(w);
@@ -67,7 +105,7 @@ impl LineBuilder {
 
     /// Calculates the line's height after line wrapping in the given terminal.
     pub fn height(&mut self, rb: &mut RustBox) -> usize {
-        let term_w = rb.width();
+        let term_w = self.max_w.unwrap_or(rb.width());
         let mut x = 0;
 
         let mut max_h = 1;
@@ -77,8 +115,7 @@ impl LineBuilder {
             } else { 0 };
 
             if col.wrap {
-                let wrap = col.wrap_to(w);
-                let h = wrap.line_count();
+                let h = col.wrap_to(w).line_count();
                 max_h = cmp::max(h, max_h);
             }
 
@@ -90,9 +127,7 @@ impl LineBuilder {
 
     /// Prints this line in the given terminal.
     pub fn print(self, y: usize, rb: &mut RustBox) {
-        use self::PadText::*;
-
-        let term_w = rb.width();
+        let term_w = self.max_w.unwrap_or(rb.width());
         let mut x = 0;
         for mut col in self.cols {
             let w = if x < term_w {
@@ -100,17 +135,19 @@ impl LineBuilder {
             } else { 0 };
 
             if col.wrap {
-                let wrap = col.wrap_to(w);
-                for (i, line) in wrap.iter_lines(&col.text).enumerate() {
-                    rb.print(x, y + i - 1, col.style, col.fgcolor, col.bgcolor, line);
+                let len = col.text.len();
+                let ranges = col.wrap_to(w).line_ranges(len);
+                for (i, (start, end)) in ranges.into_iter().enumerate() {
+                    col.print_range(x, y + i, start, end, rb);
                 }
             } else {
-                let text = match col.pad {
-                    Some(Left(w))  => { format!("{0: >1$}", col.text, w) },
-                    Some(Right(w)) => { format!("{0: <1$}", col.text, w) },
-                    None => col.text,
-                };
-                rb.print(x, y, col.style, col.fgcolor, col.bgcolor, &text);
+                match col.pad {
+                    Some(ref pad) => {
+                        let text = pad.apply(&col.text);
+                        rb.print_wide(x, y, col.style, col.fgcolor, col.bgcolor, &text);
+                    },
+                    None => col.print_range(x, y, 0, col.text.len(), rb),
+                }
             }
             x += w;
         }
@@ -122,9 +159,13 @@ impl LineBuilder {
 #[derive(Debug, Clone)]
 pub struct ColBuilder {
     text: String,
+    /// Styled segments making up `text`. Empty if the whole column is styled
+    /// the same.
+    segments: Vec<Segment>,
     /// If true, text will be wrapped onto subsequent lines.
     wrap: bool,
-    wrapping: Option<StringWrap>,
+    /// Cached wrapping for the text and the width it was wrapped to.
+    wrapping: Option<(usize, StringWrap)>,
     /// The column's width and padding. If `None`, the column will expand to
     /// fill the entire remaining width of the screen.
     pad: Option<PadText>,
@@ -174,12 +215,56 @@ impl ColBuilder {
 
 impl ColBuilder {
     /// Calculates line wrapping for this column.
-    fn wrap_to(&mut self, width: usize) -> StringWrap {
-        StringWrap::new(&self.text, width)
+    ///
+    /// The result is cached, so calling this again with the same width
+    /// doesn't wrap the text again.
+    fn wrap_to(&mut self, width: usize) -> &StringWrap {
+        let cached = match self.wrapping {
+            Some((w, _)) => w == width,
+            None => false,
+        };
+        if !cached {
+            self.wrapping = Some((width, StringWrap::new(&self.text, width)));
+        }
+        &self.wrapping.as_ref().unwrap().1
+    }
+
+    /// Prints the text between byte indices `start` and `end` at `x`, `y`,
+    /// styling each segment within it.
+    fn print_range(&self, x: usize, y: usize, start: usize, end: usize, rb: &mut RustBox) {
+        if self.segments.is_empty() {
+            rb.print_wide(x, y, self.style, self.fgcolor, self.bgcolor, &self.text[start..end]);
+            return;
+        }
+        let mut x = x;
+        let mut pos = 0;
+        for seg in self.segments.iter() {
+            let s = cmp::max(pos, start);
+            let e = cmp::min(pos + seg.text.len(), end);
+            if s < e {
+                x += rb.print_wide(x, y, self.style | seg.style,
+                                   seg.fgcolor.unwrap_or(self.fgcolor),
+                                   seg.bgcolor.unwrap_or(self.bgcolor),
+                                   &self.text[s..e]);
+            }
+            pos += seg.text.len();
+        }
     }
 }
 
 
+/// A piece of text within a column with its own style.
+#[derive(Debug, Clone)]
+pub struct Segment {
+    pub text: String,
+    pub style: Style,
+    /// If `None`, the column's fgcolor is used.
+    pub fgcolor: Option<Color>,
+    /// If `None`, the column's bgcolor is used.
+    pub bgcolor: Option<Color>,
+}
+
+
 #[derive(Debug, Clone)]
 pub enum PadText {
     /// left-pad with given width
@@ -195,4 +280,36 @@ impl PadText {
             PadText::Right(w) => w,
         }
     }
+
+    /// Pads `text` with spaces to this width, measured in terminal columns.
+    /// Text that's already wider is left as is.
+    pub fn apply(&self, text: &str) -> String {
+        let fill = self.width().saturating_sub(text.width());
+        let spaces = iter::repeat(' ').take(fill).collect::<String>();
+        match *self {
+            PadText::Left(_) => spaces + text,
+            PadText::Right(_) => text.to_owned() + &spaces,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::PadText;
+
+    #[test]
+    fn pad_ascii() {
+        assert_eq!(PadText::Left(6).apply("Forkk"), " Forkk");
+        assert_eq!(PadText::Right(6).apply("Forkk"), "Forkk ");
+        assert_eq!(PadText::Left(3).apply("Forkk"), "Forkk");
+    }
+
+    #[test]
+    fn pad_wide() {
+        // "フォーク" is four wide characters, so it takes up eight columns.
+        assert_eq!(PadText::Left(10).apply("フォーク"), "  フォーク");
+        assert_eq!(PadText::Right(9).apply("aフォーク"), "aフォーク");
+        assert_eq!(PadText::Right(10).apply("aフォーク"), "aフォーク ");
+    }
 }
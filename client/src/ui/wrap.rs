@@ -1,10 +1,16 @@
 //! Text wrapping module
 
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
 
 /// Defines wrapping points for a string wrapped to a particular width.
+///
+/// Widths are measured in terminal columns, so wide characters like CJK take
+/// up two columns each.
 #[derive(Debug, Clone)]
 pub struct StringWrap {
-    /// Character indices at which the text was wrapped.
+    /// Byte indices at which the text was wrapped. These always fall on char
+    /// boundaries.
     points: Vec<usize>,
 }
 
@@ -16,20 +22,27 @@ impl StringWrap {
         // Track the last index where we saw a space.
         let mut last_spc = None;
         let mut last_split = 0;
+        // Width of the text since the last split.
+        let mut x = 0;
         for (i, ch) in text.char_indices() {
-            let x = i - last_split;
-            // If we've exceeded our width, add a wrap point at the last space.
-            if x > width {
-                if let Some(p) = last_spc {
-                    points.push(p + 1);
-                    last_split = p + 1;
-                } else {
-                    // If there's no space to wrap to, we have to break the line
-                    // at our current position.
-                    points.push(i);
-                    last_split = i;
+            let ch_w = ch.width().unwrap_or(0);
+            // If we'd exceed our width, add a wrap point at the last space.
+            if x + ch_w > width && i > last_split {
+                match last_spc {
+                    Some(p) if p >= last_split => {
+                        points.push(p + 1);
+                        last_split = p + 1;
+                    },
+                    _ => {
+                        // If there's no space to wrap to, we have to break the
+                        // line at our current position.
+                        points.push(i);
+                        last_split = i;
+                    },
                 }
+                x = text[last_split..i].width();
             }
+            x += ch_w;
 
             if ch == ' ' { last_spc = Some(i); }
         }
@@ -40,8 +53,9 @@ impl StringWrap {
     }
 
 
-    /// Gets the x and y offset of the given index in the string.
-    pub fn idx_pos(&self, idx: usize) -> (isize, isize) {
+    /// Gets the x and y offset of the given byte index in `text`, which must
+    /// be the string this wrapping was computed for.
+    pub fn idx_pos(&self, text: &str, idx: usize) -> (isize, isize) {
         // This will store the wrap point that occurs right before the index.
         let mut point = 0;
         // This will store the line that point is on.
@@ -54,7 +68,7 @@ impl StringWrap {
                 break;
             }
         }
-        let x = idx - point;
+        let x = text[point..idx].width();
         let y = line;
         (x as isize, y as isize)
     }
@@ -65,6 +79,13 @@ impl StringWrap {
         self.points.len()
     }
 
+    /// Returns the start and end byte indices of each line in a string of
+    /// length `len`.
+    pub fn line_ranges(&self, len: usize) -> Vec<(usize, usize)> {
+        let ends = self.points.iter().skip(1).cloned().chain(Some(len));
+        self.points.iter().cloned().zip(ends).collect()
+    }
+
     /// Splits the given string at this wrapping's wrap points and returns an
     /// iterator over the resulting sub-string slices.
     pub fn iter_lines<'a>(&'a self, text: &'a str) -> IterLines<'a> {
@@ -102,3 +123,43 @@ impl<'a> Iterator for IterLines<'a> {
         }
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::StringWrap;
+
+    fn lines(text: &str, width: usize) -> Vec<&str> {
+        let wrap = StringWrap::new(text, width);
+        wrap.line_ranges(text.len()).into_iter().map(|(s, e)| &text[s..e]).collect()
+    }
+
+    #[test]
+    fn wrap_at_spaces() {
+        assert_eq!(lines("hello there world", 11), vec!["hello ", "there world"]);
+        assert_eq!(lines("short", 10), vec!["short"]);
+    }
+
+    #[test]
+    fn wrap_long_word() {
+        assert_eq!(lines("abcdefgh", 3), vec!["abc", "def", "gh"]);
+    }
+
+    #[test]
+    fn wrap_wide_chars() {
+        // Each of these takes up two columns.
+        assert_eq!(lines("こんにちわ", 4), vec!["こん", "にち", "わ"]);
+        assert_eq!(lines("ab こんにちわ", 6), vec!["ab ", "こんに", "ちわ"]);
+    }
+
+    #[test]
+    fn wide_idx_pos() {
+        let text = "aこんにちわ";
+        let wrap = StringWrap::new(text, 5);
+        // The cursor after "aこん" is 5 columns in on the first line.
+        assert_eq!(wrap.idx_pos(text, 7), (5, 0));
+        // "に" starts the second line.
+        let (x, y) = wrap.idx_pos(text, 10);
+        assert_eq!((x, y), (2, 1));
+    }
+}
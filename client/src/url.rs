@@ -0,0 +1,121 @@
+//! Finding URLs in messages and opening them in a browser.
+
+use std::ascii::AsciiExt;
+use std::cmp;
+use std::env;
+use std::io;
+use std::process::{Command, Stdio};
+
+use common::format::strip_formatting;
+use common::line::LineData;
+
+/// URL schemes we look for. These must be lowercase.
+const SCHEMES: &'static [&'static str] = &["http://", "https://", "ftp://"];
+
+
+/// Finds URLs in `text` and returns their start and end byte indices.
+pub fn find_urls(text: &str) -> Vec<(usize, usize)> {
+    // Lowercasing ASCII doesn't change byte indices.
+    let lower = text.to_ascii_lowercase();
+    let mut urls = vec![];
+    let mut pos = 0;
+    loop {
+        let start = match SCHEMES.iter().filter_map(|s| lower[pos..].find(s)).min() {
+            Some(i) => pos + i,
+            None => break,
+        };
+        let scheme = SCHEMES.iter().find(|s| lower[start..].starts_with(**s)).unwrap();
+        let scheme_end = start + scheme.len();
+
+        let end = text[start..].find(|c: char| {
+            c.is_whitespace() || c.is_control() || c == '<' || c == '>' || c == '"'
+        }).map_or(text.len(), |i| start + i);
+        let end = start + trim_url_end(&text[start..end]);
+
+        // Don't pick up schemes in the middle of a word, like "xhttp://".
+        let at_word_start = text[..start].chars().next_back()
+            .map_or(true, |c| !c.is_alphanumeric());
+        if at_word_start && end > scheme_end {
+            urls.push((start, end));
+        }
+        pos = cmp::max(end, scheme_end);
+    }
+    urls
+}
+
+/// Gets the URLs in a buffer line, in the order they appear.
+pub fn line_urls(line: &LineData) -> Vec<String> {
+    let text = match *line {
+        LineData::Message { ref msg, .. } => strip_formatting(msg),
+        LineData::Topic { ref topic, .. } => strip_formatting(topic),
+        _ => return vec![],
+    };
+    find_urls(&text).into_iter().map(|(s, e)| text[s..e].to_owned()).collect()
+}
+
+/// Opens `url` with `$BROWSER`, or `xdg-open` if that isn't set.
+pub fn open_url(url: &str) -> io::Result<()> {
+    // `$BROWSER` can be a colon separated list. We just use the first one.
+    let browser = env::var("BROWSER").ok()
+        .and_then(|b| b.split(':').next().map(|b| b.to_owned()))
+        .unwrap_or("xdg-open".to_owned());
+    // Keep the browser's output from drawing over the UI.
+    try!(Command::new(browser).arg(url)
+         .stdin(Stdio::null())
+         .stdout(Stdio::null())
+         .stderr(Stdio::null())
+         .spawn());
+    Ok(())
+}
+
+
+/// Returns the length of `url` without any trailing punctuation that's
+/// probably part of the surrounding sentence.
+fn trim_url_end(url: &str) -> usize {
+    let mut end = url.len();
+    while let Some(last) = url[..end].chars().next_back() {
+        let trim = match last {
+            '.' | ',' | ';' | ':' | '!' | '?' | '\'' => true,
+            // Keep closing parens that match one in the URL, like on Wikipedia.
+            ')' => url[..end].matches('(').count() < url[..end].matches(')').count(),
+            _ => false,
+        };
+        if !trim { break; }
+        end -= last.len_utf8();
+    }
+    end
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::find_urls;
+
+    fn urls(text: &str) -> Vec<&str> {
+        find_urls(text).into_iter().map(|(s, e)| &text[s..e]).collect()
+    }
+
+    #[test]
+    fn find_simple() {
+        assert_eq!(urls("see https://example.com/a?b=c for more"),
+                   vec!["https://example.com/a?b=c"]);
+        assert_eq!(urls("HTTP://EXAMPLE.COM and ftp://files.example.com/x"),
+                   vec!["HTTP://EXAMPLE.COM", "ftp://files.example.com/x"]);
+        assert!(urls("no links here").is_empty());
+    }
+
+    #[test]
+    fn trailing_punctuation() {
+        assert_eq!(urls("Look at http://example.com."), vec!["http://example.com"]);
+        assert_eq!(urls("(http://example.com/foo)"), vec!["http://example.com/foo"]);
+        assert_eq!(urls("https://en.wikipedia.org/wiki/Rust_(programming_language)!"),
+                   vec!["https://en.wikipedia.org/wiki/Rust_(programming_language)"]);
+    }
+
+    #[test]
+    fn ignore_partial() {
+        assert!(urls("http://").is_empty());
+        assert!(urls("xhttp://example.com").is_empty());
+        assert_eq!(urls("<http://example.com>"), vec!["http://example.com"]);
+    }
+}
@@ -1,5 +1,37 @@
 //! Common types for sending alerts between client and server.
 
-use types::{NetId, BufId};
+use types::{NetId, BufId, Nick};
 
 include!(concat!(env!("OUT_DIR"), "/alert.rs"));
+
+
+/// Adds `alert` to `alerts`, merging it into an existing alert from the same
+/// source if there is one.
+pub fn push_alert(alerts: &mut Vec<Alert>, alert: Alert) {
+    if let Some(a) = alerts.iter_mut().find(|a| a.same_source(&alert)) {
+        a.merge(alert);
+        return;
+    }
+    alerts.push(alert);
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collapse_repeats() {
+        let ping = |from: &str| Alert::ping("net".to_owned(), "#chan".to_owned(),
+                                            from.to_owned(), format!("Pinged by {}", from));
+        let mut alerts = vec![];
+        push_alert(&mut alerts, ping("bob"));
+        push_alert(&mut alerts, Alert::privmsg("net".to_owned(), "bob".to_owned(),
+                                               "bob".to_owned(), "PM".to_owned()));
+        push_alert(&mut alerts, ping("alice"));
+        assert_eq!(alerts.len(), 2);
+        assert_eq!(alerts[0].count, 2);
+        assert_eq!(alerts[0].msg, "Pinged by alice");
+        assert_eq!(alerts[1].count, 1);
+    }
+}
@@ -0,0 +1,154 @@
+//! Case-insensitive comparison of nicks and channel names.
+//!
+//! IRC servers fold case according to the `CASEMAPPING` they advertise in
+//! `RPL_ISUPPORT`. Under `rfc1459`, the default, `[]\~` are the uppercase
+//! forms of `{}|^`, so `Foo[]` and `foo{}` are the same nick.
+
+/// A casemapping a server may advertise.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaseMapping {
+    /// Only `A-Z` are folded to `a-z`.
+    Ascii,
+    /// `A-Z[]\~` are folded to `a-z{}|^`.
+    Rfc1459,
+    /// Like `Rfc1459`, but `~` and `^` are distinct.
+    StrictRfc1459,
+}
+
+impl Default for CaseMapping {
+    fn default() -> CaseMapping {
+        CaseMapping::Rfc1459
+    }
+}
+
+impl CaseMapping {
+    /// Gets the casemapping with the given `CASEMAPPING` token value, if we
+    /// know about it.
+    pub fn from_name(name: &str) -> Option<CaseMapping> {
+        match name {
+            "ascii" => Some(CaseMapping::Ascii),
+            "rfc1459" => Some(CaseMapping::Rfc1459),
+            "strict-rfc1459" => Some(CaseMapping::StrictRfc1459),
+            _ => None,
+        }
+    }
+
+    /// Gets the `CASEMAPPING` token value for this casemapping.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            CaseMapping::Ascii => "ascii",
+            CaseMapping::Rfc1459 => "rfc1459",
+            CaseMapping::StrictRfc1459 => "strict-rfc1459",
+        }
+    }
+
+    /// Folds a single character to lowercase.
+    fn lower_char(&self, c: char) -> char {
+        match (*self, c) {
+            (_, 'A'...'Z') => ((c as u8) + 32) as char,
+            (CaseMapping::Ascii, _) => c,
+            (_, '[') => '{',
+            (_, ']') => '}',
+            (_, '\\') => '|',
+            (CaseMapping::Rfc1459, '~') => '^',
+            _ => c,
+        }
+    }
+
+    /// Folds the given string to lowercase. Strings that compare equal have
+    /// the same lowercase form, so this is suitable for use as a map key.
+    pub fn to_lower(&self, s: &str) -> String {
+        s.chars().map(|c| self.lower_char(c)).collect()
+    }
+
+    /// True if `a` and `b` are the same under this casemapping.
+    pub fn eq(&self, a: &str, b: &str) -> bool {
+        a.len() == b.len() &&
+            a.chars().zip(b.chars()).all(|(x, y)| self.lower_char(x) == self.lower_char(y))
+    }
+
+    /// True if `word` occurs in `text`, ignoring case, with no letters,
+    /// digits or underscores on either side.
+    pub fn contains_word(&self, text: &str, word: &str) -> bool {
+        !self.find_words(text, word).is_empty()
+    }
+
+    /// Finds where `word` occurs in `text` like `contains_word` does.
+    /// Returns the start and end byte index of each occurrence.
+    pub fn find_words(&self, text: &str, word: &str) -> Vec<(usize, usize)> {
+        // Folding never changes a character's length, so indices into the
+        // folded text are valid in the original.
+        let text = self.to_lower(text);
+        let word = self.to_lower(word);
+        let mut found = vec![];
+        if word.is_empty() { return found; }
+        // Nicks like `foo_` are common, so `_` counts as part of a word.
+        let is_word_char = |c: char| c.is_alphanumeric() || c == '_';
+        let mut pos = 0;
+        while let Some(i) = text[pos..].find(&word[..]) {
+            let start = pos + i;
+            let end = start + word.len();
+            let before = text[..start].chars().next_back().map_or(false, &is_word_char);
+            let after = text[end..].chars().next().map_or(false, &is_word_char);
+            if !before && !after {
+                found.push((start, end));
+                pos = end;
+            } else {
+                // Step past the first character of this match and keep looking.
+                pos = start + text[start..].chars().next().map_or(1, |c| c.len_utf8());
+            }
+        }
+        found
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::CaseMapping;
+
+    #[test]
+    fn rfc1459_brackets() {
+        let cm = CaseMapping::Rfc1459;
+        assert!(cm.eq("Foo[]", "foo{}"));
+        assert!(cm.eq("a\\b~", "A|B^"));
+        assert!(!cm.eq("foo", "foobar"));
+    }
+
+    #[test]
+    fn strict_rfc1459_tilde() {
+        let cm = CaseMapping::StrictRfc1459;
+        assert!(cm.eq("Foo[]", "foo{}"));
+        assert!(!cm.eq("a~", "a^"));
+    }
+
+    #[test]
+    fn ascii_brackets() {
+        let cm = CaseMapping::Ascii;
+        assert!(cm.eq("FOO", "foo"));
+        assert!(!cm.eq("foo[]", "foo{}"));
+    }
+
+    #[test]
+    fn contains_nick() {
+        let cm = CaseMapping::default();
+        assert!(cm.contains_word("hey FORKK[away], ping", "forkk{away}"));
+        assert!(!cm.contains_word("hey there", "forkk"));
+        assert!(!cm.contains_word("forkkk", "forkk"));
+        assert!(!cm.contains_word("forkk_: hi", "forkk"));
+        assert!(cm.contains_word("forkk_: hi", "forkk_"));
+    }
+
+    #[test]
+    fn find_nicks() {
+        let cm = CaseMapping::default();
+        assert_eq!(cm.find_words("Al: al, alone? AL", "al"), vec![(0, 2), (4, 6), (15, 17)]);
+        assert_eq!(cm.find_words("nobody", "al"), vec![]);
+    }
+
+    #[test]
+    fn from_name() {
+        assert_eq!(CaseMapping::from_name("ascii"), Some(CaseMapping::Ascii));
+        assert_eq!(CaseMapping::from_name("rfc7613"), None);
+    }
+}
@@ -1,16 +1,36 @@
 //! This module implements state machine boilerplate for sending and receiving
 //! encodable messages.
 
+use std::fmt;
 use std::mem;
 use std::collections::VecDeque;
 use std::error::Error;
-use rotor::Scope;
+use std::io::{self, Read, Write};
+use std::time::Duration;
+use rotor::{Scope, Time};
 use rotor::mio::tcp::TcpStream;
 use rotor_stream::{Stream, Transport, Protocol, Intent, Exception};
 use serde::{Serialize, Deserialize};
 use bincode::SizeLimit;
-use bincode::serde::{serialize_into, serialized_size, deserialize};
+use bincode::serde::{serialize, deserialize};
 use byteorder::{LittleEndian, WriteBytesExt, ReadBytesExt};
+use flate2::Compression;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use time::precise_time_ns;
+
+
+/// Set in a message's length header if the message is zlib compressed.
+const COMPRESSED_FLAG: u64 = 1 << 63;
+
+/// Messages smaller than this aren't worth compressing.
+const COMPRESS_MIN_SIZE: usize = 256;
+
+/// Default limit on the size of a message in bytes.
+pub const DEFAULT_MAX_MSG_SIZE: usize = 4 * 1024 * 1024;
+
+/// Default time between heartbeat pings in milliseconds.
+pub const DEFAULT_PING_INTERVAL: u64 = 30_000;
 
 
 pub type ConnStream<H> = Stream<Connection<H>>;
@@ -19,8 +39,8 @@ pub type ConnStream<H> = Stream<Connection<H>>;
 pub trait Handler: Sized {
     type Context;
     type Seed : Sized;
-    type Send : Sized + Serialize;
-    type Recv : Sized + Deserialize;
+    type Send : Sized + Serialize + Heartbeat;
+    type Recv : Sized + Deserialize + Heartbeat;
 
     fn create(seed: Self::Seed, scope: &mut Scope<Self::Context>) -> Action<Self>;
 
@@ -31,6 +51,73 @@ pub trait Handler: Sized {
     fn timeout(self, scope: &mut Scope<Self::Context>) -> Action<Self>;
 
     fn wakeup(self, scope: &mut Scope<Self::Context>) -> Action<Self>;
+
+    /// The largest message in bytes we'll send or receive. Messages bigger
+    /// than this can't be sent, and peers sending them are disconnected.
+    fn max_msg_size(_scope: &mut Scope<Self::Context>) -> usize {
+        DEFAULT_MAX_MSG_SIZE
+    }
+
+    /// Milliseconds between heartbeat pings, or `None` to not send any.
+    ///
+    /// If a ping hasn't been answered by the time the next one is due, the
+    /// connection is considered dead and closed.
+    fn ping_interval(_scope: &mut Scope<Self::Context>) -> Option<u64> {
+        Some(DEFAULT_PING_INTERVAL)
+    }
+
+    /// A pong arrived for our last ping, `rtt` milliseconds after we sent it.
+    fn pong(self, _rtt: u64, _scope: &mut Scope<Self::Context>) -> Action<Self> {
+        Action::ok(self)
+    }
+}
+
+
+/// Messages which can carry heartbeat pings and pongs.
+///
+/// `Connection` answers pings and handles pongs itself, so handlers never see
+/// these messages.
+pub trait Heartbeat: Sized {
+    fn ping(token: u64) -> Self;
+    fn pong(token: u64) -> Self;
+
+    /// Gets the token if this message is a ping.
+    fn as_ping(&self) -> Option<u64>;
+    /// Gets the token if this message is a pong.
+    fn as_pong(&self) -> Option<u64>;
+}
+
+
+/// Error for messages bigger than the connection's size limit.
+#[derive(Debug)]
+pub struct MsgTooLarge {
+    pub size: u64,
+    pub max: usize,
+}
+
+impl fmt::Display for MsgTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Message of {} bytes exceeds the limit of {} bytes", self.size, self.max)
+    }
+}
+
+impl Error for MsgTooLarge {
+    fn description(&self) -> &str { "message too large" }
+}
+
+/// Error for peers which send messages they shouldn't, such as messages sent
+/// out of order.
+#[derive(Debug)]
+pub struct ProtocolError(pub String);
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Protocol error: {}", self.0)
+    }
+}
+
+impl Error for ProtocolError {
+    fn description(&self) -> &str { &self.0 }
 }
 
 
@@ -38,6 +125,7 @@ pub trait Handler: Sized {
 pub struct Action<M: Handler> {
     machine: Result<M, Option<Box<Error>>>,
     send: Vec<<M as Handler>::Send>,
+    compress: bool,
 }
 
 impl<M: Handler> Action<M> {
@@ -45,9 +133,20 @@ impl<M: Handler> Action<M> {
         Action {
             machine: Ok(machine),
             send: vec![],
+            compress: false,
         }
     }
 
+    /// Starts compressing messages we send, including any sent by this action.
+    ///
+    /// Peers which don't know about compression can't read compressed
+    /// messages, so this must only be done once the other side has said it
+    /// supports them.
+    pub fn compress(mut self) -> Action<M> {
+        self.compress = true;
+        self
+    }
+
     /// Adds a message to be sent as part of this action.
     pub fn send(mut self, msg: <M as Handler>::Send) -> Action<M> {
         self.send.push(msg);
@@ -64,8 +163,28 @@ impl<M: Handler> Action<M> {
         Action {
             machine: Err(None),
             send: vec![],
+            compress: false,
+        }
+    }
+
+    /// Closes the connection with the given error.
+    pub fn error(e: Box<Error>) -> Action<M> {
+        Action {
+            machine: Err(Some(e)),
+            send: vec![],
+            compress: false,
         }
     }
+
+    /// Checks whether this action closes the connection.
+    pub fn closes(&self) -> bool {
+        self.machine.is_err()
+    }
+
+    /// The messages this action sends.
+    pub fn sent(&self) -> &[<M as Handler>::Send] {
+        &self.send
+    }
 }
 
 
@@ -74,13 +193,31 @@ pub struct Connection<H : Handler> {
     fsm: H,
     msgq: VecDeque<<H as Handler>::Send>,
     state: ConnState,
+    /// True if we compress large outgoing messages.
+    compress: bool,
+    /// Size limit for messages, before compression.
+    max_size: usize,
+    ping: Option<PingState>,
 }
 
 enum ConnState {
     /// Waiting for the next message.
     Waiting,
-    /// Just read the header for the next message and waiting for the message.
-    Reading,
+    /// Just read the header for the next message and waiting for the message
+    /// of the given size. The flag is true if the message is compressed.
+    Reading(bool, usize),
+}
+
+/// Heartbeat state for a connection.
+struct PingState {
+    interval: u64,
+    /// When the next ping is due.
+    next: Time,
+    /// Token of the ping we're waiting for a pong to.
+    pending: Option<u64>,
+    /// Set once the peer sends a ping or pong. Older peers don't know about
+    /// heartbeats, so we don't hold missing pongs against them.
+    peer_pings: bool,
 }
 
 impl<H : Handler> Connection<H> {
@@ -92,24 +229,64 @@ impl<H : Handler> Connection<H> {
         match act.machine {
             Ok(fsm) => {
                 self.fsm = fsm;
+                self.compress |= act.compress;
                 if act.send.is_empty() {
-                    self.wait_for_data()
+                    self.resume()
                 } else {
                     for msg in act.send {
                         self.msgq.push_back(msg);
                     }
-                    Intent::of(self).expect_flush()
+                    self.flush()
                 }
             },
-            Err(Some(e)) => Intent::error(e),
+            Err(Some(e)) => {
+                error!("Closing connection: {}", e);
+                Intent::error(e)
+            },
             Err(None) => Intent::done(),
         }
     }
 
-    /// Waits for a message header.
-    fn wait_for_data(mut self) -> Intent<Self> {
-        self.state = ConnState::Waiting;
-        Intent::of(self).expect_bytes(mem::size_of::<u64>())
+    /// Flushes queued messages.
+    fn flush(self) -> Intent<Self> {
+        let dline = self.ping.as_ref().map(|p| p.next);
+        Intent::of(self).expect_flush().deadline_opt(dline)
+    }
+
+    /// Goes back to reading whatever we were reading before. This may be in
+    /// the middle of a message if we were interrupted to send something.
+    fn resume(self) -> Intent<Self> {
+        let dline = self.ping.as_ref().map(|p| p.next);
+        let bytes = match self.state {
+            ConnState::Waiting => mem::size_of::<u64>(),
+            ConnState::Reading(_, size) => size,
+        };
+        Intent::of(self).expect_bytes(bytes).deadline_opt(dline)
+    }
+
+    /// Handles heartbeat messages. Gives the connection back if `msg` isn't
+    /// one.
+    fn heartbeat(mut self, msg: &H::Recv, scope: &mut Scope<H::Context>) -> Result<Intent<Self>, Self> {
+        if let Some(token) = msg.as_ping() {
+            if let Some(ref mut ping) = self.ping { ping.peer_pings = true; }
+            self.msgq.push_back(H::Send::pong(token));
+            return Ok(self.flush());
+        }
+        if let Some(token) = msg.as_pong() {
+            let expected = self.ping.as_ref().map_or(false, |p| p.pending == Some(token));
+            if !expected {
+                debug!("Ignoring unexpected pong {}", token);
+                return Ok(self.resume());
+            }
+            if let Some(ref mut ping) = self.ping {
+                ping.peer_pings = true;
+                ping.pending = None;
+            }
+            let rtt = now_ms().saturating_sub(token);
+            trace!("Pong after {} ms", rtt);
+            return Ok(self.action(|f| f.pong(rtt, scope)));
+        }
+        Err(self)
     }
 }
 
@@ -119,6 +296,8 @@ impl<H : Handler> Protocol for Connection<H> {
     type Seed = H::Seed;
 
     fn create(seed: Self::Seed, _sock: &mut TcpStream, scope: &mut Scope<Self::Context>) -> Intent<Self> {
+        let max_size = H::max_msg_size(scope);
+        let ping_interval = H::ping_interval(scope);
         let act = H::create(seed, scope);
         match act.machine {
             Ok(fsm) => {
@@ -126,11 +305,22 @@ impl<H : Handler> Protocol for Connection<H> {
                     fsm: fsm,
                     msgq: VecDeque::new(),
                     state: ConnState::Waiting,
+                    compress: act.compress,
+                    max_size: max_size,
+                    ping: ping_interval.map(|interval| PingState {
+                        interval: interval,
+                        next: scope.now() + Duration::from_millis(interval),
+                        pending: None,
+                        peer_pings: false,
+                    }),
                 };
                 for s in act.send { conn.msgq.push_back(s); }
-                Intent::of(conn).expect_flush()
+                conn.flush()
+            },
+            Err(Some(e)) => {
+                error!("Closing connection: {}", e);
+                Intent::error(e)
             },
-            Err(Some(e)) => Intent::error(e),
             Err(None) => Intent::done(),
         }
     }
@@ -141,16 +331,21 @@ impl<H : Handler> Protocol for Connection<H> {
                      -> Intent<Self> {
         debug!("Message bytes flushed");
         if let Some(msg) = self.msgq.pop_front() {
+            let (header, data) = match encode_msg(&msg, self.max_size, self.compress) {
+                Ok(frame) => frame,
+                Err(e) => return Intent::error(e),
+            };
+
             let ref mut out = transport.output();
-            if let Err(e) = out.write_u64::<LittleEndian>(serialized_size(&msg) as u64) {
-                return Intent::error(Box::new(e) as Box<Error>);
-            }
-            match serialize_into(out, &msg, SizeLimit::Bounded(65535)) {
-                Ok(()) => Intent::of(self).expect_flush(),
+            let res = out.write_u64::<LittleEndian>(header)
+                .map_err(|e| e.into())
+                .and_then(|_| out.write_all(&data));
+            match res {
+                Ok(()) => self.flush(),
                 Err(e) => Intent::error(Box::new(e) as Box<Error>),
             }
         } else {
-            self.wait_for_data()
+            self.resume()
         }
     }
 
@@ -169,9 +364,19 @@ impl<H : Handler> Protocol for Connection<H> {
                 };
                 transport.input().consume(end);
                 match r {
-                    Ok(size) => {
-                        self.state = ConnState::Reading;
-                        Intent::of(self).expect_bytes(size as usize)
+                    Ok(header) => {
+                        let compressed = header & COMPRESSED_FLAG != 0;
+                        let size = header & !COMPRESSED_FLAG;
+                        // Check this before rotor allocates a buffer for the
+                        // message, so a garbled header can't make us allocate
+                        // something huge.
+                        if size > self.max_size as u64 {
+                            let e = MsgTooLarge { size: size, max: self.max_size };
+                            error!("Dropping connection: {}", e);
+                            return Intent::error(Box::new(e) as Box<Error>);
+                        }
+                        self.state = ConnState::Reading(compressed, size as usize);
+                        self.resume()
                     },
                     Err(e) => {
                         error!("Error reading message size: {}", e);
@@ -179,31 +384,65 @@ impl<H : Handler> Protocol for Connection<H> {
                     },
                 }
             },
-            ConnState::Reading => {
+            ConnState::Reading(compressed, _) => {
                 let msg = {
                     let data = &transport.input()[..end];
-                    deserialize(data)
+                    if compressed {
+                        match decompress(data, self.max_size) {
+                            Ok(data) => deserialize(&data),
+                            Err(e) => {
+                                error!("Error decompressing message: {}", e);
+                                return Intent::error(Box::new(e) as Box<Error>);
+                            },
+                        }
+                    } else {
+                        deserialize(data)
+                    }
                 };
                 transport.input().consume(end);
+                self.state = ConnState::Waiting;
                 match msg {
                     Ok(msg) => {
-                        self.state = ConnState::Waiting;
-                        self.action(|f| f.msg_recv(&msg, scope))
+                        match self.heartbeat(&msg, scope) {
+                            Ok(intent) => intent,
+                            Err(conn) => conn.action(|f| f.msg_recv(&msg, scope)),
+                        }
                     },
                     Err(e) => {
-                        error!("Error reading message: {}", e);
-                        Intent::error(Box::new(e) as Box<Error>)
+                        // Messages are framed, so we can skip ones we don't
+                        // understand, such as new variants from a newer peer.
+                        warn!("Ignoring message we couldn't decode: {}", e);
+                        self.resume()
                     },
                 }
             }
         }
     }
 
-    fn timeout(self,
+    fn timeout(mut self,
                _transport: &mut Transport<TcpStream>,
                scope: &mut Scope<Self::Context>)
                -> Intent<Self> {
-        self.action(|f| f.timeout(scope))
+        // Without a heartbeat, the only deadlines are the handler's.
+        if self.ping.is_none() {
+            return self.action(|f| f.timeout(scope));
+        }
+        let now = scope.now();
+        if !self.ping.as_ref().map_or(false, |p| now >= p.next) {
+            return self.resume();
+        }
+        let token = now_ms();
+        if let Some(ref mut ping) = self.ping {
+            if ping.pending.is_some() && ping.peer_pings {
+                error!("No pong from peer in {} ms. Dropping connection", ping.interval);
+                let e = io::Error::new(io::ErrorKind::TimedOut, "heartbeat timed out");
+                return Intent::error(Box::new(e) as Box<Error>);
+            }
+            ping.pending = Some(token);
+            ping.next = now + Duration::from_millis(ping.interval);
+        }
+        self.msgq.push_back(H::Send::ping(token));
+        self.flush()
     }
 
     /// Message received (from the main loop)
@@ -227,3 +466,108 @@ impl<H : Handler> Protocol for Connection<H> {
         None
     }
 }
+
+
+/// Gets a monotonic time in milliseconds for ping tokens.
+fn now_ms() -> u64 {
+    precise_time_ns() / 1_000_000
+}
+
+/// Serializes a message into its length header and body, compressing it if
+/// `compress` is set and the message is big enough to be worth it.
+fn encode_msg<T: Serialize>(msg: &T, max_size: usize, compress: bool)
+                            -> Result<(u64, Vec<u8>), Box<Error>> {
+    let data = try!(serialize(msg, SizeLimit::Bounded(max_size as u64))
+                    .map_err(|e| Box::new(e) as Box<Error>));
+    if compress && data.len() >= COMPRESS_MIN_SIZE {
+        let data = try!(compress_data(&data).map_err(|e| Box::new(e) as Box<Error>));
+        Ok((data.len() as u64 | COMPRESSED_FLAG, data))
+    } else {
+        Ok((data.len() as u64, data))
+    }
+}
+
+/// Compresses a serialized message.
+fn compress_data(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut enc = ZlibEncoder::new(Vec::new(), Compression::Default);
+    try!(enc.write_all(data));
+    enc.finish()
+}
+
+/// Decompresses a message compressed with `compress_data`, failing if it
+/// decompresses to more than `max_size` bytes.
+fn decompress(data: &[u8], max_size: usize) -> io::Result<Vec<u8>> {
+    let mut out = vec![];
+    try!(ZlibDecoder::new(data).take(max_size as u64 + 1).read_to_end(&mut out));
+    if out.len() > max_size {
+        Err(io::Error::new(io::ErrorKind::InvalidData, MsgTooLarge {
+            size: out.len() as u64,
+            max: max_size,
+        }))
+    } else {
+        Ok(out)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use bincode::serde::deserialize;
+    use time;
+    use line::{BufferLine, LineData, MsgKind};
+    use messages::{CoreMsg, CoreBufMsg, ClientMsg};
+    use super::*;
+
+    #[test]
+    fn compress_round_trip() {
+        let data = (0..50).map(|_| "some repetitive text ").collect::<String>().into_bytes();
+        let packed = compress_data(&data).unwrap();
+        assert!(packed.len() < data.len());
+        assert_eq!(decompress(&packed, data.len()).unwrap(), data);
+        assert!(decompress(&packed, data.len() - 1).is_err());
+    }
+
+    fn scrollback(count: usize) -> CoreMsg {
+        let lines = (0..count).map(|i| {
+            BufferLine::new(time::now(), LineData::Message {
+                kind: MsgKind::PrivMsg,
+                from: "Forkk".to_owned(),
+                msg: format!("Line {} of a long scrollback batch. {}", i,
+                             (0..40).map(|_| "padding ").collect::<String>()),
+            })
+        }).collect();
+        CoreMsg::BufMsg("*status*".to_owned(), CoreBufMsg::Scrollback(lines))
+    }
+
+    #[test]
+    fn large_scrollback_round_trip() {
+        let msg = scrollback(200);
+        let (header, data) = encode_msg(&msg, DEFAULT_MAX_MSG_SIZE, false).unwrap();
+        assert!(data.len() > 65535);
+        assert_eq!(header, data.len() as u64);
+        match deserialize::<CoreMsg>(&data).unwrap() {
+            CoreMsg::BufMsg(_, CoreBufMsg::Scrollback(lines)) => assert_eq!(lines.len(), 200),
+            m => panic!("Decoded wrong message: {:?}", m),
+        }
+
+        let (header, data) = encode_msg(&msg, DEFAULT_MAX_MSG_SIZE, true).unwrap();
+        assert!(header & COMPRESSED_FLAG != 0);
+        let data = decompress(&data, DEFAULT_MAX_MSG_SIZE).unwrap();
+        assert!(deserialize::<CoreMsg>(&data).is_ok());
+    }
+
+    #[test]
+    fn encode_over_limit() {
+        assert!(encode_msg(&scrollback(200), 65535, false).is_err());
+    }
+
+    #[test]
+    fn heartbeat_messages() {
+        let (_, data) = encode_msg(&ClientMsg::ping(42), DEFAULT_MAX_MSG_SIZE, true).unwrap();
+        let msg = deserialize::<ClientMsg>(&data).unwrap();
+        assert_eq!(msg.as_ping(), Some(42));
+        assert_eq!(msg.as_pong(), None);
+        assert_eq!(CoreMsg::pong(7).as_pong(), Some(7));
+        assert_eq!(CoreMsg::CompressionEnabled.as_pong(), None);
+    }
+}
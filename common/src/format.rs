@@ -0,0 +1,175 @@
+//! Parsing for mIRC-style formatting codes in message text.
+
+const BOLD: char = '\x02';
+const COLOR: char = '\x03';
+const RESET: char = '\x0f';
+const REVERSE: char = '\x16';
+const ITALIC: char = '\x1d';
+const UNDERLINE: char = '\x1f';
+
+
+/// A run of text which has the same formatting throughout.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Span {
+    pub text: String,
+    pub bold: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub reverse: bool,
+    /// mIRC foreground color number, or `None` for the default color.
+    pub fg: Option<u8>,
+    /// mIRC background color number, or `None` for the default color.
+    pub bg: Option<u8>,
+}
+
+impl Span {
+    /// Checks whether this span has the same formatting as `other`.
+    fn same_format(&self, other: &Span) -> bool {
+        self.bold == other.bold && self.italic == other.italic &&
+            self.underline == other.underline && self.reverse == other.reverse &&
+            self.fg == other.fg && self.bg == other.bg
+    }
+}
+
+
+/// Splits `text` into spans of uniformly formatted text, removing the
+/// formatting codes themselves.
+///
+/// Empty spans are never returned, so plain text gives a single span and an
+/// empty string gives none.
+pub fn parse_formatting(text: &str) -> Vec<Span> {
+    let mut spans = vec![];
+    let mut cur = Span::default();
+    let mut chars = text.chars().peekable();
+    while let Some(ch) = chars.next() {
+        let mut next = Span { text: String::new(), ..cur.clone() };
+        match ch {
+            BOLD => next.bold = !next.bold,
+            ITALIC => next.italic = !next.italic,
+            UNDERLINE => next.underline = !next.underline,
+            REVERSE => next.reverse = !next.reverse,
+            RESET => next = Span::default(),
+            COLOR => {
+                // A bare color code resets the colors.
+                match take_color(&mut chars) {
+                    Some(fg) => {
+                        next.fg = color_num(fg);
+                        if chars.peek() == Some(&',') {
+                            // The comma is only part of the code if a color
+                            // follows it.
+                            let mut ahead = chars.clone();
+                            ahead.next();
+                            if let Some(bg) = take_color(&mut ahead) {
+                                next.bg = color_num(bg);
+                                chars = ahead;
+                            }
+                        }
+                    },
+                    None => {
+                        next.fg = None;
+                        next.bg = None;
+                    },
+                }
+            },
+            _ => {
+                cur.text.push(ch);
+                continue;
+            },
+        }
+        push_span(&mut spans, cur);
+        cur = next;
+    }
+    push_span(&mut spans, cur);
+    spans
+}
+
+/// Removes all formatting codes from `text`.
+pub fn strip_formatting(text: &str) -> String {
+    parse_formatting(text).into_iter().map(|s| s.text).collect()
+}
+
+
+/// Adds `span` to `spans`, merging it into the previous span if they're
+/// formatted the same and dropping it if it's empty.
+fn push_span(spans: &mut Vec<Span>, span: Span) {
+    if span.text.is_empty() { return; }
+    if let Some(last) = spans.last_mut() {
+        if last.same_format(&span) {
+            last.text.push_str(&span.text);
+            return;
+        }
+    }
+    spans.push(span);
+}
+
+/// Reads a color number of up to two digits.
+fn take_color<I>(chars: &mut ::std::iter::Peekable<I>) -> Option<u8>
+    where I: Iterator<Item=char>
+{
+    let mut num = None;
+    for _ in 0..2 {
+        match chars.peek().and_then(|c| c.to_digit(10)) {
+            Some(d) => {
+                num = Some(num.unwrap_or(0) * 10 + d as u8);
+                chars.next();
+            },
+            None => break,
+        }
+    }
+    num
+}
+
+/// Color 99 means "default color".
+fn color_num(n: u8) -> Option<u8> {
+    if n == 99 { None } else { Some(n) }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plain(text: &str) -> Span {
+        Span { text: text.to_owned(), ..Span::default() }
+    }
+
+    #[test]
+    fn plain_text() {
+        assert_eq!(parse_formatting("hello world"), vec![plain("hello world")]);
+        assert_eq!(parse_formatting(""), vec![]);
+    }
+
+    #[test]
+    fn bold_and_reset() {
+        let spans = parse_formatting("a \x02bold\x02 b\x02\x1fc\x0fd");
+        assert_eq!(spans, vec![
+            plain("a "),
+            Span { text: "bold".to_owned(), bold: true, ..Span::default() },
+            plain(" b"),
+            Span { text: "c".to_owned(), bold: true, underline: true, ..Span::default() },
+            plain("d"),
+        ]);
+    }
+
+    #[test]
+    fn colors() {
+        let spans = parse_formatting("\x034red\x0312,1blue\x03 none\x035,text");
+        assert_eq!(spans, vec![
+            Span { text: "red".to_owned(), fg: Some(4), ..Span::default() },
+            Span { text: "blue".to_owned(), fg: Some(12), bg: Some(1), ..Span::default() },
+            plain(" none"),
+            Span { text: ",text".to_owned(), fg: Some(5), ..Span::default() },
+        ]);
+    }
+
+    #[test]
+    fn color_digits_limit() {
+        let spans = parse_formatting("\x03045");
+        assert_eq!(spans, vec![Span { text: "5".to_owned(), fg: Some(4), ..Span::default() }]);
+    }
+
+    #[test]
+    fn strip() {
+        assert_eq!(strip_formatting("\x02\x0304,99hi\x0f \x1dthere\x16"), "hi there");
+    }
+}
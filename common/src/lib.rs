@@ -3,6 +3,7 @@
 extern crate rotor_stream;
 extern crate bincode;
 extern crate byteorder;
+extern crate flate2;
 extern crate rustc_serialize;
 extern crate serde;
 extern crate time;
@@ -12,3 +13,6 @@ pub mod conn;
 pub mod messages;
 pub mod line;
 pub mod alert;
+pub mod format;
+pub mod casemap;
+pub mod tempdir;
@@ -1,5 +1,6 @@
 // use irc::client::prelude::*;
 use std::fmt;
+use std::cmp::Ordering;
 use time;
 use time::{Tm, Timespec};
 use serde::{Serializer, Deserializer};
@@ -41,6 +42,78 @@ impl Sender {
     }
 }
 
+/// Channel user prefixes, ordered from highest to lowest rank. These are used
+/// for servers that don't advertise their own.
+pub const USER_PREFIXES: &'static str = "~&@%+";
+
+impl ChannelUser {
+    pub fn new(nick: Nick) -> ChannelUser {
+        ChannelUser {
+            nick: nick,
+            prefixes: String::new(),
+            account: None,
+            ident: String::new(),
+            host: String::new(),
+            away: false,
+        }
+    }
+
+    /// Parses an entry from a `NAMES` reply, such as `@+Forkk`, on a network
+    /// whose prefixes are `prefixes`, ordered from highest to lowest rank.
+    /// With the `multi-prefix` capability, a nick may have several prefixes.
+    pub fn parse_names_entry(name: &str, prefixes: &str) -> ChannelUser {
+        let nick = name.trim_left_matches(|c| prefixes.contains(c));
+        let mut user = ChannelUser::new(nick.to_owned());
+        for pfx in name[..name.len() - nick.len()].chars() {
+            user.add_prefix(pfx, prefixes);
+        }
+        user
+    }
+
+    /// Adds the given prefix, keeping prefixes ordered by their rank in
+    /// `prefixes`.
+    pub fn add_prefix(&mut self, pfx: char, prefixes: &str) {
+        if !self.prefixes.contains(pfx) {
+            self.prefixes.push(pfx);
+            let ordered = prefixes.chars()
+                .filter(|p| self.prefixes.contains(*p))
+                .collect();
+            self.prefixes = ordered;
+        }
+    }
+
+    /// Removes the given prefix.
+    pub fn remove_prefix(&mut self, pfx: char) {
+        self.prefixes = self.prefixes.chars().filter(|p| *p != pfx).collect();
+    }
+
+    /// Returns the user's highest prefix, if any.
+    pub fn prefix(&self) -> Option<char> {
+        self.prefixes.chars().next()
+    }
+
+    /// Returns the rank of the user's highest prefix. Lower ranks come first,
+    /// prefixes other than `USER_PREFIXES` come after those, and users with
+    /// no prefix have the lowest rank.
+    pub fn rank(&self) -> usize {
+        match self.prefix() {
+            Some(p) => USER_PREFIXES.find(p).unwrap_or(USER_PREFIXES.len()),
+            None => USER_PREFIXES.len() + 1,
+        }
+    }
+}
+
+/// Sorts users by rank and then by nick, so ops come before voiced users and
+/// voiced users come before everyone else.
+pub fn sort_users(users: &mut [ChannelUser]) {
+    users.sort_by(|a, b| {
+        match a.rank().cmp(&b.rank()) {
+            Ordering::Equal => a.nick.to_lowercase().cmp(&b.nick.to_lowercase()),
+            ord => ord,
+        }
+    });
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -51,4 +124,25 @@ mod tests {
             nick: "Forkk".to_owned(), ident: "~forkk".to_owned(), host: "irc.forkk.net".to_owned(),
         }), Sender::parse_prefix("Forkk!~forkk@irc.forkk.net"));
     }
+
+    #[test]
+    fn parse_names_multi_prefix() {
+        let user = ChannelUser::parse_names_entry("+@Forkk", USER_PREFIXES);
+        assert_eq!(user.nick, "Forkk");
+        assert_eq!(user.prefixes, "@+");
+        assert_eq!(ChannelUser::parse_names_entry("Forkk", USER_PREFIXES).prefixes, "");
+    }
+
+    #[test]
+    fn sort_by_rank() {
+        let mut users = vec![
+            ChannelUser::parse_names_entry("bob", USER_PREFIXES),
+            ChannelUser::parse_names_entry("+carol", USER_PREFIXES),
+            ChannelUser::parse_names_entry("alice", USER_PREFIXES),
+            ChannelUser::parse_names_entry("@+dave", USER_PREFIXES),
+        ];
+        sort_users(&mut users);
+        let nicks = users.iter().map(|u| &u.nick[..]).collect::<Vec<_>>();
+        assert_eq!(nicks, vec!["dave", "carol", "alice", "bob"]);
+    }
 }
@@ -3,8 +3,13 @@ use std::fmt;
 pub use self::core::{CoreMsg, CoreNetMsg, CoreBufMsg};
 pub use self::client::{ClientMsg, ClientNetMsg, ClientBufMsg};
 
-pub use line::{BufferLine, MsgKind};
+pub use line::{BufferLine, MsgKind, ChannelUser};
 pub use types::{NetId, BufId};
 pub use alert::Alert;
 
+use conn::Heartbeat;
+
 include!(concat!(env!("OUT_DIR"), "/messages.rs"));
+
+/// Feature the core lists in `AuthOk` if it can compress messages.
+pub const COMPRESSION_FEATURE: &'static str = "compression";
@@ -0,0 +1,62 @@
+//! A scratch directory for tests which need to write files.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use time;
+
+static COUNTER: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// A uniquely named directory under the system's temp directory, which is
+/// removed along with its contents when dropped.
+///
+/// Tests run in parallel, so each needs its own directory rather than a fixed
+/// path.
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    /// Creates a new directory whose name starts with `prefix`.
+    pub fn new(prefix: &str) -> TempDir {
+        let n = COUNTER.fetch_add(1, Ordering::SeqCst);
+        let name = format!("distirc-{}-{}-{}", prefix, time::precise_time_ns(), n);
+        let path = env::temp_dir().join(name);
+        fs::create_dir_all(&path).expect("Failed to create temp dir");
+        TempDir { path: path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Gets the path of `name` inside the directory.
+    pub fn join<P: AsRef<Path>>(&self, name: P) -> PathBuf {
+        self.path.join(name)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::TempDir;
+
+    #[test]
+    fn unique_and_removed() {
+        let path = {
+            let a = TempDir::new("tempdir");
+            let b = TempDir::new("tempdir");
+            assert!(a.path() != b.path());
+            assert!(a.path().is_dir());
+            a.path().to_owned()
+        };
+        assert!(!path.exists());
+    }
+}
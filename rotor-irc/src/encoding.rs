@@ -0,0 +1,79 @@
+//! Character encodings for IRC lines.
+//!
+//! IRC itself doesn't specify an encoding, so lines are just bytes. Most
+//! networks use UTF-8 these days, but some still use legacy encodings.
+
+use std::borrow::Cow;
+
+/// The encoding used for an IRC connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    /// UTF-8. Invalid sequences are replaced with U+FFFD when decoding.
+    Utf8,
+    /// ISO-8859-1. Characters it can't represent are sent as `?`.
+    Latin1,
+}
+
+impl Default for Encoding {
+    fn default() -> Encoding {
+        Encoding::Utf8
+    }
+}
+
+impl Encoding {
+    /// Gets the encoding with the given name, if we support it.
+    pub fn from_name(name: &str) -> Option<Encoding> {
+        match &name.to_lowercase()[..] {
+            "utf-8" | "utf8" => Some(Encoding::Utf8),
+            "iso-8859-1" | "latin1" | "latin-1" => Some(Encoding::Latin1),
+            _ => None,
+        }
+    }
+
+    /// Decodes a line received from the server.
+    ///
+    /// This never fails. Bytes that aren't valid in the encoding are replaced
+    /// so one bad line doesn't take the connection down.
+    pub fn decode<'a>(&self, data: &'a [u8]) -> Cow<'a, str> {
+        match *self {
+            Encoding::Utf8 => String::from_utf8_lossy(data),
+            Encoding::Latin1 => Cow::Owned(data.iter().map(|&b| b as char).collect()),
+        }
+    }
+
+    /// Encodes a line to send to the server.
+    pub fn encode<'a>(&self, line: &'a str) -> Cow<'a, [u8]> {
+        match *self {
+            Encoding::Utf8 => Cow::Borrowed(line.as_bytes()),
+            Encoding::Latin1 => Cow::Owned(line.chars().map(|c| {
+                if (c as u32) < 256 { c as u8 } else { b'?' }
+            }).collect()),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::Encoding;
+
+    #[test]
+    fn utf8_lossy() {
+        assert_eq!(Encoding::Utf8.decode(b"caf\xc3\xa9"), "café");
+        assert_eq!(Encoding::Utf8.decode(b"caf\xe9"), "caf\u{fffd}");
+    }
+
+    #[test]
+    fn latin1_round_trip() {
+        assert_eq!(Encoding::Latin1.decode(b"caf\xe9"), "café");
+        assert_eq!(&Encoding::Latin1.encode("café")[..], b"caf\xe9");
+        assert_eq!(&Encoding::Latin1.encode("日本")[..], b"??");
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(Encoding::from_name("UTF-8"), Some(Encoding::Utf8));
+        assert_eq!(Encoding::from_name("latin1"), Some(Encoding::Latin1));
+        assert_eq!(Encoding::from_name("shift_jis"), None);
+    }
+}
@@ -5,7 +5,9 @@ extern crate rotor_stream;
 mod response;
 mod message;
 mod machine;
+mod encoding;
 
 pub use message::{Message, Command, ParseError};
 pub use response::Response;
-pub use machine::{IrcConnection, IrcMachine, IrcAction};
+pub use machine::{IrcConnection, IrcMachine, IrcAction, DEFAULT_MAX_LINE_LEN};
+pub use encoding::Encoding;
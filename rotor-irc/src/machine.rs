@@ -3,13 +3,16 @@
 use std::error::Error;
 use std::collections::VecDeque;
 use std::io::Write;
-use rotor::{Scope};
-use rotor_stream::{Protocol, Intent, Transport, Exception};
-use rotor::mio::tcp::{TcpStream};
+use std::marker::PhantomData;
+use rotor::{Scope, Time};
+use rotor_stream::{Protocol, Intent, Transport, Exception, StreamSocket};
 
 use message::{Message};
+use encoding::Encoding;
 
-const MAX_MSG_LEN: usize = 65536;
+/// Longest line, in bytes, that `IrcMachine::max_line_len` allows by
+/// default.
+pub const DEFAULT_MAX_LINE_LEN: usize = 65536;
 
 pub trait IrcMachine : Sized {
     type Context;
@@ -21,12 +24,23 @@ pub trait IrcMachine : Sized {
     /// Called when a message is received.
     fn recv(self, msg: Message, scope: &mut Scope<Self::Context>) -> IrcAction<Self>;
 
-    // /// A timeout occurred.
-    // fn timeout(self, scope: &mut Scope<Self::Context>) -> IrcAction<Self>;
+    /// A deadline set by a previous `IrcAction` has passed.
+    fn timeout(self, scope: &mut Scope<Self::Context>) -> IrcAction<Self>;
 
     /// The machine was woken up.
     fn wakeup(self, scope: &mut Scope<Self::Context>) -> IrcAction<Self>;
 
+    /// The encoding used to decode received lines and encode sent ones.
+    fn encoding(&self) -> Encoding {
+        Encoding::default()
+    }
+
+    /// The longest line we'll buffer while waiting for its `\r\n`. Longer
+    /// lines are dropped.
+    fn max_line_len(&self) -> usize {
+        DEFAULT_MAX_LINE_LEN
+    }
+
     /// Method called when we've disconnected from the server for any reason.
     ///
     /// The state machine must be consumed by this method.
@@ -35,12 +49,21 @@ pub trait IrcMachine : Sized {
 
 
 /// An IRC connection state machine abstraction.
-pub struct IrcConnection<M : IrcMachine> {
+///
+/// `S` is the type of socket the connection runs over.
+pub struct IrcConnection<M : IrcMachine, S : StreamSocket> {
     fsm: M,
     sendq: VecDeque<Message>,
+    /// Time at which the state machine's `timeout` method should be called.
+    deadline: Option<Time>,
+    /// True if we're throwing away the rest of a line that was too long.
+    skipping: bool,
+    /// True if we close the connection once `sendq` is sent.
+    closing: bool,
+    _sock: PhantomData<S>,
 }
 
-impl<M : IrcMachine> IrcConnection<M> {
+impl<M : IrcMachine, S : StreamSocket> IrcConnection<M, S> {
     /// Calls the given function with the FSM as an arg and handles the
     /// resulting action.
     fn action<F>(mut self, f: F) -> Intent<Self>
@@ -52,6 +75,8 @@ impl<M : IrcMachine> IrcConnection<M> {
                 trace!("Action returned OK");
                 self.fsm = fsm;
                 for s in act.send { self.sendq.push_back(s); }
+                if act.deadline.is_some() { self.deadline = act.deadline; }
+                self.closing |= act.close;
                 self.idle()
             },
             Err(Some(e)) => {
@@ -68,18 +93,28 @@ impl<M : IrcMachine> IrcConnection<M> {
     /// Waits for flush if there are messages to send, otherwise waits for more
     /// messages from the server.
     fn idle(self) -> Intent<Self> {
-        if self.sendq.is_empty() {
+        if self.closing {
+            // Nothing else happens until everything is sent.
+            trace!("Closing once output is flushed");
+            return Intent::of(self).expect_flush();
+        }
+        let deadline = self.deadline;
+        let intent = if self.sendq.is_empty() {
             self.wait_for_data()
         } else {
             trace!("There are messages to send. Waiting for output flush.");
             Intent::of(self).expect_flush()
-        }
+        };
+        if let Some(time) = deadline {
+            intent.deadline(time)
+        } else { intent }
     }
 
     /// Waits for a new message.
     fn wait_for_data(self) -> Intent<Self> {
         trace!("Waiting for data");
-        Intent::of(self).expect_delimiter("\r\n".as_bytes(), MAX_MSG_LEN)
+        let max = self.fsm.max_line_len();
+        Intent::of(self).expect_delimiter("\r\n".as_bytes(), max)
     }
 
     /// Calls `disconnect` on the state machine and returns the given error.
@@ -90,12 +125,12 @@ impl<M : IrcMachine> IrcConnection<M> {
 }
 
 
-impl<M : IrcMachine> Protocol for IrcConnection<M> {
+impl<M : IrcMachine, S : StreamSocket> Protocol for IrcConnection<M, S> {
     type Context = M::Context;
-    type Socket = TcpStream;
+    type Socket = S;
     type Seed = M::Seed;
 
-    fn create(seed: Self::Seed, _sock: &mut TcpStream, scope: &mut Scope<Self::Context>) -> Intent<Self> {
+    fn create(seed: Self::Seed, _sock: &mut S, scope: &mut Scope<Self::Context>) -> Intent<Self> {
         debug!("Starting IRC connection");
         let act = M::create(seed, scope);
         match act.state {
@@ -103,6 +138,10 @@ impl<M : IrcMachine> Protocol for IrcConnection<M> {
                 let mut conn = IrcConnection {
                     fsm: fsm,
                     sendq: VecDeque::new(),
+                    deadline: act.deadline,
+                    skipping: false,
+                    closing: act.close,
+                    _sock: PhantomData,
                 };
                 for s in act.send { conn.sendq.push_back(s); }
                 conn.idle()
@@ -119,39 +158,47 @@ impl<M : IrcMachine> Protocol for IrcConnection<M> {
     }
 
     fn bytes_flushed(mut self,
-                     transport: &mut Transport<TcpStream>,
-                     _scope: &mut Scope<Self::Context>)
+                     transport: &mut Transport<S>,
+                     scope: &mut Scope<Self::Context>)
                      -> Intent<Self>
     {
         trace!("Message bytes flushed");
         if let Some(msg) = self.sendq.pop_front() {
             let ref mut out = transport.output();
             debug!("Sent message {}", msg);
-            match out.write_fmt(format_args!("{}\r\n", msg)) {
+            let line = format!("{}\r\n", msg);
+            match out.write_all(&self.fsm.encoding().encode(&line)) {
                 Ok(()) => self.idle(),
                 Err(e) => self.fail(Box::new(e) as Box<Error>),
             }
+        } else if self.closing {
+            info!("Sent everything. Connection will exit");
+            self.fsm.disconnect(scope);
+            Intent::done()
         } else {
             warn!("Waited for flush, but there were no messages to send");
             self.idle()
         }
     }
 
-    fn bytes_read(self,
-                  transport: &mut Transport<TcpStream>,
+    fn bytes_read(mut self,
+                  transport: &mut Transport<S>,
                   end: usize,
                   scope: &mut Scope<Self::Context>)
                   -> Intent<Self>
     {
+        if self.skipping {
+            // This is the end of an overlong line we've been dropping.
+            transport.input().consume(end + 2);
+            self.skipping = false;
+            return self.idle();
+        }
         let data = transport.input()[0..end].to_vec();
         // As `end` doesn't include the "\r\n" delimiter, we consume an
         // additional two bytes to ensure we don't leave the delimiter in our
         // input stream.
         transport.input().consume(end + 2);
-        let line = match String::from_utf8(data) {
-            Ok(line) => line,
-            Err(e) => return self.fail(Box::new(e) as Box<Error>),
-        };
+        let line = self.fsm.encoding().decode(&data).into_owned();
         debug!("Received line: {}", line);
         match line.parse::<Message>() {
             Ok(msg) => self.action(move |m| m.recv(msg, scope)),
@@ -159,16 +206,27 @@ impl<M : IrcMachine> Protocol for IrcConnection<M> {
         }
     }
 
-    fn wakeup(self, _t: &mut Transport<TcpStream>, scope: &mut Scope<Self::Context>) -> Intent<Self> {
+    fn wakeup(self, _t: &mut Transport<S>, scope: &mut Scope<Self::Context>) -> Intent<Self> {
         debug!("IRC machine woke up");
         self.action(|m| m.wakeup(scope))
     }
 
-    fn exception(self,
-                 _t: &mut Transport<Self::Socket>,
+    fn exception(mut self,
+                 t: &mut Transport<Self::Socket>,
                  reason: Exception,
                  scope: &mut Scope<Self::Context>)
                  -> Intent<Self> {
+        if let Exception::LimitReached = reason {
+            // Rather than give up on the connection, drop what we have of the
+            // line and skip ahead to the next `\r\n`.
+            if !self.skipping {
+                warn!("Dropping a line longer than {} bytes", self.fsm.max_line_len());
+                self.skipping = true;
+            }
+            let skip = overlong_skip_len(&t.input()[..]);
+            t.input().consume(skip);
+            return self.idle();
+        }
         error!("Error reading data: {}", reason);
         self.fsm.disconnect(scope);
         Intent::error(Box::new(reason) as Box<Error>)
@@ -180,19 +238,30 @@ impl<M : IrcMachine> Protocol for IrcConnection<M> {
         Some(Box::new(reason))
     }
 
-    fn timeout(self, _tp: &mut Transport<TcpStream>, _s: &mut Scope<Self::Context>) -> Intent<Self> {
-        // TODO: Implement connection timeouts
-        unreachable!()
+    fn timeout(mut self, _tp: &mut Transport<S>, scope: &mut Scope<Self::Context>) -> Intent<Self> {
+        debug!("IRC machine deadline passed");
+        self.deadline = None;
+        self.action(|m| m.timeout(scope))
     }
 }
 
 
 
 
+/// Returns how much of `input`, the buffered part of an overlong line, can
+/// be thrown away. A trailing `\r` might be the start of the line's `\r\n`,
+/// so that's kept.
+fn overlong_skip_len(input: &[u8]) -> usize {
+    if input.ends_with(b"\r") { input.len() - 1 } else { input.len() }
+}
+
+
 /// An action performed by an IRC machine.
 pub struct IrcAction<M> {
     state: Result<M, Option<Box<Error>>>,
     send: Vec<Message>,
+    deadline: Option<Time>,
+    close: bool,
 }
 
 impl<M> IrcAction<M> {
@@ -201,6 +270,8 @@ impl<M> IrcAction<M> {
         IrcAction {
             state: Ok(machine),
             send: vec![],
+            deadline: None,
+            close: false,
         }
     }
 
@@ -209,6 +280,8 @@ impl<M> IrcAction<M> {
         IrcAction {
             state: Err(Some(e)),
             send: vec![],
+            deadline: None,
+            close: false,
         }
     }
 
@@ -217,6 +290,8 @@ impl<M> IrcAction<M> {
         IrcAction {
             state: Err(None),
             send: vec![],
+            deadline: None,
+            close: false,
         }
     }
 
@@ -237,4 +312,49 @@ impl<M> IrcAction<M> {
         self.send.append(&mut msg);
         self
     }
+
+    /// Sets a deadline at which the machine's `timeout` method will be called.
+    ///
+    /// The deadline stays in effect until it passes or is replaced by a later
+    /// action's deadline.
+    pub fn deadline(mut self, time: Time) -> Self {
+        self.deadline = Some(time);
+        self
+    }
+
+    /// Sends this action's messages, along with any still queued from
+    /// earlier actions, and then closes the connection, calling the
+    /// machine's `disconnect` method.
+    ///
+    /// The machine isn't called again, even if messages arrive meanwhile.
+    pub fn then_close(mut self) -> Self {
+        self.close = true;
+        self
+    }
+
+    /// Checks whether this action closes the connection, either right away
+    /// or once its messages are sent.
+    pub fn closes(&self) -> bool {
+        self.state.is_err() || self.close
+    }
+
+    /// The messages this action sends.
+    pub fn sent(&self) -> &[Message] {
+        &self.send
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::overlong_skip_len;
+
+    #[test]
+    fn overlong_skip() {
+        assert_eq!(overlong_skip_len(b"aaaa"), 4);
+        // The `\n` may be in the next read.
+        assert_eq!(overlong_skip_len(b"aaa\r"), 3);
+        assert_eq!(overlong_skip_len(b"\r"), 0);
+        assert_eq!(overlong_skip_len(b""), 0);
+    }
 }
@@ -3,12 +3,15 @@
 use std::fmt;
 use std::error::Error;
 use std::str::FromStr;
+use std::collections::HashMap;
 
 use response::Response;
 
 /// Represents an IRC message.
 #[derive(Debug, Clone, PartialEq)]
 pub struct Message {
+    /// Optional IRCv3 message tags. Tags without a value map to `None`.
+    pub tags: Option<HashMap<String, Option<String>>>,
     /// Optional message prefix.
     pub prefix: Option<String>,
     /// The IRC command.
@@ -20,16 +23,75 @@ pub struct Message {
 impl Message {
     pub fn new(prefix: Option<String>, cmd: Command, args: Vec<String>, body: Option<String>) -> Message {
         Message {
+            tags: None,
             prefix: prefix,
             command: cmd,
             args: args,
             body: body,
         }
     }
+
+    /// Starts building a message with the given command and nothing else.
+    ///
+    /// Use `arg` and `body` to fill in the rest, e.g.
+    /// `Message::cmd(Command::PART).arg("#chan").body("bye")`.
+    pub fn cmd(cmd: Command) -> Message {
+        Message::new(None, cmd, vec![], None)
+    }
+
+    /// Builds a `PRIVMSG` sending `text` to `target`.
+    pub fn privmsg<T: Into<String>, B: Into<String>>(target: T, text: B) -> Message {
+        Message::cmd(Command::PRIVMSG).arg(target).body(text)
+    }
+
+    /// Builds a `NOTICE` sending `text` to `target`.
+    pub fn notice<T: Into<String>, B: Into<String>>(target: T, text: B) -> Message {
+        Message::cmd(Command::NOTICE).arg(target).body(text)
+    }
+
+    /// Sets the message's prefix.
+    pub fn prefix<S: Into<String>>(mut self, prefix: S) -> Message {
+        self.prefix = Some(prefix.into());
+        self
+    }
+
+    /// Appends an argument.
+    pub fn arg<S: Into<String>>(mut self, arg: S) -> Message {
+        self.args.push(arg.into());
+        self
+    }
+
+    /// Appends several arguments.
+    pub fn args<I>(mut self, args: I) -> Message
+        where I: IntoIterator, I::Item: Into<String>
+    {
+        self.args.extend(args.into_iter().map(|a| a.into()));
+        self
+    }
+
+    /// Sets the message's body, the trailing argument after the `:`.
+    pub fn body<S: Into<String>>(mut self, body: S) -> Message {
+        self.body = Some(body.into());
+        self
+    }
 }
 
 impl fmt::Display for Message {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref tags) = self.tags {
+            // Sort the keys so that formatting is deterministic.
+            let mut keys: Vec<_> = tags.keys().collect();
+            keys.sort();
+            try!(write!(f, "@"));
+            for (i, key) in keys.into_iter().enumerate() {
+                if i > 0 { try!(write!(f, ";")); }
+                try!(write!(f, "{}", key));
+                if let Some(ref val) = tags[key] {
+                    try!(write!(f, "={}", escape_tag_value(val)));
+                }
+            }
+            try!(write!(f, " "));
+        }
         if let Some(ref pfx) = self.prefix {
             try!(write!(f, ":{} ", pfx));
         }
@@ -125,6 +187,7 @@ irc_commands! {
     KILL,
     PING,
     PONG,
+    ERROR,
 
     // 4 Optional Features
     AWAY,
@@ -132,9 +195,66 @@ irc_commands! {
 }
 
 
+/// Parses the tag segment of a message (without the leading `@`).
+fn parse_tags(s: &str) -> HashMap<String, Option<String>> {
+    let mut tags = HashMap::new();
+    for tag in s.split(";").filter(|t| !t.is_empty()) {
+        if let Some(eq) = tag.find("=") {
+            let val = unescape_tag_value(&tag[eq+1..]);
+            tags.insert(tag[..eq].to_owned(), if val.is_empty() { None } else { Some(val) });
+        } else {
+            tags.insert(tag.to_owned(), None);
+        }
+    }
+    tags
+}
+
+/// Unescapes a tag value as described in the IRCv3 message tags spec.
+fn unescape_tag_value(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some(':') => out.push(';'),
+                Some('s') => out.push(' '),
+                Some('r') => out.push('\r'),
+                Some('n') => out.push('\n'),
+                Some(c) => out.push(c),
+                // A trailing backslash is dropped.
+                None => {},
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// Escapes a tag value as described in the IRCv3 message tags spec.
+fn escape_tag_value(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            ';' => out.push_str("\\:"),
+            ' ' => out.push_str("\\s"),
+            '\\' => out.push_str("\\\\"),
+            '\r' => out.push_str("\\r"),
+            '\n' => out.push_str("\\n"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
 impl FromStr for Message {
     type Err = ParseError;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (s, tags) = if s.starts_with("@") {
+            let tags_end = try!(s.find(" ").ok_or(ParseError::UnexpectedEnd));
+            (&s[tags_end+1..], Some(parse_tags(&s[1..tags_end])))
+        } else { (s, None) };
+
         let (s, prefix) = if s.starts_with(":") {
             let pfx_end = try!(s.find(" ").ok_or(ParseError::UnexpectedEnd));
             (&s[pfx_end+1..], Some(s[1..pfx_end].to_owned()))
@@ -163,6 +283,7 @@ impl FromStr for Message {
         } else { vec![] };
 
         Ok(Message {
+            tags: tags,
             prefix: prefix,
             args: args,
             command: cmd,
@@ -202,6 +323,7 @@ impl fmt::Display for ParseError {
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashMap;
     use super::{Message, Command};
     use response::Response;
 
@@ -224,6 +346,7 @@ mod tests {
     parse_fmt_test!(parse_basic, format_basic, {
         let s = "PING irc.server.lol";
         let msg = Message {
+            tags: None,
             prefix: None,
             command: Command::PING,
             args: vec!["irc.server.lol".to_owned()],
@@ -235,6 +358,7 @@ mod tests {
     parse_fmt_test!(parse_prefix, format_prefix, {
         let s = ":guy!~ident@some.host JOIN #code";
         let msg = Message {
+            tags: None,
             prefix: Some("guy!~ident@some.host".to_owned()),
             command: Command::JOIN,
             args: vec!["#code".to_owned()],
@@ -246,6 +370,7 @@ mod tests {
     parse_fmt_test!(parse_body, format_body, {
         let s = "PRIVMSG #code :Rust is the best language ever";
         let msg = Message {
+            tags: None,
             prefix: None,
             command: Command::PRIVMSG,
             args: vec!["#code".to_owned()],
@@ -257,6 +382,7 @@ mod tests {
     parse_fmt_test!(parse_body_no_args, format_body_no_args, {
         let s = "PRIVMSG :Rust is the best language ever";
         let msg = Message {
+            tags: None,
             prefix: None,
             command: Command::PRIVMSG,
             args: vec![],
@@ -268,6 +394,7 @@ mod tests {
     parse_fmt_test!(parse_body_and_prefix, format_body_and_prefix, {
         let s = ":forkk!~forkk@forkk.net PRIVMSG #code :Rust is the best language ever";
         let msg = Message {
+            tags: None,
             prefix: Some("forkk!~forkk@forkk.net".to_owned()),
             command: Command::PRIVMSG,
             args: vec!["#code".to_owned()],
@@ -279,6 +406,7 @@ mod tests {
     parse_fmt_test!(parse_response, format_response, {
         let s = ":fake.irc.server 001 #code :Rust is the best language ever";
         let msg = Message {
+            tags: None,
             prefix: Some("fake.irc.server".to_owned()),
             command: Command::Response(Response::RPL_WELCOME),
             args: vec!["#code".to_owned()],
@@ -286,4 +414,56 @@ mod tests {
         };
         (s, msg)
     });
+
+    parse_fmt_test!(parse_tags, format_tags, {
+        let s = "@account=forkk;time=2016-08-01T12:00:00.000Z :forkk!~forkk@forkk.net PRIVMSG #code :hi";
+        let mut tags = HashMap::new();
+        tags.insert("account".to_owned(), Some("forkk".to_owned()));
+        tags.insert("time".to_owned(), Some("2016-08-01T12:00:00.000Z".to_owned()));
+        let msg = Message {
+            tags: Some(tags),
+            prefix: Some("forkk!~forkk@forkk.net".to_owned()),
+            command: Command::PRIVMSG,
+            args: vec!["#code".to_owned()],
+            body: Some("hi".to_owned()),
+        };
+        (s, msg)
+    });
+
+    parse_fmt_test!(parse_tags_no_value, format_tags_no_value, {
+        let s = "@a;b=c PING irc.server.lol";
+        let mut tags = HashMap::new();
+        tags.insert("a".to_owned(), None);
+        tags.insert("b".to_owned(), Some("c".to_owned()));
+        let msg = Message {
+            tags: Some(tags),
+            prefix: None,
+            command: Command::PING,
+            args: vec!["irc.server.lol".to_owned()],
+            body: None,
+        };
+        (s, msg)
+    });
+
+    parse_fmt_test!(parse_tags_escaped, format_tags_escaped, {
+        let s = r"@x=a\:b\sc\\d\re\nf PING irc.server.lol";
+        let mut tags = HashMap::new();
+        tags.insert("x".to_owned(), Some("a;b c\\d\re\nf".to_owned()));
+        let msg = Message {
+            tags: Some(tags),
+            prefix: None,
+            command: Command::PING,
+            args: vec!["irc.server.lol".to_owned()],
+            body: None,
+        };
+        (s, msg)
+    });
+
+    #[test]
+    fn builder() {
+        let msg = Message::cmd(Command::USER).args(vec!["forkk", "0", "*"]).body("Forkk");
+        assert_eq!(msg.to_string(), "USER forkk 0 * :Forkk");
+        assert_eq!(Message::privmsg("#code", "hi").to_string(), "PRIVMSG #code :hi");
+        assert_eq!(Message::notice("forkk", "hi").prefix("me").to_string(), ":me NOTICE forkk :hi");
+    }
 }
@@ -31,6 +31,23 @@ macro_rules! responses {
                     Response::Other(c) => c,
                 }
             }
+
+            /// Returns the code's name, such as `"RPL_WELCOME"`, or `None` for
+            /// codes we don't know.
+            pub fn name(self) -> Option<&'static str> {
+                match self {
+                    $( Response::$name => Some(stringify!($name)) ),*,
+                    Response::Other(_) => None,
+                }
+            }
+
+            /// Looks up a code by its name. The inverse of `name`.
+            pub fn from_name(name: &str) -> Option<Response> {
+                match name {
+                    $( stringify!($name) => Some(Response::$name) ),*,
+                    _ => None,
+                }
+            }
         }
 
         impl fmt::Display for Response {
@@ -51,8 +68,11 @@ responses! {
     RPL_CREATED         = 003,
     /// `004 <servername> <version> <available user modes> available channel modes>`
     RPL_MYINFO          = 004,
-    /// `005 Try server <server name>, port <port number>`
-    RPL_BOUNCE          = 005,
+    /// `005 <nick> *( <token> ) :are supported by this server`
+    ///
+    /// RFC 2812 defines this as `RPL_BOUNCE`, but servers use it for
+    /// `RPL_ISUPPORT` in practice.
+    RPL_ISUPPORT        = 005,
     /// `302 :*1<reply> *( " " <reply> )`
     RPL_USERHOST        = 302,
     /// `303 :*1<nick> *( " " <nick> )`
@@ -67,6 +87,8 @@ responses! {
     RPL_WHOISUSER       = 311,
     /// `312 <nick> <server> :<server info>`
     RPL_WHOISSERVER     = 312,
+    /// `307 <nick> :has identified for this nick`
+    RPL_WHOISREGNICK    = 307,
     /// `313 <nick> :is an IRC operator`
     RPL_WHOISOPERATOR   = 313,
     /// `317 <nick> <integer> :seconds idle`
@@ -75,6 +97,18 @@ responses! {
     RPL_ENDOFWHOIS      = 318,
     /// `319 <nick> :*( ( "@" / "+" ) <channel> " " )`
     RPL_WHOISCHANNELS   = 319,
+    /// `320 <nick> :<special info>`
+    RPL_WHOISSPECIAL    = 320,
+    /// `330 <nick> <account> :is logged in as`
+    RPL_WHOISACCOUNT    = 330,
+    /// `338 <nick> [<user>@]<host> <ip> :Actual user@host, Actual IP`
+    RPL_WHOISACTUALLY   = 338,
+    /// `378 <nick> :is connecting from *@<host> <ip>`
+    RPL_WHOISHOST       = 378,
+    /// `379 <nick> :is using modes <modes>`
+    RPL_WHOISMODES      = 379,
+    /// `671 <nick> :is using a secure connection`
+    RPL_WHOISSECURE     = 671,
     /// `314 <nick> <user> <host> * :<real name>`
     RPL_WHOWASUSER      = 314,
     /// `369 <nick> :End of WHOWAS`
@@ -89,10 +123,14 @@ responses! {
     RPL_UNIQOPIS        = 325,
     /// `324 <channel> <mode> <mode params>`
     RPL_CHANNELMODEIS   = 324,
+    /// `329 <channel> <creation time>`
+    RPL_CREATIONTIME    = 329,
     /// `331 <channel> :No topic is set`
     RPL_NOTOPIC         = 331,
     /// `332 <channel> :<topic>`
     RPL_TOPIC           = 332,
+    /// `333 <channel> <nick> <set at>`
+    RPL_TOPICWHOTIME    = 333,
     /// `341 <channel> <nick>`
     RPL_INVITING        = 341,
     /// `342 <user> :Summoning user to IRC`
@@ -112,6 +150,9 @@ responses! {
     RPL_WHOREPLY        = 352,
     /// `315 <name> :End of WHO list`
     RPL_ENDOFWHO        = 315,
+    /// `354 [<token>] <fields>...` The fields requested in a WHOX query, in a
+    /// fixed order.
+    RPL_WHOSPCRPL       = 354,
     /// `353 ( "=" / "*" / "@" ) <channel> :[ "@" / "+" ] <nick> *( " " [ "@" / "+" ] <nick> )`
     RPL_NAMREPLY        = 353,
     /// `366 <channel> :End of NAMES list`
@@ -150,6 +191,8 @@ responses! {
     RPL_ENDOFUSERS      = 394,
     /// `395 :Nobody logged in
     RPL_NOUSERS         = 395,
+    /// `396 <host> :is now your displayed host`
+    RPL_HOSTHIDDEN      = 396,
     /** `200 Link <version & debug level> <destination> <next server> V<protocol version>
         <link uptime in seconds> <backstream sendq> <upstream sendq> **/
     RPL_TRACELINK       = 200,
@@ -204,6 +247,10 @@ responses! {
     RPL_LUSERCHANNELS   = 254,
     /// `255 :I have <integer> clients and <integer> servers
     RPL_LUSERME         = 255,
+    /// `265 [<u> <m>] :Current local users <u>, max <m>`
+    RPL_LOCALUSERS      = 265,
+    /// `266 [<u> <m>] :Current global users <u>, max <m>`
+    RPL_GLOBALUSERS     = 266,
     /// `256 <server> :Administrative info
     RPL_ADMINME         = 256,
     /// `257 :<admin info>
@@ -214,6 +261,14 @@ responses! {
     RPL_ADMINEMAIL      = 259,
     /// `263 <command> :Please wait a while and try again.
     RPL_TRYAGAIN        = 263,
+    /// `670 :STARTTLS successful, proceed with TLS handshake`
+    RPL_STARTTLS        = 670,
+    /// `704 <subject> :<first line of help section>`
+    RPL_HELPSTART       = 704,
+    /// `705 <subject> :<line of help text>`
+    RPL_HELPTXT         = 705,
+    /// `706 <subject> :<last line of help text>`
+    RPL_ENDOFHELP       = 706,
     /// `730 <nick> :target[,target2]*
     RPL_MONONLINE       = 730,
     /// `731 <nick> :target[,target2]*
@@ -238,6 +293,8 @@ responses! {
     RPL_SASLMECHS       = 908,
 
     // Error replies
+    /// `400 <command> :<info>`
+    ERR_UNKNOWNERROR        = 400,
     /// `401 <nickname> :No such nick/channel
     ERR_NOSUCHNICK          = 401,
     /// `402 <server name> :No such server
@@ -256,6 +313,8 @@ responses! {
     ERR_NOSUCHSERVICE       = 408,
     /// `409 :No origin specified
     ERR_NOORIGIN            = 409,
+    /// `410 <subcommand> :Invalid CAP command`
+    ERR_INVALIDCAPCMD       = 410,
     /// `411 :No recipient given (<command>)
     ERR_NORECIPIENT         = 411,
     /// `412 :No text to send
@@ -266,6 +325,8 @@ responses! {
     ERR_WILDTOPLEVEL        = 414,
     /// `415 <mask> :Bad Server/host mask
     ERR_BADMASK             = 415,
+    /// `417 :Input line was too long`
+    ERR_INPUTTOOLONG        = 417,
     /// `421 <command> :Unknown command
     ERR_UNKNOWNCOMMAND      = 421,
     /// `422 :MOTD File is missing
@@ -314,6 +375,8 @@ responses! {
     ERR_KEYSET              = 467,
     /// `471 <channel> :Cannot join channel (+l)
     ERR_CHANNELISFULL       = 471,
+    /// `470 <channel> <new channel> :Forwarding to another channel`
+    ERR_LINKCHANNEL         = 470,
     /// `472 <char> :is unknown mode char to me for <channel>
     ERR_UNKNOWNMODE         = 472,
     /// `473 <channel> :Cannot join channel (+i)
@@ -344,6 +407,14 @@ responses! {
     ERR_UMODEUNKNOWNFLAG    = 501,
     /// `502 :Cannot change mode for other users
     ERR_USERSDONTMATCH      = 502,
+    /// `524 <subject> :No help available on this topic`
+    ERR_HELPNOTFOUND        = 524,
+    /// `691 :STARTTLS failed`
+    ERR_STARTTLS            = 691,
+    /// `696 <target> <mode char> <parameter> :<description>`
+    ERR_INVALIDMODEPARAM    = 696,
+    /// `723 <priv> :Insufficient oper privileges.`
+    ERR_NOPRIVS             = 723,
     /// `734 <nick> <limit> <targets> :Monitor list is full.
     ERR_MONLISTFULL         = 734,
     /// `764 <target> :metadata limit reached
@@ -369,3 +440,32 @@ responses! {
     /// `907 <nick> :You have already authenticated using SASL
     ERR_SASLALREADY         = 907,
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::Response;
+
+    #[test]
+    fn u16_round_trip() {
+        for c in 0..1000 {
+            assert_eq!(Response::from_u16(c).to_u16(), c);
+        }
+        assert_eq!(Response::from_u16(333), Response::RPL_TOPICWHOTIME);
+        assert_eq!(Response::from_u16(999), Response::Other(999));
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(Response::RPL_TOPIC.name(), Some("RPL_TOPIC"));
+        assert_eq!(Response::Other(999).name(), None);
+        assert_eq!(Response::from_name("ERR_NICKNAMEINUSE"), Some(Response::ERR_NICKNAMEINUSE));
+        assert_eq!(Response::from_name("RPL_BOGUS"), None);
+        for c in 0..1000 {
+            let r = Response::from_u16(c);
+            if let Some(name) = r.name() {
+                assert_eq!(Response::from_name(name), Some(r));
+            }
+        }
+    }
+}
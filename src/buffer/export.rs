@@ -0,0 +1,139 @@
+//! Exports buffer logs as plain text in the style of irssi's logs, so they can
+//! be read and searched with ordinary tools.
+
+use std::path::Path;
+use time::{self, Timespec};
+
+use common::line::{BufferLine, LineData, MsgKind};
+
+use super::BufferConfig;
+use super::log::BufferLog;
+
+
+/// Exports the logs for the buffer `name` on the network `nid` to `out_dir`.
+///
+/// Returns the number of day files written.
+pub fn export_logs(cfg: &BufferConfig, nid: &str, name: &str, out_dir: &Path)
+                   -> Result<usize, String>
+{
+    let log = try!(BufferLog::open_existing(&cfg.log_dir, nid, name)
+                   .ok_or(format!("No logs for {} on {}", name, nid)));
+    log.export(name, out_dir)
+        .map_err(|e| format!("Failed to write logs to {}: {}", out_dir.display(), e))
+}
+
+
+/// Formats a log line as text, like `12:34:56 <nick> message`.
+///
+/// `chan` is the name of the buffer the line is from. Most lines become a
+/// single line of text, but `WHOIS` replies take one line per entry.
+pub fn format_line(line: &BufferLine, chan: &str) -> Vec<String> {
+    let time = line.time().strftime("%H:%M:%S").map(|t| t.to_string()).unwrap_or(String::new());
+    let texts = match line.data {
+        LineData::Message { ref kind, ref from, ref msg } => vec![match *kind {
+            MsgKind::PrivMsg => format!("<{}> {}", from, msg),
+            MsgKind::Notice => format!("-{}- {}", from, msg),
+            MsgKind::Action => format!(" * {} {}", from, msg),
+            MsgKind::Response(_) |
+            MsgKind::Status => format!("-!- {}", msg),
+        }],
+        LineData::Topic { by: Some(ref by), ref topic, .. } =>
+            vec![format!("-!- {} changed the topic of {} to: {}", by, chan, topic)],
+        LineData::Topic { by: None, ref topic, set: None } =>
+            vec![format!("-!- Topic for {}: {}", chan, topic)],
+        LineData::Topic { by: None, ref topic, set: Some((ref by, at)) } => {
+            let at = time::at(Timespec::new(at, 0));
+            let at = at.strftime("%Y-%m-%d %H:%M:%S").map(|t| t.to_string()).unwrap_or(String::new());
+            vec![format!("-!- Topic for {}: {}", chan, topic),
+                 format!("-!- Topic set by {} [{}]", by, at)]
+        },
+        LineData::Join { ref user } =>
+            vec![format!("-!- {} [{}@{}] has joined {}", user.nick, user.ident, user.host, chan)],
+        LineData::Part { ref user, ref reason } =>
+            vec![format!("-!- {} [{}@{}] has left {} [{}]",
+                         user.nick, user.ident, user.host, chan, reason)],
+        LineData::Quit { ref user, ref msg } =>
+            vec![format!("-!- {} [{}@{}] has quit [{}]",
+                         user.nick, user.ident, user.host, msg.as_ref().map_or("", |m| &m[..]))],
+        LineData::Kick { ref by, ref user, ref reason } =>
+            vec![format!("-!- {} was kicked from {} by {} [{}]", user, chan, by.nick, reason)],
+        LineData::Nick { ref user, ref new } =>
+            vec![format!("-!- {} is now known as {}", user.nick, new)],
+        LineData::Mode { ref by, ref modes, ref args } => {
+            let mut modes = modes.clone();
+            for arg in args {
+                modes.push(' ');
+                modes.push_str(arg);
+            }
+            vec![format!("-!- mode/{} [{}] by {}", chan, modes, by.nick)]
+        },
+        LineData::Whois { ref info, .. } =>
+            info.iter().map(|i| format!("-!- {}", i)).collect(),
+    };
+    texts.into_iter().map(|t| format!("{} {}", time, t)).collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::Read;
+    use time;
+
+    use common::line::{BufferLine, LineData, MsgKind, User};
+    use common::tempdir::TempDir;
+    use super::super::test_util::test_cfg;
+    use super::super::log::BufferLog;
+    use super::{export_logs, format_line};
+
+    fn bob() -> User {
+        User { nick: "bob".to_owned(), ident: "b".to_owned(), host: "host".to_owned() }
+    }
+
+    fn text(data: LineData) -> String {
+        let lines = format_line(&BufferLine::new(time::now(), data), "#chan");
+        assert_eq!(lines.len(), 1);
+        // Leave out the time.
+        lines[0][9..].to_owned()
+    }
+
+    #[test]
+    fn line_formats() {
+        assert_eq!(text(LineData::Message {
+            kind: MsgKind::PrivMsg, from: "bob".to_owned(), msg: "hi".to_owned(),
+        }), "<bob> hi");
+        assert_eq!(text(LineData::Message {
+            kind: MsgKind::Action, from: "bob".to_owned(), msg: "waves".to_owned(),
+        }), " * bob waves");
+        assert_eq!(text(LineData::Join { user: bob() }), "-!- bob [b@host] has joined #chan");
+        assert_eq!(text(LineData::Quit { user: bob(), msg: None }), "-!- bob [b@host] has quit []");
+        assert_eq!(text(LineData::Mode {
+            by: bob(), modes: "+o".to_owned(), args: vec!["alice".to_owned()],
+        }), "-!- mode/#chan [+o alice] by bob");
+    }
+
+    #[test]
+    fn export_days() {
+        let dir = TempDir::new("export");
+        let cfg = test_cfg(&dir);
+        let out = TempDir::new("export-out");
+        let out_dir = out.join("logs");
+        assert!(export_logs(&cfg, "net", "#chan", &out_dir).is_err());
+
+        let now = time::now();
+        let mut log = BufferLog::new(&cfg.log_dir, "net", "#chan");
+        log.write_lines(vec![
+            BufferLine::new(now, LineData::Join { user: bob() }),
+            BufferLine::new(now, LineData::Message {
+                kind: MsgKind::PrivMsg, from: "bob".to_owned(), msg: "hi".to_owned(),
+            }),
+        ]);
+        assert_eq!(export_logs(&cfg, "net", "#chan", &out_dir), Ok(1));
+
+        let name = format!("{}.log", now.strftime("%Y-%m-%d").unwrap());
+        let mut data = String::new();
+        File::open(out_dir.join(name)).unwrap().read_to_string(&mut data).unwrap();
+        let lines: Vec<_> = data.lines().map(|l| &l[9..]).collect();
+        assert_eq!(lines, vec!["-!- bob [b@host] has joined #chan", "<bob> hi"]);
+    }
+}
@@ -0,0 +1,68 @@
+//! Deciding which channel messages should ping the user.
+
+use network::casemap::CaseMapping;
+
+
+/// A user's highlight settings.
+#[derive(Debug, Clone, Default)]
+pub struct Highlights {
+    /// Extra words that ping the user, besides their nick.
+    pub words: Vec<String>,
+    /// Nicks whose messages never ping the user.
+    pub ignore: Vec<String>,
+}
+
+impl Highlights {
+    pub fn new(words: Vec<String>, ignore: Vec<String>) -> Highlights {
+        Highlights {
+            words: words.into_iter().filter(|w| !w.is_empty()).collect(),
+            ignore: ignore,
+        }
+    }
+
+    /// Checks whether a message `msg` from `from` should ping a user whose
+    /// nick is `my_nick`.
+    pub fn is_highlight(&self, from: &str, msg: &str, my_nick: &str, cm: CaseMapping) -> bool {
+        if self.ignore.iter().any(|n| cm.eq(n, from)) {
+            return false;
+        }
+        cm.contains_word(msg, my_nick) || self.words.iter().any(|w| cm.contains_word(msg, w))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use network::casemap::CaseMapping;
+    use super::Highlights;
+
+    #[test]
+    fn keywords() {
+        let hl = Highlights::new(vec!["distirc".to_owned(), "".to_owned()], vec![]);
+        let cm = CaseMapping::Rfc1459;
+        assert!(hl.is_highlight("bob", "anyone using DistIRC?", "me", cm));
+        assert!(hl.is_highlight("bob", "distirc: hi", "me", cm));
+        assert!(!hl.is_highlight("bob", "distircd is different", "me", cm));
+        assert!(!hl.is_highlight("bob", "nothing here", "me", cm));
+        assert!(hl.is_highlight("bob", "hey Me", "me", cm));
+    }
+
+    #[test]
+    fn nick_boundaries() {
+        let hl = Highlights::default();
+        let cm = CaseMapping::Rfc1459;
+        assert!(!hl.is_highlight("bob", "already done", "Al", cm));
+        assert!(hl.is_highlight("bob", "Al: done?", "Al", cm));
+        assert!(hl.is_highlight("bob", "hi Al", "Al", cm));
+        assert!(hl.is_highlight("bob", "@Al", "Al", cm));
+        assert!(hl.is_highlight("bob", "ping al[m]", "Al{M}", cm));
+    }
+
+    #[test]
+    fn ignored_nicks() {
+        let hl = Highlights::new(vec!["distirc".to_owned()], vec!["Bot[1]".to_owned()]);
+        let cm = CaseMapping::Rfc1459;
+        assert!(!hl.is_highlight("bot{1}", "distirc commit pushed by me", "me", cm));
+        assert!(hl.is_highlight("bob", "distirc commit pushed by me", "me", cm));
+    }
+}
@@ -0,0 +1,356 @@
+//! Imports logs written by ZNC's `log` module, so users switching from ZNC
+//! keep their history as scrollback.
+
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::Path;
+use time::{self, Tm};
+
+use common::line::{BufferLine, LineData, MsgKind, User};
+
+use super::BufferConfig;
+use super::log::BufferLog;
+
+
+/// Like `try!`, but for `Option`s.
+macro_rules! try_opt {
+    ( $e: expr ) => {
+        match $e {
+            Some(v) => v,
+            None => return None,
+        }
+    }
+}
+
+
+/// How ZNC was set up to write its logs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ZncFormat {
+    /// strftime format of the timestamp at the start of each line. It must
+    /// always format to the same width.
+    pub timestamp: String,
+    /// strftime format of the date at the end of each log file's name, before
+    /// the `.log` extension.
+    pub file_date: String,
+}
+
+impl Default for ZncFormat {
+    /// ZNC's defaults, which give log files like `2016-08-01.log` full of lines
+    /// like `[12:34:56] <nick> message`.
+    fn default() -> ZncFormat {
+        ZncFormat {
+            timestamp: "[%H:%M:%S]".to_owned(),
+            file_date: "%Y-%m-%d".to_owned(),
+        }
+    }
+}
+
+
+/// Imports the ZNC logs in `src_dir` into the logs for the buffer `name` on
+/// the network `nid`. Lines which are already logged are skipped.
+///
+/// The caller should hold the `LogDirLock` for the log directory, since the
+/// buffer's day files are rewritten.
+///
+/// Returns the number of lines imported.
+pub fn import_znc(cfg: &BufferConfig, nid: &str, name: &str, src_dir: &Path, fmt: &ZncFormat)
+                  -> Result<usize, String>
+{
+    let entries = try!(fs::read_dir(src_dir)
+                       .map_err(|e| format!("Failed to read {}: {}", src_dir.display(), e)));
+    let mut lines = vec![];
+    for entry in entries.filter_map(|e| e.ok()) {
+        let path = entry.path();
+        let day = match path.file_stem().and_then(|s| s.to_str()) {
+            Some(stem) if path.extension().map_or(false, |e| e == "log") =>
+                parse_file_date(stem, &fmt.file_date),
+            _ => continue,
+        };
+        let day = match day {
+            Some(day) => day,
+            None => {
+                warn!("Skipping {}, which has no date in its name", path.display());
+                continue;
+            },
+        };
+        let mut data = String::new();
+        if let Err(e) = File::open(&path).and_then(|mut f| f.read_to_string(&mut data)) {
+            return Err(format!("Failed to read {}: {}", path.display(), e));
+        }
+        for text in data.lines() {
+            match parse_line(text, &day, fmt) {
+                Some(line) => lines.push(line),
+                None => warn!("Skipping unparseable line in {}: {}", path.display(), text),
+            }
+        }
+    }
+
+    let mut log = BufferLog::new(&cfg.log_dir, nid, name);
+    log.import_lines(lines).map_err(|e| format!("Failed to write logs: {}", e))
+}
+
+
+/// Returns the width of the text `fmt` formats times to.
+fn format_width(fmt: &str) -> usize {
+    time::empty_tm().strftime(fmt).map(|t| t.to_string().len()).unwrap_or(0)
+}
+
+/// Parses the date at the end of a log file's name. Older ZNC versions put the
+/// network and channel before it, like `net_#chan_20160801`.
+fn parse_file_date(stem: &str, fmt: &str) -> Option<Tm> {
+    let width = format_width(fmt);
+    if stem.len() < width || !stem.is_char_boundary(stem.len() - width) {
+        return None;
+    }
+    time::strptime(&stem[stem.len() - width..], fmt).ok()
+}
+
+/// Parses a line of a ZNC log for the given day.
+fn parse_line(text: &str, day: &Tm, fmt: &ZncFormat) -> Option<BufferLine> {
+    let width = format_width(&fmt.timestamp);
+    if text.len() < width || !text.is_char_boundary(width) {
+        return None;
+    }
+    let stamp = match time::strptime(&text[..width], &fmt.timestamp) {
+        Ok(stamp) => stamp,
+        Err(_) => return None,
+    };
+    let mut tm = day.clone();
+    tm.tm_hour = stamp.tm_hour;
+    tm.tm_min = stamp.tm_min;
+    tm.tm_sec = stamp.tm_sec;
+    // ZNC logs in local time. Leave working out whether DST applies to
+    // `mktime`.
+    tm.tm_utcoff = time::now().tm_utcoff;
+    tm.tm_isdst = -1;
+    let time = time::at(tm.to_timespec());
+
+    let rest = text[width..].trim_left_matches(' ');
+    Some(BufferLine::new(time, parse_line_data(rest)))
+}
+
+/// Works out what kind of line this is from ZNC's conventions. Anything we
+/// don't recognize becomes a status message.
+fn parse_line_data(text: &str) -> LineData {
+    if text.starts_with("*** ") {
+        if let Some(data) = parse_event(&text[4..]) {
+            return data;
+        }
+    } else if text.starts_with("* ") {
+        if let Some((nick, msg)) = split_word(&text[2..]) {
+            return message(MsgKind::Action, nick, msg);
+        }
+    } else if text.starts_with('<') {
+        if let Some(end) = text.find("> ") {
+            return message(MsgKind::PrivMsg, &text[1..end], &text[end + 2..]);
+        }
+    } else if text.starts_with('-') {
+        if let Some(end) = text[1..].find("- ").map(|i| i + 1) {
+            return message(MsgKind::Notice, &text[1..end], &text[end + 2..]);
+        }
+    }
+    message(MsgKind::Status, "znc", text)
+}
+
+/// Parses the events ZNC logs with a `***` prefix.
+fn parse_event(text: &str) -> Option<LineData> {
+    if text.starts_with("Joins: ") {
+        let (user, _) = try_opt!(parse_user(&text[7..]));
+        Some(LineData::Join { user: user })
+    } else if text.starts_with("Parts: ") {
+        let (user, rest) = try_opt!(parse_user(&text[7..]));
+        Some(LineData::Part { user: user, reason: parens(rest).to_owned() })
+    } else if text.starts_with("Quits: ") {
+        let (user, rest) = try_opt!(parse_user(&text[7..]));
+        let msg = parens(rest);
+        Some(LineData::Quit {
+            user: user,
+            msg: if msg.is_empty() { None } else { Some(msg.to_owned()) },
+        })
+    } else if let Some(i) = text.find(" is now known as ") {
+        Some(LineData::Nick {
+            user: nick_user(&text[..i]),
+            new: text[i + 17..].to_owned(),
+        })
+    } else if let Some(i) = text.find(" was kicked by ") {
+        let (by, rest) = try_opt!(split_word(&text[i + 15..]));
+        Some(LineData::Kick {
+            by: nick_user(by),
+            user: text[..i].to_owned(),
+            reason: parens(rest).to_owned(),
+        })
+    } else if let Some(i) = text.find(" changes topic to '") {
+        let topic = &text[i + 19..];
+        Some(LineData::Topic {
+            by: Some(text[..i].to_owned()),
+            topic: topic.trim_right_matches('\'').to_owned(),
+            set: None,
+        })
+    } else if let Some(i) = text.find(" sets mode: ") {
+        let mut parts = text[i + 12..].split(' ');
+        let modes = try_opt!(parts.next()).to_owned();
+        Some(LineData::Mode {
+            by: nick_user(&text[..i]),
+            modes: modes,
+            args: parts.map(|a| a.to_owned()).collect(),
+        })
+    } else {
+        None
+    }
+}
+
+/// Parses `nick (ident@host)` from the start of `text`, returning the user and
+/// the rest of the text.
+fn parse_user(text: &str) -> Option<(User, &str)> {
+    let (nick, rest) = try_opt!(split_word(text));
+    if !rest.starts_with('(') { return None; }
+    let end = try_opt!(rest.find(')'));
+    let mut mask = rest[1..end].splitn(2, '@');
+    let ident = mask.next().unwrap_or("");
+    let host = mask.next().unwrap_or("");
+    let user = User {
+        nick: nick.to_owned(),
+        ident: ident.to_owned(),
+        host: host.to_owned(),
+    };
+    Some((user, rest[end + 1..].trim_left_matches(' ')))
+}
+
+/// Splits off the first word of `text`.
+fn split_word(text: &str) -> Option<(&str, &str)> {
+    text.find(' ').map(|i| (&text[..i], &text[i + 1..]))
+}
+
+/// Strips the parentheses around a reason.
+fn parens(text: &str) -> &str {
+    if text.starts_with('(') && text.ends_with(')') {
+        &text[1..text.len() - 1]
+    } else {
+        text
+    }
+}
+
+/// ZNC only logs nicks for some events, so we don't know the user's ident or
+/// host.
+fn nick_user(nick: &str) -> User {
+    User {
+        nick: nick.to_owned(),
+        ident: String::new(),
+        host: String::new(),
+    }
+}
+
+fn message(kind: MsgKind, from: &str, msg: &str) -> LineData {
+    LineData::Message {
+        kind: kind,
+        from: from.to_owned(),
+        msg: msg.to_owned(),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use time;
+    use common::line::{LineData, MsgKind};
+    use super::{ZncFormat, parse_line, parse_line_data, parse_file_date};
+
+    #[test]
+    fn messages() {
+        match parse_line_data("<bob> hi there") {
+            LineData::Message { kind: MsgKind::PrivMsg, ref from, ref msg } => {
+                assert_eq!(from, "bob");
+                assert_eq!(msg, "hi there");
+            },
+            l => panic!("Parsed message wrong: {:?}", l),
+        }
+        match parse_line_data("* bob waves") {
+            LineData::Message { kind: MsgKind::Action, ref msg, .. } => assert_eq!(msg, "waves"),
+            l => panic!("Parsed action wrong: {:?}", l),
+        }
+        match parse_line_data("-NickServ- You are now identified") {
+            LineData::Message { kind: MsgKind::Notice, ref from, .. } => assert_eq!(from, "NickServ"),
+            l => panic!("Parsed notice wrong: {:?}", l),
+        }
+    }
+
+    #[test]
+    fn events() {
+        match parse_line_data("*** Joins: bob (~b@host.example)") {
+            LineData::Join { ref user } => {
+                assert_eq!(user.nick, "bob");
+                assert_eq!(user.ident, "~b");
+                assert_eq!(user.host, "host.example");
+            },
+            l => panic!("Parsed join wrong: {:?}", l),
+        }
+        match parse_line_data("*** Quits: bob (~b@host.example) (Ping timeout)") {
+            LineData::Quit { ref msg, .. } => assert_eq!(msg.as_ref().unwrap(), "Ping timeout"),
+            l => panic!("Parsed quit wrong: {:?}", l),
+        }
+        match parse_line_data("*** alice was kicked by bob (spam)") {
+            LineData::Kick { ref by, ref user, ref reason } => {
+                assert_eq!((&by.nick[..], &user[..], &reason[..]), ("bob", "alice", "spam"));
+            },
+            l => panic!("Parsed kick wrong: {:?}", l),
+        }
+        match parse_line_data("*** bob sets mode: +o alice") {
+            LineData::Mode { ref modes, ref args, .. } => {
+                assert_eq!(modes, "+o");
+                assert_eq!(args, &vec!["alice".to_owned()]);
+            },
+            l => panic!("Parsed mode wrong: {:?}", l),
+        }
+        match parse_line_data("*** Something new happened") {
+            LineData::Message { kind: MsgKind::Status, .. } => {},
+            l => panic!("Unknown event parsed wrong: {:?}", l),
+        }
+    }
+
+    #[test]
+    fn timestamps() {
+        let fmt = ZncFormat::default();
+        let day = parse_file_date("2016-08-01", &fmt.file_date).unwrap();
+        assert_eq!(parse_file_date("net_#chan_20160801", "%Y%m%d").unwrap().tm_mday, 1);
+
+        let line = parse_line("[12:34:56] <bob> hi", &day, &fmt).unwrap();
+        let tm = time::at(line.time().to_timespec());
+        assert_eq!((tm.tm_year, tm.tm_mon, tm.tm_mday), (116, 7, 1));
+        assert_eq!((tm.tm_hour, tm.tm_min, tm.tm_sec), (12, 34, 56));
+        assert!(parse_line("<bob> no timestamp", &day, &fmt).is_none());
+    }
+
+    #[test]
+    fn import_dir() {
+        use std::fs::{self, File};
+        use std::io::Write;
+        use common::tempdir::TempDir;
+        use buffer::log::BufferLog;
+        use buffer::test_util::test_cfg;
+        use super::import_znc;
+
+        let dir = TempDir::new("import-znc");
+        let cfg = test_cfg(&dir);
+        let src = dir.join("znc");
+        fs::create_dir_all(&src).unwrap();
+        let mut f = File::create(src.join("2016-08-01.log")).unwrap();
+        writeln!(f, "[12:00:00] <bob> hi").unwrap();
+        writeln!(f, "[12:00:05] *** Joins: alice (~a@host.example)").unwrap();
+        writeln!(f, "garbage").unwrap();
+        drop(f);
+        // Files without a date are skipped.
+        File::create(src.join("notes.log")).unwrap();
+
+        let fmt = ZncFormat::default();
+        assert_eq!(import_znc(&cfg, "net", "#chan", &src, &fmt), Ok(2));
+        assert_eq!(import_znc(&cfg, "net", "#chan", &src, &fmt), Ok(0));
+
+        let mut log = BufferLog::new(&cfg.log_dir, "net", "#chan");
+        let lines = log.fetch_lines();
+        assert_eq!(lines.len(), 2);
+        match lines[1].data {
+            LineData::Message { ref from, ref msg, .. } => assert_eq!((&from[..], &msg[..]), ("bob", "hi")),
+            ref l => panic!("Imported message wrong: {:?}", l),
+        }
+    }
+}
@@ -1,91 +1,670 @@
 //! This module implements the disk logging system for buffers.
-use std::path::PathBuf;
-use std::io::{Read, Write};
-use std::fs::{File, OpenOptions, DirBuilder};
-use time::{Tm, Duration, now};
+//!
+//! Each buffer's logs live in `<log dir>/<user>/<network>/<buffer>`, with a file for
+//! each day at `<year>/<month>/<day>`. Day files hold one JSON encoded
+//! `BufferLine` per line, oldest first. The buffer's directory also has a
+//! `format` file holding the `LOG_FORMAT_VERSION` its lines were written in.
+use std::cmp;
+use std::path::{Path, PathBuf};
+use std::collections::HashSet;
+use std::io::{self, Read, Write};
+use std::fs::{self, File, OpenOptions, DirBuilder};
+use std::os::unix::io::AsRawFd;
+use libc;
+use time::{Tm, now};
+use serde_json::{self, Value};
 
-use common::line::BufferLine;
+use common::line::{BufferLine, LineData};
+use common::format::strip_formatting;
+
+use super::export::format_line;
+
+
+/// Version of the format log lines are written in.
+///
+/// 1. The original format. Buffers logged before the `format` file existed
+///    are assumed to be in this version.
+/// 2. Lines have an `id` field.
+///
+/// Bump this when `BufferLine` or `LineData` change in a way old code or old
+/// logs can't cope with, and teach `upgrade_record` to upgrade older lines.
+pub const LOG_FORMAT_VERSION: u32 = 2;
+
+/// Name of the file holding a buffer's log format version.
+const FORMAT_FILE: &'static str = "format";
+
+/// Name of the file in the log directory `lock_dir` locks.
+const LOCK_FILE: &'static str = ".lock";
+
+/// Most lines a search returns, whatever limit the client asks for.
+pub const MAX_SEARCH_RESULTS: usize = 500;
+
+/// Most day files a search reads. Searches run on the event loop, so this
+/// keeps one from holding everything else up for too long.
+pub const MAX_SEARCH_DAYS: usize = 366;
 
 
 /// Represents a handle for reading and writing to on-disk log files.
 #[derive(Debug, Clone)]
 pub struct BufferLog {
     dir: PathBuf,
-    /// The last day's log we've read. This is stored as Tm, but any precision
-    /// past days is ignored.
-    next_read_day: Tm,
+    /// The oldest day we've read logs for, as `(year, month, day)`. Only days
+    /// before this are left to fetch.
+    read_before: (u32, u32, u32),
+    /// Keys of the lines logged in the most recently checked day file, used
+    /// by `is_logged`.
+    logged: Option<(PathBuf, HashSet<String>)>,
 }
 
 impl BufferLog {
-    pub fn new(path: PathBuf) -> BufferLog {
-        DirBuilder::new().recursive(true).create(&path).unwrap();
-        BufferLog {
+    /// Opens the logs for the buffer `name` in the directory `dir`, both of
+    /// which are placed under `log_dir`.
+    ///
+    /// Logs written in an older format are upgraded to the current one.
+    pub fn new(log_dir: &Path, dir: &str, name: &str) -> BufferLog {
+        let log = BufferLog::at_path(log_dir, dir, name);
+        DirBuilder::new().recursive(true).create(&log.dir).unwrap();
+        log.migrate();
+        log
+    }
+
+    /// Like `new`, but returns `None` instead of creating the log directory
+    /// if it doesn't exist.
+    pub fn open_existing(log_dir: &Path, dir: &str, name: &str) -> Option<BufferLog> {
+        let log = BufferLog::at_path(log_dir, dir, name);
+        if log.dir.is_dir() { Some(log) } else { None }
+    }
+
+    fn at_path(log_dir: &Path, dir: &str, name: &str) -> BufferLog {
+        let mut path = log_dir.to_path_buf();
+        path.push(sanitize_name(dir));
+        path.push(sanitize_name(name));
+        let mut log = BufferLog {
             dir: path,
-            next_read_day: now() - Duration::days(1),
+            read_before: (0, 0, 0),
+            logged: None,
+        };
+        // Nothing has been read yet, so everything up to today is left.
+        log.rewind();
+        log
+    }
+
+    /// Returns the format version of the logs on disk.
+    fn format_version(&self) -> u32 {
+        let mut data = String::new();
+        match File::open(self.dir.join(FORMAT_FILE)).and_then(|mut f| f.read_to_string(&mut data)) {
+            Ok(_) => data.trim().parse().unwrap_or_else(|_| {
+                warn!("Invalid log format version {:?} in {}", data.trim(), self.dir.display());
+                LOG_FORMAT_VERSION
+            }),
+            // Logs with no version file predate it, unless there aren't any.
+            Err(_) if self.day_files().is_empty() => LOG_FORMAT_VERSION,
+            Err(_) => 1,
         }
     }
 
-    /// Writes the given lines to today's log.
+    /// Upgrades the logs to `LOG_FORMAT_VERSION` if they're older.
+    fn migrate(&self) {
+        let version = self.format_version();
+        if version > LOG_FORMAT_VERSION {
+            warn!("Logs in {} are in format version {}, but we only know up to {}",
+                  self.dir.display(), version, LOG_FORMAT_VERSION);
+            return;
+        }
+        if version < LOG_FORMAT_VERSION {
+            info!("Upgrading logs in {} from format version {} to {}",
+                  self.dir.display(), version, LOG_FORMAT_VERSION);
+            for path in self.day_files() {
+                if let Err(e) = upgrade_log_file(&path, version) {
+                    // Leave the version alone so we try again next time.
+                    error!("Failed to upgrade log file {}: {}", path.display(), e);
+                    return;
+                }
+            }
+        }
+        let res = File::create(self.dir.join(FORMAT_FILE))
+            .and_then(|mut f| writeln!(f, "{}", LOG_FORMAT_VERSION));
+        if let Err(e) = res {
+            error!("Failed to write log format version in {}: {}", self.dir.display(), e);
+        }
+    }
+
+    /// Writes the given lines to the logs for the days they were sent on.
     pub fn write_lines(&mut self, lines: Vec<BufferLine>) {
-        let path = self.file_for_day(&now());
-        DirBuilder::new().recursive(true).create(&path.parent().unwrap()).unwrap();
-        match OpenOptions::new().create(true).write(true).append(true).open(&path) {
-            Err(e) => error!("Error opening log file for writing: {}", e),
-            Ok(mut f) => {
-                for line in lines {
-                    use serde_json::ser;
-                    let mut data = ser::to_string(&line).unwrap();
-                    data.push('\n');
-                    f.write_all(data.as_bytes()).expect("Failed writing to log file");
+        // The day file we're writing to. Batches usually all belong to the
+        // same day, so it's only reopened when the day changes.
+        let mut file: Option<(PathBuf, File)> = None;
+        for line in lines {
+            let line = strip_line(line);
+            let path = self.file_for_day(&line.time());
+            if let Some((ref day, ref mut keys)) = self.logged {
+                if *day == path { keys.insert(line_key(&line)); }
+            }
+            if file.as_ref().map_or(true, |f| f.0 != path) {
+                DirBuilder::new().recursive(true).create(&path.parent().unwrap()).unwrap();
+                match OpenOptions::new().create(true).write(true).append(true).open(&path) {
+                    Err(e) => {
+                        error!("Error opening log file for writing: {}", e);
+                        file = None;
+                        continue;
+                    },
+                    Ok(f) => file = Some((path, f)),
                 }
-            },
+            }
+            if let Some((_, ref mut f)) = file {
+                let mut data = serde_json::to_string(&line).unwrap();
+                data.push('\n');
+                f.write_all(data.as_bytes()).expect("Failed writing to log file");
+            }
         }
     }
 
-    /// Reads the lines for the given day.
-    pub fn lines_for_day(&mut self, day: &Tm) -> Vec<BufferLine> {
-        let path = self.file_for_day(day);
-        trace!("Fetching lines from {}", path.display());
-        let mut data = String::new();
+    /// Checks whether a line with the same time and contents as `line` has
+    /// already been logged.
+    ///
+    /// This reads the line's day file, so it's best kept for lines which
+    /// might be duplicates, like history a server replays when we reconnect.
+    pub fn is_logged(&mut self, line: &BufferLine) -> bool {
+        let path = self.file_for_day(&line.time());
+        let cached = self.logged.as_ref().map_or(false, |l| l.0 == path);
+        if !cached {
+            let keys = read_log_file(&path).iter().map(line_key).collect();
+            self.logged = Some((path, keys));
+        }
+        let key = line_key(&strip_line(line.clone()));
+        self.logged.as_ref().map_or(false, |l| l.1.contains(&key))
+    }
 
-        if let Ok(mut f) = File::open(&path) {
-            if let Err(e) = f.read_to_string(&mut data) {
-                error!("Error reading log file: {}", e);
-                return vec![];
+    /// Adds older lines, such as ones imported from another client, to the
+    /// logs.
+    ///
+    /// Unlike `write_lines`, the lines don't have to be newer than what's
+    /// already logged. Each day file they belong in is rewritten with its
+    /// lines in order. Lines with the same time and contents as one already
+    /// logged are skipped, so importing the same logs twice is harmless.
+    ///
+    /// This rewrites files the core may be writing to, so the caller should
+    /// hold the `LogDirLock` for the log directory.
+    ///
+    /// Returns the number of lines added.
+    pub fn import_lines(&mut self, lines: Vec<BufferLine>) -> io::Result<usize> {
+        let mut days: Vec<(PathBuf, Vec<BufferLine>)> = vec![];
+        for line in lines {
+            let path = self.file_for_day(&line.time());
+            let line = strip_line(line);
+            match days.iter().position(|d| d.0 == path) {
+                Some(i) => days[i].1.push(line),
+                None => days.push((path, vec![line])),
             }
+        }
+        let mut added = 0;
+        for (path, new_lines) in days {
+            // Keep lines we can't decode as they are, rather than lose them
+            // when we rewrite the file.
+            let mut lines = vec![];
+            let mut unreadable = vec![];
+            for rec in read_log_records(&path) {
+                match rec {
+                    Ok(line) => lines.push(line),
+                    Err(text) => unreadable.push(text),
+                }
+            }
+            let mut keys = lines.iter().map(line_key).collect::<HashSet<_>>();
+            let old_len = lines.len();
+            lines.extend(new_lines.into_iter().filter(|l| keys.insert(line_key(l))));
+            if lines.len() == old_len { continue; }
+            added += lines.len() - old_len;
+            // This sort is stable, so lines sent in the same second keep
+            // their order.
+            lines.sort_by(|a, b| a.time().to_timespec().cmp(&b.time().to_timespec()));
+            try!(DirBuilder::new().recursive(true).create(&path.parent().unwrap()));
+            // Write the new file beside the old one, so we never leave a half
+            // written day behind.
+            let tmp = path.with_extension("tmp");
+            {
+                let mut f = try!(File::create(&tmp));
+                for line in lines {
+                    let mut data = serde_json::to_string(&line).unwrap();
+                    data.push('\n');
+                    try!(f.write_all(data.as_bytes()));
+                }
+                for text in unreadable {
+                    try!(writeln!(f, "{}", text));
+                }
+            }
+            try!(fs::rename(&tmp, &path));
+        }
+        Ok(added)
+    }
 
-            let lines = data.lines().flat_map(|l| {
-                use serde_json::de;
-                match de::from_str(l) {
-                    Ok(l) => Some(l),
-                    Err(e) => {
-                        warn!("Error parsing log line: {}", e);
-                        None
+    /// Searches the logs for messages containing `query`, ignoring case.
+    ///
+    /// Day files are searched newest first, and at most `limit` lines are
+    /// returned, newest first. Only the newest `MAX_SEARCH_DAYS` day files
+    /// are searched, and `limit` is capped at `MAX_SEARCH_RESULTS`.
+    pub fn search(&self, query: &str, limit: usize) -> Vec<BufferLine> {
+        let query = query.to_lowercase();
+        let limit = cmp::min(limit, MAX_SEARCH_RESULTS);
+        let mut results = vec![];
+        if limit == 0 {
+            return results;
+        }
+        for path in self.day_files().into_iter().take(MAX_SEARCH_DAYS) {
+            for line in read_log_file(&path) {
+                let matches = match line.data {
+                    LineData::Message { ref msg, .. } => msg.to_lowercase().contains(&query),
+                    _ => false,
+                };
+                if matches {
+                    results.push(line);
+                    if results.len() >= limit {
+                        return results;
                     }
                 }
-            }).rev().collect();
-            lines
-        } else {
-            vec![]
+            }
         }
+        results
     }
 
-    /// Reads a batch of lines from the log files.
+    /// Starts reading from the newest day file again.
+    pub fn rewind(&mut self) {
+        let (y, m, d) = day_key(&now());
+        self.read_before = (y, m, d + 1);
+    }
+
+    /// Reads a batch of lines from the log files, newest first.
     ///
-    /// This usually just reads an entire file of logs, but may vary.
+    /// This reads the newest day file we haven't read yet, skipping days with
+    /// no lines. Returns an empty list once every file has been read.
     pub fn fetch_lines(&mut self) -> Vec<BufferLine> {
-        let day = self.next_read_day.clone();
-        let lines = self.lines_for_day(&day);
-        self.next_read_day = (self.next_read_day - Duration::days(1)).to_local();
-        trace!("Next day: {} Lines: {:?}", self.next_read_day.ctime(), lines);
-        lines
+        for (day, path) in self.dated_day_files() {
+            if day >= self.read_before { continue; }
+            self.read_before = day;
+            let lines = read_log_file(&path);
+            trace!("Read {} lines from {}", lines.len(), path.display());
+            if !lines.is_empty() {
+                return lines;
+            }
+        }
+        vec![]
+    }
+
+    /// Writes the logs for the buffer `name` to `out_dir` as plain text, with
+    /// one `YYYY-MM-DD.log` file per day.
+    ///
+    /// Returns the number of day files written.
+    pub fn export(&self, name: &str, out_dir: &Path) -> io::Result<usize> {
+        try!(DirBuilder::new().recursive(true).create(out_dir));
+        let mut days = 0;
+        for ((y, m, d), path) in self.dated_day_files() {
+            let lines = read_log_file(&path);
+            if lines.is_empty() { continue; }
+            let out_path = out_dir.join(format!("{:04}-{:02}-{:02}.log", y, m, d));
+            let mut out = try!(File::create(&out_path));
+            // Log files are read newest first, but they're written oldest
+            // first.
+            for line in lines.iter().rev() {
+                for text in format_line(line, name) {
+                    try!(writeln!(out, "{}", text));
+                }
+            }
+            days += 1;
+        }
+        Ok(days)
+    }
+
+    /// Lists all of the day files in the log, newest first.
+    fn day_files(&self) -> Vec<PathBuf> {
+        self.dated_day_files().into_iter().map(|(_, p)| p).collect()
+    }
+
+    /// Like `day_files`, but pairs each file with its `(year, month, day)`.
+    fn dated_day_files(&self) -> Vec<((u32, u32, u32), PathBuf)> {
+        let mut files = vec![];
+        for (y, year) in numbered_entries(&self.dir) {
+            for (m, month) in numbered_entries(&year) {
+                for (d, day) in numbered_entries(&month) {
+                    files.push(((y, m, d), day));
+                }
+            }
+        }
+        files
     }
 
     fn file_for_day(&self, day: &Tm) -> PathBuf {
+        let (y, m, d) = day_key(day);
         let mut path = self.dir.clone();
-        path.push(format!("{}", day.tm_year + 1900));
-        path.push(format!("{}", day.tm_mon + 1));
-        path.push(format!("{}", day.tm_mday));
+        path.push(format!("{}", y));
+        path.push(format!("{}", m));
+        path.push(format!("{}", d));
         path
     }
 }
+
+
+/// An exclusive lock on a log directory, released when dropped.
+///
+/// The core holds this while it runs, so commands which rewrite logs can tell
+/// not to touch files it has open.
+pub struct LogDirLock {
+    _file: File,
+}
+
+/// Locks the log directory `dir`, creating it if needed.
+///
+/// Fails with `ErrorKind::WouldBlock` if another process holds the lock. The
+/// lock goes away with the process, so a crashed core doesn't leave it
+/// behind.
+pub fn lock_dir(dir: &Path) -> io::Result<LogDirLock> {
+    try!(DirBuilder::new().recursive(true).create(dir));
+    let file = try!(OpenOptions::new().write(true).create(true).open(dir.join(LOCK_FILE)));
+    let ret = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+    if ret == 0 {
+        Ok(LogDirLock { _file: file })
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+
+/// Identifies a logged line by its time and contents, ignoring its ID.
+fn line_key(line: &BufferLine) -> String {
+    format!("{} {}", line.time().to_timespec().sec, serde_json::to_string(&line.data).unwrap())
+}
+
+
+/// Returns the `(year, month, day)` a log file for `day` is stored under.
+fn day_key(day: &Tm) -> (u32, u32, u32) {
+    ((day.tm_year + 1900) as u32, (day.tm_mon + 1) as u32, day.tm_mday as u32)
+}
+
+
+/// Reads the lines in a log file, newest first.
+fn read_log_file(path: &Path) -> Vec<BufferLine> {
+    trace!("Fetching lines from {}", path.display());
+    read_log_records(path).into_iter().filter_map(|rec| rec.ok()).rev().collect()
+}
+
+/// Reads the lines in a log file, oldest first. Lines we can't decode are
+/// returned as `Err`s holding their text.
+fn read_log_records(path: &Path) -> Vec<Result<BufferLine, String>> {
+    let mut data = String::new();
+
+    if let Ok(mut f) = File::open(path) {
+        if let Err(e) = f.read_to_string(&mut data) {
+            error!("Error reading log file: {}", e);
+            return vec![];
+        }
+
+        data.lines().map(|l| {
+            decode_line(l).map_err(|e| {
+                warn!("Error parsing log line in {}: {}", path.display(), e);
+                l.to_owned()
+            })
+        }).collect()
+    } else {
+        vec![]
+    }
+}
+
+/// Decodes a line from a log file.
+///
+/// Logs are upgraded when they're opened, but in case an older line slipped
+/// through, lines which fail to decode are upgraded and tried again.
+fn decode_line(text: &str) -> Result<BufferLine, serde_json::Error> {
+    let err = match serde_json::from_str(text) {
+        Ok(line) => return Ok(line),
+        Err(e) => e,
+    };
+    let mut rec: Value = match serde_json::from_str(text) {
+        Ok(rec) => rec,
+        Err(_) => return Err(err),
+    };
+    upgrade_record(1, &mut rec);
+    serde_json::from_value(rec).map_err(|_| err)
+}
+
+/// Upgrades a line record written in format version `from` to
+/// `LOG_FORMAT_VERSION`.
+fn upgrade_record(from: u32, rec: &mut Value) {
+    if let Some(obj) = rec.as_object_mut() {
+        if from < 2 && !obj.contains_key("id") {
+            // Lines from before IDs were added don't have one.
+            obj.insert("id".to_owned(), Value::U64(0));
+        }
+    }
+}
+
+/// Rewrites a log file written in format version `from` in the current
+/// format.
+fn upgrade_log_file(path: &Path, from: u32) -> io::Result<()> {
+    let mut data = String::new();
+    try!(File::open(path).and_then(|mut f| f.read_to_string(&mut data)));
+    let mut out = String::with_capacity(data.len());
+    for text in data.lines() {
+        match serde_json::from_str::<Value>(text) {
+            Ok(mut rec) => {
+                upgrade_record(from, &mut rec);
+                out.push_str(&serde_json::to_string(&rec).unwrap());
+            },
+            // Keep lines we can't parse as they are rather than lose them.
+            Err(_) => out.push_str(text),
+        }
+        out.push('\n');
+    }
+    // Write a new file and move it into place so we don't leave a half
+    // upgraded file if something goes wrong.
+    let tmp = path.with_extension("tmp");
+    try!(File::create(&tmp).and_then(|mut f| f.write_all(out.as_bytes())));
+    fs::rename(&tmp, path)
+}
+
+/// Lists the entries in `dir` whose names are numbers, largest first, along
+/// with their numbers.
+///
+/// Log files are stored in `year/month/day` directories, so this sorts them by
+/// date rather than by name.
+fn numbered_entries(dir: &Path) -> Vec<(u32, PathBuf)> {
+    let mut entries: Vec<(u32, PathBuf)> = match fs::read_dir(dir) {
+        Ok(rd) => rd.filter_map(|e| e.ok()).filter_map(|e| {
+            let path = e.path();
+            let num = path.file_name().and_then(|n| n.to_str()).and_then(|n| n.parse().ok());
+            num.map(|n| (n, path))
+        }).collect(),
+        Err(_) => vec![],
+    };
+    entries.sort_by(|a, b| b.0.cmp(&a.0));
+    entries
+}
+
+
+/// Removes formatting codes from the line's text so the logs stay readable.
+fn strip_line(mut line: BufferLine) -> BufferLine {
+    match line.data {
+        LineData::Message { ref mut msg, .. } |
+        LineData::Quit { msg: Some(ref mut msg), .. } => {
+            *msg = strip_formatting(msg);
+        },
+        LineData::Topic { ref mut topic, .. } => {
+            *topic = strip_formatting(topic);
+        },
+        LineData::Part { ref mut reason, .. } |
+        LineData::Kick { ref mut reason, .. } => {
+            *reason = strip_formatting(reason);
+        },
+        _ => {},
+    }
+    line
+}
+
+/// Turns a buffer or network name into something safe to use as a single path
+/// component.
+///
+/// Channel and nick names can contain characters like `/`, which would
+/// otherwise let a buffer write logs outside its directory.
+pub fn sanitize_name(name: &str) -> String {
+    let mut clean: String = name.chars().map(|c| match c {
+        '/' | '\\' | '*' | '?' | ':' | '"' | '<' | '>' | '|' => '_',
+        c if c.is_control() => '_',
+        c => c,
+    }).collect();
+    if clean.is_empty() || clean.starts_with('.') {
+        clean.insert(0, '_');
+    }
+    clean
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use time::{self, Duration, Timespec};
+    use common::line::{BufferLine, LineData, MsgKind};
+    use common::tempdir::TempDir;
+    use super::{BufferLog, MAX_SEARCH_RESULTS, sanitize_name, strip_line};
+
+    fn msg(days_ago: i64, msg: &str) -> BufferLine {
+        BufferLine::new(time::now() - Duration::days(days_ago), LineData::Message {
+            kind: MsgKind::PrivMsg,
+            from: "Forkk".to_owned(),
+            msg: msg.to_owned(),
+        })
+    }
+
+    fn msg_text(line: &BufferLine) -> &str {
+        match line.data {
+            LineData::Message { ref msg, .. } => msg,
+            _ => panic!("Expected a message"),
+        }
+    }
+
+    #[test]
+    fn search_newest_first() {
+        let dir = TempDir::new("search");
+        let mut log = BufferLog::new(dir.path(), "net", "#chan");
+        log.write_lines(vec![
+            msg(40, "Hello from last month"),
+            msg(2, "hello again"),
+            msg(2, "goodbye"),
+            msg(0, "HELLO today"),
+        ]);
+
+        let found = log.search("hello", 10);
+        let found = found.iter().map(msg_text).collect::<Vec<_>>();
+        assert_eq!(found, vec!["HELLO today", "hello again", "Hello from last month"]);
+        assert_eq!(log.search("hello", 2).len(), 2);
+        assert!(log.search("nothing", 10).is_empty());
+    }
+
+    #[test]
+    fn search_limit_capped() {
+        let dir = TempDir::new("search-cap");
+        let mut log = BufferLog::new(dir.path(), "net", "#chan");
+        log.write_lines((0..MAX_SEARCH_RESULTS + 10).map(|_| msg(0, "spam")).collect());
+        assert_eq!(log.search("spam", usize::max_value()).len(), MAX_SEARCH_RESULTS);
+    }
+
+    #[test]
+    fn replayed_lines_are_logged() {
+        let dir = TempDir::new("replay");
+        let mut log = BufferLog::new(dir.path(), "net", "#chan");
+        let line = msg(0, "hello");
+        assert!(!log.is_logged(&line));
+        log.write_lines(vec![line.clone()]);
+        assert!(log.is_logged(&line));
+        assert!(!log.is_logged(&msg(0, "something else")));
+
+        // A fresh log has to find the line on disk.
+        let mut log = BufferLog::new(dir.path(), "net", "#chan");
+        assert!(log.is_logged(&line));
+    }
+
+    #[test]
+    fn upgrade_old_logs() {
+        use std::fs::File;
+        use std::io::{Read, Write};
+        use super::LOG_FORMAT_VERSION;
+
+        let dir = TempDir::new("upgrade");
+        // A version 1 line, from before lines had IDs.
+        let day = dir.join("net").join("#chan").join("2016").join("8").join("1");
+        fs::create_dir_all(day.parent().unwrap()).unwrap();
+        let mut f = File::create(&day).unwrap();
+        writeln!(f, r#"{{"time":1470052800,"data":{{"Message":{{"kind":"PrivMsg","from":"Forkk","msg":"old"}}}}}}"#).unwrap();
+        writeln!(f, "not json").unwrap();
+        drop(f);
+
+        let mut log = BufferLog::new(dir.path(), "net", "#chan");
+        let lines = log.fetch_lines();
+        assert_eq!(lines.iter().map(msg_text).collect::<Vec<_>>(), vec!["old"]);
+
+        let mut data = String::new();
+        File::open(&day).unwrap().read_to_string(&mut data).unwrap();
+        assert!(data.lines().next().unwrap().contains(r#""id":0"#));
+        // Lines we can't read are kept.
+        assert_eq!(data.lines().nth(1), Some("not json"));
+        let mut version = String::new();
+        File::open(dir.join("net").join("#chan").join("format")).unwrap()
+            .read_to_string(&mut version).unwrap();
+        assert_eq!(version.trim(), format!("{}", LOG_FORMAT_VERSION));
+    }
+
+    #[test]
+    fn import_merges_and_dedupes() {
+        let dir = TempDir::new("import");
+        let mut log = BufferLog::new(dir.path(), "net", "#chan");
+        // Ten seconds apart at noon UTC, so both are in the same day file.
+        let old = BufferLine::new(time::at(Timespec::new(1470052800, 0)), msg(0, "old").data);
+        let logged = BufferLine::new(time::at(Timespec::new(1470052810, 0)), old.data.clone());
+        log.write_lines(vec![logged.clone()]);
+
+        assert_eq!(log.import_lines(vec![old.clone(), logged.clone()]).unwrap(), 1);
+        // Importing again adds nothing.
+        assert_eq!(log.import_lines(vec![old.clone(), logged]).unwrap(), 0);
+
+        let mut log = BufferLog::new(dir.path(), "net", "#chan");
+        let lines = log.fetch_lines();
+        assert_eq!(lines.len(), 2);
+        // Newest first.
+        assert_eq!(lines[1].time().to_timespec(), old.time().to_timespec());
+    }
+
+    #[test]
+    fn lock_is_exclusive() {
+        use std::io::ErrorKind;
+        use super::lock_dir;
+
+        let dir = TempDir::new("lock");
+        let lock = lock_dir(dir.path()).unwrap();
+        // flock locks belong to the open file, so a second open conflicts
+        // even within one process.
+        assert_eq!(lock_dir(dir.path()).err().map(|e| e.kind()), Some(ErrorKind::WouldBlock));
+        drop(lock);
+        assert!(lock_dir(dir.path()).is_ok());
+    }
+
+    #[test]
+    fn logs_strip_formatting() {
+        let line = strip_line(msg(0, "\x02bold\x02 and \x034red"));
+        assert_eq!(msg_text(&line), "bold and red");
+    }
+
+    #[test]
+    fn sanitize_plain() {
+        assert_eq!(sanitize_name("#rust"), "#rust");
+        assert_eq!(sanitize_name("Forkk"), "Forkk");
+    }
+
+    #[test]
+    fn sanitize_separators() {
+        assert_eq!(sanitize_name("#foo/../bar"), "#foo_.._bar");
+        assert_eq!(sanitize_name("*network*"), "_network_");
+        assert_eq!(sanitize_name("a\\b"), "a_b");
+    }
+
+    #[test]
+    fn sanitize_dots() {
+        assert_eq!(sanitize_name(".."), "_..");
+        assert_eq!(sanitize_name(""), "_");
+    }
+}
@@ -1,62 +1,149 @@
-use std::collections::HashSet;
-use std::env;
-use time;
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use time::{self, Tm};
 use rotor_irc::Response;
 
-use common::line::{BufferLine, LineData, MsgKind, User};
-use common::messages::{NetId, BufInfo, Alert, BufTarget, CoreBufMsg};
+use common::line::{BufferLine, LineData, MsgKind, User, ChannelUser, sort_users};
+use common::messages::{NetId, BufId, BufInfo, Alert, BufTarget, CoreBufMsg};
 
 use network::BufferCmd;
+use network::mode::format_modes;
+use network::casemap::CaseMapping;
+use network::isupport::ISupport;
 
 mod log;
+mod highlight;
+pub mod export;
+pub mod import;
 
 use handle::UpdateHandle;
 use self::log::BufferLog;
+pub use self::highlight::Highlights;
+pub use self::log::{LogDirLock, lock_dir};
+
+
+/// Directory global buffers are logged in, alongside the networks' log
+/// directories. Networks can't be given this name, or their logs would be mixed
+/// up with the global buffers'.
+pub const GLOBAL_LOG_DIR: &'static str = "global";
+
+
+/// Settings shared by all buffers.
+#[derive(Debug, Clone)]
+pub struct BufferConfig {
+    /// Directory to store logs in.
+    pub log_dir: PathBuf,
+    /// Maximum number of lines to keep in the front buffer. Older lines are
+    /// dropped, and read back from the logs if clients ask for them.
+    pub max_lines: usize,
+}
+
+impl BufferConfig {
+    /// Gets the settings for the given user's buffers, which are logged in a
+    /// directory of their own so users' logs don't get mixed up.
+    pub fn for_user(&self, uid: &str) -> BufferConfig {
+        BufferConfig {
+            log_dir: self.log_dir.join(log::sanitize_name(uid)),
+            max_lines: self.max_lines,
+        }
+    }
+}
 
 
 /// A buffer within a network.
+///
+/// Lines are indexed the same way as in the client. Index 0 is the first line
+/// received since the core started, and lines loaded from logs have negative
+/// indices. A line's index never changes, even when it's dropped from the
+/// front buffer and later read back from the logs.
 #[derive(Debug, Clone)]
 pub struct Buffer {
     id: BufTarget,
     nid: NetId,
-    line_id: usize,
+    /// ID to give the next line pushed to the buffer.
+    next_id: u64,
     topic: String,
-    /// Messages received since the core started running.
-    front: Vec<BufferLine>,
-    /// Messages loaded from logs. These have negative indices.
-    back: Vec<BufferLine>,
+    /// Who set the topic and when, if we know.
+    topic_set: Option<(String, i64)>,
+    /// The topic from `RPL_TOPIC` and when we got it. The line showing it is
+    /// held back until `RPL_TOPICWHOTIME` arrives, so who set it can go in
+    /// the same line.
+    pending_topic: Option<(String, Tm)>,
+    /// The most recent messages received since the core started running.
+    front: VecDeque<BufferLine>,
+    /// The index of the first line in `front`. This is the number of lines
+    /// that have been dropped to keep `front` under `max_lines`.
+    front_start: isize,
+    max_lines: usize,
+    /// Older lines read from the logs, newest first.
+    ///
+    /// This holds a run of lines ending just before index `back_end`, which
+    /// may leave a gap before `front_start` once lines have been dropped from
+    /// `front`. Lines in the gap are read from the logs again when needed.
+    back: VecDeque<BufferLine>,
+    back_end: isize,
+    /// ID of the newest line the user's clients have read.
+    read_id: u64,
     joined: bool,
-    /// Nicks of users in this channel.
-    users: HashSet<String>,
+    /// For private buffers, whether the other user is online.
+    online: bool,
+    /// Users in this channel, keyed by their nick folded to lowercase.
+    users: HashMap<String, ChannelUser>,
     names_ended: bool,
+    /// `WHOIS` replies received so far, keyed by the nick being queried.
+    whois: HashMap<String, Vec<String>>,
     log: BufferLog,
 }
 
 // Buffer behavior
 impl Buffer {
-    pub fn new(nid: NetId, id: BufTarget) -> Buffer {
-        let mut path = env::current_dir().expect("Failed to get cwd");
-        path.push("logs");
-        path.push(nid.clone());
-        path.push(id.name());
-        let mut log = BufferLog::new(path);
-
+    pub fn new(nid: NetId, id: BufTarget, cfg: &BufferConfig) -> Buffer {
         let joined = if let BufTarget::Private(_) = id {
             true
         } else {
             false
         };
+        Buffer::with_log_dir(nid, id, joined, cfg)
+    }
+
+    /// Creates a global buffer, which belongs to a user rather than a network.
+    ///
+    /// Global buffers are logged under `GLOBAL_LOG_DIR` in the log directory
+    /// and are always joined. Since clients only look at the name of a global
+    /// buffer, its ID is a `BufTarget::Channel` with the given name.
+    pub fn new_global(id: BufId, cfg: &BufferConfig) -> Buffer {
+        Buffer::with_log_dir(GLOBAL_LOG_DIR.to_owned(), BufTarget::Channel(id), true, cfg)
+    }
+
+    /// Creates a buffer which logs to `<log_dir>/<dir>/<name>`.
+    fn with_log_dir(nid: NetId, id: BufTarget, joined: bool, cfg: &BufferConfig) -> Buffer {
+        let mut log = BufferLog::new(&cfg.log_dir, &nid, id.name());
+        let back: VecDeque<BufferLine> = log.fetch_lines().into_iter().collect();
+        // Carry on numbering from the newest logged line.
+        let next_id = back.front().and_then(|l| l.id()).unwrap_or(0) + 1;
 
         Buffer {
             id: id,
             nid: nid,
-            line_id: 0,
+            next_id: next_id,
             topic: String::new(),
-            front: vec![],
-            back: log.fetch_lines(),
+            topic_set: None,
+            pending_topic: None,
+            front: VecDeque::new(),
+            front_start: 0,
+            max_lines: cfg.max_lines,
+            back: back,
+            back_end: 0,
+            // Lines from before the core started count as read unless the
+            // session says otherwise.
+            read_id: next_id - 1,
             joined: joined,
-            users: HashSet::new(),
+            // Assume the other user is online until we hear otherwise.
+            online: true,
+            users: HashMap::new(),
             names_ended: true,
+            whois: HashMap::new(),
             log: log,
         }
     }
@@ -73,41 +160,171 @@ impl Buffer {
     }
 
 
+    /// Gets the channel's current topic. This is empty if no topic is set.
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+
+    /// True if we're currently in this buffer's channel.
+    pub fn joined(&self) -> bool {
+        self.joined
+    }
+
+    /// For private buffers, true if the other user is online.
+    pub fn online(&self) -> bool {
+        self.online
+    }
+
+
     /// True if a user with the given nick is present in the channel.
-    pub fn has_user(&self, nick: &str) -> bool {
-        self.users.contains(nick)
+    pub fn has_user(&self, nick: &str, cm: CaseMapping) -> bool {
+        self.users.contains_key(&cm.to_lower(nick))
     }
 
+    /// Returns the account of the user with the given nick if they're in
+    /// this channel and we know they're logged in.
+    pub fn user_account(&self, nick: &str, cm: CaseMapping) -> Option<&str> {
+        self.users.get(&cm.to_lower(nick)).and_then(|cu| cu.account.as_ref()).map(|a| &a[..])
+    }
 
-    pub fn get_line(&mut self, idx: isize) -> Option<&BufferLine> {
-        if idx < self.last_idx() {
-            self.back.extend(self.log.fetch_lines());
+    /// Sets the account of the user with the given nick, if they're in this
+    /// channel, and tells clients if it changed.
+    pub fn set_user_account<U>(&mut self, nick: &str, account: Option<String>, cm: CaseMapping,
+                               u: &mut U)
+        where U : UpdateHandle<CoreBufMsg>
+    {
+        let cu = match self.users.get_mut(&cm.to_lower(nick)) {
+            Some(cu) if cu.account != account => {
+                cu.account = account;
+                cu.clone()
+            },
+            _ => return,
         };
-        if idx < 0 {
-            self.back.get((-idx) as usize - 1)
-        } else { self.front.get(idx as usize) }
+        u.send_clients(CoreBufMsg::UserUpdate(cu));
     }
 
+    /// Returns the users in the channel, sorted by rank and then by nick.
+    pub fn users(&self) -> Vec<ChannelUser> {
+        let mut users = self.users.values().cloned().collect::<Vec<_>>();
+        sort_users(&mut users);
+        users
+    }
 
-    pub fn last_idx(&self) -> isize {
-        if self.back.is_empty() {
-            // If the back is empty, the first line in the front buffer is the last index.
-            0
-        } else {
-            -(self.back.len() as isize)
+
+    /// Gets the line at the given index, loading logs until we reach it.
+    ///
+    /// Returns `None` if `idx` is past the oldest line in the logs.
+    pub fn get_line(&mut self, idx: isize) -> Option<&BufferLine> {
+        if idx < self.front_start && (idx < self.back_start() || idx >= self.back_end) {
+            self.load_back(idx);
+        }
+        self.line_at(idx)
+    }
+
+    /// Reads logs into `back` until it holds the line at `idx`.
+    ///
+    /// Only the `max_lines` lines nearest `idx` are kept, plus the rest of the
+    /// day file `idx` is in.
+    fn load_back(&mut self, idx: isize) {
+        if self.back.is_empty() || idx >= self.back_end {
+            // Every line up to the newest is in the logs, so count back from
+            // there.
+            self.log.rewind();
+            self.back.clear();
+            self.back_end = self.front_len();
         }
+        let max = cmp::max(self.max_lines, 1);
+        while idx < self.back_start() {
+            let lines = self.log.fetch_lines();
+            if lines.is_empty() { break; }
+            self.back.extend(lines);
+            // Drop lines that are in `front` and those too new to be wanted.
+            while self.back_end > self.front_start ||
+                  (self.back.len() > max && self.back_end - 1 > idx) {
+                self.back.pop_front();
+                self.back_end -= 1;
+            }
+        }
+    }
+
+    /// Gets the line at the given index without loading more logs.
+    fn line_at(&self, idx: isize) -> Option<&BufferLine> {
+        if idx >= self.front_start {
+            self.front.get((idx - self.front_start) as usize)
+        } else if idx < self.back_end {
+            self.back.get((self.back_end - 1 - idx) as usize)
+        } else { None }
+    }
+
+    /// Returns the index of the oldest line in `back`.
+    fn back_start(&self) -> isize {
+        self.back_end - self.back.len() as isize
+    }
+
+
+    /// Returns the index of the oldest line we have in memory.
+    pub fn last_idx(&self) -> isize {
+        if self.back.is_empty() { self.front_start } else { self.back_start() }
+    }
+
+    /// Returns the index of the oldest line received since the core started
+    /// that's still in memory. Older lines have to be read from the logs.
+    pub fn front_start(&self) -> isize {
+        self.front_start
     }
 
-    /// Returns the length of the front buffer. This is the index of the most
-    /// recently received message + 1.
+    /// Returns the number of lines received since the core started. This is
+    /// the index of the most recently received message + 1.
     pub fn front_len(&self) -> isize {
-        self.front.len() as isize
+        self.front_start + self.front.len() as isize
     }
 
-    /// Returns the length of the back buffer. This is the negative of the index
-    /// of the oldest message.
+    /// Returns the lines received since the core started, starting at the given
+    /// index.
+    pub fn front_since(&self, idx: isize) -> Vec<BufferLine> {
+        let idx = cmp::max(idx, 0);
+        (idx..self.front_len()).filter_map(|i| self.line_at(i).cloned()).collect()
+    }
+
+    /// Returns the number of lines loaded from logs. This is the negative of
+    /// the index of the oldest message.
     pub fn back_len(&self) -> isize {
-        self.back.len() as isize
+        -self.last_idx()
+    }
+
+    /// Returns the number of lines that haven't been marked as read.
+    pub fn unread(&self) -> usize {
+        (self.next_id - 1).saturating_sub(self.read_id) as usize
+    }
+
+    /// Returns the ID of the newest line that's been marked as read.
+    pub fn read_id(&self) -> u64 {
+        self.read_id
+    }
+
+    /// Marks every line up to and including the one with ID `id` as read and
+    /// tells clients the new unread count.
+    ///
+    /// The read marker never moves backwards.
+    pub fn mark_read<U>(&mut self, id: u64, u: &mut U)
+        where U : UpdateHandle<CoreBufMsg>
+    {
+        let id = cmp::min(id, self.next_id - 1);
+        if id > self.read_id {
+            self.read_id = id;
+            u.send_clients(CoreBufMsg::Unread(self.unread()));
+        }
+    }
+
+    /// Restores the read marker saved in the session.
+    pub fn restore_read_id(&mut self, id: u64) {
+        self.read_id = cmp::min(id, self.next_id - 1);
+    }
+
+    /// Searches this buffer's logs for messages containing `query`.
+    pub fn search_logs(&self, query: &str, limit: usize) -> Vec<BufferLine> {
+        self.log.search(query, limit)
     }
 
 
@@ -116,15 +333,142 @@ impl Buffer {
     pub fn push_line<U>(&mut self, data: LineData, u: &mut U)
         where U : UpdateHandle<CoreBufMsg>
     {
-        let line = BufferLine::new(time::now(), data);
+        self.push_line_at(data, time::now(), u)
+    }
+
+    /// Like `push_line`, but stamps the line with the given time instead of
+    /// the current time.
+    ///
+    /// Lines older than the newest line we have may be history a server or
+    /// bouncer is replaying after we reconnect. These are dropped if they've
+    /// already been logged.
+    pub fn push_line_at<U>(&mut self, data: LineData, time: Tm, u: &mut U)
+        where U : UpdateHandle<CoreBufMsg>
+    {
+        let line = BufferLine::with_id(self.next_id, time, data);
+        let newest = self.front.back().or(self.back.front()).map(|l| l.time().to_timespec());
+        if newest.map_or(false, |t| time.to_timespec() < t) && self.log.is_logged(&line) {
+            trace!("Buffer {}: Dropping replayed line {:?}", self.id.name(), line);
+            return;
+        }
         trace!("Buffer {}: Pushing line {:?}", self.id.name(), line);
-        self.line_id += 1;
-        self.front.push(line.clone());
+        self.next_id += 1;
+        self.front.push_back(line.clone());
         self.log.write_lines(vec![line.clone()]);
+        // The logs have the lines we drop, and they keep their indices, so
+        // clients' scrollback positions stay valid.
+        while self.front.len() > cmp::max(self.max_lines, 1) {
+            self.front.pop_front();
+            self.front_start += 1;
+        }
 
         u.send_clients(CoreBufMsg::NewLines(vec![line]));
     }
 
+    /// Adds or replaces the given user and tells clients about it.
+    fn update_user<U>(&mut self, cu: ChannelUser, cm: CaseMapping, u: &mut U)
+        where U : UpdateHandle<CoreBufMsg>
+    {
+        self.users.insert(cm.to_lower(&cu.nick), cu.clone());
+        u.send_clients(CoreBufMsg::UserUpdate(cu));
+    }
+
+    /// Removes the user with the given nick and tells clients about it.
+    fn remove_user<U>(&mut self, nick: &str, cm: CaseMapping, u: &mut U)
+        where U : UpdateHandle<CoreBufMsg>
+    {
+        if let Some(cu) = self.users.remove(&cm.to_lower(nick)) {
+            u.send_clients(CoreBufMsg::UserRemoved(cu.nick));
+        }
+    }
+
+    /// Clears the user list and tells clients about it.
+    fn clear_users<U>(&mut self, u: &mut U)
+        where U : UpdateHandle<CoreBufMsg>
+    {
+        self.users.clear();
+        u.send_clients(CoreBufMsg::Users(vec![]));
+    }
+
+    /// Sets the channel's topic and tells clients about it.
+    ///
+    /// This forgets who set the old topic. Use `set_topic_by` to record who
+    /// set the new one.
+    fn set_topic<U>(&mut self, topic: String, u: &mut U)
+        where U : UpdateHandle<CoreBufMsg>
+    {
+        self.topic = topic.clone();
+        self.topic_set = None;
+        u.send_clients(CoreBufMsg::Topic(topic));
+    }
+
+    /// Sets the channel's topic without telling clients. Used to restore the
+    /// topic from the previous session before any clients have connected.
+    pub fn restore_topic(&mut self, topic: String) {
+        self.topic = topic;
+    }
+
+    /// Records who set the channel's topic and when, and tells clients.
+    fn set_topic_by<U>(&mut self, by: String, at: i64, u: &mut U)
+        where U : UpdateHandle<CoreBufMsg>
+    {
+        self.topic_set = Some((by.clone(), at));
+        u.send_clients(CoreBufMsg::TopicSetBy(by, at));
+    }
+
+    /// Pushes the line for a topic from `RPL_TOPIC` that's still waiting on
+    /// `RPL_TOPICWHOTIME`, if any.
+    fn flush_topic<U>(&mut self, set: Option<(String, i64)>, u: &mut U)
+        where U : UpdateHandle<CoreBufMsg>
+    {
+        if let Some((topic, time)) = self.pending_topic.take() {
+            self.push_line_at(LineData::Topic {
+                by: None,
+                topic: topic,
+                set: set,
+            }, time, u)
+        }
+    }
+
+    /// Marks a channel buffer as not joined after losing the connection to
+    /// the server, and says so in the buffer.
+    pub fn disconnected<U>(&mut self, u: &mut U)
+        where U : UpdateHandle<CoreBufMsg>
+    {
+        self.flush_topic(None, u);
+        if let BufTarget::Channel(_) = self.id {
+            if self.joined {
+                self.set_joined(false, u);
+                self.clear_users(u);
+                self.push_line(LineData::Message {
+                    kind: MsgKind::Status,
+                    from: "*".to_owned(),
+                    msg: "Disconnected from server".to_owned(),
+                }, u);
+            }
+        }
+    }
+
+    /// Marks a private buffer's user as online or offline, saying so in the
+    /// buffer if it changed.
+    pub fn set_online<U>(&mut self, online: bool, u: &mut U)
+        where U : UpdateHandle<CoreBufMsg>
+    {
+        let msg = match self.id {
+            BufTarget::Private(ref nick) if self.online != online => {
+                format!("{} is now {}", nick, if online { "online" } else { "offline" })
+            },
+            _ => return,
+        };
+        self.online = online;
+        u.send_clients(CoreBufMsg::Online(online));
+        self.push_line(LineData::Message {
+            kind: MsgKind::Status,
+            from: "*".to_owned(),
+            msg: msg,
+        }, u);
+    }
+
     /// Sets whether we're joined in this buffer or not and sends a status update.
     fn set_joined<U>(&mut self, joined: bool, u: &mut U)
         where U : UpdateHandle<CoreBufMsg>
@@ -138,148 +482,251 @@ impl Buffer {
 
 // IRC Message Handling
 impl Buffer {
-    pub fn handle_cmd<U>(&mut self, cmd: BufferCmd, my_nick: &str, u: &mut U)
+    pub fn handle_cmd<U>(&mut self,
+                         cmd: BufferCmd,
+                         my_nick: &str,
+                         hl: &Highlights,
+                         isup: &ISupport,
+                         time: Tm,
+                         u: &mut U)
         where U : UpdateHandle<CoreBufMsg>
     {
         use network::BufferCmd::*;
+        let cm = isup.casemapping;
+        if let RPL_TOPICWHOTIME(..) = cmd {} else {
+            // Not all servers send `RPL_TOPICWHOTIME`.
+            self.flush_topic(None, u);
+        }
         match cmd {
-            JOIN(user) => {
-                if user.nick == my_nick {
+            JOIN(user, account) => {
+                if cm.eq(&user.nick, my_nick) {
                     debug!("Joined channel {}", self.id.name());
                     self.set_joined(true, u);
+                    // The server sends the current topic after we join, so
+                    // forget the old one in case it was cleared while we
+                    // were away.
+                    if !self.topic.is_empty() {
+                        self.set_topic(String::new(), u);
+                    }
                 } else {
                     debug!("User {} joined channel {}", user, self.id.name());
-                    self.users.insert(user.nick.clone());
+                    let mut cu = ChannelUser::new(user.nick.clone());
+                    cu.account = account;
+                    cu.ident = user.ident.clone();
+                    cu.host = user.host.clone();
+                    self.update_user(cu, cm, u);
                     trace!("Users: {:?}", self.users);
                 }
 
-                self.push_line(LineData::Join { user: user }, u)
+                self.push_line_at(LineData::Join { user: user }, time, u)
             },
             PART(user, reason) => {
                 let reason = reason.unwrap_or("No reason given".to_owned());
-                if user.nick == my_nick {
+                if cm.eq(&user.nick, my_nick) {
                     debug!("Parted channel {}", self.id.name());
                     self.set_joined(false, u);
-                    self.users.clear();
+                    self.clear_users(u);
                 } else {
                     debug!("User {} left channel {}", user, self.id.name());
-                    self.users.remove(&user.nick);
+                    self.remove_user(&user.nick, cm, u);
                     trace!("Users: {:?}", self.users);
                 }
 
-                self.push_line(LineData::Part {
+                self.push_line_at(LineData::Part {
                     user: user,
                     reason: reason,
-                }, u)
+                }, time, u)
             },
             KICK { by, targ, reason } => {
                 let reason = reason.unwrap_or("No reason given".to_owned());
-                if targ == my_nick {
+                if cm.eq(&targ, my_nick) {
                     debug!("Kicked from channel {} by {}", self.id.name(), by);
                     self.set_joined(false, u);
-                    self.users.clear();
+                    self.clear_users(u);
                 } else {
                     debug!("User {} kicked from channel {} by {}", targ, self.id.name(), by);
-                    self.users.remove(&targ);
+                    self.remove_user(&targ, cm, u);
                     trace!("Users: {:?}", self.users);
                 }
 
-                self.push_line(LineData::Kick {
+                self.push_line_at(LineData::Kick {
                     by: by,
                     user: targ,
                     reason: reason,
-                }, u)
+                }, time, u)
             },
 
             PRIVMSG(user, msg) => {
+                // Our own messages, echoed back by the server, never alert.
+                let own = cm.eq(&user.nick, my_nick);
                 if let BufTarget::Channel(ref bid) = self.id {
                     // Check if the message pings us.
-                    if msg.contains(my_nick) {
+                    if !own && hl.is_highlight(&user.nick, &msg, my_nick, cm) {
                         // Push a ping
                         let msg = format!("Pinged by {} in channel {}", &user.nick, bid);
-                        u.post_alert(Alert::ping(self.nid.clone(), bid.clone(), msg));
+                        u.post_alert(Alert::ping(self.nid.clone(), bid.clone(), user.nick.clone(), msg));
                     }
                 } else if let BufTarget::Private(ref bid) = self.id {
                     // If it's a PM, send an alert regardless of the contents.
-                    let msg = format!("New private message from {}", &user.nick);
-                    u.post_alert(Alert::privmsg(self.nid.clone(), bid.clone(), msg));
+                    if !own {
+                        let msg = format!("New private message from {}", &user.nick);
+                        u.post_alert(Alert::privmsg(self.nid.clone(), bid.clone(), user.nick.clone(), msg));
+                    }
                 }
 
-                self.push_line(LineData::Message {
+                self.push_line_at(LineData::Message {
                     kind: MsgKind::PrivMsg,
                     from: user.nick,
                     msg: msg,
-                }, u)
+                }, time, u)
             },
             NOTICE(sender, msg) => {
                 // NOTE: Should we check notices for pings?
-                self.push_line(LineData::Message {
+                self.push_line_at(LineData::Message {
                     kind: MsgKind::Notice,
                     from: sender.name().to_owned(),
                     msg: msg.clone(),
-                }, u)
+                }, time, u)
             },
             ACTION(user, msg) => {
                 // NOTE: Should we check actions for pings?
-                self.push_line(LineData::Message {
+                self.push_line_at(LineData::Message {
                     kind: MsgKind::Action,
                     from: user.nick.to_owned(),
                     msg: msg.clone(),
-                }, u)
+                }, time, u)
+            },
+
+            TOPIC(user, topic) => {
+                debug!("User {} changed topic in {} to {}", user, self.id.name(), topic);
+                self.set_topic(topic.clone(), u);
+                self.set_topic_by(user.nick.clone(), time.to_timespec().sec, u);
+                self.push_line_at(LineData::Topic {
+                    by: Some(user.nick),
+                    topic: topic,
+                    set: None,
+                }, time, u)
+            },
+            RPL_TOPIC(topic) => {
+                trace!("Topic for {}: {}", self.id.name(), topic);
+                self.set_topic(topic.clone(), u);
+                self.pending_topic = Some((topic, time));
+            },
+            RPL_NOTOPIC => {
+                trace!("No topic set for {}", self.id.name());
+                self.set_topic(String::new(), u);
+            },
+            RPL_TOPICWHOTIME(by, at) => {
+                trace!("Topic for {} set by {} at {}", self.id.name(), by, at);
+                self.set_topic_by(by.clone(), at, u);
+                self.flush_topic(Some((by, at)), u);
+            },
+
+            MODE(by, changes) => {
+                debug!("User {} set modes in {}: {:?}", by, self.id.name(), changes);
+                for change in changes.iter() {
+                    if let Some((pfx, nick)) = change.user_prefix(isup) {
+                        if let Some(cu) = self.users.get_mut(&cm.to_lower(nick)) {
+                            if change.set {
+                                cu.add_prefix(pfx, &isup.prefixes());
+                            } else {
+                                cu.remove_prefix(pfx);
+                            }
+                            u.send_clients(CoreBufMsg::UserUpdate(cu.clone()));
+                        }
+                    }
+                }
+                let (modes, args) = format_modes(&changes);
+                self.push_line_at(LineData::Mode {
+                    by: by,
+                    modes: modes,
+                    args: args,
+                }, time, u)
             },
 
             RPL_NAMREPLY(body) => {
                 if self.names_ended { self.users.clear(); }
-                for name in body.split(' ') {
-                    let name = if name.starts_with("@") || name.starts_with("+") {
-                        &name[1..]
-                    } else {
-                        name
-                    };
-                    self.users.insert(name.to_owned());
+                self.names_ended = false;
+                let prefixes = isup.prefixes();
+                for name in body.split(' ').filter(|n| !n.is_empty()) {
+                    let cu = ChannelUser::parse_names_entry(name, &prefixes);
+                    self.users.insert(cm.to_lower(&cu.nick), cu);
                 }
                 trace!("User list update: {:?}", self.users);
             },
             RPL_ENDOFNAMES => {
                 trace!("Final user list: {:?}", self.users);
                 self.names_ended = true;
+                u.send_clients(CoreBufMsg::Users(self.users()));
+            },
+
+            RPL_WHOREPLY(who) => {
+                // Clients get the whole list once the reply ends, rather than
+                // an update per user.
+                if let Some(cu) = self.users.get_mut(&cm.to_lower(&who.nick)) {
+                    cu.ident = who.ident;
+                    cu.host = who.host;
+                    cu.away = who.away;
+                    if who.account.is_some() {
+                        cu.account = who.account;
+                    }
+                }
+            },
+            RPL_ENDOFWHO => {
+                trace!("User list after WHO: {:?}", self.users);
+                u.send_clients(CoreBufMsg::Users(self.users()));
+            },
+
+            WhoisReply(nick, line) => {
+                self.whois.entry(nick).or_insert_with(Vec::new).push(line);
+            },
+            RPL_ENDOFWHOIS(nick) => {
+                if let Some(info) = self.whois.remove(&nick) {
+                    self.push_line_at(LineData::Whois {
+                        nick: nick,
+                        info: info,
+                    }, time, u)
+                }
             },
 
             RPL_MOTD(msg) => {
                 // NOTE: Should we check notices for pings?
-                self.push_line(LineData::Message {
+                self.push_line_at(LineData::Message {
                     kind: MsgKind::Response(Response::RPL_MOTD.to_u16()),
                     from: "motd".to_owned(),
                     msg: msg.clone(),
-                }, u)
+                }, time, u)
             },
         }
     }
 
     /// Handles `user` quitting.
-    pub fn handle_quit<U>(&mut self, user: &User, msg: Option<String>, u: &mut U)
+    pub fn handle_quit<U>(&mut self, user: &User, msg: Option<String>, cm: CaseMapping, time: Tm, u: &mut U)
         where U : UpdateHandle<CoreBufMsg>
     {
         debug!("User {} quit buffer {}", user.nick, self.id.name());
-        self.users.remove(&user.nick);
-        self.push_line(LineData::Quit {
+        self.remove_user(&user.nick, cm, u);
+        self.push_line_at(LineData::Quit {
             user: user.clone(),
             msg: msg,
-        }, u);
+        }, time, u);
         trace!("Users: {:?}", self.users);
     }
 
     /// Handles `user` changing nick to `new`.
-    pub fn handle_nick<U>(&mut self, user: &User, new: String, u: &mut U)
+    pub fn handle_nick<U>(&mut self, user: &User, new: String, cm: CaseMapping, time: Tm, u: &mut U)
         where U : UpdateHandle<CoreBufMsg>
     {
         debug!("User {} changed nick to {} in {:?}", user, new, &self.id);
-        self.users.remove(&user.nick);
-        self.users.insert(new.clone());
-        self.push_line(LineData::Nick {
+        if let Some(mut cu) = self.users.remove(&cm.to_lower(&user.nick)) {
+            u.send_clients(CoreBufMsg::UserRemoved(cu.nick.clone()));
+            cu.nick = new.clone();
+            self.update_user(cu, cm, u);
+        }
+        self.push_line_at(LineData::Nick {
             user: user.clone(),
             new: new,
-        }, u);
+        }, time, u);
         trace!("Users: {:?}", self.users);
     }
 }
@@ -288,6 +735,271 @@ impl Buffer {
 impl Buffer {
     /// Gets `BufInfo` data for this buffer.
     pub fn as_info(&self) -> BufInfo {
-        BufInfo { id: self.id.clone(), joined: self.joined }
+        BufInfo {
+            id: self.id.clone(),
+            joined: self.joined,
+            online: self.online,
+            topic: self.topic.clone(),
+            topic_set: self.topic_set.clone(),
+            unread: self.unread(),
+        }
+    }
+}
+
+
+/// Helpers shared by tests which need buffers.
+#[cfg(test)]
+pub mod test_util {
+    use common::line::{LineData, MsgKind};
+    use common::messages::BufTarget;
+    use common::tempdir::TempDir;
+    use super::{Buffer, BufferConfig};
+
+    /// A config which logs to the given temporary directory.
+    pub fn test_cfg(dir: &TempDir) -> BufferConfig {
+        BufferConfig {
+            log_dir: dir.path().to_owned(),
+            max_lines: 2000,
+        }
+    }
+
+    /// A buffer for `#chan` on `net` which logs to the given temporary
+    /// directory.
+    pub fn chan_buf(dir: &TempDir) -> Buffer {
+        chan_buf_with(&test_cfg(dir))
+    }
+
+    /// A buffer for `#chan` on `net` with the given config.
+    pub fn chan_buf_with(cfg: &BufferConfig) -> Buffer {
+        Buffer::new("net".to_owned(), BufTarget::Channel("#chan".to_owned()), cfg)
+    }
+
+    /// A message whose text is `i`.
+    pub fn msg(i: usize) -> LineData {
+        LineData::Message {
+            kind: MsgKind::PrivMsg,
+            from: "Forkk".to_owned(),
+            msg: format!("{}", i),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use time::{self, Duration};
+    use common::line::{BufferLine, LineData};
+    use common::messages::{BufTarget, CoreBufMsg};
+    use common::tempdir::TempDir;
+    use handle::BaseUpdateHandle;
+    use super::Buffer;
+    use super::log::BufferLog;
+    use super::test_util::{test_cfg, msg, chan_buf, chan_buf_with};
+
+    fn msg_text(buf: &mut Buffer, idx: isize) -> String {
+        match buf.get_line(idx).map(|l| &l.data) {
+            Some(&LineData::Message { ref msg, .. }) => msg.clone(),
+            l => panic!("Expected a message, got {:?}", l),
+        }
+    }
+
+    #[test]
+    fn front_eviction_keeps_indices() {
+        let dir = TempDir::new("eviction");
+        let mut cfg = test_cfg(&dir);
+        cfg.max_lines = 3;
+        let mut buf = chan_buf_with(&cfg);
+        let mut u = BaseUpdateHandle::<CoreBufMsg>::new();
+        for i in 0..5 {
+            buf.push_line(msg(i), &mut u);
+        }
+
+        assert_eq!(buf.front.len(), 3);
+        assert_eq!(buf.front_len(), 5);
+        // Dropped lines aren't kept in memory, but can be read back.
+        assert_eq!(buf.last_idx(), 2);
+        for i in 0..5 {
+            assert_eq!(msg_text(&mut buf, i as isize), format!("{}", i));
+        }
+        assert_eq!(buf.front_since(1).len(), 4);
+    }
+
+    #[test]
+    fn scrollback_stays_bounded() {
+        let dir = TempDir::new("bounded");
+        let mut cfg = test_cfg(&dir);
+        cfg.max_lines = 2;
+        let mut buf = chan_buf_with(&cfg);
+        let mut u = BaseUpdateHandle::<CoreBufMsg>::new();
+        for i in 0..8 {
+            buf.push_line(msg(i), &mut u);
+        }
+        assert!(buf.back.is_empty());
+
+        // Scrolling back keeps at most `max_lines` newer than what's wanted.
+        assert_eq!(msg_text(&mut buf, 0), "0");
+        assert_eq!(buf.back.len(), 2);
+        // Lines in the gap before `front` are read again.
+        assert_eq!(msg_text(&mut buf, 5), "5");
+        assert_eq!(msg_text(&mut buf, 1), "1");
+    }
+
+    #[test]
+    fn mark_read() {
+        let dir = TempDir::new("mark-read");
+        let mut buf = chan_buf(&dir);
+        let mut u = BaseUpdateHandle::<CoreBufMsg>::new();
+        for i in 0..5 {
+            buf.push_line(msg(i), &mut u);
+        }
+        assert_eq!(buf.unread(), 5);
+
+        // IDs start at 1.
+        buf.mark_read(3, &mut u);
+        assert_eq!(buf.unread(), 2);
+        // Marking older lines doesn't make newer ones unread again.
+        buf.mark_read(1, &mut u);
+        assert_eq!(buf.unread(), 2);
+        // Lines pushed after the client saw the buffer stay unread.
+        buf.push_line(msg(5), &mut u);
+        buf.mark_read(5, &mut u);
+        assert_eq!(buf.unread(), 1);
+        buf.mark_read(100, &mut u);
+        assert_eq!(buf.unread(), 0);
+        assert_eq!(buf.read_id(), 6);
+    }
+
+    #[test]
+    fn fetch_across_days() {
+        let dir = TempDir::new("fetch-days");
+        let cfg = test_cfg(&dir);
+        {
+            // Leave gaps between days and an empty file, which shouldn't stop
+            // us from reading older days.
+            let mut log = BufferLog::new(&cfg.log_dir, "net", "#chan");
+            let mut lines = vec![];
+            let mut i = 0;
+            for &days_ago in &[9, 9, 9, 5, 3, 3, 1, 0, 0] {
+                lines.push(BufferLine::new(time::now() - Duration::days(days_ago), msg(i)));
+                i += 1;
+            }
+            log.write_lines(lines);
+            let empty_day = time::now() - Duration::days(2);
+            let path = cfg.log_dir.join("net").join("#chan")
+                .join(format!("{}", empty_day.tm_year + 1900))
+                .join(format!("{}", empty_day.tm_mon + 1))
+                .join(format!("{}", empty_day.tm_mday));
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::File::create(&path).unwrap();
+        }
+
+        let mut buf = chan_buf_with(&cfg);
+        let mut u = BaseUpdateHandle::<CoreBufMsg>::new();
+        buf.push_line(msg(9), &mut u);
+
+        assert_eq!(msg_text(&mut buf, 0), "9");
+        for i in 1..10 {
+            assert_eq!(msg_text(&mut buf, -i), format!("{}", 9 - i));
+        }
+        assert!(buf.get_line(-10).is_none());
+        assert_eq!(buf.last_idx(), -9);
+    }
+
+    #[test]
+    fn ids_survive_restart() {
+        let dir = TempDir::new("line-ids");
+        let mut u = BaseUpdateHandle::<CoreBufMsg>::new();
+        {
+            let mut buf = chan_buf(&dir);
+            for i in 0..3 {
+                buf.push_line(msg(i), &mut u);
+            }
+            assert_eq!(buf.get_line(2).unwrap().id(), Some(3));
+        }
+
+        let mut buf = chan_buf(&dir);
+        buf.push_line(msg(3), &mut u);
+        assert_eq!(buf.get_line(0).unwrap().id(), Some(4));
+        assert_eq!(buf.get_line(-1).unwrap().id(), Some(3));
+        // Logged lines count as read, unless the session says otherwise.
+        assert_eq!(buf.unread(), 1);
+        buf.restore_read_id(1);
+        assert_eq!(buf.unread(), 3);
+    }
+
+    #[test]
+    fn disconnect_parts_channels() {
+        use common::line::User;
+        use network::BufferCmd;
+        use network::isupport::ISupport;
+        use super::Highlights;
+
+        let dir = TempDir::new("disconnect");
+        let mut buf = chan_buf(&dir);
+        let mut u = BaseUpdateHandle::<CoreBufMsg>::new();
+        let me = User { nick: "me".to_owned(), ident: "me".to_owned(), host: "host".to_owned() };
+        buf.handle_cmd(BufferCmd::JOIN(me, None), "me", &Highlights::default(),
+                       &ISupport::default(), time::now(), &mut u);
+        assert!(buf.joined());
+
+        buf.disconnected(&mut u);
+        assert!(!buf.joined());
+        assert_eq!(msg_text(&mut buf, 1), "Disconnected from server");
+        // Failed reconnects don't keep adding lines.
+        buf.disconnected(&mut u);
+        assert_eq!(buf.front_len(), 2);
+    }
+
+    #[test]
+    fn join_topic_line() {
+        use common::line::{LineData, User};
+        use network::BufferCmd;
+        use network::isupport::ISupport;
+        use super::Highlights;
+
+        let dir = TempDir::new("topic");
+        let mut buf = chan_buf(&dir);
+        let mut u = BaseUpdateHandle::<CoreBufMsg>::new();
+        let hl = Highlights::default();
+        let isup = ISupport::default();
+        buf.handle_cmd(BufferCmd::RPL_TOPIC("hello".to_owned()), "me", &hl, &isup, time::now(), &mut u);
+        assert_eq!(buf.front_len(), 0);
+        buf.handle_cmd(BufferCmd::RPL_TOPICWHOTIME("bob".to_owned(), 100), "me", &hl, &isup, time::now(), &mut u);
+        match buf.get_line(0).unwrap().data {
+            LineData::Topic { by: None, ref topic, set: Some((ref by, 100)) } => {
+                assert_eq!(topic, "hello");
+                assert_eq!(by, "bob");
+            },
+            ref d => panic!("Expected a topic line, got {:?}", d),
+        }
+
+        // Without `RPL_TOPICWHOTIME`, the topic shows up before the next line.
+        buf.handle_cmd(BufferCmd::RPL_TOPIC("again".to_owned()), "me", &hl, &isup, time::now(), &mut u);
+        let bob = User { nick: "bob".to_owned(), ident: "bob".to_owned(), host: "host".to_owned() };
+        buf.handle_cmd(BufferCmd::JOIN(bob, None), "me", &hl, &isup, time::now(), &mut u);
+        assert_eq!(buf.front_len(), 3);
+        match buf.get_line(1).unwrap().data {
+            LineData::Topic { set: None, ref topic, .. } => assert_eq!(topic, "again"),
+            ref d => panic!("Expected a topic line, got {:?}", d),
+        }
+    }
+
+    #[test]
+    fn private_online() {
+        let dir = TempDir::new("online");
+        let cfg = test_cfg(&dir);
+        let mut buf = Buffer::new("net".to_owned(), BufTarget::Private("bob".to_owned()), &cfg);
+        let mut u = BaseUpdateHandle::<CoreBufMsg>::new();
+        assert!(buf.online());
+        buf.set_online(true, &mut u);
+        assert_eq!(buf.front_len(), 0);
+
+        buf.set_online(false, &mut u);
+        assert!(!buf.online());
+        // We can still message users who are offline.
+        assert!(buf.joined());
+        assert_eq!(msg_text(&mut buf, 0), "bob is now offline");
+        buf.set_online(true, &mut u);
+        assert_eq!(msg_text(&mut buf, 1), "bob is now online");
     }
 }
@@ -1,13 +1,20 @@
-use std::fs::File;
-use std::io::Read;
-use std::path::Path;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::env;
+use std::path::{Path, PathBuf};
+use std::net::{SocketAddr, ToSocketAddrs};
 use std::collections::HashMap;
+use std::time::Duration;
 use std::default::Default;
 use toml;
 use toml::Parser;
 use rustc_serialize::Decodable;
+use rotor_irc::Encoding;
 
-use common::types::NetId;
+use common::types::{NetId, BufId};
+use common::conn::DEFAULT_MAX_MSG_SIZE;
+
+use buffer::{BufferConfig, Highlights};
 
 pub type UserId = String;
 
@@ -36,12 +43,142 @@ pub fn read_config(path: &Path) -> ChatConfig {
     }
 }
 
+/// Writes `cfg` to the config file at `path`.
+///
+/// The file is rewritten from scratch, so any comments in it are lost.
+pub fn write_config(path: &Path, cfg: &ChatConfig) -> io::Result<()> {
+    let s = toml::encode_str(cfg);
+    // Write to a temporary file first so a failed write doesn't leave us
+    // with half a config.
+    let tmp = path.with_extension("toml.tmp");
+    {
+        let mut f = try!(File::create(&tmp));
+        try!(f.write_all(s.as_bytes()));
+    }
+    fs::rename(&tmp, path)
+}
+
+/// Parses a network's settings from an inline TOML table, like
+/// `{ server = "irc.example.net", nick = "me", alt_nicks = [], channels = [] }`.
+///
+/// The keys are the same as in a network table in the config file.
+pub fn parse_net_config(s: &str) -> Result<NetConfig, String> {
+    let doc = format!("net = {}", s);
+    let mut parser = Parser::new(&doc);
+    match parser.parse().and_then(|mut t| t.remove("net")) {
+        Some(val @ toml::Value::Table(_)) => {
+            let mut dec = toml::Decoder::new(val);
+            NetConfig::decode(&mut dec).map_err(|e| format!("Invalid network settings: {}", e))
+        },
+        Some(_) => Err("Network settings must be a table".to_owned()),
+        None => {
+            let errs = parser.errors.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+            Err(format!("Failed to parse network settings: {}", errs.join("; ")))
+        },
+    }
+}
+
 
 #[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
 pub struct ChatConfig {
+    /// Settings for the core itself.
+    pub core: Option<CoreConfig>,
     pub user: HashMap<UserId, UserConfig>,
 }
 
+impl ChatConfig {
+    /// Returns the core settings, or the defaults if there's no `[core]`
+    /// section.
+    pub fn core(&self) -> CoreConfig {
+        self.core.clone().unwrap_or_default()
+    }
+}
+
+/// Settings for the core itself.
+#[derive(Debug, Clone, Default, RustcEncodable, RustcDecodable)]
+pub struct CoreConfig {
+    /// Address to listen for client connections on.
+    listen_host: Option<String>,
+    /// Port to listen for client connections on.
+    listen_port: Option<u16>,
+    /// Directory to store buffer logs in.
+    log_dir: Option<String>,
+    /// Number of recent lines each buffer keeps in memory.
+    buffer_lines: Option<usize>,
+    /// Largest message in bytes we'll send to or accept from clients.
+    max_message_size: Option<usize>,
+    /// Address to serve the JSON status page on.
+    status_host: Option<String>,
+    /// Port to serve the JSON status page on. The status page is disabled
+    /// unless this is set.
+    status_port: Option<u16>,
+    /// Message to quit networks with when the core shuts down.
+    quit_message: Option<String>,
+}
+
+impl CoreConfig {
+    pub fn listen_host(&self) -> &str {
+        self.listen_host.as_ref().map_or("127.0.0.1", |h| &h[..])
+    }
+    pub fn listen_port(&self) -> u16 { self.listen_port.unwrap_or(4242) }
+
+    /// Resolves the configured listen host and port to a socket address.
+    pub fn listen_addr(&self) -> Result<SocketAddr, String> {
+        let host = self.listen_host();
+        let port = self.listen_port();
+        let mut addrs = try!((host, port).to_socket_addrs()
+                             .map_err(|e| format!("Invalid listen address {}:{}: {}", host, port, e)));
+        addrs.next().ok_or(format!("Listen address {}:{} didn't resolve to anything", host, port))
+    }
+
+    /// Returns the directory buffer logs are stored in.
+    ///
+    /// Defaults to `$XDG_DATA_HOME/distirc/logs`, or
+    /// `~/.local/share/distirc/logs` if that isn't set. If we can't find a home
+    /// directory either, logs go in `logs` under the working directory.
+    pub fn log_dir(&self) -> PathBuf {
+        if let Some(ref dir) = self.log_dir {
+            return PathBuf::from(dir);
+        }
+        let data_dir = match env::var_os("XDG_DATA_HOME") {
+            Some(ref dir) if !dir.is_empty() => Some(PathBuf::from(dir)),
+            _ => env::home_dir().map(|h| h.join(".local").join("share")),
+        };
+        match data_dir {
+            Some(dir) => dir.join("distirc").join("logs"),
+            None => PathBuf::from("logs"),
+        }
+    }
+
+    pub fn buffer_lines(&self) -> usize { self.buffer_lines.unwrap_or(2000) }
+    pub fn max_message_size(&self) -> usize {
+        self.max_message_size.unwrap_or(DEFAULT_MAX_MSG_SIZE)
+    }
+
+    pub fn quit_message(&self) -> String {
+        self.quit_message.clone().unwrap_or("distirc shutting down".to_owned())
+    }
+
+    /// Returns the address to serve the status page on, or `None` if it's
+    /// disabled.
+    pub fn status_addr(&self) -> Option<Result<SocketAddr, String>> {
+        self.status_port.map(|port| {
+            let host = self.status_host.as_ref().map_or("127.0.0.1", |h| &h[..]);
+            let mut addrs = try!((host, port).to_socket_addrs()
+                                 .map_err(|e| format!("Invalid status address {}:{}: {}", host, port, e)));
+            addrs.next().ok_or(format!("Status address {}:{} didn't resolve to anything", host, port))
+        })
+    }
+
+    /// Returns the settings shared by all buffers.
+    pub fn buffer_config(&self) -> BufferConfig {
+        BufferConfig {
+            log_dir: self.log_dir(),
+            max_lines: self.buffer_lines(),
+        }
+    }
+}
+
 /// Represents the configuration for a user.
 #[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
 pub struct UserConfig {
@@ -49,24 +186,104 @@ pub struct UserConfig {
     /// Password to authenticate as this user.
     pub password: String,
     /// Command to run when there are no clients to send alerts to.
+    ///
+    /// `%m`, `%n`, `%b`, `%s` and `%k` are replaced with the alert's message,
+    /// network, buffer, sender and kind.
     pub alert_cmd: Option<String>,
+    /// Seconds to wait before running the alert command again for alerts
+    /// from the same place. Defaults to 60.
+    pub alert_interval: Option<u64>,
+    /// Names of buffers that belong to the user rather than a network.
+    pub global_bufs: Option<Vec<BufId>>,
+    /// Whether to answer CTCP queries like `VERSION` and `PING`.
+    pub ctcp_replies: Option<bool>,
+    /// Words besides the user's nick that should ping them.
+    pub highlight_words: Option<Vec<String>>,
+    /// Nicks whose messages never ping the user.
+    pub ignore: Option<Vec<String>>,
+}
+
+/// Global buffers created when a user doesn't configure any.
+const DEFAULT_GLOBAL_BUFS: &'static [&'static str] = &["notes"];
+
+impl UserConfig {
+    pub fn ctcp_replies(&self) -> bool { self.ctcp_replies.unwrap_or(true) }
+    pub fn alert_interval(&self) -> u64 { self.alert_interval.unwrap_or(60) }
+
+    /// Returns the user's highlight words and ignored nicks.
+    pub fn highlights(&self) -> Highlights {
+        Highlights::new(self.highlight_words.clone().unwrap_or_default(),
+                        self.ignore.clone().unwrap_or_default())
+    }
+
+    pub fn global_bufs(&self) -> Vec<BufId> {
+        match self.global_bufs {
+            Some(ref bufs) => bufs.clone(),
+            None => DEFAULT_GLOBAL_BUFS.iter().map(|b| (*b).to_owned()).collect(),
+        }
+    }
 }
 
 
+/// Capabilities requested when a network doesn't configure any.
+const DEFAULT_CAPS: &'static [&'static str] = &[
+    "server-time", "multi-prefix", "away-notify",
+    "extended-join", "account-tag", "account-notify",
+    "echo-message",
+];
+
 #[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
 pub struct NetConfig {
     nick: String,
     alt_nicks: Vec<String>,
     nickserv_pass: Option<String>,
+    /// Nick of the services bot to identify with. Defaults to `NickServ`.
+    nickserv_nick: Option<String>,
+    sasl_user: Option<String>,
+    sasl_pass: Option<String>,
     channels: Vec<String>,
+    /// Channels to rejoin after being kicked from them. `"*"` means every
+    /// channel.
+    auto_rejoin: Option<Vec<String>>,
     username: Option<String>,
     realname: Option<String>,
+    /// IRCv3 capabilities to request from the server.
+    caps: Option<Vec<String>>,
 
     // Server options
     server: String,
+    /// Other hostnames for this network, tried in turn after `server` when
+    /// connecting fails.
+    fallback_servers: Option<Vec<String>>,
     port: Option<u16>,
     password: Option<String>,
     use_ssl: Option<bool>,
+    /// Character encoding the network uses. Defaults to UTF-8.
+    encoding: Option<String>,
+    /// Whether to verify the server's TLS certificate and hostname. Turning
+    /// this off accepts any certificate at all.
+    tls_verify: Option<bool>,
+    /// Path to a PEM file with the certificate the server must present. Use
+    /// this for servers with self-signed certificates.
+    tls_cert: Option<String>,
+
+    // Flood protection options
+    /// Number of messages we can send at once before being throttled.
+    flood_burst: Option<u32>,
+    /// Milliseconds between messages once we're being throttled.
+    flood_interval: Option<u64>,
+    /// Seconds the server can be quiet before we send a `PING` to check the
+    /// connection is still alive. Defaults to 120.
+    ping_interval: Option<u64>,
+    /// Seconds to wait for the server to answer our `PING` before
+    /// reconnecting. Defaults to 60.
+    ping_timeout: Option<u64>,
+
+    // Reconnection options
+    /// Seconds to wait before the first reconnection attempt.
+    reconnect_delay: Option<u64>,
+    /// Maximum number of seconds to wait between reconnection attempts.
+    reconnect_max: Option<u64>,
 }
 
 impl NetConfig {
@@ -83,12 +300,70 @@ impl NetConfig {
     pub fn nickserv_pass(&self) -> Option<&str> {
         self.nickserv_pass.as_ref().map(|n| &n[..])
     }
+    pub fn nickserv_nick(&self) -> &str {
+        self.nickserv_nick.as_ref().map_or("NickServ", |n| &n[..])
+    }
+    /// Returns the SASL username and password if both are configured.
+    pub fn sasl(&self) -> Option<(&str, &str)> {
+        match (self.sasl_user.as_ref(), self.sasl_pass.as_ref()) {
+            (Some(user), Some(pass)) => Some((&user[..], &pass[..])),
+            _ => None,
+        }
+    }
+    pub fn caps(&self) -> Vec<String> {
+        match self.caps {
+            Some(ref caps) => caps.clone(),
+            None => DEFAULT_CAPS.iter().map(|c| (*c).to_owned()).collect(),
+        }
+    }
 
     pub fn server(&self) -> &str { &self.server }
-    pub fn port(&self) -> u16 { self.port.unwrap_or(6667) }
+    /// Returns the main server followed by any fallback servers.
+    pub fn servers(&self) -> Vec<String> {
+        let mut servers = vec![self.server.clone()];
+        if let Some(ref fallbacks) = self.fallback_servers {
+            servers.extend(fallbacks.iter().cloned());
+        }
+        servers
+    }
+    pub fn password(&self) -> Option<&str> {
+        self.password.as_ref().map(|p| &p[..])
+    }
+    pub fn port(&self) -> u16 {
+        self.port.unwrap_or(if self.use_ssl() { 6697 } else { 6667 })
+    }
+    pub fn encoding(&self) -> Encoding {
+        match self.encoding {
+            Some(ref name) => Encoding::from_name(name).unwrap_or_else(|| {
+                warn!("Unsupported encoding {}. Using UTF-8", name);
+                Encoding::Utf8
+            }),
+            None => Encoding::Utf8,
+        }
+    }
+    pub fn use_ssl(&self) -> bool { self.use_ssl.unwrap_or(false) }
+    pub fn tls_verify(&self) -> bool { self.tls_verify.unwrap_or(true) }
+    pub fn tls_cert(&self) -> Option<&str> {
+        self.tls_cert.as_ref().map(|p| &p[..])
+    }
+    pub fn flood_burst(&self) -> u32 { self.flood_burst.unwrap_or(5) }
+    pub fn flood_interval(&self) -> Duration {
+        Duration::from_millis(self.flood_interval.unwrap_or(2000))
+    }
+    pub fn ping_interval(&self) -> Duration {
+        Duration::from_secs(self.ping_interval.unwrap_or(120))
+    }
+    pub fn ping_timeout(&self) -> Duration {
+        Duration::from_secs(self.ping_timeout.unwrap_or(60))
+    }
+    pub fn reconnect_delay(&self) -> u64 { self.reconnect_delay.unwrap_or(5) }
+    pub fn reconnect_max(&self) -> u64 { self.reconnect_max.unwrap_or(300) }
     pub fn channels(&self) -> Vec<String> {
         self.channels.iter().map(|n| n.clone()).collect()
     }
+    pub fn auto_rejoin(&self) -> Vec<String> {
+        self.auto_rejoin.clone().unwrap_or(vec![])
+    }
 }
 
 
@@ -98,6 +373,11 @@ impl Default for UserConfig {
             net: HashMap::new(),
             password: String::new(),
             alert_cmd: None,
+            alert_interval: None,
+            global_bufs: None,
+            ctcp_replies: None,
+            highlight_words: None,
+            ignore: None,
         }
     }
 }
@@ -1,15 +1,18 @@
 use std::collections::HashMap;
 use rotor::Scope;
+use rotor_irc::Message;
 
-use common::conn::{Handler, Action};
+use common::conn::{Handler, Action, ProtocolError};
 use common::messages::{
-    BufTarget, CoreMsg, CoreNetMsg, CoreBufMsg,
-    ClientMsg, ClientNetMsg, ClientBufMsg,
+    NetId, BufId, BufTarget, BufferLine, CoreMsg, CoreNetMsg, CoreBufMsg,
+    ClientMsg, ClientNetMsg, ClientBufMsg, COMPRESSION_FEATURE,
 };
+use common::line::LineData;
 
-use state::{UserHandle, UserClientHandle};
-use config::UserId;
+use state::{UserHandle, UserClientHandle, AuthError, MissedBuf};
+use config::{UserId, parse_net_config};
 use network::IrcNetwork;
+use buffer::Buffer;
 use handle::{UpdateHandle, BaseUpdateHandle};
 
 use super::Context;
@@ -24,6 +27,8 @@ pub enum Client {
         uid: UserId,
         rx: UserClientHandle,
         bufs: HashMap<BufTarget, ClientBuf>,
+        /// Like `bufs`, but for the user's global buffers.
+        global_bufs: HashMap<BufId, ClientBuf>,
     },
 }
 
@@ -33,43 +38,111 @@ pub struct ClientBuf {
     last_sent_idx: isize,
 }
 
+impl ClientBuf {
+    /// Starts tracking a buffer the client already knows about, but has no
+    /// lines from. Scrollback starts from the newest line.
+    fn seeded(buf: &Buffer) -> ClientBuf {
+        ClientBuf { last_sent_idx: buf.front_len() }
+    }
+
+    /// Gets up to `count` lines of scrollback from `buf` that we haven't sent
+    /// the client yet, newest first.
+    fn fetch_lines(&mut self, buf: &mut Buffer, count: usize) -> Vec<BufferLine> {
+        let start = self.last_sent_idx - 1;
+        let mut lines = vec![];
+        for i in 0..count as isize {
+            if let Some(line) = buf.get_line(start - i) {
+                lines.push(line.clone());
+                self.last_sent_idx -= 1;
+            } else {
+                break;
+            }
+        }
+        lines
+    }
+}
+
 impl Client {
     fn handle_auth_msgs(msg: &ClientMsg, s: &mut Scope<Context>) -> Action<Self> {
         if let &ClientMsg::Authenticate(ref uid, ref pass) = msg {
             let notif = s.notifier();
-            let usr = match s.core.get_user_mut(uid) {
-                Some(u) => u,
-                None => {
-                    error!("Unknown user state: {}", uid);
-                    return Action::done();
-                },
-            };
-            if &usr.cfg.password == &pass.0 {
-                info!("Client authenticated successfully as {}", uid);
+            match s.core.authenticate(uid, &pass.0) {
+                Ok(usr) => {
+                    info!("Client authenticated successfully as {}", uid);
 
-                // Register our client with the user.
-                let rx = usr.register_client(notif);
+                    // Register our client with the user.
+                    let rx = usr.register_client(notif);
 
-                // Send the networks list.
-                let mut nets = vec![];
-                for (_nid, net) in usr.iter_nets() {
-                    nets.push(net.to_info());
-                }
+                    // Send the networks list.
+                    let mut nets = vec![];
+                    for (_nid, net) in usr.iter_nets() {
+                        nets.push(net.to_info());
+                    }
 
-                let me = Client::Connected {
-                    uid: uid.to_owned(),
-                    rx: rx,
-                    bufs: HashMap::new(),
-                };
-                Action::ok(me)
-                    .send(CoreMsg::AuthOk)
-                    .send(CoreMsg::Networks(nets))
-            } else {
-                Action::ok(Client::Authing).send(CoreMsg::AuthErr)
+                    // Replay lines that arrived while no clients were
+                    // connected. Scrollback requests pick up from the first
+                    // replayed line. Since clients count replayed lines as
+                    // unread, we follow them with the real unread count.
+                    let mut bufs = HashMap::new();
+                    let mut global_bufs = HashMap::new();
+                    let mut playback = vec![];
+                    for (key, start, lines) in usr.missed_lines() {
+                        let cb = ClientBuf { last_sent_idx: start };
+                        debug!("Replaying {} missed lines in {:?}", lines.len(), key);
+                        match key {
+                            MissedBuf::Net(nid, targ) => {
+                                bufs.insert(targ.clone(), cb);
+                                let unread = usr.get_net(&nid).and_then(|net| net.get_buf(&targ))
+                                    .map_or(0, |buf| buf.unread());
+                                let bmsg = CoreNetMsg::BufMsg(targ.clone(), CoreBufMsg::NewLines(lines));
+                                playback.push(CoreMsg::NetMsg(nid.clone(), bmsg));
+                                let bmsg = CoreNetMsg::BufMsg(targ, CoreBufMsg::Unread(unread));
+                                playback.push(CoreMsg::NetMsg(nid, bmsg));
+                            },
+                            MissedBuf::Global(bid) => {
+                                global_bufs.insert(bid.clone(), cb);
+                                let unread = usr.get_global_buf_mut(&bid).map_or(0, |buf| buf.unread());
+                                playback.push(CoreMsg::BufMsg(bid.clone(), CoreBufMsg::NewLines(lines)));
+                                playback.push(CoreMsg::BufMsg(bid, CoreBufMsg::Unread(unread)));
+                            },
+                        }
+                    }
+
+                    // The client gets every new line in the other buffers,
+                    // so their scrollback starts from the current line.
+                    for (_nid, net) in usr.iter_nets() {
+                        for buf in net.iter_bufs() {
+                            bufs.entry(buf.id().clone()).or_insert_with(|| ClientBuf::seeded(buf));
+                        }
+                    }
+                    for (bid, buf) in usr.iter_global_bufs() {
+                        global_bufs.entry(bid.clone()).or_insert_with(|| ClientBuf::seeded(buf));
+                    }
+
+                    let me = Client::Connected {
+                        uid: uid.to_owned(),
+                        rx: rx,
+                        bufs: bufs,
+                        global_bufs: global_bufs,
+                    };
+                    Action::ok(me)
+                        .send(CoreMsg::AuthOk { features: vec![COMPRESSION_FEATURE.to_owned()] })
+                        .send(CoreMsg::Networks(nets))
+                        .send(CoreMsg::GlobalBufs(usr.global_buf_infos()))
+                        .send_all(playback)
+                },
+                Err(AuthError::BadPassword) => {
+                    warn!("Client failed to authenticate as {}: wrong password", uid);
+                    Action::ok(Client::Authing).send(CoreMsg::AuthErr)
+                },
+                Err(e @ AuthError::UnknownUser) => {
+                    error!("Client tried to authenticate as unknown user {}. Dropping connection", uid);
+                    Action::error(Box::new(e))
+                },
             }
         } else {
-            error!("Client failed to send authentication request during auth phase. Aborting connection");
-            Action::done()
+            let e = ProtocolError(format!("Client sent {:?} before authenticating", msg));
+            Action::error(Box::new(e))
         }
     }
 }
@@ -93,15 +166,48 @@ impl Handler for Client {
             Client::Authing => {
                 Self::handle_auth_msgs(msg, s)
             },
-            Client::Connected { uid, rx, bufs } => {
-                let mut user = match s.core.get_user_mut(&uid) {
-                    Some(u) => u,
-                    None => {
-                        error!("Unknown user state: {}", uid);
-                        return Action::done();
-                    },
+            Client::Connected { uid, rx, bufs, global_bufs } => {
+                // Adding and removing networks needs the context too.
+                let res = match *msg {
+                    ClientMsg::AddNetwork(ref nid, ref cfg) => Some(add_network(&uid, nid, cfg, s)),
+                    ClientMsg::RemoveNetwork(ref nid) => Some(remove_network(&uid, nid, s)),
+                    _ => None,
+                };
+                if let Some(res) = res {
+                    let act = Action::ok(Client::Connected {
+                        uid: uid, rx: rx, bufs: bufs, global_bufs: global_bufs
+                    });
+                    return match res {
+                        Ok(()) => act,
+                        Err(e) => act.send(CoreMsg::Status(e)),
+                    };
+                }
+                let connect = match *msg {
+                    ClientMsg::NetMsg(ref nid, ClientNetMsg::Connect) => Some((nid, false, None)),
+                    ClientMsg::NetMsg(ref nid, ClientNetMsg::Reconnect(ref quit)) =>
+                        Some((nid, true, quit.clone())),
+                    _ => None,
                 };
-                Client::Connected { uid: uid, rx: rx, bufs: bufs }.handle_user_msg(msg, &mut user)
+                if let Some((nid, reconnect, quit)) = connect {
+                    connect_network(&uid, nid, reconnect, quit, s);
+                    return Action::ok(Client::Connected {
+                        uid: uid, rx: rx, bufs: bufs, global_bufs: global_bufs
+                    });
+                }
+                let act = {
+                    let mut user = match s.core.get_user_mut(&uid) {
+                        Some(u) => u,
+                        None => {
+                            error!("Unknown user state: {}", uid);
+                            return Action::error(Box::new(AuthError::UnknownUser));
+                        },
+                    };
+                    Client::Connected {
+                        uid: uid, rx: rx, bufs: bufs, global_bufs: global_bufs
+                    }.handle_user_msg(msg, &mut user)
+                };
+                s.save_session_if_changed();
+                act
             },
         }
     }
@@ -111,6 +217,10 @@ impl Handler for Client {
         unreachable!("Unexpected timeout")
     }
 
+    fn max_msg_size(s: &mut Scope<Self::Context>) -> usize {
+        s.max_msg_size
+    }
+
     fn wakeup(self, _s: &mut Scope<Self::Context>) -> Action<Self> {
         trace!("Client woke up");
         match self {
@@ -118,29 +228,17 @@ impl Handler for Client {
                 warn!("Client was woken up during authentication phase");
                 Action::ok(self)
             },
-            Client::Connected { uid, mut rx, bufs } => {
+            Client::Connected { uid, mut rx, mut bufs, global_bufs } => {
                 // Send new messages to the client.
                 let mut msgs = vec![];
                 while let Some(msg) = rx.recv() {
-                    // FIXME: This hack doesn't work. We need to find another way.
-                    // // This is hacky, but it's really the only way to catch when
-                    // // a client is told about a buffer. We do this so we can
-                    // // ensure that we set a buffer's last sent index to the
-                    // // appropriate line.
-                    // if let CoreMsg::NetMsg(ref nid, CoreNetMsg::Buffers(ref bs)) = msg {
-                    //     for buf in bs {
-                    //         bufs.insert(buf.id.clone(), ClientBuf {
-                    //             last_sent_idx: s.core.get_user(&uid).unwrap()
-                    //                 .get_net(&nid).unwrap()
-                    //                 .get_buf(&buf.id).unwrap()
-                    //                 .front_len(),
-                    //         });
-                    //     }
-                    // }
+                    track_new_bufs(&mut bufs, &msg);
                     trace!("Sending client message: {:?}", msg);
                     msgs.push(msg);
                 }
-                let mut a = Action::ok(Client::Connected{ uid: uid, rx: rx, bufs: bufs });
+                let mut a = Action::ok(Client::Connected {
+                    uid: uid, rx: rx, bufs: bufs, global_bufs: global_bufs
+                });
                 a = a.send_all(msgs.clone());
                 a
             }
@@ -148,6 +246,93 @@ impl Handler for Client {
     }
 }
 
+/// Starts tracking scrollback for any buffers `msg` tells the client about.
+///
+/// Buffers are announced as soon as they're created, before any lines are
+/// pushed to them, so the client gets all of their lines as `NewLines` and
+/// scrollback starts at index 0.
+fn track_new_bufs(bufs: &mut HashMap<BufTarget, ClientBuf>, msg: &CoreMsg) {
+    if let CoreMsg::NetMsg(_, CoreNetMsg::Buffers(ref infos)) = *msg {
+        for info in infos {
+            bufs.insert(info.id.clone(), ClientBuf { last_sent_idx: 0 });
+        }
+    }
+}
+
+/// Brings a network back online, spawning a connection if it doesn't have
+/// one. If `reconnect` is set, an existing connection is quit with `quit` so
+/// it reconnects.
+fn connect_network(uid: &UserId, nid: &NetId, reconnect: bool, quit: Option<String>, s: &mut Scope<Context>) {
+    let spawn = match s.core.get_user_mut(uid) {
+        Some(usr) => {
+            let mut uh = BaseUpdateHandle::<CoreMsg>::new();
+            let spawn = match usr.get_net_mut(nid) {
+                Some(net) => {
+                    let mut u = uh.wrap(|msg| CoreMsg::NetMsg(nid.clone(), msg));
+                    let spawn = net.set_online(&mut u);
+                    if !spawn && reconnect {
+                        info!("Reconnecting user {}'s network {} on client request", uid, nid);
+                        if let Err(e) = net.send_quit(quit, &mut u) {
+                            warn!("Failed to quit network {} to reconnect: {}", nid, e);
+                        }
+                    }
+                    spawn
+                },
+                None => false,
+            };
+            usr.exec_update_handle(uh);
+            spawn
+        },
+        None => false,
+    };
+    if spawn {
+        info!("Connecting user {}'s network {} on client request", uid, nid);
+        s.spawn_conn(uid.clone(), nid.clone());
+    }
+}
+
+/// Adds a network to the user's config from the given TOML settings, connects
+/// to it and saves the config.
+fn add_network(uid: &UserId, nid: &NetId, cfg: &str, s: &mut Scope<Context>) -> Result<(), String> {
+    let cfg = try!(parse_net_config(cfg));
+    {
+        let usr = try!(s.core.get_user_mut(uid).ok_or(format!("Unknown user {}", uid)));
+        try!(usr.add_net_cfg(nid.clone(), cfg));
+        let info = usr.get_net(nid).map(|net| net.to_info());
+        if let Some(info) = info {
+            usr.broadcast(&CoreMsg::Networks(vec![info]));
+        }
+    }
+    info!("Added network {} for user {}", nid, uid);
+    s.spawn_conn(uid.clone(), nid.clone());
+    s.save_config();
+    Ok(())
+}
+
+/// Disconnects from a network, removes it from the user's config and saves
+/// the config.
+fn remove_network(uid: &UserId, nid: &NetId, s: &mut Scope<Context>) -> Result<(), String> {
+    {
+        let usr = try!(s.core.get_user_mut(uid).ok_or(format!("Unknown user {}", uid)));
+        let mut net = try!(usr.remove_network(nid).ok_or(format!("There's no network called {}", nid)));
+        if net.is_connected() {
+            // Nobody is left to hear about the disconnect, so the update
+            // handle is thrown away. Queueing the `QUIT` wakes up the
+            // connection, which finds the network gone, sends the `QUIT` and
+            // waits for the server to close the link.
+            let mut u = BaseUpdateHandle::<CoreNetMsg>::new();
+            if let Err(e) = net.send_quit(None, &mut u) {
+                warn!("Failed to quit removed network {}: {}", nid, e);
+            }
+        }
+        usr.broadcast(&CoreMsg::NetworkRemoved(nid.clone()));
+    }
+    info!("Removed network {} for user {}", nid, uid);
+    s.save_config();
+    Ok(())
+}
+
+
 impl Client {
     fn handle_user_msg(self, msg: &ClientMsg, user: &mut UserHandle) -> Action<Self> {
         let mut uh = BaseUpdateHandle::<CoreMsg>::new();
@@ -159,13 +344,17 @@ impl Client {
                     Action::ok(self)
                 }
             },
-            ClientMsg::BufMsg(ref _bid, ref _msg) => {
-                warn!("Global buffer message routing unimplemented");
-                Action::ok(self)
+            ClientMsg::BufMsg(ref bid, ref msg) => {
+                if let Some(buf) = user.get_global_buf_mut(bid) {
+                    let mut u = uh.wrap(|msg| CoreMsg::BufMsg(bid.clone(), msg));
+                    self.handle_global_buf_msg(msg, buf, &mut u)
+                } else {
+                    warn!("Ignoring message for unknown global buffer {}. Message: {:?}", bid, msg);
+                    Action::ok(self)
+                }
             },
             ClientMsg::ListGlobalBufs => {
-                warn!("Global buffer message routing unimplemented");
-                Action::ok(self)
+                Action::ok(self).send(CoreMsg::GlobalBufs(user.global_buf_infos()))
             },
             ClientMsg::ListNets => {
                 let mut nets = vec![];
@@ -177,7 +366,19 @@ impl Client {
             ClientMsg::Authenticate(_, _) => {
                 error!("Authenticated client sent auth request. Ignoring.");
                 Action::ok(self)
-            }
+            },
+            ClientMsg::AddNetwork(..) | ClientMsg::RemoveNetwork(..) => {
+                // These are handled in `msg_recv`, since they need the context.
+                Action::ok(self)
+            },
+            ClientMsg::EnableCompression => {
+                debug!("Enabling compression for client");
+                Action::ok(self).send(CoreMsg::CompressionEnabled).compress()
+            },
+            ClientMsg::Ping(_) | ClientMsg::Pong(_) => {
+                // The connection answers these itself.
+                Action::ok(self)
+            },
         };
         user.exec_update_handle(uh);
         act
@@ -193,6 +394,11 @@ impl Client {
         let mut u = u.wrap(|msg| CoreMsg::NetMsg(nid.clone(), msg));
         match *msg {
             ClientNetMsg::BufMsg(ref targ, ref msg) => {
+                // Sending to a nick we haven't talked to yet starts a new
+                // conversation.
+                if let (&BufTarget::Private(ref nick), &ClientBufMsg::SendMsg(..)) = (targ, msg) {
+                    net.open_private(nick.clone(), &mut u);
+                }
                 if let Some(_) = net.get_buf(&targ) {
                     self.handle_buf_msg(msg, targ, net, &mut u)
                 } else {
@@ -204,8 +410,8 @@ impl Client {
                 warn!("ListBufs not implemented");
                 Action::ok(self)
             },
-            ClientNetMsg::JoinChan(ref chan) => {
-                if let Err(e) = net.send_join_chan(chan.clone(), &mut u) {
+            ClientNetMsg::JoinChan { ref chan, ref key } => {
+                if let Err(e) = net.send_join_chan(chan.clone(), key.clone(), &mut u) {
                     Action::ok(self).send(CoreMsg::Status(format!("Can't join channel: {}", e)))
                 } else {
                     Action::ok(self)
@@ -225,6 +431,80 @@ impl Client {
                     Action::ok(self)
                 }
             },
+            ClientNetMsg::SetAway(ref msg) => {
+                if let Err(e) = net.send_away(msg.clone(), &mut u) {
+                    Action::ok(self).send(CoreMsg::Status(format!("Can't set away: {}", e)))
+                } else {
+                    Action::ok(self)
+                }
+            },
+            ClientNetMsg::Disconnect(ref msg) => {
+                if let Err(e) = net.send_disconnect(msg.clone(), &mut u) {
+                    Action::ok(self).send(CoreMsg::Status(format!("Can't disconnect: {}", e)))
+                } else {
+                    Action::ok(self)
+                }
+            },
+            ClientNetMsg::Connect | ClientNetMsg::Reconnect(_) => {
+                // These are handled in `msg_recv`, since they need the context.
+                Action::ok(self)
+            },
+            ClientNetMsg::Whois(ref nick) => {
+                if let Err(e) = net.send_whois(nick.clone(), &mut u) {
+                    Action::ok(self).send(CoreMsg::Status(format!("Can't send whois: {}", e)))
+                } else {
+                    Action::ok(self)
+                }
+            },
+            ClientNetMsg::SetTopic(ref targ, ref topic) => {
+                if let Err(e) = net.send_topic(targ.clone(), topic.clone(), &mut u) {
+                    Action::ok(self).send(CoreMsg::Status(format!("Can't set topic: {}", e)))
+                } else {
+                    Action::ok(self)
+                }
+            },
+            ClientNetMsg::RefreshNames(ref targ) => {
+                if let Err(e) = net.send_names(targ.clone(), &mut u) {
+                    Action::ok(self).send(CoreMsg::Status(format!("Can't list users: {}", e)))
+                } else {
+                    Action::ok(self)
+                }
+            },
+            ClientNetMsg::CloseBuf(ref targ) => {
+                if net.remove_buf(targ, &mut u) {
+                    Action::ok(self)
+                } else {
+                    Action::ok(self).send(CoreMsg::Status(format!("Can't close buffer {}", targ.name())))
+                }
+            },
+            ClientNetMsg::ListChannels(ref filter) => {
+                if let Err(e) = net.send_list(filter.clone(), &mut u) {
+                    Action::ok(self).send(CoreMsg::Status(format!("Can't list channels: {}", e)))
+                } else {
+                    Action::ok(self)
+                }
+            },
+            ClientNetMsg::RawLine(ref line) => {
+                // A line break would let one raw line smuggle in several.
+                let parsed = if line.contains('\r') || line.contains('\n') {
+                    Err("Line contains a line break".to_owned())
+                } else {
+                    line.trim().parse::<Message>().map_err(|e| format!("{}", e))
+                };
+                match parsed {
+                    Ok(msg) => {
+                        info!("Sending raw line to network {}: {}", nid, line);
+                        if let Err(e) = net.send(msg, &mut u) {
+                            Action::ok(self).send(CoreMsg::Status(format!("Can't send raw line: {}", e)))
+                        } else {
+                            Action::ok(self)
+                        }
+                    },
+                    Err(e) => {
+                        Action::ok(self).send(CoreMsg::Status(format!("Invalid IRC line: {}", e)))
+                    },
+                }
+            },
         }
     }
 
@@ -247,9 +527,10 @@ impl Client {
             ClientBufMsg::FetchLogs(count) => {
                 let buf = net.get_buf_mut(targ).unwrap();
 
-                let (mut bufs, rx, uid) = if let Client::Connected { bufs, rx, uid } = self {
-                    (bufs, rx, uid)
-                } else { unreachable!(); };
+                let (mut bufs, rx, uid, global_bufs) =
+                    if let Client::Connected { bufs, rx, uid, global_bufs } = self {
+                        (bufs, rx, uid, global_bufs)
+                    } else { unreachable!(); };
 
                 let lines = {
                     let mut cb = bufs.entry(targ.clone()).or_insert_with(|| {
@@ -259,23 +540,156 @@ impl Client {
                             last_sent_idx: buf.front_len(),
                         }
                     });
-                    let start = cb.last_sent_idx - 1;
-                    let mut lines = vec![];
-                    for i in 0..count as isize {
-                        if let Some(line) = buf.get_line(start - i) {
-                            lines.push(line.clone());
-                            cb.last_sent_idx -= 1;
-                        } else {
-                            break;
-                        }
-                    }
-                    lines
+                    cb.fetch_lines(buf, count)
                 };
                 let nmsg = CoreNetMsg::BufMsg(buf.id().clone(), CoreBufMsg::Scrollback(lines));
                 Action::ok(Client::Connected {
-                    bufs: bufs, rx: rx, uid: uid
+                    bufs: bufs, rx: rx, uid: uid, global_bufs: global_bufs
                 }).send(CoreMsg::NetMsg(buf.nid().clone(), nmsg))
             },
+            ClientBufMsg::SearchLogs { ref query, limit } => {
+                let buf = net.get_buf_mut(targ).unwrap();
+                let lines = buf.search_logs(query, limit);
+                let nmsg = CoreNetMsg::BufMsg(buf.id().clone(), CoreBufMsg::SearchResults(lines));
+                Action::ok(self).send(CoreMsg::NetMsg(buf.nid().clone(), nmsg))
+            },
+            ClientBufMsg::MarkRead(line_id) => {
+                {
+                    let buf = net.get_buf_mut(targ).unwrap();
+                    let id = buf.id().clone();
+                    let mut buf_uh = u.wrap(|msg| CoreNetMsg::BufMsg(id.clone(), msg));
+                    buf.mark_read(line_id, &mut buf_uh);
+                }
+                // The read markers are kept in the session.
+                net.mark_session_changed();
+                Action::ok(self)
+            },
         }
     }
+
+    fn handle_global_buf_msg<U>(self,
+                                msg: &ClientBufMsg,
+                                buf: &mut Buffer,
+                                u: &mut U)
+                                -> Action<Self>
+        where U : UpdateHandle<CoreBufMsg>
+    {
+        let (bufs, rx, uid, mut global_bufs) =
+            if let Client::Connected { bufs, rx, uid, global_bufs } = self {
+                (bufs, rx, uid, global_bufs)
+            } else { unreachable!(); };
+
+        let act = match *msg {
+            ClientBufMsg::SendMsg(ref msg, ref kind) => {
+                // There's nobody on the other end of a global buffer, so we
+                // just post the message to it.
+                buf.push_line(LineData::Message {
+                    kind: kind.to_msg_kind(),
+                    from: uid.clone(),
+                    msg: msg.clone(),
+                }, u);
+                None
+            },
+            ClientBufMsg::FetchLogs(count) => {
+                let bid = buf.id().name().to_owned();
+                let lines = {
+                    let mut cb = global_bufs.entry(bid.clone()).or_insert_with(|| {
+                        ClientBuf {
+                            last_sent_idx: buf.front_len(),
+                        }
+                    });
+                    cb.fetch_lines(buf, count)
+                };
+                Some(CoreMsg::BufMsg(bid, CoreBufMsg::Scrollback(lines)))
+            },
+            ClientBufMsg::SearchLogs { ref query, limit } => {
+                let bid = buf.id().name().to_owned();
+                let lines = buf.search_logs(query, limit);
+                Some(CoreMsg::BufMsg(bid, CoreBufMsg::SearchResults(lines)))
+            },
+            ClientBufMsg::MarkRead(line_id) => {
+                buf.mark_read(line_id, u);
+                None
+            },
+        };
+        let a = Action::ok(Client::Connected {
+            bufs: bufs, rx: rx, uid: uid, global_bufs: global_bufs
+        });
+        match act {
+            Some(msg) => a.send(msg),
+            None => a,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use common::conn::Handler;
+    use common::line::LineData;
+    use common::messages::{BufferLine, ClientMsg, CoreMsg, CoreNetMsg, CoreBufMsg, Password};
+    use common::tempdir::TempDir;
+    use buffer::test_util::{test_cfg, msg, chan_buf};
+    use conn::test_util::TestScope;
+    use handle::BaseUpdateHandle;
+    use super::{Client, ClientBuf, track_new_bufs};
+
+    fn texts(lines: Vec<BufferLine>) -> Vec<String> {
+        lines.into_iter().map(|l| match l.data {
+            LineData::Message { msg, .. } => msg,
+            d => panic!("Expected a message, got {:?}", d),
+        }).collect()
+    }
+
+    #[test]
+    fn scrollback_after_join() {
+        let dir = TempDir::new("scrollback-join");
+        let mut buf = chan_buf(&dir);
+        let mut u = BaseUpdateHandle::<CoreBufMsg>::new();
+
+        // The client hears about the buffer, then gets its lines as they come.
+        let mut bufs = HashMap::new();
+        let announce = CoreMsg::NetMsg("net".to_owned(), CoreNetMsg::Buffers(vec![buf.as_info()]));
+        track_new_bufs(&mut bufs, &announce);
+        for i in 0..3 {
+            buf.push_line(msg(i), &mut u);
+        }
+
+        let cb = bufs.get_mut(buf.id()).unwrap();
+        assert!(cb.fetch_lines(&mut buf, 10).is_empty());
+    }
+
+    #[test]
+    fn scrollback_after_connect() {
+        let dir = TempDir::new("scrollback-connect");
+        let mut buf = chan_buf(&dir);
+        let mut u = BaseUpdateHandle::<CoreBufMsg>::new();
+        for i in 0..3 {
+            buf.push_line(msg(i), &mut u);
+        }
+
+        // A client connects, then receives some new lines.
+        let mut cb = ClientBuf::seeded(&buf);
+        for i in 3..5 {
+            buf.push_line(msg(i), &mut u);
+        }
+
+        assert_eq!(texts(cb.fetch_lines(&mut buf, 2)), vec!["2", "1"]);
+        assert_eq!(texts(cb.fetch_lines(&mut buf, 2)), vec!["0"]);
+        assert!(cb.fetch_lines(&mut buf, 2).is_empty());
+    }
+
+    #[test]
+    fn unknown_user_disconnected() {
+        let dir = TempDir::new("unknown-user");
+        let mut ts = TestScope::new(test_cfg(&dir));
+        let act = Client::create((), &mut ts.scope());
+        assert!(!act.closes());
+
+        let auth = ClientMsg::Authenticate("nobody".to_owned(), Password("hunter2".to_owned()));
+        let act = Client::Authing.msg_recv(&auth, &mut ts.scope());
+        assert!(act.closes());
+        assert!(act.sent().is_empty());
+    }
 }
@@ -1,15 +1,19 @@
 //! Manages a network's IRC connection
 
-use rotor::Scope;
-use rotor_irc::{Message, Command, IrcMachine, IrcAction};
+use std::cmp;
+use std::time::{Duration, Instant};
+use rotor::{Scope, Time};
+use rustc_serialize::base64::{ToBase64, STANDARD};
+use rotor_irc::{Message, Command, IrcMachine, IrcAction, Encoding};
 
 use common::types::NetId;
-use common::messages::CoreMsg;
+use common::messages::{CoreMsg, CoreNetMsg};
 
-use conn::Context;
+use conn::{Context, ConnSpawner, ServerAddrs};
+use conn::throttle::Throttle;
 use config::UserId;
 use handle::{UpdateHandle, BaseUpdateHandle};
-use network::IrcSendRx;
+use network::{IrcNetwork, IrcSendRx};
 
 /// Gets a user from the scope or closes the connection.
 macro_rules! try_usr {
@@ -49,16 +53,83 @@ pub struct IrcNetConn {
     nid: NetId,
     rx: IrcSendRx,
     state: NetConnState,
+    /// The server's addresses we haven't tried yet. If we can't connect to
+    /// this one, we move on to these.
+    addrs: ServerAddrs,
+    /// True once we've received anything from the server.
+    got_data: bool,
+    /// Capabilities advertised by the server so far in response to `CAP LS`.
+    cap_ls: Vec<String>,
+    /// True if we've successfully authenticated with SASL.
+    sasl_done: bool,
+    /// Number of times our nick has been rejected as in use during
+    /// registration.
+    nick_attempts: usize,
+    /// The network's configured character encoding.
+    encoding: Encoding,
+    /// Rate limits messages the user sends.
+    throttle: Throttle,
+    /// Time at which we give up on CAP negotiation.
+    reg_deadline: Option<Time>,
+    /// Time at which we give up on SASL authentication. This starts when we
+    /// send `AUTHENTICATE PLAIN`.
+    sasl_deadline: Option<Time>,
+    /// When we last received anything from the server.
+    last_recv: Time,
+    /// How long the server can be quiet before we check on it with a `PING`.
+    ping_interval: Duration,
+    /// How long we wait for a `PONG` before giving up on the connection.
+    ping_timeout: Duration,
+    /// Token of the keepalive `PING` we're waiting on a reply to, and when
+    /// we sent it.
+    ping_sent: Option<(String, Time)>,
+    /// Number of keepalive `PING`s sent, used to make tokens.
+    pings: u32,
+    /// When we next ask the server which of the users we have private
+    /// buffers with are online.
+    next_presence_poll: Time,
+    /// When we next rejoin a channel we were kicked from, if we're waiting
+    /// to rejoin any.
+    rejoin_at: Option<Time>,
     // Identification string printed in log messages.
     log_id: String,
 }
 
+/// Number of seconds to wait for the server to finish capability negotiation
+/// before falling back to plain registration.
+const CAP_TIMEOUT_SECS: u64 = 10;
+
+/// Number of seconds to wait for the server to finish SASL authentication
+/// before giving up on the connection.
+const SASL_TIMEOUT_SECS: u64 = 30;
+
+/// Number of seconds between checks on whether the users we have private
+/// buffers with are online.
+const PRESENCE_POLL_SECS: u64 = 60;
+
+/// Number of underscores we'll try appending to our nick once we've run out of
+/// alternate nicks.
+const MAX_NICK_UNDERSCORES: usize = 3;
+
+/// Maximum length of a single `AUTHENTICATE` payload.
+const SASL_CHUNK_LEN: usize = 400;
+
+/// Number of seconds to wait for the server to close the connection after the
+/// network is removed and we've sent its `QUIT`.
+const QUIT_TIMEOUT_SECS: u64 = 5;
+
 /// This enum represents the connection's various states of inititialization.
 ///
 /// If, for example, the state is `Identifying`, the connection state machine
 /// will wait for `RPL_MYINFO`, authenticate with `NickServ`, and go into the
 /// `Authing` state.
 enum NetConnState {
+    /// Waiting for the server to list its capabilities and acknowledge the
+    /// ones we requested. Registration is held until we send `CAP END`.
+    CapNegotiating,
+    /// The server acknowledged `sasl` and we're authenticating with `PLAIN`.
+    /// If authentication fails, the connection is closed.
+    SaslAuthing,
     /// Waiting for the server to respond to our `USER` and `NICK` messages.
     /// This waits for `RPL_MYINFO` and then auths with `NickServ` if
     /// applicable.
@@ -69,24 +140,208 @@ enum NetConnState {
     // /// We're waiting on joining initial channels.
     // Joining,
     Connected,
+    /// The network was removed. We've sent everything it queued, ending with
+    /// its `QUIT`, and are waiting until the given time for the server to
+    /// close the connection.
+    Quitting(Time),
+}
+
+impl IrcNetConn {
+    /// Handles a `CAP` message received at `now` during capability
+    /// negotiation.
+    fn handle_cap(&mut self, msg: &Message, net: &mut IrcNetwork, now: Time, msgs: &mut Vec<Message>) {
+        let sub = msg.args.get(1).map_or("", |s| &s[..]);
+        let caps: Vec<String> = msg.body.as_ref().map_or(vec![], |b| {
+            b.split_whitespace().map(|c| c.to_owned()).collect()
+        });
+        match sub {
+            "LS" => {
+                // Version 302 replies may include values, e.g., `sasl=PLAIN`.
+                self.cap_ls.extend(caps.iter().map(|c| c.split('=').next().unwrap().to_owned()));
+                // A `*` before the list means there are more lines to come.
+                if msg.args.get(2).map_or(false, |a| a == "*") {
+                    return;
+                }
+                let mut wanted = net.cfg.caps();
+                if net.cfg.sasl().is_some() && !wanted.iter().any(|c| c == "sasl") {
+                    wanted.push("sasl".to_owned());
+                }
+                let req: Vec<String> = wanted.into_iter()
+                    .filter(|c| self.cap_ls.contains(c))
+                    .collect();
+                if req.is_empty() {
+                    info!("{}: No supported capabilities to request", &self.log_id);
+                    self.end_cap(msgs);
+                } else {
+                    info!("{}: Requesting capabilities: {}", &self.log_id, req.join(" "));
+                    msgs.push(cap_msg("REQ", vec![], Some(req.join(" "))));
+                }
+            },
+            "ACK" => {
+                info!("{}: Server acknowledged capabilities: {}", &self.log_id, caps.join(" "));
+                let sasl = caps.iter().any(|c| c == "sasl");
+                net.set_caps(caps);
+                if sasl && net.cfg.sasl().is_some() {
+                    info!("{}: Authenticating with SASL", &self.log_id);
+                    msgs.push(Message::new(None, Command::Other("AUTHENTICATE".to_owned()),
+                                           vec!["PLAIN".to_owned()], None));
+                    self.state = NetConnState::SaslAuthing;
+                    // Negotiation is done, so SASL gets its own deadline.
+                    self.reg_deadline = None;
+                    self.sasl_deadline = Some(now + Duration::from_secs(SASL_TIMEOUT_SECS));
+                } else {
+                    self.end_cap(msgs);
+                }
+            },
+            "NAK" => {
+                warn!("{}: Server rejected capabilities: {}", &self.log_id, caps.join(" "));
+                self.end_cap(msgs);
+            },
+            _ => debug!("{}: Ignoring CAP {} during negotiation", &self.log_id, sub),
+        }
+    }
+
+    /// Sends our SASL `PLAIN` credentials in response to the server's `+`
+    /// challenge.
+    fn send_sasl_plain(&self, net: &IrcNetwork, msgs: &mut Vec<Message>) {
+        let (user, pass) = match net.cfg.sasl() {
+            Some(creds) => creds,
+            None => return,
+        };
+        let payload = format!("\0{}\0{}", user, pass).as_bytes().to_base64(STANDARD);
+        // Payloads are split into 400 byte chunks. If the last chunk is
+        // exactly 400 bytes long, an empty `+` message marks the end.
+        let chunks: Vec<_> = payload.as_bytes().chunks(SASL_CHUNK_LEN).collect();
+        for chunk in chunks.iter() {
+            let chunk = String::from_utf8_lossy(chunk).into_owned();
+            msgs.push(Message::new(None, Command::Other("AUTHENTICATE".to_owned()), vec![chunk], None));
+        }
+        if chunks.last().map_or(true, |c| c.len() == SASL_CHUNK_LEN) {
+            msgs.push(Message::new(None, Command::Other("AUTHENTICATE".to_owned()),
+                                   vec!["+".to_owned()], None));
+        }
+    }
+
+    /// Picks the next nick to try after the server told us our nick is in use
+    /// and sends a `NICK` for it.
+    ///
+    /// Alternate nicks from the config are tried first, followed by the main
+    /// nick with underscores appended. Returns false if we've run out of nicks
+    /// to try.
+    fn try_next_nick(&mut self, net: &IrcNetwork, msgs: &mut Vec<Message>) -> bool {
+        let alts = net.cfg.alt_nicks();
+        let attempt = self.nick_attempts;
+        self.nick_attempts += 1;
+        let nick = if attempt < alts.len() {
+            alts[attempt].clone()
+        } else if attempt - alts.len() < MAX_NICK_UNDERSCORES {
+            let count = attempt - alts.len() + 1;
+            format!("{}{}", net.cfg.nick(), ::std::iter::repeat('_').take(count).collect::<String>())
+        } else {
+            error!("{}: All nicks are in use. Giving up", &self.log_id);
+            return false;
+        };
+        info!("{}: Nick in use. Trying {}", &self.log_id, nick);
+        msgs.push(Message::cmd(Command::NICK).arg(nick));
+        true
+    }
+
+    /// Sends the given messages and sets a deadline for when the next
+    /// throttled message can be sent, registration or SASL times out, the
+    /// keepalive needs checking, we next poll for online users or we rejoin a
+    /// channel, whichever comes first.
+    fn throttled_action(self, msgs: Vec<Message>, scope: &mut Scope<Context>) -> IrcAction<Self> {
+        let send_at = self.throttle.wait(Instant::now()).map(|wait| scope.now() + wait);
+        let mut deadline = cmp::min(self.keepalive_deadline(), self.next_presence_poll);
+        let deadlines = self.reg_deadline.into_iter().chain(self.sasl_deadline).chain(self.rejoin_at);
+        for time in send_at.into_iter().chain(deadlines) {
+            deadline = cmp::min(deadline, time);
+        }
+        IrcAction::ok(self).send_all(msgs).deadline(deadline)
+    }
+
+    /// Returns when we should next send a keepalive `PING`, or give up
+    /// waiting for the `PONG` if we've sent one.
+    fn keepalive_deadline(&self) -> Time {
+        match self.ping_sent {
+            Some((_, sent)) => sent + self.ping_timeout,
+            None => self.last_recv + self.ping_interval,
+        }
+    }
+
+    /// Checks whether `msg` answers our keepalive `PING`.
+    fn is_ping_reply(&self, msg: &Message) -> bool {
+        match self.ping_sent {
+            Some((ref token, _)) if msg.command == Command::PONG => {
+                msg.body.as_ref().or(msg.args.last()) == Some(token)
+            },
+            _ => false,
+        }
+    }
+
+    /// Closes the connection and tells the network, so it reconnects.
+    fn close(self, scope: &mut Scope<Context>) -> IrcAction<Self> {
+        self.disconnect(scope);
+        IrcAction::close()
+    }
+
+    /// Ends capability negotiation and continues registration.
+    fn end_cap(&mut self, msgs: &mut Vec<Message>) {
+        msgs.push(cap_msg("END", vec![], None));
+        self.state = NetConnState::Identifying;
+    }
+
+    /// Called once we've received `RPL_WELCOME` addressed to `nick`.
+    /// Authenticates with `NickServ` if applicable, or joins channels.
+    fn registered<U>(&mut self, net: &mut IrcNetwork, nick: &str, u: &mut U, msgs: &mut Vec<Message>)
+        where U : UpdateHandle<CoreNetMsg>
+    {
+        net.reset_reconnects();
+        net.push_status(format!("Registered as {}", nick), u);
+        if self.sasl_done {
+            info!("{}: Authenticated with SASL. Joining channels", &self.log_id);
+            msgs.extend(net.autojoin_msg());
+            self.state = NetConnState::Connected;
+        } else if let Some(pass) = net.cfg.nickserv_pass() {
+            info!("{}: Authenticating with NickServ", &self.log_id);
+            msgs.push(Message::privmsg(net.cfg.nickserv_nick(), format!("identify {}", pass)));
+            self.state = NetConnState::Authing;
+        } else {
+            info!("{}: No NickServ auth. Joining channels", &self.log_id);
+            // If we don't have a `NickServ` password, skip straight
+            // to joining channels.
+            msgs.extend(net.autojoin_msg());
+            self.state = NetConnState::Connected;
+        }
+    }
+}
+
+/// Builds a `CAP` message with the given subcommand.
+fn cap_msg(sub: &str, mut args: Vec<String>, body: Option<String>) -> Message {
+    args.insert(0, sub.to_owned());
+    Message::new(None, Command::Other("CAP".to_owned()), args, body)
 }
 
 impl IrcMachine for IrcNetConn {
     type Context = Context;
-    type Seed = (UserId, NetId);
+    type Seed = (UserId, NetId, ServerAddrs);
 
-    fn create(seed: (UserId, NetId), scope: &mut Scope<Self::Context>) -> IrcAction<Self> {
-        let (uid, nid) = seed;
+    fn create(seed: Self::Seed, scope: &mut Scope<Self::Context>) -> IrcAction<Self> {
+        let (uid, nid, addrs) = seed;
         let log_id = format!("{}.{}", uid, nid);
         debug!("{}: Starting IRC connection", &log_id);
 
         let notif = scope.notifier();
         let usr = try_usr!(&log_id, scope, &uid);
         let mut u = BaseUpdateHandle::<CoreMsg>::new();
-        let (rx, nname, uname, rname) = {
+        let (rx, nname, uname, rname, pass, encoding, throttle, ping_interval, ping_timeout) = {
             let mut net = try_net!(&log_id, usr, &nid);
             let rx = net.register_conn(notif, &mut u);
-            (rx, net.cfg.nick().to_owned(), net.cfg.username().to_owned(), net.cfg.realname().to_owned())
+            net.push_status("Connected".to_owned(), &mut u.wrap(|m| CoreMsg::NetMsg(nid.clone(), m)));
+            let throttle = Throttle::new(net.cfg.flood_burst(), net.cfg.flood_interval(), Instant::now());
+            (rx, net.initial_nick().to_owned(), net.cfg.username().to_owned(), net.cfg.realname().to_owned(),
+             net.cfg.password().map(|p| p.to_owned()), net.cfg.encoding(), throttle,
+             net.cfg.ping_interval(), net.cfg.ping_timeout())
         };
         usr.exec_update_handle(u);
 
@@ -94,121 +349,271 @@ impl IrcMachine for IrcNetConn {
             uid: uid,
             nid: nid,
             rx: rx,
-            state: NetConnState::Identifying,
+            state: NetConnState::CapNegotiating,
+            addrs: addrs,
+            got_data: false,
+            cap_ls: vec![],
+            sasl_done: false,
+            nick_attempts: 0,
+            encoding: encoding,
+            throttle: throttle,
+            reg_deadline: Some(scope.now() + Duration::from_secs(CAP_TIMEOUT_SECS)),
+            sasl_deadline: None,
+            last_recv: scope.now(),
+            ping_interval: ping_interval,
+            ping_timeout: ping_timeout,
+            ping_sent: None,
+            pings: 0,
+            next_presence_poll: scope.now() + Duration::from_secs(PRESENCE_POLL_SECS),
+            rejoin_at: None,
             log_id: log_id,
         };
         info!("{}: Started IRC connection", &state.log_id);
-        IrcAction::ok(state)
-            .send(Message {
-                prefix: None,
-                command: Command::USER,
-                args: vec![uname, "0".to_owned(), "*".to_owned()],
-                body: Some(rname),
-            })
-            .send(Message {
-                prefix: None,
-                command: Command::NICK,
-                args: vec![nname],
-                body: None,
-            })
+        let deadline = state.reg_deadline.unwrap();
+        let mut act = IrcAction::ok(state)
+            .deadline(deadline)
+            .send(cap_msg("LS", vec!["302".to_owned()], None));
+        // The server password has to be sent before registering.
+        if let Some(pass) = pass {
+            act = act.send(Message::cmd(Command::PASS).arg(pass));
+        }
+        act.send(Message::cmd(Command::USER).args(vec![uname, "0".to_owned(), "*".to_owned()])
+                                            .body(rname))
+            .send(Message::cmd(Command::NICK).arg(nname))
     }
 
     fn recv(mut self, msg: Message, scope: &mut Scope<Self::Context>) -> IrcAction<Self> {
         debug!("{}: Received message: {}", &self.log_id, msg);
-        let usr = try_usr!(&self.log_id, scope, &self.uid);
+        if let NetConnState::Quitting(_) = self.state {
+            // There's no network left to handle anything but the server's
+            // goodbye.
+            return if msg.command == Command::ERROR { IrcAction::close() } else { IrcAction::ok(self) };
+        }
+        self.last_recv = scope.now();
+        self.got_data = true;
+        if self.is_ping_reply(&msg) {
+            trace!("{}: Got keepalive PONG", &self.log_id);
+            self.ping_sent = None;
+        }
         let mut msgs = vec![];
-        let mut u = BaseUpdateHandle::<CoreMsg>::new();
+        // The server closes the link after sending ERROR, so we may as well
+        // close our end now rather than wait for the socket to drop.
+        let mut abort = msg.command == Command::ERROR;
+        {
+            let usr = try_usr!(&self.log_id, scope, &self.uid);
+            let mut u = BaseUpdateHandle::<CoreMsg>::new();
 
-        if let Message { command: Command::PING, args, body, .. } = msg {
-            debug!("Sending pong: {:?} {:?}", args, body);
-            msgs.push(Message {
-                prefix: None,
-                command: Command::PONG,
-                args: args,
-                body: body,
-            });
-        } else {
-            use rotor_irc::Response::*;
-            let mut net = try_net!(&self.log_id, usr, &self.nid);
-            let nid = self.nid.clone();
-
-            // TODO: Implement SASL authentication
-            match self.state {
-                NetConnState::Identifying => {
-                    net.handle_msg(msg.clone(), &mut u.wrap(|msg| CoreMsg::NetMsg(nid.clone(), msg)));
-                    if let Message { command: Command::Response(RPL_WELCOME), .. } = msg {
-                        if let Some(pass) = net.cfg.nickserv_pass() {
-                            info!("{}: Authenticating with NickServ", &self.log_id);
-                            msgs.push(Message {
-                                prefix: None,
-                                command: Command::PRIVMSG,
-                                args: vec!["NickServ".to_owned()], // TODO: Allow configuring `NickServ`'s nick
-                                body: Some(format!("identify {}", pass)),
-                            });
-                            self.state = NetConnState::Authing;
+            if let Message { command: Command::PING, args, body, .. } = msg {
+                debug!("Sending pong: {:?} {:?}", args, body);
+                // The server drops us if we take too long to answer, so this
+                // goes ahead of anything else we're waiting to send.
+                self.throttle.push_front(Message::new(None, Command::PONG, args, body));
+            } else {
+                use rotor_irc::Response::*;
+                let mut net = try_net!(&self.log_id, usr, &self.nid);
+                let nid = self.nid.clone();
+
+                let registering = match self.state {
+                    NetConnState::CapNegotiating |
+                    NetConnState::SaslAuthing |
+                    NetConnState::Identifying => true,
+                    _ => false,
+                };
+                if registering && msg.command == Command::Response(ERR_NICKNAMEINUSE) {
+                    if !self.try_next_nick(net, &mut msgs) {
+                        abort = true;
+                    }
+                }
+
+                match self.state {
+                    NetConnState::CapNegotiating => {
+                        if msg.command == Command::Other("CAP".to_owned()) {
+                            let now = self.last_recv;
+                            self.handle_cap(&msg, net, now, &mut msgs);
                         } else {
-                            info!("{}: No NickServ auth. Joining channels", &self.log_id);
-                            // If we don't have a `NickServ` password, skip straight
-                            // to joining channels.
-                            msgs.push(Message {
-                                prefix: None,
-                                command: Command::JOIN,
-                                args: vec![net.cfg.channels().join(",")],
-                                body: None,
-                            });
+                            net.handle_msg(msg.clone(), &mut u.wrap(|msg| CoreMsg::NetMsg(nid.clone(), msg)));
+                            match msg {
+                                Message { command: Command::Response(ERR_UNKNOWNCOMMAND), ref args, .. }
+                                if args.get(1).map_or(false, |c| c == "CAP") => {
+                                    info!("{}: Server doesn't support CAP. Continuing registration", &self.log_id);
+                                    self.state = NetConnState::Identifying;
+                                },
+                                Message { command: Command::Response(RPL_WELCOME), ref args, .. } => {
+                                    info!("{}: Registered without finishing CAP negotiation", &self.log_id);
+                                    let nick = args.get(0).map_or("", |n| &n[..]);
+                                    self.registered(net, nick, &mut u.wrap(|msg| CoreMsg::NetMsg(nid.clone(), msg)),
+                                                    &mut msgs);
+                                },
+                                _ => {},
+                            }
+                        }
+                    },
+                    NetConnState::SaslAuthing => {
+                        match msg {
+                            Message { command: Command::Other(ref cmd), ref args, .. }
+                            if cmd == "AUTHENTICATE" && args.get(0).map_or(false, |a| a == "+") => {
+                                self.send_sasl_plain(net, &mut msgs);
+                            },
+                            Message { command: Command::Response(RPL_SASLSUCCESS), .. } => {
+                                info!("{}: SASL authentication succeeded", &self.log_id);
+                                self.sasl_done = true;
+                                self.sasl_deadline = None;
+                                self.end_cap(&mut msgs);
+                            },
+                            Message { command: Command::Response(ERR_SASLFAIL), ref body, .. } |
+                            Message { command: Command::Response(ERR_SASLTOOLONG), ref body, .. } => {
+                                error!("{}: SASL authentication failed: {}", &self.log_id,
+                                       body.as_ref().map_or("", |b| &b[..]));
+                                abort = true;
+                            },
+                            _ => {},
+                        }
+                        net.handle_msg(msg, &mut u.wrap(|msg| CoreMsg::NetMsg(nid.clone(), msg)));
+                    },
+                    NetConnState::Identifying => {
+                        net.handle_msg(msg.clone(), &mut u.wrap(|msg| CoreMsg::NetMsg(nid.clone(), msg)));
+                        if let Message { command: Command::Response(RPL_WELCOME), ref args, .. } = msg {
+                            let nick = args.get(0).map_or("", |n| &n[..]);
+                            self.registered(net, nick, &mut u.wrap(|msg| CoreMsg::NetMsg(nid.clone(), msg)),
+                                            &mut msgs);
+                        }
+                    },
+                    NetConnState::Authing => {
+                        net.handle_msg(msg.clone(), &mut u.wrap(|msg| CoreMsg::NetMsg(nid.clone(), msg)));
+                        // FIXME: Maybe we should do something more fancy than just
+                        // waiting for any NOTICE from NickServ.
+                        if let Message { command: Command::NOTICE, body: Some(body), .. } = msg {
+                            info!("{}: NickServ authentication finished. Reply: {}", &self.log_id, body);
+                            msgs.extend(net.autojoin_msg());
                             self.state = NetConnState::Connected;
                         }
                     }
-                },
-                NetConnState::Authing => {
-                    net.handle_msg(msg.clone(), &mut u.wrap(|msg| CoreMsg::NetMsg(nid.clone(), msg)));
-                    // FIXME: Maybe we should do something more fancy than just
-                    // waiting for any NOTICE from NickServ.
-                    if let Message { command: Command::NOTICE, body: Some(body), .. } = msg {
-                        info!("{}: NickServ authentication finished. Reply: {}", &self.log_id, body);
-                        msgs.push(Message {
-                            prefix: None,
-                            command: Command::JOIN,
-                            args: vec![net.cfg.channels().join(",")],
-                            body: None,
-                        });
-                        self.state = NetConnState::Connected;
-                    }
+                    NetConnState::Connected => {
+                        trace!("{}: Handling message as connected", &self.log_id);
+                        net.handle_msg(msg, &mut u.wrap(|msg| CoreMsg::NetMsg(nid.clone(), msg)));
+                        // We may have been kicked from a channel we rejoin.
+                        let now = self.last_recv;
+                        self.rejoin_at = net.next_rejoin().map(|wait| now + wait);
+                    },
+                    // Handled above.
+                    NetConnState::Quitting(_) => unreachable!(),
                 }
-                NetConnState::Connected => {
-                    trace!("{}: Handling message as connected", &self.log_id);
-                    net.handle_msg(msg, &mut u.wrap(|msg| CoreMsg::NetMsg(nid.clone(), msg)));
-                },
             }
+            usr.exec_update_handle(u);
         }
-        usr.exec_update_handle(u);
-        for msg in msgs.iter() {
-            debug!("{}: Sending message: {}", &self.log_id, msg);
+        scope.save_session_if_changed();
+        if abort {
+            return self.close(scope);
+        }
+        for msg in msgs {
+            debug!("{}: Queueing message: {}", &self.log_id, msg);
+            self.throttle.push(msg);
         }
-        IrcAction::ok(self).send_all(msgs)
+        let msgs = self.throttle.take(Instant::now());
+        self.throttled_action(msgs, scope)
     }
 
-    fn wakeup(mut self, _s: &mut Scope<Self::Context>) -> IrcAction<Self> {
+    fn timeout(mut self, scope: &mut Scope<Self::Context>) -> IrcAction<Self> {
         let mut msgs = vec![];
+        let now = scope.now();
+        if let NetConnState::Quitting(dline) = self.state {
+            if dline <= now {
+                info!("{}: Server didn't close the connection after QUIT. Closing it", &self.log_id);
+                return IrcAction::close();
+            }
+            return IrcAction::ok(self).deadline(dline);
+        }
+        if self.reg_deadline.map_or(false, |t| t <= now) {
+            self.reg_deadline = None;
+            if let NetConnState::CapNegotiating = self.state {
+                warn!("{}: Timed out waiting for CAP negotiation. Continuing registration", &self.log_id);
+                self.end_cap(&mut msgs);
+            }
+        }
+        if self.sasl_deadline.map_or(false, |t| t <= now) {
+            self.sasl_deadline = None;
+            if let NetConnState::SaslAuthing = self.state {
+                // Like a failed authentication, this closes the connection,
+                // but we end negotiation first so the server isn't left
+                // waiting on us.
+                error!("{}: Timed out waiting for SASL authentication", &self.log_id);
+                return IrcAction::ok(self).send(cap_msg("END", vec![], None)).then_close();
+            }
+        }
+        if self.keepalive_deadline() <= now {
+            if self.ping_sent.is_some() {
+                error!("{}: Server didn't answer our PING. Closing the connection", &self.log_id);
+                return self.close(scope);
+            }
+            self.pings += 1;
+            let token = format!("distirc-{}", self.pings);
+            debug!("{}: Server has been quiet. Sending PING {}", &self.log_id, token);
+            self.throttle.push_front(Message::cmd(Command::PING).body(token.clone()));
+            self.ping_sent = Some((token, now));
+        }
+        if self.next_presence_poll <= now {
+            self.next_presence_poll = now + Duration::from_secs(PRESENCE_POLL_SECS);
+            if let NetConnState::Connected = self.state {
+                let usr = try_usr!(&self.log_id, scope, &self.uid);
+                let net = try_net!(&self.log_id, usr, &self.nid);
+                msgs.extend(net.presence_poll());
+            }
+        }
+        if self.rejoin_at.map_or(false, |t| t <= now) {
+            self.rejoin_at = None;
+            if let NetConnState::Connected = self.state {
+                let usr = try_usr!(&self.log_id, scope, &self.uid);
+                let net = try_net!(&self.log_id, usr, &self.nid);
+                msgs.extend(net.due_rejoins());
+                self.rejoin_at = net.next_rejoin().map(|wait| now + wait);
+            }
+        }
+        // Otherwise, this deadline was set so we could send throttled messages.
+        for msg in msgs {
+            self.throttle.push(msg);
+        }
+        let msgs = self.throttle.take(Instant::now());
+        self.throttled_action(msgs, scope)
+    }
+
+    fn wakeup(mut self, scope: &mut Scope<Self::Context>) -> IrcAction<Self> {
         loop {
             match self.rx.recv() {
                 Ok(Some(msg)) => {
-                    debug!("{}: Sending message: {}", &self.log_id, msg);
-                    msgs.push(msg);
+                    debug!("{}: Queueing message: {}", &self.log_id, msg);
+                    self.throttle.push(msg);
                 },
                 Ok(None) => break,
                 Err(_) => {
-                    error!("{}: IRC message sender dropped. Disconnecting", &self.log_id);
-                    return IrcAction::close();
+                    if let NetConnState::Quitting(_) = self.state {
+                        return IrcAction::ok(self);
+                    }
+                    // The network was removed. Anything it sent before it
+                    // went, like its `QUIT`, is still queued, so send that
+                    // and let the server close the connection.
+                    info!("{}: Network removed. Sending queued messages and disconnecting", &self.log_id);
+                    let msgs = self.throttle.take_all();
+                    let dline = scope.now() + Duration::from_secs(QUIT_TIMEOUT_SECS);
+                    self.state = NetConnState::Quitting(dline);
+                    return IrcAction::ok(self).send_all(msgs).deadline(dline);
                 },
             }
         }
+        let msgs = self.throttle.take(Instant::now());
         trace!("{}: Sending messages: {:?}", &self.log_id, msgs);
-        IrcAction::ok(self).send_all(msgs)
+        self.throttled_action(msgs, scope)
+    }
+
+    fn encoding(&self) -> Encoding {
+        self.encoding
     }
 
     fn disconnect(self, scope: &mut Scope<Self::Context>) {
         info!("{}: Disconnected from IRC", &self.log_id);
+        if let NetConnState::Quitting(_) = self.state {
+            // The network is gone, so there's nothing to update or retry.
+            return;
+        }
         if let Some(usr) = scope.core.get_user_mut(&self.uid) {
             let mut u = BaseUpdateHandle::<CoreMsg>::new();
             if let Some(net) = usr.get_net_mut(&self.nid) {
@@ -223,5 +628,89 @@ impl IrcMachine for IrcNetConn {
             error!("{}: Missing associated user {} for IRC network connection", &self.log_id, self.uid);
             return;
         }
+        // Connections finish in the background, so if this one never got
+        // going, the next address might still work.
+        if !self.got_data && !self.addrs.addrs.is_empty() {
+            info!("{}: Trying the next address for {}", &self.log_id, self.addrs.host);
+            scope.connect_next_addr(self.uid, self.nid, self.addrs);
+        } else {
+            ConnSpawner::retry_later(scope, self.uid, self.nid);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::time::{Duration, Instant};
+    use rotor::Time;
+    use rotor_irc::{Message, IrcMachine};
+    use common::messages::CoreMsg;
+    use common::tempdir::TempDir;
+    use buffer::test_util::test_cfg;
+    use config::parse_net_config;
+    use conn::ServerAddrs;
+    use conn::test_util::TestScope;
+    use conn::throttle::Throttle;
+    use handle::BaseUpdateHandle;
+    use network::IrcNetwork;
+    use super::{IrcNetConn, NetConnState, CAP_TIMEOUT_SECS, SASL_TIMEOUT_SECS};
+
+    /// Starts a connection whose server has just acknowledged `sasl` at
+    /// `acked`, so we're waiting on SASL authentication.
+    fn sasl_conn(dir: &TempDir, ts: &mut TestScope, acked: Time) -> IrcNetConn {
+        let cfg = parse_net_config(r#"{ server = "irc.example.net", nick = "me", alt_nicks = [],
+                                        channels = [], sasl_user = "me", sasl_pass = "hunter2" }"#)
+            .unwrap();
+        let mut net = IrcNetwork::new("net".to_owned(), &cfg, test_cfg(dir));
+        let rx = net.register_conn(ts.scope().notifier(), &mut BaseUpdateHandle::<CoreMsg>::new());
+        let mut conn = IrcNetConn {
+            uid: "user".to_owned(),
+            nid: "net".to_owned(),
+            rx: rx,
+            state: NetConnState::CapNegotiating,
+            addrs: ServerAddrs { host: "irc.example.net".to_owned(), addrs: VecDeque::new() },
+            got_data: true,
+            cap_ls: vec!["sasl".to_owned()],
+            sasl_done: false,
+            nick_attempts: 0,
+            encoding: cfg.encoding(),
+            throttle: Throttle::new(5, Duration::from_secs(2), Instant::now()),
+            reg_deadline: Some(Time::zero() + Duration::from_secs(CAP_TIMEOUT_SECS)),
+            sasl_deadline: None,
+            last_recv: acked,
+            ping_interval: Duration::from_secs(120),
+            ping_timeout: Duration::from_secs(60),
+            ping_sent: None,
+            pings: 0,
+            next_presence_poll: acked + Duration::from_secs(60),
+            rejoin_at: None,
+            log_id: "user.net".to_owned(),
+        };
+        let mut msgs = vec![];
+        let ack = ":irc.example.net CAP me ACK :sasl".parse::<Message>().unwrap();
+        conn.handle_cap(&ack, &mut net, acked, &mut msgs);
+        assert_eq!(msgs.iter().map(|m| m.to_string()).collect::<Vec<_>>(), vec!["AUTHENTICATE PLAIN"]);
+        conn
+    }
+
+    #[test]
+    fn sasl_timeout() {
+        let dir = TempDir::new("sasl-timeout");
+        let mut ts = TestScope::new(test_cfg(&dir));
+        // The server took most of the CAP timeout to acknowledge `sasl`, but
+        // SASL still gets its own time.
+        let acked = Time::zero() + Duration::from_secs(CAP_TIMEOUT_SECS - 1);
+        let conn = sasl_conn(&dir, &mut ts, acked);
+        let act = conn.timeout(&mut ts.scope_at(acked + Duration::from_secs(2)));
+        assert!(!act.closes());
+        assert!(act.sent().is_empty());
+
+        // Once it runs out, we end negotiation and close the connection.
+        let conn = sasl_conn(&dir, &mut ts, acked);
+        let act = conn.timeout(&mut ts.scope_at(acked + Duration::from_secs(SASL_TIMEOUT_SECS)));
+        assert!(act.closes());
+        assert_eq!(act.sent().iter().map(|m| m.to_string()).collect::<Vec<_>>(), vec!["CAP END"]);
     }
 }
@@ -1,24 +1,38 @@
 //! This module implements the server socket.
 
-use std::collections::VecDeque;
-use std::net::ToSocketAddrs;
-use rotor::{Machine, Response, Scope, EventSet, Notifier};
+use std::io;
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use rotor::{Machine, Response, Scope, EventSet, Notifier, Time};
 use rotor::void::Void;
 use rotor::mio::tcp::TcpStream;
 use rotor_stream::Stream;
 use rotor_irc::IrcConnection;
+use time::{self, Timespec};
 
-use common::conn::Handler;
-use common::messages::{NetId};
+use common::conn::{Handler, DEFAULT_MAX_MSG_SIZE};
+use common::messages::{NetId, CoreMsg};
 
 use state::Core;
-use config::UserId;
+use state::session::{Session, write_session};
+use buffer::BufferConfig;
+use config::{UserId, ChatConfig, CoreConfig, write_config};
+use handle::{UpdateHandle, BaseUpdateHandle};
 
 mod client;
+mod tls;
+mod throttle;
+mod status;
 pub mod irc;
 
 use self::irc::IrcNetConn;
+use self::tls::IrcSocket;
 pub use self::client::{Client};
+pub use self::status::StatusConn;
 
 
 // #[derive(Debug)]
@@ -28,14 +42,45 @@ pub struct Context {
     /// Notifier to spawn new connections
     pub notif: Notifier,
     pub spawn_conns: VecDeque<(UserId, NetId)>,
+    /// Connections waiting to be re-spawned at the given time.
+    pub reconnects: Vec<(Time, UserId, NetId)>,
+    /// Addresses left to try for connections whose last attempt failed before
+    /// it got anywhere. These are used instead of looking up the next server.
+    untried_addrs: HashMap<(UserId, NetId), ServerAddrs>,
+    /// Size limit for messages to and from clients.
+    pub max_msg_size: usize,
+    /// When the core was started.
+    pub started: Timespec,
+    /// Set when we've been asked to shut down.
+    pub shutdown: Arc<AtomicBool>,
+    /// Message to quit networks with when shutting down.
+    pub quit_msg: String,
+    /// Once we've sent our `QUIT`s, the time to stop the event loop at.
+    quitting: Option<Time>,
+    /// Path of the config file, for saving changes clients make.
+    pub cfg_path: PathBuf,
+    /// The `[core]` section of the config file, for saving it back.
+    pub core_cfg: Option<CoreConfig>,
+    /// Path of the file we save each network's session to on shutdown.
+    pub session_path: PathBuf,
 }
 
 impl Context {
-    pub fn new(notif: Notifier) -> Context {
+    pub fn new(notif: Notifier, buf_cfg: BufferConfig) -> Context {
         Context {
-            core: Core::new(),
+            core: Core::new(buf_cfg),
             notif: notif,
             spawn_conns: VecDeque::new(),
+            reconnects: vec![],
+            untried_addrs: HashMap::new(),
+            max_msg_size: DEFAULT_MAX_MSG_SIZE,
+            started: time::get_time(),
+            shutdown: Arc::new(AtomicBool::new(false)),
+            quit_msg: String::new(),
+            quitting: None,
+            cfg_path: PathBuf::from("config.toml"),
+            core_cfg: None,
+            session_path: PathBuf::from("session.json"),
         }
     }
 
@@ -45,6 +90,85 @@ impl Context {
         self.notif.wakeup().unwrap();
     }
 
+    /// Spawns an IRC connection for the given user and network to the next of
+    /// the given addresses.
+    pub fn connect_next_addr(&mut self, uid: UserId, nid: NetId, addrs: ServerAddrs) {
+        self.untried_addrs.insert((uid.clone(), nid.clone()), addrs);
+        self.spawn_conn(uid, nid);
+    }
+
+    /// Schedules an IRC connection for the given user and network to be
+    /// spawned at the given time.
+    pub fn schedule_reconnect(&mut self, uid: UserId, nid: NetId, at: Time) {
+        self.reconnects.push((at, uid, nid));
+        self.notif.wakeup().unwrap();
+    }
+
+    /// Moves reconnections that are due into the spawn queue and returns the
+    /// time of the next pending reconnection, if any.
+    fn poll_reconnects(&mut self, now: Time) -> Option<Time> {
+        let (due, pending): (Vec<_>, Vec<_>) = self.reconnects.drain(..).partition(|r| r.0 <= now);
+        self.reconnects = pending;
+        for (_, uid, nid) in due {
+            info!("Reconnecting user {}'s network {}", uid, nid);
+            self.spawn_conns.push_back((uid, nid));
+        }
+        self.reconnects.iter().map(|r| r.0).min()
+    }
+
+    /// Saves the users' current settings to the config file.
+    pub fn save_config(&self) {
+        let cfg = ChatConfig {
+            core: self.core_cfg.clone(),
+            user: self.core.iter_users().map(|(uid, usr)| (uid.clone(), usr.cfg.clone())).collect(),
+        };
+        match write_config(&self.cfg_path, &cfg) {
+            Ok(()) => info!("Saved config to {}", self.cfg_path.display()),
+            Err(e) => error!("Failed to save config to {}: {}", self.cfg_path.display(), e),
+        }
+    }
+
+    /// Saves each network's nick and channels to the session file.
+    fn save_session(&self) {
+        let session = Session::capture(&self.core);
+        match write_session(&self.session_path, &session) {
+            Ok(()) => debug!("Saved session to {}", self.session_path.display()),
+            Err(e) => error!("Failed to save session to {}: {}", self.session_path.display(), e),
+        }
+    }
+
+    /// Saves the session if any network's part of it has changed, so it's
+    /// up to date even if the core doesn't get to shut down cleanly.
+    pub fn save_session_if_changed(&mut self) {
+        let mut changed = false;
+        for (_, usr) in self.core.iter_users_mut() {
+            for (_, net) in usr.iter_nets_mut() {
+                changed |= net.take_session_changed();
+            }
+        }
+        if changed {
+            self.save_session();
+        }
+    }
+
+    /// Sends a `QUIT` to every network we're connected to.
+    fn quit_all(&mut self) {
+        let msg = self.quit_msg.clone();
+        for (uid, usr) in self.core.iter_users_mut() {
+            let mut u = BaseUpdateHandle::<CoreMsg>::new();
+            for (nid, net) in usr.iter_nets_mut() {
+                if !net.is_connected() { continue; }
+                info!("Quitting user {}'s network {}", uid, nid);
+                let res = net.send_quit(Some(msg.clone()),
+                                        &mut u.wrap(|m| CoreMsg::NetMsg(nid.clone(), m)));
+                if let Err(e) = res {
+                    warn!("Failed to quit user {}'s network {}: {}", uid, nid, e);
+                }
+            }
+            usr.exec_update_handle(u);
+        }
+    }
+
     /// Spawns IRC connections for all users.
     pub fn spawn_conns(&mut self) {
         for (uid, usr) in self.core.iter_users() {
@@ -57,6 +181,53 @@ impl Context {
 }
 
 
+/// Milliseconds to give `QUIT`s to go out before stopping the event loop.
+const SHUTDOWN_GRACE: u64 = 2000;
+
+
+/// A server's resolved addresses which haven't been tried yet.
+pub struct ServerAddrs {
+    pub host: String,
+    pub addrs: VecDeque<SocketAddr>,
+}
+
+impl ServerAddrs {
+    /// Looks up the addresses of the given host.
+    fn resolve(host: &str, port: u16) -> io::Result<ServerAddrs> {
+        let addrs: VecDeque<_> = try!((host, port).to_socket_addrs()).collect();
+        if addrs.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::NotFound,
+                                      format!("no addresses found for {}", host)));
+        }
+        Ok(ServerAddrs { host: host.to_owned(), addrs: addrs })
+    }
+
+    /// Tries each address in order until connecting to one of them starts,
+    /// removing the ones tried.
+    ///
+    /// The connection finishes in the background, so if it fails there, the
+    /// connection machine moves on to the remaining addresses.
+    fn connect_any(&mut self) -> io::Result<TcpStream> {
+        let mut last_err = None;
+        while let Some(addr) = self.addrs.pop_front() {
+            match TcpStream::connect(&addr) {
+                Ok(sock) => {
+                    info!("Connecting to {} at {}", self.host, addr);
+                    return Ok(sock);
+                },
+                Err(e) => {
+                    warn!("Failed to connect to {} at {}: {}", self.host, addr, e);
+                    last_err = Some(e);
+                },
+            }
+        }
+        Err(last_err.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("no addresses left for {}", self.host))
+        }))
+    }
+}
+
+
 /// State machine that handles spawning IRC connections.
 ///
 /// This machine is responsible for spawning IRC server connections. When the
@@ -64,7 +235,83 @@ impl Context {
 /// in `spawn_conns` and spawns connections from there.
 pub enum ConnSpawner {
     Spawner,
-    Conn(Stream<IrcConnection<IrcNetConn>>),
+    Conn(Stream<IrcConnection<IrcNetConn, IrcSocket>>),
+}
+
+impl ConnSpawner {
+    /// Schedules a reconnection to the given network after its backoff delay,
+    /// unless the user has disconnected from it.
+    pub fn retry_later(scope: &mut Scope<Context>, uid: UserId, nid: NetId) {
+        let delay = scope.core.get_user_mut(&uid)
+            .and_then(|usr| usr.get_net_mut(&nid))
+            .and_then(|net| {
+                if net.is_offline() { None } else { Some(net.next_reconnect_delay()) }
+            });
+        if let Some(delay) = delay {
+            info!("Reconnecting to user {}'s network {} in {} seconds", uid, nid, delay);
+            let at = scope.now() + Duration::from_secs(delay);
+            scope.schedule_reconnect(uid, nid, at);
+        }
+    }
+
+    /// Pushes a status line into a network's buffer.
+    fn push_net_status(scope: &mut Scope<Context>, uid: &UserId, nid: &NetId, msg: String) {
+        if let Some(usr) = scope.core.get_user_mut(uid) {
+            let mut u = BaseUpdateHandle::<CoreMsg>::new();
+            if let Some(net) = usr.get_net_mut(nid) {
+                net.push_status(msg, &mut u.wrap(|m| CoreMsg::NetMsg(nid.clone(), m)));
+            }
+            usr.exec_update_handle(u);
+        }
+    }
+
+    /// Spawns the next queued connection, if any, and sets a deadline for the
+    /// next pending reconnection.
+    fn spawn_next(scope: &mut Scope<Context>) -> Response<Self, (UserId, NetId)> {
+        let now = scope.now();
+        if scope.shutdown.load(Ordering::SeqCst) {
+            return Self::shut_down(scope);
+        }
+        let next = scope.poll_reconnects(now);
+        if let Some(seed) = scope.spawn_conns.pop_front() {
+            info!("Spawning IRC connection for user {}'s network {}", seed.0, seed.1);
+            // If there are still more connections to spawn, we wake ourself up
+            // again so we can spawn them.
+            if !scope.spawn_conns.is_empty() {
+                scope.notif.wakeup().unwrap();
+            }
+            // rotor doesn't allow a deadline on a spawn response, so the next
+            // reconnect's deadline is set again in `spawned`.
+            Response::spawn(ConnSpawner::Spawner, seed)
+        } else if let Some(time) = next {
+            Response::ok(ConnSpawner::Spawner).deadline(time)
+        } else {
+            Response::ok(ConnSpawner::Spawner)
+        }
+    }
+
+    /// Quits all networks, then stops the event loop once the `QUIT`s have
+    /// had time to go out.
+    fn shut_down(scope: &mut Scope<Context>) -> Response<Self, (UserId, NetId)> {
+        let now = scope.now();
+        let quitting = scope.quitting;
+        match quitting {
+            None => {
+                scope.save_session();
+                info!("Quitting all networks");
+                scope.quit_all();
+                let at = now + Duration::from_millis(SHUTDOWN_GRACE);
+                scope.quitting = Some(at);
+                Response::ok(ConnSpawner::Spawner).deadline(at)
+            },
+            Some(at) if at <= now => {
+                info!("Stopping event loop");
+                scope.shutdown_loop();
+                Response::done()
+            },
+            Some(at) => Response::ok(ConnSpawner::Spawner).deadline(at),
+        }
+    }
 }
 
 impl Machine for ConnSpawner {
@@ -73,17 +320,18 @@ impl Machine for ConnSpawner {
 
     fn create(seed: Self::Seed, scope: &mut Scope<Context>) -> Response<Self, Void> {
         let (uid, nid) = seed;
-        let addr = if let Some(usr) = scope.core.get_user_mut(&uid) {
+        let untried = scope.untried_addrs.remove(&(uid.clone(), nid.clone()));
+        let (host, port, cfg) = if let Some(usr) = scope.core.get_user_mut(&uid) {
             if let Some(net) = usr.get_net_mut(&nid) {
-                let result = (net.cfg.server(), net.cfg.port()).to_socket_addrs()
-                    .map(|mut iter| iter.next().unwrap());
-                match result {
-                    Ok(addr) => addr,
-                    Err(e) => {
-                        error!("Error parsing network address for network {}: {:?}", &nid, e);
-                        return Response::done();
-                    }
+                if net.is_offline() {
+                    info!("Not connecting to offline network {}", &nid);
+                    return Response::done();
                 }
+                let host = match untried {
+                    Some(ref addrs) => addrs.host.clone(),
+                    None => net.next_server(),
+                };
+                (host, net.cfg.port(), net.cfg.clone())
             } else {
                 error!("Tried to spawn connection for nonexistant network");
                 return Response::done();
@@ -93,12 +341,44 @@ impl Machine for ConnSpawner {
             return Response::done();
         };
 
-        match TcpStream::connect(&addr) {
-            Ok(sock) => Stream::new(sock, (uid, nid), scope)
+        let mut addrs = match untried {
+            Some(addrs) => addrs,
+            None => {
+                Self::push_net_status(scope, &uid, &nid, format!("Connecting to {}:{}", host, port));
+                match ServerAddrs::resolve(&host, port) {
+                    Ok(addrs) => addrs,
+                    Err(e) => {
+                        error!("Error looking up IRC server {} for user {} on network {}: {}",
+                               host, uid, nid, e);
+                        Self::push_net_status(scope, &uid, &nid, format!("Couldn't connect to {}: {}", host, e));
+                        Self::retry_later(scope, uid, nid);
+                        return Response::done();
+                    },
+                }
+            },
+        };
+        let sock = match addrs.connect_any() {
+            Ok(sock) => sock,
+            Err(e) => {
+                error!("Error connecting to IRC server {} for user {} on network {}: {}",
+                       host, uid, nid, e);
+                Self::push_net_status(scope, &uid, &nid, format!("Couldn't connect to {}: {}", host, e));
+                Self::retry_later(scope, uid, nid);
+                return Response::done();
+            },
+        };
+        let sock = if cfg.use_ssl() {
+            IrcSocket::tls(sock, &host, &cfg)
+        } else {
+            Ok(IrcSocket::Plain(sock))
+        };
+        match sock {
+            Ok(sock) => Stream::new(sock, (uid, nid, addrs), scope)
                 .map(ConnSpawner::Conn, |_| unreachable!("Connection spawned machine")),
             Err(e) => {
-                error!("Error connecting to IRC server for user {} on network {}: {}",
-                       uid, nid, e);
+                error!("Error setting up TLS for user {} on network {}: {}", uid, nid, e);
+                Self::push_net_status(scope, &uid, &nid, format!("Couldn't set up TLS: {}", e));
+                Self::retry_later(scope, uid, nid);
                 Response::done()
             },
         }
@@ -106,7 +386,14 @@ impl Machine for ConnSpawner {
 
     fn spawned(self, s: &mut Scope<Context>) -> Response<Self, Self::Seed> {
         match self {
-            ConnSpawner::Spawner => Response::ok(self),
+            ConnSpawner::Spawner => {
+                // The spawn response couldn't carry a deadline, so we set the
+                // one for the next pending reconnect here.
+                match s.reconnects.iter().map(|r| r.0).min() {
+                    Some(time) => Response::ok(self).deadline(time),
+                    None => Response::ok(self),
+                }
+            },
             ConnSpawner::Conn(conn) => {
                 conn.spawned(s).map(ConnSpawner::Conn, |_| unreachable!("Connection spawned machine"))
             },
@@ -124,7 +411,10 @@ impl Machine for ConnSpawner {
 
     fn timeout(self, scope: &mut Scope<Context>) -> Response<Self, Self::Seed> {
         match self {
-            ConnSpawner::Spawner => unreachable!(),
+            ConnSpawner::Spawner => {
+                trace!("Spawner timed out");
+                Self::spawn_next(scope)
+            },
             ConnSpawner::Conn(conn) => {
                 conn.timeout(scope).map(ConnSpawner::Conn, |_| unreachable!("Connection spawned machine"))
             },
@@ -135,17 +425,7 @@ impl Machine for ConnSpawner {
         match self {
             ConnSpawner::Spawner => {
                 trace!("Spawner woke up");
-                if let Some(seed) = scope.spawn_conns.pop_front() {
-                    info!("Spawning IRC connection for user {}'s network {}", seed.0, seed.1);
-                    // If there are still more connections to spawn, we wake ourself up
-                    // again so we can spawn them.
-                    if !scope.spawn_conns.is_empty() {
-                        scope.notif.wakeup().unwrap();
-                    }
-                    Response::spawn(ConnSpawner::Spawner, seed)
-                } else {
-                    Response::ok(ConnSpawner::Spawner)
-                }
+                Self::spawn_next(scope)
             },
             ConnSpawner::Conn(conn) => {
                 conn.wakeup(scope).map(ConnSpawner::Conn, |_| unreachable!("Connection spawned machine"))
@@ -153,3 +433,81 @@ impl Machine for ConnSpawner {
         }
     }
 }
+
+
+/// Helpers for tests which drive the connection machines by hand.
+#[cfg(test)]
+pub mod test_util {
+    use std::io;
+    use rotor::{self, Scope, Time, Notifier, Evented, EventSet, PollOpt, Timeout, TimerError};
+    use rotor::mio::{EventLoop, Handler, Sender, Token};
+    use buffer::BufferConfig;
+    use super::Context;
+
+    /// An event loop which ignores the sockets machines register with it.
+    struct NullLoop;
+
+    impl rotor::_LoopApi for NullLoop {
+        fn register(&mut self, _io: &Evented, _token: Token,
+                    _interest: EventSet, _opt: PollOpt) -> io::Result<()> {
+            Ok(())
+        }
+        fn reregister(&mut self, _io: &Evented, _token: Token,
+                      _interest: EventSet, _opt: PollOpt) -> io::Result<()> {
+            Ok(())
+        }
+        fn deregister(&mut self, _io: &Evented) -> io::Result<()> {
+            Ok(())
+        }
+        fn timeout_ms(&mut self, _token: Token, _delay: u64) -> Result<Timeout, TimerError> {
+            panic!("Test machine set a timeout")
+        }
+        fn clear_timeout(&mut self, _token: Timeout) -> bool {
+            false
+        }
+        fn shutdown(&mut self) {}
+    }
+
+    /// Handler for the event loop we only use for its notification channel.
+    struct NullHandler;
+
+    impl Handler for NullHandler {
+        type Timeout = ();
+        type Message = rotor::_Notify;
+    }
+
+    /// A context and what's needed to make scopes for it.
+    pub struct TestScope {
+        pub ctx: Context,
+        chan: Sender<rotor::_Notify>,
+        lp: NullLoop,
+        // Keeps the channel open.
+        _evloop: EventLoop<NullHandler>,
+    }
+
+    impl TestScope {
+        pub fn new(buf_cfg: BufferConfig) -> TestScope {
+            let evloop = EventLoop::<NullHandler>::new().unwrap();
+            let mut chan = evloop.channel();
+            let mut lp = NullLoop;
+            let notif: Notifier = rotor::_scope(Time::zero(), Token(0), &mut (), &mut chan, &mut lp)
+                .notifier();
+            TestScope {
+                ctx: Context::new(notif, buf_cfg),
+                chan: chan,
+                lp: lp,
+                _evloop: evloop,
+            }
+        }
+
+        /// Makes a scope for a machine to be run in.
+        pub fn scope(&mut self) -> Scope<Context> {
+            self.scope_at(Time::zero())
+        }
+
+        /// Makes a scope whose current time is `now`.
+        pub fn scope_at(&mut self, now: Time) -> Scope<Context> {
+            rotor::_scope(now, Token(1), &mut self.ctx, &mut self.chan, &mut self.lp)
+        }
+    }
+}
@@ -0,0 +1,192 @@
+//! A small read-only HTTP server which reports the core's status as JSON.
+//!
+//! This is meant for monitoring tools, so it only answers `GET /status` and
+//! closes the connection after each response.
+
+use std::error::Error;
+use std::io::Write;
+use std::time::Duration;
+use rotor::Scope;
+use rotor::mio::tcp::TcpStream;
+use rotor_stream::{Protocol, Intent, Transport, Exception};
+use rustc_serialize::json;
+use time::{self, Timespec};
+
+use common::messages::BufTarget;
+
+use state::Core;
+use super::Context;
+
+
+/// Longest request header we'll read.
+const MAX_REQUEST_SIZE: usize = 8192;
+/// Seconds a client has to send its request.
+const REQUEST_TIMEOUT: u64 = 10;
+
+
+/// Snapshot of the core's state.
+#[derive(Debug, RustcEncodable)]
+pub struct CoreStatus {
+    /// Seconds since the core started.
+    pub uptime: i64,
+    pub users: Vec<UserStatus>,
+}
+
+#[derive(Debug, RustcEncodable)]
+pub struct UserStatus {
+    pub id: String,
+    /// Number of clients connected as this user.
+    pub clients: usize,
+    pub networks: Vec<NetStatus>,
+}
+
+#[derive(Debug, RustcEncodable)]
+pub struct NetStatus {
+    pub id: String,
+    pub connected: bool,
+    /// True if the user disconnected from the network on purpose.
+    pub offline: bool,
+    pub nick: String,
+    /// Channels we're currently in.
+    pub channels: Vec<String>,
+}
+
+impl CoreStatus {
+    /// Builds a status snapshot of `core`.
+    pub fn new(core: &Core, started: Timespec) -> CoreStatus {
+        let mut users = vec![];
+        for (uid, usr) in core.iter_users() {
+            let mut networks = vec![];
+            for (nid, net) in usr.iter_nets() {
+                let mut channels = net.iter_bufs().filter_map(|buf| {
+                    match *buf.id() {
+                        BufTarget::Channel(ref chan) if buf.joined() => Some(chan.clone()),
+                        _ => None,
+                    }
+                }).collect::<Vec<_>>();
+                channels.sort();
+                networks.push(NetStatus {
+                    id: nid.clone(),
+                    connected: net.is_connected(),
+                    offline: net.is_offline(),
+                    nick: net.nick().to_owned(),
+                    channels: channels,
+                });
+            }
+            networks.sort_by(|a, b| a.id.cmp(&b.id));
+            users.push(UserStatus {
+                id: uid.clone(),
+                clients: usr.client_count(),
+                networks: networks,
+            });
+        }
+        users.sort_by(|a, b| a.id.cmp(&b.id));
+        CoreStatus {
+            uptime: (time::get_time() - started).num_seconds(),
+            users: users,
+        }
+    }
+}
+
+
+/// Connection state machine for the status server.
+pub struct StatusConn;
+
+impl Protocol for StatusConn {
+    type Context = Context;
+    type Socket = TcpStream;
+    type Seed = ();
+
+    fn create(_seed: (), _sock: &mut TcpStream, scope: &mut Scope<Context>) -> Intent<Self> {
+        Intent::of(StatusConn)
+            .expect_delimiter(b"\r\n\r\n", MAX_REQUEST_SIZE)
+            .deadline(scope.now() + Duration::from_secs(REQUEST_TIMEOUT))
+    }
+
+    fn bytes_read(self,
+                  transport: &mut Transport<TcpStream>,
+                  end: usize,
+                  scope: &mut Scope<Context>)
+                  -> Intent<Self> {
+        let (code, body) = {
+            let req = String::from_utf8_lossy(&transport.input()[..end]);
+            let line = req.lines().next().unwrap_or("");
+            match route_request(line) {
+                Ok(()) => {
+                    let status = CoreStatus::new(&scope.core, scope.started);
+                    match json::encode(&status) {
+                        Ok(body) => ("200 OK", body),
+                        Err(e) => {
+                            error!("Failed to encode core status: {}", e);
+                            ("500 Internal Server Error", "{}".to_owned())
+                        },
+                    }
+                },
+                Err(code) => (code, "{}".to_owned()),
+            }
+        };
+        transport.input().consume(end);
+        let res = write!(transport.output(),
+                         "HTTP/1.0 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                         code, body.len(), body);
+        match res {
+            Ok(()) => Intent::of(self).expect_flush(),
+            Err(e) => Intent::error(Box::new(e) as Box<Error>),
+        }
+    }
+
+    fn bytes_flushed(self, _transport: &mut Transport<TcpStream>, _scope: &mut Scope<Context>) -> Intent<Self> {
+        Intent::done()
+    }
+
+    fn timeout(self, _transport: &mut Transport<TcpStream>, _scope: &mut Scope<Context>) -> Intent<Self> {
+        debug!("Status request timed out");
+        Intent::done()
+    }
+
+    fn wakeup(self, _transport: &mut Transport<TcpStream>, _scope: &mut Scope<Context>) -> Intent<Self> {
+        Intent::done()
+    }
+
+    fn exception(self,
+                 _transport: &mut Transport<TcpStream>,
+                 reason: Exception,
+                 _scope: &mut Scope<Context>)
+                 -> Intent<Self> {
+        debug!("Error reading status request: {}", reason);
+        Intent::done()
+    }
+
+    fn fatal(self, reason: Exception, _scope: &mut Scope<Context>) -> Option<Box<Error>> {
+        debug!("Fatal error in status connection: {}", reason);
+        None
+    }
+}
+
+
+/// Checks an HTTP request line. Returns the error status to respond with if
+/// it isn't a request for the status.
+fn route_request(line: &str) -> Result<(), &'static str> {
+    let mut parts = line.split(' ');
+    match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/")) | (Some("GET"), Some("/status")) => Ok(()),
+        (Some("GET"), Some(_)) => Err("404 Not Found"),
+        (Some(_), Some(_)) => Err("405 Method Not Allowed"),
+        _ => Err("400 Bad Request"),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::route_request;
+
+    #[test]
+    fn routes() {
+        assert_eq!(route_request("GET /status HTTP/1.1"), Ok(()));
+        assert_eq!(route_request("GET / HTTP/1.0"), Ok(()));
+        assert_eq!(route_request("GET /users HTTP/1.1"), Err("404 Not Found"));
+        assert_eq!(route_request("POST /status HTTP/1.1"), Err("405 Method Not Allowed"));
+        assert_eq!(route_request("garbage"), Err("400 Bad Request"));
+    }
+}
@@ -0,0 +1,139 @@
+//! Rate limiting for messages sent to IRC.
+//!
+//! Servers disconnect clients that send too much too quickly, so everything we
+//! send after registering is queued here and let out through a token bucket.
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use rotor_irc::Message;
+
+
+/// A token bucket which allows `burst` messages at once and then one message
+/// every `interval`.
+pub struct Throttle {
+    burst: u32,
+    interval: Duration,
+    /// The time at which the bucket will be full again.
+    full_at: Instant,
+    queue: VecDeque<Message>,
+}
+
+impl Throttle {
+    pub fn new(burst: u32, interval: Duration, now: Instant) -> Throttle {
+        Throttle {
+            burst: cmp::max(burst, 1),
+            interval: interval,
+            full_at: now,
+            queue: VecDeque::new(),
+        }
+    }
+
+    /// Queues a message to be sent.
+    pub fn push(&mut self, msg: Message) {
+        self.queue.push_back(msg);
+    }
+
+    /// Queues a message to be sent before any others, for replies the server
+    /// is waiting on, like `PONG`.
+    pub fn push_front(&mut self, msg: Message) {
+        self.queue.push_front(msg);
+    }
+
+    /// Takes as many queued messages as we're allowed to send at `now`.
+    pub fn take(&mut self, now: Instant) -> Vec<Message> {
+        let mut msgs = vec![];
+        while !self.queue.is_empty() && self.full_at <= self.limit(now) {
+            self.full_at = cmp::max(self.full_at, now) + self.interval;
+            msgs.push(self.queue.pop_front().unwrap());
+        }
+        msgs
+    }
+
+    /// Takes every queued message, ignoring the rate limit. This is for when
+    /// we're about to close the connection anyway.
+    pub fn take_all(&mut self) -> Vec<Message> {
+        self.queue.drain(..).collect()
+    }
+
+    /// Returns how long to wait before the next queued message can be sent,
+    /// or `None` if the queue is empty.
+    pub fn wait(&self, now: Instant) -> Option<Duration> {
+        if self.queue.is_empty() {
+            None
+        } else if self.full_at <= self.limit(now) {
+            Some(Duration::from_secs(0))
+        } else {
+            Some(self.full_at - self.limit(now))
+        }
+    }
+
+    /// The latest `full_at` time at which we can still send a message.
+    fn limit(&self, now: Instant) -> Instant {
+        now + self.interval * (self.burst - 1)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use rotor_irc::{Message, Command};
+    use super::Throttle;
+
+    fn msg(i: usize) -> Message {
+        Message::new(None, Command::PRIVMSG, vec!["#chan".to_owned()], Some(format!("{}", i)))
+    }
+
+    fn throttle(burst: u32, now: Instant) -> Throttle {
+        let mut t = Throttle::new(burst, Duration::from_secs(2), now);
+        for i in 0..10 { t.push(msg(i)); }
+        t
+    }
+
+    #[test]
+    fn burst_then_wait() {
+        let now = Instant::now();
+        let mut t = throttle(5, now);
+        assert_eq!(t.take(now).len(), 5);
+        assert!(t.take(now).is_empty());
+        assert_eq!(t.wait(now), Some(Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn refills_over_time() {
+        let now = Instant::now();
+        let mut t = throttle(5, now);
+        t.take(now);
+        assert_eq!(t.take(now + Duration::from_secs(2)).len(), 1);
+        assert_eq!(t.take(now + Duration::from_secs(7)).len(), 2);
+        assert_eq!(t.take(now + Duration::from_secs(60)).len(), 2);
+        assert_eq!(t.wait(now + Duration::from_secs(60)), None);
+    }
+
+    #[test]
+    fn front_skips_queue() {
+        let now = Instant::now();
+        let mut t = throttle(1, now);
+        t.push_front(Message::cmd(Command::PONG).body("token"));
+        assert_eq!(t.take(now)[0].command, Command::PONG);
+        assert_eq!(t.take(now + Duration::from_secs(2)), vec![msg(0)]);
+    }
+
+    #[test]
+    fn take_all_ignores_limit() {
+        let now = Instant::now();
+        let mut t = throttle(1, now);
+        t.take(now);
+        assert_eq!(t.take_all().len(), 9);
+        assert_eq!(t.wait(now), None);
+    }
+
+    #[test]
+    fn no_burst() {
+        let now = Instant::now();
+        let mut t = throttle(0, now);
+        assert_eq!(t.take(now).len(), 1);
+        assert_eq!(t.wait(now), Some(Duration::from_secs(2)));
+    }
+}
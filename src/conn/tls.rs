@@ -0,0 +1,189 @@
+//! Socket type for IRC connections which may or may not be wrapped in TLS.
+
+use std::io;
+use std::io::{Read, Write};
+use std::fs::File;
+use std::mem;
+use native_tls::{Certificate, TlsConnector, TlsStream, MidHandshakeTlsStream, HandshakeError};
+use rotor::mio::{Evented, Selector, Token, EventSet, PollOpt};
+use rotor::mio::tcp::TcpStream;
+use rotor_stream::SocketError;
+
+use config::NetConfig;
+
+
+/// A socket for an IRC connection.
+pub enum IrcSocket {
+    Plain(TcpStream),
+    /// A TLS connection. The first field is a clone of the underlying socket,
+    /// which is used to register the socket with the event loop even if the
+    /// TLS stream is gone.
+    Tls(TcpStream, TlsState),
+}
+
+/// State of a TLS connection.
+///
+/// Since our sockets are non-blocking, the TLS handshake can't be completed
+/// up front. Instead, it's driven forward whenever the socket is read from or
+/// written to, which returns `WouldBlock` until the handshake is finished.
+pub enum TlsState {
+    /// The handshake is in progress. The second field is the DER encoding of
+    /// the certificate the server must present, if it's pinned.
+    Handshaking(MidHandshakeTlsStream<TcpStream>, Option<Vec<u8>>),
+    Connected(TlsStream<TcpStream>),
+    /// The handshake failed.
+    Failed,
+}
+
+impl IrcSocket {
+    /// Wraps the given socket connected to `host` in TLS, verifying
+    /// certificates as configured in `cfg`.
+    ///
+    /// If `cfg` pins a certificate, the server must present exactly that
+    /// certificate, and the usual chain and hostname checks are skipped.
+    pub fn tls(sock: TcpStream, host: &str, cfg: &NetConfig) -> io::Result<IrcSocket> {
+        let tcp = try!(sock.try_clone());
+        let pin = match cfg.tls_cert() {
+            Some(path) => Some(try!(read_cert(path))),
+            None => None,
+        };
+        let verify = cfg.tls_verify() && pin.is_none();
+        let conn = try!(TlsConnector::builder()
+            .danger_accept_invalid_certs(!verify)
+            .danger_accept_invalid_hostnames(!verify)
+            .build()
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e)));
+        let state = match conn.connect(host, sock) {
+            Ok(stream) => {
+                try!(check_pin(&stream, &pin));
+                TlsState::Connected(stream)
+            },
+            Err(HandshakeError::WouldBlock(mid)) => TlsState::Handshaking(mid, pin),
+            Err(HandshakeError::Failure(e)) => return Err(io::Error::new(io::ErrorKind::Other, e)),
+        };
+        Ok(IrcSocket::Tls(tcp, state))
+    }
+
+    /// Gets the underlying TCP socket.
+    fn tcp(&self) -> &TcpStream {
+        match *self {
+            IrcSocket::Plain(ref s) => s,
+            IrcSocket::Tls(ref s, _) => s,
+        }
+    }
+}
+
+impl TlsState {
+    /// Continues the TLS handshake if it isn't finished and returns the
+    /// stream.
+    fn stream(&mut self) -> io::Result<&mut TlsStream<TcpStream>> {
+        if let TlsState::Handshaking(..) = *self {
+            let (mid, pin) = match mem::replace(self, TlsState::Failed) {
+                TlsState::Handshaking(mid, pin) => (mid, pin),
+                _ => unreachable!(),
+            };
+            match mid.handshake() {
+                Ok(stream) => {
+                    debug!("TLS handshake finished");
+                    if let Err(e) = check_pin(&stream, &pin) {
+                        error!("{}", e);
+                        return Err(e);
+                    }
+                    *self = TlsState::Connected(stream);
+                },
+                Err(HandshakeError::WouldBlock(mid)) => {
+                    *self = TlsState::Handshaking(mid, pin);
+                    return Err(io::Error::new(io::ErrorKind::WouldBlock, "TLS handshake in progress"));
+                },
+                Err(HandshakeError::Failure(e)) => {
+                    error!("TLS handshake failed: {}", e);
+                    return Err(io::Error::new(io::ErrorKind::Other, e));
+                },
+            }
+        }
+        match *self {
+            TlsState::Connected(ref mut s) => Ok(s),
+            _ => Err(io::Error::new(io::ErrorKind::NotConnected, "TLS handshake failed")),
+        }
+    }
+}
+
+/// Reads a PEM certificate from the given file and returns its DER encoding.
+fn read_cert(path: &str) -> io::Result<Vec<u8>> {
+    let mut pem = vec![];
+    try!(try!(File::open(path)).read_to_end(&mut pem));
+    Certificate::from_pem(&pem)
+        .and_then(|cert| cert.to_der())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData,
+                                    format!("Invalid certificate in {}: {}", path, e)))
+}
+
+/// Checks that the server presented the pinned certificate, if there is one.
+fn check_pin(stream: &TlsStream<TcpStream>, pin: &Option<Vec<u8>>) -> io::Result<()> {
+    let pin = match *pin {
+        Some(ref pin) => pin,
+        None => return Ok(()),
+    };
+    let cert = try!(stream.peer_certificate()
+        .and_then(|cert| match cert {
+            Some(cert) => cert.to_der().map(Some),
+            None => Ok(None),
+        })
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e)));
+    if cert.as_ref() == Some(pin) {
+        Ok(())
+    } else {
+        Err(io::Error::new(io::ErrorKind::Other,
+                           "Server's TLS certificate doesn't match the pinned certificate"))
+    }
+}
+
+
+impl Read for IrcSocket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            IrcSocket::Plain(ref mut s) => s.read(buf),
+            IrcSocket::Tls(_, ref mut s) => try!(s.stream()).read(buf),
+        }
+    }
+}
+
+impl Write for IrcSocket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            IrcSocket::Plain(ref mut s) => s.write(buf),
+            IrcSocket::Tls(_, ref mut s) => try!(s.stream()).write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            IrcSocket::Plain(ref mut s) => s.flush(),
+            IrcSocket::Tls(_, ref mut s) => try!(s.stream()).flush(),
+        }
+    }
+}
+
+impl Evented for IrcSocket {
+    fn register(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt)
+                -> io::Result<()>
+    {
+        self.tcp().register(selector, token, interest, opts)
+    }
+
+    fn reregister(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt)
+                  -> io::Result<()>
+    {
+        self.tcp().reregister(selector, token, interest, opts)
+    }
+
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        self.tcp().deregister(selector)
+    }
+}
+
+impl SocketError for IrcSocket {
+    fn take_socket_error(&self) -> io::Result<()> {
+        self.tcp().take_socket_error()
+    }
+}
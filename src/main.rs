@@ -8,13 +8,20 @@ extern crate serde;
 extern crate serde_json;
 extern crate time;
 extern crate toml;
+extern crate native_tls;
+extern crate libc;
 
 extern crate common;
 
+use std::env;
+use std::io::{self, Write};
+use std::process;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
 use std::path::Path;
 use rotor::{Machine, Response, Loop, Config as LoopCfg};
 use rotor::mio::tcp::TcpListener;
-use rotor_stream::Accept;
+use rotor_stream::{Accept, Stream};
 
 use common::conn::ConnStream;
 
@@ -25,14 +32,17 @@ pub mod network;
 pub mod buffer;
 pub mod conn;
 pub mod state;
+pub mod signal;
 
-use self::config::read_config;
-use self::conn::{Client, Context, ConnSpawner};
+use self::config::{read_config, ChatConfig};
+use self::buffer::BufferConfig;
+use self::conn::{Client, Context, ConnSpawner, StatusConn};
 
 rotor_compose!{
     pub enum Fsm/Seed<Context> {
         Client(Accept<ConnStream<Client>, TcpListener>),
         Spawner(ConnSpawner),
+        Status(Accept<Stream<StatusConn>, TcpListener>),
     }
 }
 
@@ -42,13 +52,55 @@ fn main() {
     let cfg_path = Path::new("config.toml");
     let cfg = read_config(cfg_path);
 
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.first().map(|a| &a[..]) {
+        Some("export-logs") => process::exit(export_logs(&args[1..], &cfg)),
+        Some("import-znc") => process::exit(import_znc(&args[1..], &cfg)),
+        _ => {},
+    }
+
     debug!("Creating loop.");
     let mut loop_creator = Loop::new(&LoopCfg::new()).unwrap();
-    let sock = TcpListener::bind(&"127.0.0.1:4242".parse().unwrap()).unwrap();
+    let addr = match cfg.core().listen_addr() {
+        Ok(addr) => addr,
+        Err(e) => {
+            error!("{}", e);
+            process::exit(1);
+        },
+    };
+    let sock = match TcpListener::bind(&addr) {
+        Ok(sock) => sock,
+        Err(e) => {
+            error!("Failed to listen on {}: {}", addr, e);
+            process::exit(1);
+        },
+    };
+    info!("Listening for clients on {}", addr);
     loop_creator.add_machine_with(|scope| {
         Accept::<ConnStream<Client>, _>::new(sock, (), scope).wrap(Fsm::Client)
     }).unwrap();
 
+    match cfg.core().status_addr() {
+        Some(Ok(addr)) => {
+            let sock = match TcpListener::bind(&addr) {
+                Ok(sock) => sock,
+                Err(e) => {
+                    error!("Failed to serve status on {}: {}", addr, e);
+                    process::exit(1);
+                },
+            };
+            info!("Serving status on {}", addr);
+            loop_creator.add_machine_with(|scope| {
+                Accept::<Stream<StatusConn>, _>::new(sock, (), scope).wrap(Fsm::Status)
+            }).unwrap();
+        },
+        Some(Err(e)) => {
+            error!("{}", e);
+            process::exit(1);
+        },
+        None => {},
+    }
+
     let mut notif = None;
     loop_creator.add_machine_with(|scope| {
         notif = Some(scope.notifier());
@@ -57,10 +109,33 @@ fn main() {
     let notif = notif.expect("Notifier was not set.");
 
     debug!("Creating context.");
-    let mut ctx = Context::new(notif);
+    let buf_cfg = cfg.core().buffer_config();
+    info!("Storing logs in {}", buf_cfg.log_dir.display());
+    // Held until we exit, so log imports know to wait for us.
+    let _log_lock = match buffer::lock_dir(&buf_cfg.log_dir) {
+        Ok(lock) => lock,
+        Err(e) => {
+            error!("Failed to lock log directory {}: {}. Is another core running?",
+                   buf_cfg.log_dir.display(), e);
+            process::exit(1);
+        },
+    };
+    let shutdown = Arc::new(AtomicBool::new(false));
+    // Only the core needs this. The subcommands above keep the default
+    // handling, so Ctrl-C still stops them.
+    signal::block_signals();
+    signal::watch_signals(shutdown.clone(), notif.clone());
+    let mut ctx = Context::new(notif, buf_cfg);
+    ctx.max_msg_size = cfg.core().max_message_size();
+    ctx.shutdown = shutdown;
+    ctx.quit_msg = cfg.core().quit_message();
+    ctx.cfg_path = cfg_path.to_path_buf();
+    ctx.core_cfg = cfg.core.clone();
+    ctx.session_path = state::session::session_path(cfg_path);
     for (uid, ucfg) in cfg.user.iter() {
         ctx.core.add_user(uid.clone(), ucfg.clone());
     }
+    state::session::read_session(&ctx.session_path).restore(&mut ctx.core);
 
     debug!("Initializing context.");
     ctx.spawn_conns();
@@ -68,3 +143,83 @@ fn main() {
     debug!("Starting");
     loop_creator.run(ctx).unwrap();
 }
+
+/// Gets the buffer settings for the user with the given ID, or prints an error
+/// if there's no such user.
+fn user_buffer_config(cfg: &ChatConfig, uid: &str) -> Option<BufferConfig> {
+    if cfg.user.contains_key(uid) {
+        Some(cfg.core().buffer_config().for_user(uid))
+    } else {
+        let _ = writeln!(io::stderr(), "There's no user called {}", uid);
+        None
+    }
+}
+
+/// Handles `export-logs <user> <network> <buffer> <dir>`, which writes a
+/// buffer's logs to `dir` as plain text. Returns the exit status.
+fn export_logs(args: &[String], cfg: &ChatConfig) -> i32 {
+    if args.len() != 4 {
+        let _ = writeln!(io::stderr(), "Usage: distirc export-logs <user> <network> <buffer> <dir>");
+        return 2;
+    }
+    let buf_cfg = match user_buffer_config(cfg, &args[0]) {
+        Some(buf_cfg) => buf_cfg,
+        None => return 1,
+    };
+    match buffer::export::export_logs(&buf_cfg, &args[1], &args[2], Path::new(&args[3])) {
+        Ok(days) => {
+            println!("Exported {} days of logs to {}", days, args[3]);
+            0
+        },
+        Err(e) => {
+            let _ = writeln!(io::stderr(), "{}", e);
+            1
+        },
+    }
+}
+
+/// Handles `import-znc <user> <network> <buffer> <dir> [<timestamp format>
+/// [<file date format>]]`, which adds the ZNC logs in `dir` to a buffer's
+/// logs. The formats default to ZNC's. Returns the exit status.
+fn import_znc(args: &[String], cfg: &ChatConfig) -> i32 {
+    use buffer::import::ZncFormat;
+
+    if args.len() < 4 || args.len() > 6 {
+        let _ = writeln!(io::stderr(),
+                         "Usage: distirc import-znc <user> <network> <buffer> <dir> \
+                          [<timestamp format> [<file date format>]]");
+        return 2;
+    }
+    let buf_cfg = match user_buffer_config(cfg, &args[0]) {
+        Some(buf_cfg) => buf_cfg,
+        None => return 1,
+    };
+    // Importing rewrites day files, so it mustn't happen under a running
+    // core.
+    let log_dir = cfg.core().buffer_config().log_dir;
+    let _lock = match buffer::lock_dir(&log_dir) {
+        Ok(lock) => lock,
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+            let _ = writeln!(io::stderr(), "The core is using the logs in {}. Stop it before importing",
+                             log_dir.display());
+            return 1;
+        },
+        Err(e) => {
+            let _ = writeln!(io::stderr(), "Failed to lock {}: {}", log_dir.display(), e);
+            return 1;
+        },
+    };
+    let mut fmt = ZncFormat::default();
+    if let Some(ts) = args.get(4) { fmt.timestamp = ts.clone(); }
+    if let Some(date) = args.get(5) { fmt.file_date = date.clone(); }
+    match buffer::import::import_znc(&buf_cfg, &args[1], &args[2], Path::new(&args[3]), &fmt) {
+        Ok(lines) => {
+            println!("Imported {} lines into {} on {}", lines, args[2], args[1]);
+            0
+        },
+        Err(e) => {
+            let _ = writeln!(io::stderr(), "{}", e);
+            1
+        },
+    }
+}
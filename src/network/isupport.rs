@@ -0,0 +1,234 @@
+//! Parsing for `RPL_ISUPPORT` (005) tokens.
+//!
+//! Servers advertise their features as a list of `KEY=VALUE` tokens spread
+//! over one or more 005 replies. A token of the form `-KEY` removes a
+//! previously advertised feature.
+
+use common::line::USER_PREFIXES;
+use super::casemap::CaseMapping;
+
+/// Features the server advertised in `RPL_ISUPPORT`.
+///
+/// Features the server doesn't advertise keep the defaults from RFC 1459,
+/// except that the prefix and channel modes cover the ones most servers
+/// use.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ISupport {
+    /// Channel user prefix modes and their prefix characters, from highest to
+    /// lowest rank, e.g., `('o', '@')`.
+    pub prefix: Vec<(char, char)>,
+    /// Characters that channel names start with.
+    pub chantypes: String,
+    /// Channel modes grouped by how they take parameters: list modes, modes
+    /// that always take one, modes that take one only when set, and modes
+    /// that never take one.
+    pub chanmodes: [String; 4],
+    /// How the server folds case in nicks and channel names.
+    pub casemapping: CaseMapping,
+    /// The network's name.
+    pub network: Option<String>,
+    /// The maximum nick length.
+    pub nicklen: Option<usize>,
+    /// True if the server supports `MONITOR`.
+    pub monitor: bool,
+    /// True if the server supports WHOX, which lets us pick the fields in
+    /// `WHO` replies.
+    pub whox: bool,
+}
+
+impl Default for ISupport {
+    fn default() -> ISupport {
+        ISupport {
+            prefix: "qaohv".chars().zip(USER_PREFIXES.chars()).collect(),
+            chantypes: "#&".to_owned(),
+            chanmodes: ["beI".to_owned(), "k".to_owned(), "l".to_owned(), "imnpst".to_owned()],
+            casemapping: CaseMapping::default(),
+            network: None,
+            nicklen: None,
+            monitor: false,
+            whox: false,
+        }
+    }
+}
+
+impl ISupport {
+    /// Updates our features from the tokens of a single 005 reply.
+    ///
+    /// The tokens are the reply's arguments after our nick.
+    pub fn parse_tokens(&mut self, tokens: &[String]) {
+        for token in tokens {
+            self.parse_token(token);
+        }
+    }
+
+    /// Updates our features from a single token.
+    fn parse_token(&mut self, token: &str) {
+        if token.starts_with('-') {
+            self.reset(&token[1..]);
+            return;
+        }
+        let (key, val) = match token.find('=') {
+            Some(idx) => (&token[..idx], &token[idx+1..]),
+            None => (token, ""),
+        };
+        match key {
+            "PREFIX" => {
+                match parse_prefix(val) {
+                    Some(prefix) => self.prefix = prefix,
+                    None => warn!("Invalid ISUPPORT PREFIX {:?}", val),
+                }
+            },
+            "CHANTYPES" => self.chantypes = val.to_owned(),
+            "CHANMODES" => {
+                let mut groups = val.split(',');
+                for group in self.chanmodes.iter_mut() {
+                    *group = groups.next().unwrap_or("").to_owned();
+                }
+            },
+            "CASEMAPPING" => {
+                match CaseMapping::from_name(val) {
+                    Some(cm) => self.casemapping = cm,
+                    None => warn!("Unknown casemapping {:?}, using rfc1459", val),
+                }
+            },
+            "NETWORK" if !val.is_empty() => self.network = Some(val.to_owned()),
+            "NICKLEN" => self.nicklen = val.parse().ok(),
+            "MONITOR" => self.monitor = true,
+            "WHOX" => self.whox = true,
+            _ => trace!("Ignoring ISUPPORT token {}", token),
+        }
+    }
+
+    /// Resets the given feature to its default.
+    fn reset(&mut self, key: &str) {
+        let def = ISupport::default();
+        match key {
+            "PREFIX" => self.prefix = def.prefix,
+            "CHANTYPES" => self.chantypes = def.chantypes,
+            "CHANMODES" => self.chanmodes = def.chanmodes,
+            "CASEMAPPING" => self.casemapping = def.casemapping,
+            "NETWORK" => self.network = def.network,
+            "NICKLEN" => self.nicklen = def.nicklen,
+            "MONITOR" => self.monitor = def.monitor,
+            "WHOX" => self.whox = def.whox,
+            _ => {},
+        }
+    }
+
+    /// Gets the user prefix characters, from highest to lowest rank.
+    pub fn prefixes(&self) -> String {
+        self.prefix.iter().map(|&(_, p)| p).collect()
+    }
+
+    /// Gets the user prefix character the given channel mode gives, if it's
+    /// a prefix mode.
+    pub fn mode_prefix(&self, mode: char) -> Option<char> {
+        self.prefix.iter().find(|&&(m, _)| m == mode).map(|&(_, p)| p)
+    }
+
+    /// True if the given channel mode takes a parameter when it's set or
+    /// unset as specified by `set`.
+    ///
+    /// Prefix modes, list modes and modes in the second `CHANMODES` group
+    /// always take one, and modes in the third group only take one when set.
+    pub fn mode_takes_param(&self, mode: char, set: bool) -> bool {
+        self.mode_prefix(mode).is_some() ||
+            self.chanmodes[0].contains(mode) ||
+            self.chanmodes[1].contains(mode) ||
+            (set && self.chanmodes[2].contains(mode))
+    }
+
+    /// True if the given target names a channel rather than a user.
+    pub fn is_channel(&self, targ: &str) -> bool {
+        targ.chars().next().map_or(false, |c| self.chantypes.contains(c))
+    }
+}
+
+/// Parses a `PREFIX` value such as `(ov)@+` into pairs of modes and prefixes.
+fn parse_prefix(val: &str) -> Option<Vec<(char, char)>> {
+    if val.is_empty() {
+        return Some(vec![]);
+    }
+    if !val.starts_with('(') {
+        return None;
+    }
+    let end = match val.find(')') {
+        Some(end) => end,
+        None => return None,
+    };
+    let modes = &val[1..end];
+    let prefixes = &val[end+1..];
+    if modes.chars().count() != prefixes.chars().count() {
+        return None;
+    }
+    Some(modes.chars().zip(prefixes.chars()).collect())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::ISupport;
+    use network::casemap::CaseMapping;
+
+    fn tokens(s: &str) -> Vec<String> {
+        s.split(' ').map(|s| s.to_owned()).collect()
+    }
+
+    #[test]
+    fn parse_common_tokens() {
+        let mut isup = ISupport::default();
+        isup.parse_tokens(&tokens("PREFIX=(qaohv)~&@%+ CHANTYPES=#& CHANMODES=beI,k,l,imnpst \
+                                   CASEMAPPING=ascii NETWORK=freenode NICKLEN=16 EXCEPTS MONITOR=100 WHOX"));
+        assert_eq!(isup.prefix, vec![('q', '~'), ('a', '&'), ('o', '@'), ('h', '%'), ('v', '+')]);
+        assert_eq!(isup.chantypes, "#&");
+        assert_eq!(isup.chanmodes[0], "beI");
+        assert_eq!(isup.chanmodes[3], "imnpst");
+        assert_eq!(isup.casemapping, CaseMapping::Ascii);
+        assert_eq!(isup.network, Some("freenode".to_owned()));
+        assert_eq!(isup.nicklen, Some(16));
+        assert!(isup.monitor);
+        assert!(isup.whox);
+    }
+
+    #[test]
+    fn negated_token() {
+        let mut isup = ISupport::default();
+        isup.parse_tokens(&tokens("NETWORK=foo CASEMAPPING=ascii"));
+        isup.parse_tokens(&tokens("-NETWORK -CASEMAPPING"));
+        assert_eq!(isup, ISupport::default());
+    }
+
+    #[test]
+    fn invalid_prefix() {
+        let mut isup = ISupport::default();
+        isup.parse_tokens(&tokens("PREFIX=(ov)@"));
+        assert_eq!(isup.prefix, ISupport::default().prefix);
+    }
+
+    #[test]
+    fn prefix_modes() {
+        let mut isup = ISupport::default();
+        assert_eq!(isup.prefixes(), "~&@%+");
+        isup.parse_tokens(&tokens("PREFIX=(Yov)!@+ CHANMODES=beI,kfj,l,imnpst"));
+        assert_eq!(isup.prefixes(), "!@+");
+        assert_eq!(isup.mode_prefix('Y'), Some('!'));
+        assert_eq!(isup.mode_prefix('h'), None);
+        assert!(isup.mode_takes_param('Y', false));
+        assert!(isup.mode_takes_param('f', false));
+        assert!(!isup.mode_takes_param('h', true));
+        assert!(isup.mode_takes_param('l', true));
+        assert!(!isup.mode_takes_param('l', false));
+    }
+
+    #[test]
+    fn channel_types() {
+        let mut isup = ISupport::default();
+        assert!(isup.is_channel("#rust"));
+        assert!(!isup.is_channel("+chan"));
+        isup.parse_tokens(&tokens("CHANTYPES=#&+"));
+        assert!(isup.is_channel("+chan"));
+        assert!(isup.is_channel("&local"));
+        assert!(!isup.is_channel("Forkk"));
+        assert!(!isup.is_channel(""));
+    }
+}
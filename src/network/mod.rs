@@ -1,26 +1,53 @@
 use std::fmt;
+use std::cmp;
+use std::mem;
 use std::error::Error;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::collections::hash_map;
+use std::time::Duration;
 use rotor::Notifier;
+use time::{self, Tm, Timespec};
 use rotor_irc::{Message, Command};
 
-use common::messages::{NetInfo, BufTarget, CoreMsg, CoreNetMsg, SendMsgKind};
-use common::line::{LineData, MsgKind};
+use common::messages::{NetInfo, BufTarget, CoreMsg, CoreNetMsg, SendMsgKind, Alert};
+use common::line::{LineData, MsgKind, Sender};
 use common::types::{NetId, Nick};
 
 use config::NetConfig;
-use buffer::Buffer;
+use buffer::{Buffer, BufferConfig, Highlights};
 use handle::UpdateHandle;
 
 mod routing;
 mod sender;
+mod split;
+pub mod mode;
+pub mod isupport;
+pub use common::casemap;
 
 pub use self::routing::{RoutedMsg, BufferCmd, NetworkCmd};
-use self::routing::route_message;
+use self::routing::{route_message, server_time, account_tag, CtcpMsg, WHOX_TOKEN};
 
 use self::sender::IrcSender;
 pub use self::sender::IrcSendRx;
+use self::casemap::CaseMapping;
+use self::isupport::ISupport;
+use state::session::{NetSession, ChanSession};
+
+
+/// Number of `LIST` entries we collect before sending them to clients.
+const LIST_BATCH_SIZE: usize = 500;
+
+/// Most nicks we ask about in a single `ISON` query.
+const ISON_MAX_NICKS: usize = 16;
+
+/// Seconds to wait before rejoining a channel we were kicked from.
+const REJOIN_DELAY_SECS: u64 = 5;
+/// Most seconds to wait before rejoining a channel, however often we've been
+/// kicked from it.
+const REJOIN_MAX_SECS: u64 = 600;
+/// Kicks from a channel this many seconds apart or more don't make us wait
+/// longer to rejoin.
+const KICK_WINDOW_SECS: i64 = 900;
 
 
 /// An IRC network.
@@ -32,9 +59,70 @@ pub use self::sender::IrcSendRx;
 pub struct IrcNetwork {
     id: NetId,
     nick: Nick,
+    /// The nick the user last changed to, if they've changed it. We register
+    /// with this rather than the configured nick, but not with fallback
+    /// nicks we picked because the nick we wanted was taken.
+    chosen_nick: Option<Nick>,
+    /// The nick the user most recently asked to change to.
+    nick_req: Option<Nick>,
+    /// True if something saved in the session has changed since
+    /// `take_session_changed` was last called.
+    session_changed: bool,
     pub cfg: NetConfig,
+    /// Buffers keyed by `buf_key`, so channel names differing only in case
+    /// share a buffer.
     bufs: HashMap<BufTarget, Buffer>,
     conn: Option<IrcSender>,
+    /// IRCv3 capabilities the server has acknowledged.
+    caps: Vec<String>,
+    /// Our away message if the server has confirmed we're away.
+    away: Option<String>,
+    /// The away message we most recently asked the server to set.
+    away_req: Option<String>,
+    /// True if the user explicitly disconnected from this network. We don't
+    /// reconnect to offline networks.
+    offline: bool,
+    /// Number of reconnection attempts since we last registered successfully.
+    reconnects: u32,
+    /// Index into the configured server list of the next server to try.
+    next_server: usize,
+    /// Features the server advertised in `RPL_ISUPPORT`.
+    isupport: ISupport,
+    /// Channels we're in, keyed by their lowercased name, along with their
+    /// names and the keys we joined them with. This is kept across reconnects
+    /// so we can rejoin them.
+    chans: HashMap<String, (String, Option<String>)>,
+    /// Keys for joins we've sent but haven't seen succeed yet, keyed by the
+    /// lowercased channel name.
+    join_keys: HashMap<String, String>,
+    /// Lowercased names of channels the user explicitly parted. We don't
+    /// autojoin these even if they're configured.
+    parted: Vec<String>,
+    /// Settings for the network's buffers.
+    buf_cfg: BufferConfig,
+    /// Whether we answer CTCP queries.
+    ctcp_replies: bool,
+    /// Decides which channel messages ping the user.
+    highlights: Highlights,
+    /// `LIST` entries we haven't sent to clients yet.
+    chan_list: Vec<(String, usize, String)>,
+    /// Nicks in each `ISON` query we're waiting on a reply to, oldest first.
+    ///
+    /// Replies are matched to the query asking about every nick they list,
+    /// so replies to `ISON` queries the user sent themselves are skipped.
+    ison_queries: VecDeque<Vec<Nick>>,
+    /// Channels we've joined but haven't sent a `WHO` for yet. We only send
+    /// one `WHO` at a time so joining lots of channels doesn't flood us.
+    who_queue: VecDeque<String>,
+    /// The lowercased name of the channel we're waiting on a `WHO` reply
+    /// for.
+    who_pending: Option<String>,
+    /// Channels we were kicked from and will rejoin, with the keys we joined
+    /// them with and when to rejoin them.
+    rejoins: Vec<(String, Option<String>, Timespec)>,
+    /// Number of times in a row we've been kicked from each channel and when
+    /// we last were, keyed by the lowercased channel name.
+    kicks: HashMap<String, (u32, Timespec)>,
 }
 
 /// Buffer access and other info
@@ -46,42 +134,159 @@ impl IrcNetwork {
 
     /// Returns an iterator over the buffers in the set.
     pub fn iter_bufs(&self) -> IterBufs {
-        self.bufs.iter()
+        self.bufs.values()
     }
 
     /// Returns an iterator over the buffers in the set.
     pub fn iter_bufs_mut(&mut self) -> IterBufsMut {
-        self.bufs.iter_mut()
+        self.bufs.values_mut()
     }
 
     /// Gets a reference to the given buffer if it exists.
     pub fn get_buf(&self, targ: &BufTarget) -> Option<&Buffer> {
-        self.bufs.get(targ)
+        self.bufs.get(&self.buf_key(targ))
+    }
+
+    /// Gets our current nick on this network.
+    pub fn nick(&self) -> &str {
+        &self.nick
+    }
+
+    /// Gets the nick to register with. This is the nick the user last
+    /// changed to, if any, so their nick changes are kept across reconnects
+    /// and restarts. Otherwise it's the configured nick.
+    pub fn initial_nick(&self) -> &str {
+        self.chosen_nick.as_ref().map_or(self.cfg.nick(), |n| &n[..])
+    }
+
+    /// Notes that something saved in the session has changed.
+    pub fn mark_session_changed(&mut self) {
+        self.session_changed = true;
+    }
+
+    /// Returns true if something saved in the session has changed since the
+    /// last call.
+    pub fn take_session_changed(&mut self) -> bool {
+        mem::replace(&mut self.session_changed, false)
+    }
+
+    /// True if the user explicitly disconnected from this network.
+    pub fn is_offline(&self) -> bool {
+        self.offline
+    }
+
+    /// True if we have a connection to this network.
+    pub fn is_connected(&self) -> bool {
+        self.conn.is_some()
+    }
+
+    /// Checks whether the server acknowledged the given capability.
+    pub fn has_cap(&self, cap: &str) -> bool {
+        self.caps.iter().any(|c| c == cap)
+    }
+
+    /// Sets the list of capabilities acknowledged by the server.
+    pub fn set_caps(&mut self, caps: Vec<String>) {
+        self.caps = caps;
+    }
+
+    /// Gets the casemapping used to compare nicks on this network.
+    pub fn casemap(&self) -> CaseMapping {
+        self.isupport.casemapping
+    }
+
+    /// Gets the features the server advertised in `RPL_ISUPPORT`.
+    pub fn isupport(&self) -> &ISupport {
+        &self.isupport
     }
 
     /// Gets a mutable reference to the given buffer.
     pub fn get_buf_mut<'a>(&'a mut self, targ: &BufTarget) -> Option<&mut Buffer> {
-        self.bufs.get_mut(targ)
+        let key = self.buf_key(targ);
+        self.bufs.get_mut(&key)
+    }
+
+    /// Returns the key the buffer for `targ` is stored under. Channel names
+    /// are lowercased with the network's casemapping, while the buffer keeps
+    /// the name it was created with for display.
+    fn buf_key(&self, targ: &BufTarget) -> BufTarget {
+        match *targ {
+            BufTarget::Channel(ref chan) => BufTarget::Channel(self.isupport.casemapping.to_lower(chan)),
+            ref other => other.clone(),
+        }
+    }
+
+    /// Re-keys the buffers after the casemapping changes.
+    fn rekey_bufs(&mut self) {
+        let bufs = mem::replace(&mut self.bufs, HashMap::new());
+        for (_, buf) in bufs {
+            let key = self.buf_key(buf.id());
+            if self.bufs.contains_key(&key) {
+                warn!("Dropping buffer {:?}, which is now the same channel as another buffer",
+                      buf.id());
+                continue;
+            }
+            self.bufs.insert(key, buf);
+        }
+    }
+
+    /// Creates a private message buffer for the given nick if there isn't one
+    /// already.
+    pub fn open_private<U>(&mut self, nick: Nick, u: &mut U)
+        where U : UpdateHandle<CoreNetMsg>
+    {
+        self.get_create_buf(BufTarget::Private(nick), u);
     }
 }
 
-pub type IterBufs<'a> = hash_map::Iter<'a, BufTarget, Buffer>;
-pub type IterBufsMut<'a> = hash_map::IterMut<'a, BufTarget, Buffer>;
+pub type IterBufs<'a> = hash_map::Values<'a, BufTarget, Buffer>;
+pub type IterBufsMut<'a> = hash_map::ValuesMut<'a, BufTarget, Buffer>;
 
 /// IRC message handling
 impl IrcNetwork {
-    pub fn new(id: String, cfg: &NetConfig) -> IrcNetwork {
+    pub fn new(id: String, cfg: &NetConfig, buf_cfg: BufferConfig) -> IrcNetwork {
         // TODO: Allow configuring reconnection settings.
-        // TODO: Allow configuring encoding.
         IrcNetwork {
             id: id.to_owned(),
             cfg: cfg.clone(),
             nick: String::new(),
+            chosen_nick: None,
+            nick_req: None,
+            session_changed: false,
             conn: None,
             bufs: HashMap::new(),
+            caps: vec![],
+            away: None,
+            away_req: None,
+            offline: false,
+            reconnects: 0,
+            next_server: 0,
+            isupport: ISupport::default(),
+            chans: HashMap::new(),
+            join_keys: HashMap::new(),
+            parted: vec![],
+            buf_cfg: buf_cfg,
+            ctcp_replies: true,
+            highlights: Highlights::default(),
+            chan_list: vec![],
+            ison_queries: VecDeque::new(),
+            who_queue: VecDeque::new(),
+            who_pending: None,
+            rejoins: vec![],
+            kicks: HashMap::new(),
         }
     }
 
+    /// Sets whether we answer CTCP queries.
+    pub fn set_ctcp_replies(&mut self, replies: bool) {
+        self.ctcp_replies = replies;
+    }
+
+    /// Sets the words and ignored nicks used to decide what pings the user.
+    pub fn set_highlights(&mut self, hl: Highlights) {
+        self.highlights = hl;
+    }
+
     /// Used to register a connection state machine as the connection for this
     /// network.
     ///
@@ -115,96 +320,295 @@ impl IrcNetwork {
         where U : UpdateHandle<CoreNetMsg>
     {
         self.conn = None;
+        self.caps.clear();
+        let old_cm = self.isupport.casemapping;
+        self.isupport = ISupport::default();
+        if self.isupport.casemapping != old_cm {
+            self.rekey_bufs();
+        }
+        self.away = None;
+        self.ison_queries.clear();
+        self.who_queue.clear();
+        self.who_pending = None;
+        self.rejoins.clear();
         u.send_clients(CoreNetMsg::Connection(false));
+        // We'll be back in our channels once we've reconnected and rejoined,
+        // but until then we aren't.
+        for buf in self.bufs.values_mut() {
+            let id = buf.id().clone();
+            let mut buf_uh = u.wrap(|msg| CoreNetMsg::BufMsg(id.clone(), msg));
+            buf.disconnected(&mut buf_uh);
+        }
+    }
+
+    /// Returns the number of seconds to wait before the next reconnection
+    /// attempt and counts the attempt.
+    pub fn next_reconnect_delay(&mut self) -> u64 {
+        let delay = backoff_secs(self.cfg.reconnect_delay(), self.cfg.reconnect_max(), self.reconnects);
+        self.reconnects += 1;
+        delay
+    }
+
+    /// Returns the hostname to use for the next connection attempt.
+    ///
+    /// Each call moves on to the next configured server, wrapping around to
+    /// the first one after the last.
+    pub fn next_server(&mut self) -> String {
+        let servers = self.cfg.servers();
+        let server = servers[self.next_server % servers.len()].clone();
+        self.next_server = (self.next_server + 1) % servers.len();
+        server
+    }
+
+    /// Resets the reconnection delay. Called once we've registered with the
+    /// server.
+    pub fn reset_reconnects(&mut self) {
+        self.reconnects = 0;
     }
 
     /// Handles a message from IRC
     pub fn handle_msg<U>(&mut self, msg: Message, u: &mut U)
         where U : UpdateHandle<CoreNetMsg>
     {
-        match route_message(msg, &self.nick) {
-            Some(RoutedMsg::Network(cmd)) => self.handle_net_cmd(cmd, u),
+        let time = server_time(&msg).unwrap_or_else(time::now);
+        // With `account-tag`, messages from logged in users say which
+        // account they're using.
+        if let Some(account) = account_tag(&msg) {
+            if let Some(Sender::User(user)) = msg.prefix.as_ref().map(|p| Sender::parse_prefix(p)) {
+                self.set_account(&user.nick, Some(account), u);
+            }
+        }
+        match route_message(msg, &self.isupport) {
+            Some(RoutedMsg::Network(cmd)) => self.handle_net_cmd(cmd, time, u),
             Some(RoutedMsg::Channel(chan, cmd)) => {
                 let nick = self.nick.clone();
-                let buf = self.get_create_buf(BufTarget::Channel(chan), u);
-                let id = buf.id().clone();
-                let mut buf_uh = u.wrap(|msg| CoreNetMsg::BufMsg(id.clone(), msg));
-                buf.handle_cmd(cmd, &nick, &mut buf_uh);
+                let hl = self.highlights.clone();
+                let isup = self.isupport.clone();
+                let cm = isup.casemapping;
+                let end_who = match cmd { BufferCmd::RPL_ENDOFWHO => true, _ => false };
+                let kicked = match cmd {
+                    BufferCmd::KICK { ref targ, .. } => cm.eq(targ, &nick),
+                    _ => false,
+                };
+                let joined = {
+                    let buf = self.get_create_buf(BufTarget::Channel(chan.clone()), u);
+                    let was_joined = buf.joined();
+                    let id = buf.id().clone();
+                    let mut buf_uh = u.wrap(|msg| CoreNetMsg::BufMsg(id.clone(), msg));
+                    buf.handle_cmd(cmd, &nick, &hl, &isup, time, &mut buf_uh);
+                    if buf.joined() != was_joined { Some(buf.joined()) } else { None }
+                };
+                if end_who && self.who_pending.as_ref() == Some(&cm.to_lower(&chan)) {
+                    self.who_pending = None;
+                    self.send_next_who(u);
+                }
+                if kicked && joined == Some(false) {
+                    self.schedule_rejoin(&chan, u);
+                }
+                if let Some(joined) = joined {
+                    if joined {
+                        self.who_queue.push_back(chan.clone());
+                        self.send_next_who(u);
+                    } else {
+                        self.cancel_who(&chan, u);
+                    }
+                    self.set_chan_joined(chan, joined);
+                }
             },
-            Some(RoutedMsg::Private(user, cmd)) => {
+            Some(RoutedMsg::Private(user, to, cmd)) => {
                 let nick = self.nick.clone();
-                let buf = self.get_create_buf(BufTarget::Private(user.nick), u);
+                let hl = self.highlights.clone();
+                let isup = self.isupport.clone();
+                let cm = isup.casemapping;
+                // With `echo-message`, messages we send come back from us, and
+                // belong in the buffer of the user we sent them to.
+                let other = if cm.eq(&user.nick, &nick) { to } else { user.nick };
+                let buf = self.get_create_buf(BufTarget::Private(other), u);
                 let id = buf.id().clone();
                 let mut buf_uh = u.wrap(|msg| CoreNetMsg::BufMsg(id.clone(), msg));
-                buf.handle_cmd(cmd, &nick, &mut buf_uh);
+                buf.handle_cmd(cmd, &nick, &hl, &isup, time, &mut buf_uh);
             },
             Some(RoutedMsg::NetBuffer(cmd)) => {
                 let nick = self.nick.clone();
+                let hl = self.highlights.clone();
+                let isup = self.isupport.clone();
                 let buf = self.get_create_buf(BufTarget::Network, u);
                 let id = buf.id().clone();
                 let mut buf_uh = u.wrap(|msg| CoreNetMsg::BufMsg(id.clone(), msg));
-                buf.handle_cmd(cmd, &nick, &mut buf_uh);
+                buf.handle_cmd(cmd, &nick, &hl, &isup, time, &mut buf_uh);
             },
             None => {},
         }
     }
 
     /// Handles network-routed IRC messages.
-    fn handle_net_cmd<U>(&mut self, cmd: NetworkCmd, u: &mut U)
+    fn handle_net_cmd<U>(&mut self, cmd: NetworkCmd, time: Tm, u: &mut U)
         where U : UpdateHandle<CoreNetMsg>
     {
         use self::routing::NetworkCmd::*;
+        let cm = self.isupport.casemapping;
         match cmd {
             QUIT(user, reason) => {
-                for (targ, ref mut buf) in self.bufs.iter_mut() {
-                    if buf.has_user(&user.nick) {
-                        let mut buf_uh = u.wrap(|msg| CoreNetMsg::BufMsg(targ.clone(), msg));
-                        buf.handle_quit(&user, reason.clone(), &mut buf_uh);
+                for buf in self.bufs.values_mut() {
+                    if buf.has_user(&user.nick, cm) {
+                        let id = buf.id().clone();
+                        let mut buf_uh = u.wrap(|msg| CoreNetMsg::BufMsg(id.clone(), msg));
+                        buf.handle_quit(&user, reason.clone(), cm, time, &mut buf_uh);
                     }
                 }
+                self.set_user_online(&user.nick, false, u);
             },
             NICK(user, new) => {
-                if user.nick == self.nick {
+                if cm.eq(&user.nick, &self.nick) {
                     debug!("Nick changed to {}", new);
                     self.nick = new.clone();
+                    if self.nick_req.as_ref().map_or(false, |n| cm.eq(n, &new)) {
+                        self.nick_req = None;
+                        self.chosen_nick = Some(new.clone());
+                        self.session_changed = true;
+                    }
                     u.send_clients(CoreNetMsg::NickChanged(new.clone()));
                 }
-                for (targ, ref mut buf) in self.bufs.iter_mut() {
-                    if buf.has_user(&user.nick) {
-                        let mut buf_uh = u.wrap(|msg| CoreNetMsg::BufMsg(targ.clone(), msg));
-                        buf.handle_nick(&user, new.clone(), &mut buf_uh);
+                for buf in self.bufs.values_mut() {
+                    if buf.has_user(&user.nick, cm) {
+                        let id = buf.id().clone();
+                        let mut buf_uh = u.wrap(|msg| CoreNetMsg::BufMsg(id.clone(), msg));
+                        buf.handle_nick(&user, new.clone(), cm, time, &mut buf_uh);
                     }
                 }
             },
+            ACCOUNT(user, account) => {
+                debug!("User {} changed account from {:?} to {:?}",
+                       user.nick, self.account(&user.nick), account);
+                self.set_account(&user.nick, account, u);
+            },
+            UMODE(changes) => {
+                let (modes, args) = mode::format_modes(&changes);
+                info!("User modes changed: {} {:?}", modes, args);
+                self.push_status(format!("User mode set: {}", modes), u);
+            },
+            INVITE(user, chan) => {
+                info!("Invited to {} by {}", chan, user.nick);
+                self.push_status(format!("{} invited you to {}", user.nick, chan), u);
+                let joined = self.get_buf(&BufTarget::Channel(chan.clone()))
+                    .map_or(false, |b| b.joined());
+                // Don't alert for invites we sent ourselves or for channels
+                // we're already in.
+                if !cm.eq(&user.nick, &self.nick) && !joined {
+                    let msg = format!("{} invited you to {}", user.nick, chan);
+                    u.post_alert(Alert::invite(self.id.clone(), chan, user.nick.clone(), msg));
+                }
+            },
+            ERROR(reason) => {
+                warn!("Server closed the connection: {}", reason);
+                self.push_status(format!("Server closed the connection: {}", reason), u);
+            },
+            RPL_UNAWAY => {
+                info!("No longer marked as away");
+                self.away = None;
+                u.send_clients(CoreNetMsg::Away(None));
+                self.push_status("You are no longer marked as away".to_owned(), u);
+            },
+            RPL_NOWAWAY => {
+                let msg = self.away_req.take().unwrap_or("Away".to_owned());
+                info!("Marked as away: {}", msg);
+                self.away = Some(msg.clone());
+                u.send_clients(CoreNetMsg::Away(Some(msg)));
+                self.push_status("You have been marked as away".to_owned(), u);
+            },
+            RPL_LISTSTART => self.chan_list.clear(),
+            RPL_LIST(chan, users, topic) => {
+                self.chan_list.push((chan, users, topic));
+                // Servers send one line per channel, so we batch them up to
+                // avoid sending clients thousands of tiny messages.
+                if self.chan_list.len() >= LIST_BATCH_SIZE {
+                    let batch = mem::replace(&mut self.chan_list, vec![]);
+                    u.send_clients(CoreNetMsg::ChannelList { chans: batch, end: false });
+                }
+            },
+            RPL_LISTEND => {
+                // This may be empty, but clients still want to know we're
+                // done.
+                let batch = mem::replace(&mut self.chan_list, vec![]);
+                debug!("Channel list finished");
+                u.send_clients(CoreNetMsg::ChannelList { chans: batch, end: true });
+            },
             RPL_MYINFO(nick) => {
                 info!("Set initial nick to {}", nick);
                 self.nick = nick;
             },
+            RPL_ISUPPORT(tokens) => {
+                let old_name = self.isupport.network.clone();
+                let old_cm = self.isupport.casemapping;
+                let had_monitor = self.isupport.monitor;
+                self.isupport.parse_tokens(&tokens);
+                if self.isupport.monitor && !had_monitor {
+                    let nicks = self.private_nicks();
+                    if !nicks.is_empty() {
+                        debug!("Monitoring {:?}", nicks);
+                        let _ = self.send(monitor_msg("+", &nicks), u);
+                    }
+                }
+                debug!("Server supports: {:?}", self.isupport);
+                if self.isupport.network != old_name {
+                    if let Some(ref name) = self.isupport.network {
+                        info!("Network {} is named {}", self.id, name);
+                        u.send_clients(CoreNetMsg::NetName(name.clone()));
+                    }
+                }
+                if self.isupport.casemapping != old_cm {
+                    self.rekey_bufs();
+                    let name = self.isupport.casemapping.name().to_owned();
+                    u.send_clients(CoreNetMsg::CaseMapping(name));
+                }
+            },
 
-            CtcpQuery(ref user, _, ref query) if query.tag == "VERSION" => {
-                info!("Received CTCP version request from {}", user.nick);
+            RPL_ISON(online) => {
+                let idx = self.ison_queries.iter().position(|asked| {
+                    online.iter().all(|n| asked.iter().any(|a| cm.eq(a, n)))
+                });
+                let asked = match idx.and_then(|i| self.ison_queries.remove(i)) {
+                    Some(asked) => asked,
+                    None => {
+                        debug!("Ignoring ISON reply we didn't ask for: {:?}", online);
+                        return;
+                    },
+                };
+                for nick in asked {
+                    let is_online = online.iter().any(|n| cm.eq(n, &nick));
+                    self.set_user_online(&nick, is_online, u);
+                }
+            },
+            RPL_MONONLINE(nicks) => {
+                for nick in nicks { self.set_user_online(&nick, true, u); }
+            },
+            RPL_MONOFFLINE(nicks) => {
+                for nick in nicks { self.set_user_online(&nick, false, u); }
+            },
+
+            // Queries from ourselves are echoes of ones we sent.
+            CtcpQuery(ref user, _, ref query) if cm.eq(&user.nick, &self.nick) => {
+                trace!("Ignoring echo of our CTCP query {}", query.tag);
+            },
+            CtcpQuery(ref user, _, ref query) if self.ctcp_replies && ctcp_reply(query).is_some() => {
+                info!("Received CTCP {} request from {}", query.tag, user.nick);
                 {
                     let mut buf_uh = u.wrap(|msg| CoreNetMsg::BufMsg(BufTarget::Network, msg));
                     let mut buf = self.get_buf_mut(&BufTarget::Network).unwrap();
                     buf.push_line(LineData::Message {
                         kind: MsgKind::Status,
                         from: user.nick.clone(),
-                        msg: format!("*CTCP VERSION request*"),
+                        msg: format!("*CTCP {} request*", query.tag),
                     }, &mut buf_uh);
                 }
 
-                let vsn = env!("CARGO_PKG_VERSION");
-                let vsn_msg = format!("\u{1}VERSION distirc {}\u{1}", vsn);
+                let reply = ctcp_reply(query).unwrap();
                 // We don't care too much if we fail to respond to CTCP.
-                let _ = self.send(Message {
-                    prefix: None,
-                    command: Command::NOTICE,
-                    args: vec![user.nick.clone()],
-                    body: Some(vsn_msg),
-                }, u);
+                let _ = self.send(Message::notice(user.nick.clone(),
+                                                  format!("\u{1}{}\u{1}", reply)), u);
             },
             CtcpQuery(_, _, query) => {
-                info!("Ignoring unsupported CTCP query {}", query.tag);
+                info!("Ignoring CTCP query {}", query.tag);
             },
             CtcpReply(_, _, query) => {
                 info!("Ignoring unsupported CTCP reply {}", query.tag);
@@ -212,21 +616,339 @@ impl IrcNetwork {
 
             UnknownCode(code, args, body) => {
                 warn!(target: "distirc::network::rplcode",
-                      "Unknown reply code {:?} args: {:?} body: {:?}", code, args, body);
+                      "Unhandled reply code {} ({}) args: {:?} body: {:?}",
+                      code, code.name().unwrap_or("unknown"), args, body);
             },
         }
     }
 
+    /// Returns the account the user with the given nick is logged in to, if
+    /// they share a channel with us and we know it.
+    pub fn account(&self, nick: &str) -> Option<&str> {
+        let cm = self.isupport.casemapping;
+        self.bufs.values().filter_map(|buf| buf.user_account(nick, cm)).next()
+    }
+
+    /// Records the account of the user with the given nick in every channel
+    /// they're in.
+    fn set_account<U>(&mut self, nick: &str, account: Option<String>, u: &mut U)
+        where U : UpdateHandle<CoreNetMsg>
+    {
+        let cm = self.isupport.casemapping;
+        for buf in self.bufs.values_mut() {
+            if buf.has_user(nick, cm) {
+                let id = buf.id().clone();
+                let mut buf_uh = u.wrap(|msg| CoreNetMsg::BufMsg(id.clone(), msg));
+                buf.set_user_account(nick, account.clone(), cm, &mut buf_uh);
+            }
+        }
+    }
+
+    /// Sends a `WHO` for the next channel in the queue, unless we're still
+    /// waiting on a reply to the last one.
+    ///
+    /// With WHOX, we ask for users' accounts as well.
+    fn send_next_who<U>(&mut self, u: &mut U)
+        where U : UpdateHandle<CoreNetMsg>
+    {
+        if self.who_pending.is_some() { return; }
+        let chan = match self.who_queue.pop_front() {
+            Some(chan) => chan,
+            None => return,
+        };
+        let mut msg = Message::cmd(Command::WHO).arg(chan.clone());
+        if self.isupport.whox {
+            msg = msg.arg(format!("%tcuhnfa,{}", WHOX_TOKEN));
+        }
+        if self.send(msg, u).is_ok() {
+            self.who_pending = Some(self.isupport.casemapping.to_lower(&chan));
+        }
+    }
+
+    /// Forgets about any `WHO` for `chan` after we leave it, so the queue
+    /// doesn't wait on a reply we no longer care about.
+    fn cancel_who<U>(&mut self, chan: &str, u: &mut U)
+        where U : UpdateHandle<CoreNetMsg>
+    {
+        let cm = self.isupport.casemapping;
+        self.who_queue.retain(|c| !cm.eq(c, chan));
+        if self.who_pending.as_ref() == Some(&cm.to_lower(chan)) {
+            self.who_pending = None;
+            self.send_next_who(u);
+        }
+    }
+
+    /// Returns the nicks of the users we have private buffers with.
+    fn private_nicks(&self) -> Vec<Nick> {
+        self.bufs.keys().filter_map(|targ| match *targ {
+            BufTarget::Private(ref nick) => Some(nick.clone()),
+            _ => None,
+        }).collect()
+    }
+
+    /// Marks the user with the given nick as online or offline in our private
+    /// buffer with them, if there is one.
+    fn set_user_online<U>(&mut self, nick: &str, online: bool, u: &mut U)
+        where U : UpdateHandle<CoreNetMsg>
+    {
+        let cm = self.isupport.casemapping;
+        for buf in self.bufs.values_mut() {
+            let id = buf.id().clone();
+            if let BufTarget::Private(ref pnick) = id {
+                if cm.eq(pnick, nick) {
+                    let mut buf_uh = u.wrap(|msg| CoreNetMsg::BufMsg(id.clone(), msg));
+                    buf.set_online(online, &mut buf_uh);
+                }
+            }
+        }
+    }
+
+    /// Builds `ISON` queries asking whether the users we have private
+    /// buffers with are online.
+    ///
+    /// This is called periodically by the connection. Servers that support
+    /// `MONITOR` tell us when users come and go, so this returns no queries
+    /// for them.
+    pub fn presence_poll(&mut self) -> Vec<Message> {
+        if self.isupport.monitor {
+            return vec![];
+        }
+        let nicks = self.private_nicks();
+        let mut msgs = vec![];
+        for chunk in nicks.chunks(ISON_MAX_NICKS) {
+            msgs.push(Message::cmd(Command::ISON).body(chunk.join(" ")));
+            self.ison_queries.push_back(chunk.to_vec());
+        }
+        msgs
+    }
+
+    /// Arranges to rejoin `chan` after we were kicked from it, if it's set to
+    /// be rejoined automatically.
+    ///
+    /// The more often we're kicked, the longer we wait, so a channel that
+    /// keeps kicking us doesn't get flooded with joins.
+    fn schedule_rejoin<U>(&mut self, chan: &str, u: &mut U)
+        where U : UpdateHandle<CoreNetMsg>
+    {
+        let cm = self.isupport.casemapping;
+        if !self.cfg.auto_rejoin().iter().any(|c| c == "*" || cm.eq(c, chan)) {
+            return;
+        }
+        let lower = cm.to_lower(chan);
+        let now = time::get_time();
+        let attempt = match self.kicks.get(&lower) {
+            Some(&(n, last)) if now - last < time::Duration::seconds(KICK_WINDOW_SECS) => n,
+            _ => 0,
+        };
+        self.kicks.insert(lower.clone(), (attempt + 1, now));
+        let delay = backoff_secs(REJOIN_DELAY_SECS, REJOIN_MAX_SECS, attempt);
+        info!("Rejoining {} in {} seconds", chan, delay);
+
+        let key = self.chans.get(&lower).and_then(|&(_, ref key)| key.clone());
+        self.rejoins.retain(|&(ref c, _, _)| !cm.eq(c, chan));
+        self.rejoins.push((chan.to_owned(), key, now + time::Duration::seconds(delay as i64)));
+
+        if let Some(buf) = self.get_buf_mut(&BufTarget::Channel(chan.to_owned())) {
+            let id = buf.id().clone();
+            let mut buf_uh = u.wrap(|msg| CoreNetMsg::BufMsg(id.clone(), msg));
+            buf.push_line(LineData::Message {
+                kind: MsgKind::Status,
+                from: "*".to_owned(),
+                msg: format!("Rejoining in {} seconds", delay),
+            }, &mut buf_uh);
+        }
+    }
+
+    /// Returns how long until we should next rejoin a channel we were kicked
+    /// from, if we're waiting to rejoin any.
+    pub fn next_rejoin(&self) -> Option<Duration> {
+        let now = time::get_time();
+        self.rejoins.iter().map(|&(_, _, at)| at).min().map(|at| {
+            Duration::from_millis(cmp::max((at - now).num_milliseconds(), 0) as u64)
+        })
+    }
+
+    /// Builds `JOIN`s for the channels it's time to rejoin.
+    ///
+    /// This is called by the connection once `next_rejoin` has passed.
+    /// Channels the user has since parted or joined again are skipped.
+    pub fn due_rejoins(&mut self) -> Vec<Message> {
+        let cm = self.isupport.casemapping;
+        let now = time::get_time();
+        let (due, later): (Vec<_>, Vec<_>) = self.rejoins.drain(..).partition(|&(_, _, at)| at <= now);
+        self.rejoins = later;
+        let mut msgs = vec![];
+        for (chan, key, _) in due {
+            let lower = cm.to_lower(&chan);
+            if self.parted.contains(&lower) || self.chans.contains_key(&lower) {
+                continue;
+            }
+            let mut args = vec![chan];
+            if let Some(key) = key {
+                self.join_keys.insert(lower, key.clone());
+                args.push(key);
+            }
+            msgs.push(Message::new(None, Command::JOIN, args, None));
+        }
+        msgs
+    }
+
+    /// Updates the set of channels we're in after we join or leave `chan`.
+    fn set_chan_joined(&mut self, chan: String, joined: bool) {
+        let cm = self.isupport.casemapping;
+        let lower = cm.to_lower(&chan);
+        if joined {
+            // We're back, so there's no need to rejoin.
+            self.rejoins.retain(|&(ref c, _, _)| !cm.eq(c, &chan));
+            let key = self.join_keys.remove(&lower);
+            self.chans.insert(lower, (chan, key));
+            self.session_changed = true;
+        } else if self.chans.remove(&lower).is_some() {
+            self.session_changed = true;
+        }
+    }
+
+    /// Builds a `JOIN` message for the configured channels and the channels
+    /// we were in before reconnecting, leaving out any the user parted.
+    ///
+    /// Returns `None` if there are no channels to join.
+    pub fn autojoin_msg(&self) -> Option<Message> {
+        let cm = self.isupport.casemapping;
+        let mut chans: Vec<(String, Option<String>)> = self.chans.values().cloned().collect();
+        for chan in self.cfg.channels() {
+            let lower = cm.to_lower(&chan);
+            if !self.chans.contains_key(&lower) && !self.parted.contains(&lower) {
+                chans.push((chan, None));
+            }
+        }
+        if chans.is_empty() {
+            return None;
+        }
+        // Keys are matched to channels by position, so keyed channels have to
+        // come first.
+        chans.sort_by_key(|&(_, ref key)| key.is_none());
+        let names = chans.iter().map(|&(ref c, _)| c.clone()).collect::<Vec<_>>();
+        let keys = chans.iter().filter_map(|&(_, ref k)| k.clone()).collect::<Vec<_>>();
+        let mut args = vec![names.join(",")];
+        if !keys.is_empty() {
+            args.push(keys.join(","));
+        }
+        Some(Message::new(None, Command::JOIN, args, None))
+    }
+
+    /// Takes a snapshot of our nick, the channels we're in and how far the
+    /// user has read, so they can be restored after the core restarts.
+    pub fn session(&self) -> NetSession {
+        let mut channels = self.chans.values().map(|&(ref name, ref key)| {
+            let topic = self.get_buf(&BufTarget::Channel(name.clone()))
+                .map_or(String::new(), |b| b.topic().to_owned());
+            ChanSession { name: name.clone(), key: key.clone(), topic: topic }
+        }).collect::<Vec<_>>();
+        channels.sort_by(|a, b| a.name.cmp(&b.name));
+        let read_ids = self.bufs.values()
+            .filter(|b| b.read_id() > 0)
+            .map(|b| (b.id().name().to_owned(), b.read_id()))
+            .collect();
+        NetSession {
+            nick: self.chosen_nick.clone().unwrap_or(String::new()),
+            channels: channels,
+            read_ids: read_ids,
+        }
+    }
+
+    /// Restores the nick and channels saved by `session`. The channels are
+    /// rejoined once we connect.
+    pub fn restore_session(&mut self, session: NetSession) {
+        if !session.nick.is_empty() {
+            self.chosen_nick = Some(session.nick);
+        }
+        for chan in session.channels {
+            let lower = self.isupport.casemapping.to_lower(&chan.name);
+            let targ = BufTarget::Channel(chan.name.clone());
+            let key = self.buf_key(&targ);
+            if !self.bufs.contains_key(&key) {
+                let buf = Buffer::new(self.id.clone(), targ, &self.buf_cfg);
+                self.bufs.insert(key.clone(), buf);
+            }
+            if let Some(buf) = self.bufs.get_mut(&key) {
+                buf.restore_topic(chan.topic);
+            }
+            self.chans.insert(lower, (chan.name, chan.key));
+        }
+        for (name, id) in session.read_ids {
+            let targ = if name == BufTarget::Network.name() {
+                BufTarget::Network
+            } else if self.isupport.is_channel(&name) {
+                BufTarget::Channel(name)
+            } else {
+                BufTarget::Private(name)
+            };
+            let key = self.buf_key(&targ);
+            let buf_cfg = &self.buf_cfg;
+            let nid = &self.id;
+            self.bufs.entry(key)
+                .or_insert_with(|| Buffer::new(nid.clone(), targ, buf_cfg))
+                .restore_read_id(id);
+        }
+    }
+
+    /// Removes the given buffer. If it's a private buffer, we stop monitoring
+    /// its user.
+    ///
+    /// The network buffer and channels we're in can't be removed. Returns
+    /// false if the buffer wasn't removed.
+    pub fn remove_buf<U>(&mut self, targ: &BufTarget, u: &mut U) -> bool
+        where U : UpdateHandle<CoreNetMsg>
+    {
+        let key = self.buf_key(targ);
+        let removable = match (targ, self.bufs.get(&key)) {
+            (_, None) | (&BufTarget::Network, _) => false,
+            (&BufTarget::Channel(_), Some(buf)) => !buf.joined(),
+            (&BufTarget::Private(_), Some(_)) => true,
+        };
+        if !removable {
+            return false;
+        }
+        self.bufs.remove(&key);
+        self.session_changed = true;
+        debug!("Removed buffer {:?}", targ);
+        if let BufTarget::Private(ref nick) = *targ {
+            if self.isupport.monitor && self.is_connected() {
+                let _ = self.send(monitor_msg("-", &[nick.clone()]), u);
+            }
+        }
+        true
+    }
+
+    /// Pushes a status message into the network buffer.
+    pub fn push_status<U>(&mut self, msg: String, u: &mut U)
+        where U : UpdateHandle<CoreNetMsg>
+    {
+        let buf = self.get_create_buf(BufTarget::Network, u);
+        let mut buf_uh = u.wrap(|msg| CoreNetMsg::BufMsg(BufTarget::Network, msg));
+        buf.push_line(LineData::Message {
+            kind: MsgKind::Status,
+            from: "*".to_owned(),
+            msg: msg,
+        }, &mut buf_uh);
+    }
+
     /// Returns the given buffer, creating one if it doesn't exist.
     fn get_create_buf<U>(&mut self, targ: BufTarget, u: &mut U) -> &mut Buffer
         where U : UpdateHandle<CoreNetMsg>
     {
-        if !self.bufs.contains_key(&targ) {
-            let buf = Buffer::new(self.id.clone(), targ.clone());
+        let key = self.buf_key(&targ);
+        if !self.bufs.contains_key(&key) {
+            let buf = Buffer::new(self.id.clone(), targ.clone(), &self.buf_cfg);
             u.send_clients(CoreNetMsg::Buffers(vec![buf.as_info()]));
-            self.bufs.entry(targ.clone()).or_insert(buf)
+            if let BufTarget::Private(ref nick) = targ {
+                if self.isupport.monitor && self.is_connected() {
+                    let _ = self.send(monitor_msg("+", &[nick.clone()]), u);
+                }
+            }
+            self.bufs.entry(key).or_insert(buf)
         } else {
-            self.bufs.get_mut(&targ).unwrap()
+            self.bufs.get_mut(&key).unwrap()
         }
     }
 }
@@ -263,19 +985,34 @@ impl IrcNetwork {
         }
     }
 
-    /// Attempts to join the given channel.
-    pub fn send_join_chan<U>(&mut self, chan: String, u: &mut U)
+    /// Attempts to join the given channel with an optional key.
+    ///
+    /// The key is remembered so we can rejoin the channel after reconnecting.
+    pub fn send_join_chan<U>(&mut self, chan: String, key: Option<String>, u: &mut U)
                              -> Result<(), IrcSendErr>
         where U : UpdateHandle<CoreNetMsg>
     {
-        self.send(Message::new(None, Command::JOIN, vec![chan], None), u)
+        let lower = self.isupport.casemapping.to_lower(&chan);
+        self.parted.retain(|c| c != &lower);
+        let mut args = vec![chan];
+        if let Some(key) = key {
+            self.join_keys.insert(lower, key.clone());
+            args.push(key);
+        }
+        self.send(Message::new(None, Command::JOIN, args, None), u)
     }
 
-    /// Attempts to join the given channel.
+    /// Attempts to part the given channel.
+    ///
+    /// Parted channels aren't rejoined after reconnecting.
     pub fn send_part_chan<U>(&mut self, chan: String, optmsg: Option<String>, u: &mut U)
                              -> Result<(), IrcSendErr>
         where U : UpdateHandle<CoreNetMsg>
     {
+        let lower = self.isupport.casemapping.to_lower(&chan);
+        if !self.parted.contains(&lower) {
+            self.parted.push(lower);
+        }
         self.send(Message::new(None, Command::PART, vec![chan], optmsg), u)
     }
 
@@ -284,7 +1021,113 @@ impl IrcNetwork {
                                -> Result<(), IrcSendErr>
         where U : UpdateHandle<CoreNetMsg>
     {
-        self.send(Message::new(None, Command::NICK, vec![nick], None), u)
+        let req = nick.clone();
+        try!(self.send(Message::cmd(Command::NICK).arg(nick), u));
+        self.nick_req = Some(req);
+        Ok(())
+    }
+
+    /// Marks us as away with the given message, or as no longer away if
+    /// `msg` is `None`.
+    ///
+    /// Our away state is updated once the server confirms the change.
+    pub fn send_away<U>(&mut self, msg: Option<String>, u: &mut U)
+                        -> Result<(), IrcSendErr>
+        where U : UpdateHandle<CoreNetMsg>
+    {
+        self.away_req = msg.clone();
+        self.send(Message::new(None, Command::AWAY, vec![], msg), u)
+    }
+
+    /// Disconnects from the network and marks it offline so we won't
+    /// reconnect until the user asks us to.
+    pub fn send_disconnect<U>(&mut self, msg: Option<String>, u: &mut U)
+                              -> Result<(), IrcSendErr>
+        where U : UpdateHandle<CoreNetMsg>
+    {
+        if !self.offline {
+            self.offline = true;
+            u.send_clients(CoreNetMsg::Offline(true));
+        }
+        self.send_quit(msg, u)
+    }
+
+    /// Quits the network without marking it offline, so we'll reconnect.
+    pub fn send_quit<U>(&mut self, msg: Option<String>, u: &mut U)
+                        -> Result<(), IrcSendErr>
+        where U : UpdateHandle<CoreNetMsg>
+    {
+        self.send(Message::new(None, Command::QUIT, vec![], msg), u)
+    }
+
+    /// Marks the network as online again. Returns true if a new connection
+    /// should be spawned.
+    pub fn set_online<U>(&mut self, u: &mut U) -> bool
+        where U : UpdateHandle<CoreNetMsg>
+    {
+        if self.offline {
+            self.offline = false;
+            u.send_clients(CoreNetMsg::Offline(false));
+        }
+        self.reconnects = 0;
+        self.conn.is_none()
+    }
+
+    /// Sends a `WHOIS` query for the given nick.
+    pub fn send_whois<U>(&mut self, nick: String, u: &mut U)
+                         -> Result<(), IrcSendErr>
+        where U : UpdateHandle<CoreNetMsg>
+    {
+        self.send(Message::cmd(Command::WHOIS).arg(nick), u)
+    }
+
+    /// Requests the channel list, optionally filtered by `filter`.
+    pub fn send_list<U>(&mut self, filter: Option<String>, u: &mut U)
+                        -> Result<(), IrcSendErr>
+        where U : UpdateHandle<CoreNetMsg>
+    {
+        self.chan_list.clear();
+        self.send(Message::cmd(Command::LIST).args(filter), u)
+    }
+
+    /// Sets the topic of the channel `targ`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `BadTarget` if `targ` isn't a channel, and `Unavail` if we
+    /// don't have a buffer for it.
+    pub fn send_topic<U>(&mut self, targ: BufTarget, topic: String, u: &mut U)
+                         -> Result<(), IrcSendErr>
+        where U : UpdateHandle<CoreNetMsg>
+    {
+        let chan = match targ {
+            BufTarget::Channel(ref chan) => chan.clone(),
+            _ => return Err(IrcSendErr::BadTarget),
+        };
+        if self.get_buf(&targ).is_none() {
+            return Err(IrcSendErr::Unavail);
+        }
+        self.send(Message::cmd(Command::TOPIC).arg(chan).body(topic), u)
+    }
+
+    /// Asks the server for the user list of the channel `targ`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `BadTarget` if `targ` isn't a channel, and `Unavail` if we
+    /// don't have a buffer for it.
+    pub fn send_names<U>(&mut self, targ: BufTarget, u: &mut U)
+                         -> Result<(), IrcSendErr>
+        where U : UpdateHandle<CoreNetMsg>
+    {
+        let chan = match targ {
+            BufTarget::Channel(ref chan) => chan.clone(),
+            _ => return Err(IrcSendErr::BadTarget),
+        };
+        if self.get_buf(&targ).is_none() {
+            return Err(IrcSendErr::Unavail);
+        }
+        self.send(Message::cmd(Command::NAMES).arg(chan), u)
     }
 
     /// Sends a `PrivMsg`, `Action`, or `Notice` to the buffer specified by
@@ -301,7 +1144,10 @@ impl IrcNetwork {
                         -> Result<(), IrcSendErr>
         where U : UpdateHandle<CoreNetMsg>
     {
-        let buf = try!(self.bufs.get_mut(&targ).ok_or(IrcSendErr::Unavail));
+        let echo = self.has_cap("echo-message");
+        let key = self.buf_key(&targ);
+        let buf = try!(self.bufs.get_mut(&key).ok_or(IrcSendErr::Unavail));
+        let id = buf.id().clone();
         let dest = match targ {
             BufTarget::Channel(ref dest) => dest.clone(),
             BufTarget::Private(ref dest) => dest.clone(),
@@ -311,30 +1157,34 @@ impl IrcNetwork {
                 return Err(IrcSendErr::BadTarget);
             },
         };
-        let ircmsg = match kind {
-            SendMsgKind::PrivMsg =>
-                Message::new(None, Command::PRIVMSG, vec![dest.clone()], Some(msg.clone())),
-            SendMsgKind::Notice =>
-                Message::new(None, Command::NOTICE, vec![dest.clone()], Some(msg.clone())),
-            SendMsgKind::Action => Message {
-                prefix: None,
-                command: Command::PRIVMSG,
-                args: vec![dest.clone()],
-                body: Some(format!("\u{1}ACTION {}\u{1}", msg)),
-            },
+        let max_len = match kind {
+            SendMsgKind::PrivMsg => split::max_body_len(&self.nick, "PRIVMSG", &dest),
+            SendMsgKind::Notice => split::max_body_len(&self.nick, "NOTICE", &dest),
+            // Leave room for the `\u{1}ACTION ` and `\u{1}` around the body.
+            SendMsgKind::Action =>
+                split::max_body_len(&self.nick, "PRIVMSG", &dest).saturating_sub(9),
         };
-        let r = Self::send_with_conn(&mut self.conn, ircmsg, u);
-        if r.is_ok() {
-            let mut buf_uh = u.wrap(|msg| CoreNetMsg::BufMsg(targ.clone(), msg));
+        for chunk in split::split_msg(&msg, max_len) {
+            let ircmsg = match kind {
+                SendMsgKind::PrivMsg => Message::privmsg(dest.clone(), chunk.clone()),
+                SendMsgKind::Notice => Message::notice(dest.clone(), chunk.clone()),
+                SendMsgKind::Action =>
+                    Message::privmsg(dest.clone(), format!("\u{1}ACTION {}\u{1}", chunk)),
+            };
+            try!(Self::send_with_conn(&mut self.conn, ircmsg, u));
 
+            // The server will echo the message back to us, so it's added
+            // to the buffer when it arrives.
+            if echo { continue; }
+            let mut buf_uh = u.wrap(|msg| CoreNetMsg::BufMsg(id.clone(), msg));
             debug_assert!(!self.nick.is_empty(), "Sending message with empty nick");
             buf.push_line(LineData::Message {
                 kind: kind.to_msg_kind(),
                 from: self.nick.clone(),
-                msg: msg,
+                msg: chunk,
             }, &mut buf_uh);
         }
-        r
+        Ok(())
     }
 }
 
@@ -347,12 +1197,30 @@ impl IrcNetwork {
         NetInfo {
             id: self.id.clone(),
             nick: self.nick.clone(),
+            name: self.isupport.network.clone(),
+            away: self.away.clone(),
+            connected: self.is_connected(),
+            offline: self.offline,
+            casemapping: self.isupport.casemapping.name().to_owned(),
             buffers: bufs,
         }
     }
 }
 
 
+/// Builds a `MONITOR` message adding (`+`) or removing (`-`) the given nicks.
+fn monitor_msg(op: &str, nicks: &[Nick]) -> Message {
+    Message::cmd(Command::Other("MONITOR".to_owned())).arg(op).arg(nicks.join(","))
+}
+
+/// Computes the delay before a reconnection attempt. The delay starts at
+/// `base` and doubles with each attempt up to `max`.
+fn backoff_secs(base: u64, max: u64, attempt: u32) -> u64 {
+    let mult = 1u64.checked_shl(attempt).unwrap_or(u64::max_value());
+    cmp::min(base.saturating_mul(mult), max)
+}
+
+
 /// Errors that can happen when trying to send messages to IRC.
 #[derive(Debug, Clone)]
 pub enum IrcSendErr {
@@ -380,3 +1248,239 @@ impl fmt::Display for IrcSendErr {
         write!(f, "{}", self.description())
     }
 }
+
+
+/// CTCP queries we answer.
+const CTCP_TAGS: &'static [&'static str] = &["CLIENTINFO", "PING", "TIME", "VERSION"];
+
+/// Builds our reply to a CTCP query, or returns `None` if we don't answer it.
+fn ctcp_reply(query: &CtcpMsg) -> Option<CtcpMsg> {
+    let tag = query.tag.to_uppercase();
+    let args = match &tag[..] {
+        "VERSION" => vec!["distirc".to_owned(), env!("CARGO_PKG_VERSION").to_owned()],
+        "PING" => query.args.clone(),
+        "TIME" => vec![format!("{}", time::now().rfc822())],
+        "CLIENTINFO" => CTCP_TAGS.iter().map(|t| (*t).to_owned()).collect(),
+        _ => return None,
+    };
+    Some(CtcpMsg {
+        tag: tag,
+        args: args,
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::Duration;
+    use common::tempdir::TempDir;
+    use config::parse_net_config;
+    use buffer::test_util::test_cfg;
+    use state::session::{NetSession, ChanSession};
+    use rotor_irc::Message;
+    use common::messages::{CoreMsg, CoreNetMsg, BufTarget, SendMsgKind};
+    use conn::test_util::TestScope;
+    use handle::BaseUpdateHandle;
+    use super::{IrcNetwork, backoff_secs, ctcp_reply};
+    use super::routing::CtcpMsg;
+
+    #[test]
+    fn backoff_doubles() {
+        let delays: Vec<_> = (0..4).map(|a| backoff_secs(5, 300, a)).collect();
+        assert_eq!(delays, vec![5, 10, 20, 40]);
+    }
+
+    #[test]
+    fn backoff_capped() {
+        assert_eq!(backoff_secs(5, 300, 7), 300);
+        assert_eq!(backoff_secs(5, 300, 200), 300);
+    }
+
+    fn ctcp(tag: &str, args: &[&str]) -> CtcpMsg {
+        CtcpMsg {
+            tag: tag.to_owned(),
+            args: args.iter().map(|a| (*a).to_owned()).collect(),
+        }
+    }
+
+    #[test]
+    fn ctcp_ping_echoes() {
+        assert_eq!(ctcp_reply(&ctcp("PING", &["1234"])), Some(ctcp("PING", &["1234"])));
+    }
+
+    #[test]
+    fn ctcp_clientinfo() {
+        let reply = ctcp_reply(&ctcp("clientinfo", &[])).unwrap();
+        assert_eq!(format!("{}", reply), "CLIENTINFO CLIENTINFO PING TIME VERSION");
+    }
+
+    #[test]
+    fn ctcp_unsupported() {
+        assert_eq!(ctcp_reply(&ctcp("FINGER", &[])), None);
+    }
+
+    /// Makes a network whose config has our nick set to `me`, plus the given
+    /// extra config fields.
+    fn test_net(dir: &TempDir, extra: &str) -> IrcNetwork {
+        let cfg = parse_net_config(&format!(
+            r#"{{ server = "irc.example.net", nick = "me", alt_nicks = [], channels = [] {} }}"#,
+            extra)).unwrap();
+        IrcNetwork::new("net".to_owned(), &cfg, test_cfg(dir))
+    }
+
+    #[test]
+    fn session_restore() {
+        let dir = TempDir::new("session-net");
+        let mut net = test_net(&dir, "");
+        assert_eq!(net.initial_nick(), "me");
+
+        let session = NetSession {
+            nick: "me_away".to_owned(),
+            channels: vec![ChanSession {
+                name: "#Rust".to_owned(),
+                key: None,
+                topic: "Rust things".to_owned(),
+            }],
+            read_ids: HashMap::new(),
+        };
+        net.restore_session(session.clone());
+        assert_eq!(net.initial_nick(), "me_away");
+        assert_eq!(net.session(), session);
+        let join = net.autojoin_msg().unwrap();
+        assert_eq!(join.args, vec!["#Rust".to_owned()]);
+    }
+
+    #[test]
+    fn fallback_nick_not_saved() {
+        let dir = TempDir::new("fallback-net");
+        let mut net = test_net(&dir, "");
+        let mut u = BaseUpdateHandle::<CoreNetMsg>::new();
+        net.handle_msg(":irc.example.net 004 me_ irc.example.net".parse::<Message>().unwrap(), &mut u);
+        net.handle_msg(":me_!m@host NICK me__".parse::<Message>().unwrap(), &mut u);
+
+        assert_eq!(net.initial_nick(), "me");
+        assert!(!net.take_session_changed());
+        assert_eq!(net.session().nick, "");
+    }
+
+    #[test]
+    fn ison_replies_match_query() {
+        let dir = TempDir::new("ison-net");
+        let mut net = test_net(&dir, "");
+        let mut u = BaseUpdateHandle::<CoreNetMsg>::new();
+        net.open_private("bob".to_owned(), &mut u);
+        let polls = net.presence_poll();
+        assert_eq!(polls.len(), 1);
+        assert_eq!(polls[0].body, Some("bob".to_owned()));
+
+        let bob = BufTarget::Private("bob".to_owned());
+        // A reply to an ISON the user sent doesn't answer ours.
+        net.handle_msg(":irc.example.net 303 me :carol".parse::<Message>().unwrap(), &mut u);
+        assert!(net.get_buf(&bob).unwrap().online());
+        net.handle_msg(":irc.example.net 303 me :".parse::<Message>().unwrap(), &mut u);
+        assert!(!net.get_buf(&bob).unwrap().online());
+        assert!(net.get_buf(&bob).unwrap().joined());
+        // Our query has been answered, so further replies are ignored.
+        net.handle_msg(":irc.example.net 303 me :Bob".parse::<Message>().unwrap(), &mut u);
+        assert!(!net.get_buf(&bob).unwrap().online());
+    }
+
+    #[test]
+    fn removed_private_reopens() {
+        let dir = TempDir::new("remove-net");
+        let mut net = test_net(&dir, "");
+        let mut u = BaseUpdateHandle::<CoreNetMsg>::new();
+        let bob = BufTarget::Private("bob".to_owned());
+        net.open_private("bob".to_owned(), &mut u);
+        assert!(net.remove_buf(&bob, &mut u));
+        assert!(net.get_buf(&bob).is_none());
+        assert!(!net.remove_buf(&bob, &mut u));
+        assert!(!net.remove_buf(&BufTarget::Network, &mut u));
+
+        net.handle_msg(":bob!b@host PRIVMSG me :still there?".parse::<Message>().unwrap(), &mut u);
+        assert_eq!(net.get_buf(&bob).unwrap().front_len(), 1);
+    }
+
+    /// Sends "hi" to bob on a connected network, with or without
+    /// echo-message, and returns how many lines bob's buffer got.
+    fn send_hi(echo: bool) -> isize {
+        let dir = TempDir::new("echo-net");
+        let mut net = test_net(&dir, "");
+        let mut ts = TestScope::new(test_cfg(&dir));
+        let mut u = BaseUpdateHandle::<CoreNetMsg>::new();
+        if echo {
+            net.set_caps(vec!["echo-message".to_owned()]);
+        }
+        let mut rx = net.register_conn(ts.scope().notifier(), &mut BaseUpdateHandle::<CoreMsg>::new());
+        net.handle_msg(":irc.example.net 004 me irc.example.net".parse::<Message>().unwrap(), &mut u);
+        net.open_private("bob".to_owned(), &mut u);
+
+        let bob = BufTarget::Private("bob".to_owned());
+        net.send_chat_msg(bob.clone(), "hi".to_owned(), SendMsgKind::PrivMsg, &mut u).unwrap();
+        let sent = rx.recv().unwrap().unwrap();
+        assert_eq!(sent.body, Some("hi".to_owned()));
+        net.get_buf(&bob).unwrap().front_len()
+    }
+
+    #[test]
+    fn sent_msg_pushed_locally() {
+        assert_eq!(send_hi(false), 1);
+    }
+
+    #[test]
+    fn sent_msg_waits_for_echo() {
+        assert_eq!(send_hi(true), 0);
+    }
+
+    #[test]
+    fn kick_rejoin_backs_off() {
+        let dir = TempDir::new("rejoin-net");
+        let mut net = test_net(&dir, r#", auto_rejoin = ["*"]"#);
+        let mut u = BaseUpdateHandle::<CoreNetMsg>::new();
+        assert_eq!(net.next_rejoin(), None);
+        net.handle_msg(":irc.example.net 004 me irc.example.net".parse::<Message>().unwrap(), &mut u);
+
+        let mut delays = vec![];
+        for _ in 0..3 {
+            net.handle_msg(":me!m@host JOIN #chan".parse::<Message>().unwrap(), &mut u);
+            assert_eq!(net.next_rejoin(), None);
+            net.handle_msg(":bob!b@host KICK #chan me :reason".parse::<Message>().unwrap(), &mut u);
+            delays.push(net.next_rejoin().unwrap());
+            // It's too soon to rejoin.
+            assert!(net.due_rejoins().is_empty());
+        }
+        assert!(delays[0] > Duration::from_secs(4) && delays[0] <= Duration::from_secs(5));
+        assert!(delays[1] > Duration::from_secs(9) && delays[1] <= Duration::from_secs(10));
+        assert!(delays[2] > Duration::from_secs(19) && delays[2] <= Duration::from_secs(20));
+    }
+
+    #[test]
+    fn kick_without_auto_rejoin() {
+        let dir = TempDir::new("no-rejoin-net");
+        let mut net = test_net(&dir, "");
+        let mut u = BaseUpdateHandle::<CoreNetMsg>::new();
+        net.handle_msg(":irc.example.net 004 me irc.example.net".parse::<Message>().unwrap(), &mut u);
+        net.handle_msg(":me!m@host JOIN #chan".parse::<Message>().unwrap(), &mut u);
+        net.handle_msg(":bob!b@host KICK #chan me :reason".parse::<Message>().unwrap(), &mut u);
+        assert_eq!(net.next_rejoin(), None);
+    }
+
+    #[test]
+    fn channel_bufs_ignore_case() {
+        let dir = TempDir::new("case-net");
+        let mut net = test_net(&dir, "");
+        let mut u = BaseUpdateHandle::<CoreNetMsg>::new();
+        net.handle_msg(":irc.example.net 004 me irc.example.net".parse::<Message>().unwrap(), &mut u);
+        net.handle_msg(":me!m@host JOIN #Chan".parse::<Message>().unwrap(), &mut u);
+        net.handle_msg(":bob!b@host PRIVMSG #chan :hi".parse::<Message>().unwrap(), &mut u);
+
+        let chans = net.iter_bufs().filter(|b| b.id() != &BufTarget::Network).count();
+        assert_eq!(chans, 1);
+        let buf = net.get_buf(&BufTarget::Channel("#CHAN".to_owned())).unwrap();
+        // The buffer keeps the name we first saw.
+        assert_eq!(buf.id(), &BufTarget::Channel("#Chan".to_owned()));
+        assert!(buf.joined());
+        assert_eq!(buf.front_len(), 2);
+    }
+}
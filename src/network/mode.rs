@@ -0,0 +1,172 @@
+//! Parsing for IRC mode strings.
+
+use std::fmt;
+
+use super::isupport::ISupport;
+
+
+/// A single mode change parsed out of a mode string.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModeChange {
+    /// True if the mode is being set, false if it's being unset.
+    pub set: bool,
+    /// The mode character.
+    pub mode: char,
+    /// The mode's parameter, if it takes one.
+    pub arg: Option<String>,
+}
+
+impl ModeChange {
+    /// If this change sets or unsets a user prefix mode such as `+o` on the
+    /// network with the given features, returns the prefix character and the
+    /// nick it applies to.
+    pub fn user_prefix(&self, isup: &ISupport) -> Option<(char, &str)> {
+        match (isup.mode_prefix(self.mode), self.arg.as_ref()) {
+            (Some(pfx), Some(nick)) => Some((pfx, &nick[..])),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ModeChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{}{}", if self.set { '+' } else { '-' }, self.mode));
+        if let Some(ref arg) = self.arg {
+            try!(write!(f, " {}", arg));
+        }
+        Ok(())
+    }
+}
+
+
+/// Parses a mode string such as `+oo-v` along with its parameters into a list
+/// of individual mode changes.
+///
+/// Each mode consumes the next parameter if it takes one on a network with the
+/// given features. If the parameter list runs out, the remaining modes are
+/// given no parameter.
+pub fn parse_modes(modes: &str, args: &[String], isup: &ISupport) -> Vec<ModeChange> {
+    let mut changes = vec![];
+    let mut args = args.iter();
+    let mut set = true;
+    for ch in modes.chars() {
+        match ch {
+            '+' => set = true,
+            '-' => set = false,
+            mode => {
+                let arg = if isup.mode_takes_param(mode, set) {
+                    args.next().cloned()
+                } else { None };
+                changes.push(ModeChange {
+                    set: set,
+                    mode: mode,
+                    arg: arg,
+                });
+            },
+        }
+    }
+    changes
+}
+
+/// Formats a list of mode changes back into a mode string and its parameters.
+///
+/// This is the inverse of `parse_modes`, except that the resulting mode string
+/// is normalized to only include `+` and `-` when the direction changes.
+pub fn format_modes(changes: &[ModeChange]) -> (String, Vec<String>) {
+    let mut modes = String::new();
+    let mut args = vec![];
+    let mut cur_set = None;
+    for change in changes {
+        if cur_set != Some(change.set) {
+            modes.push(if change.set { '+' } else { '-' });
+            cur_set = Some(change.set);
+        }
+        modes.push(change.mode);
+        if let Some(ref arg) = change.arg {
+            args.push(arg.clone());
+        }
+    }
+    (modes, args)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use network::isupport::ISupport;
+    use super::{ModeChange, format_modes};
+
+    fn args(s: &str) -> Vec<String> {
+        s.split(' ').map(|s| s.to_owned()).collect()
+    }
+
+    /// Parses modes with the default features.
+    fn parse_modes(modes: &str, args: &[String]) -> Vec<ModeChange> {
+        super::parse_modes(modes, args, &ISupport::default())
+    }
+
+    fn change(set: bool, mode: char, arg: Option<&str>) -> ModeChange {
+        ModeChange { set: set, mode: mode, arg: arg.map(|s| s.to_owned()) }
+    }
+
+    #[test]
+    fn parse_compound() {
+        let changes = parse_modes("+oo-v", &args("a b c"));
+        assert_eq!(changes, vec![
+            change(true, 'o', Some("a")),
+            change(true, 'o', Some("b")),
+            change(false, 'v', Some("c")),
+        ]);
+    }
+
+    #[test]
+    fn parse_mixed_params() {
+        // `n` and `t` take no params, `l` only takes one when set, and `k`
+        // always takes one.
+        let changes = parse_modes("+ntl-lk", &args("10 key"));
+        assert_eq!(changes, vec![
+            change(true, 'n', None),
+            change(true, 't', None),
+            change(true, 'l', Some("10")),
+            change(false, 'l', None),
+            change(false, 'k', Some("key")),
+        ]);
+    }
+
+    #[test]
+    fn parse_missing_params() {
+        let changes = parse_modes("+bb", &args("*!*@host"));
+        assert_eq!(changes, vec![
+            change(true, 'b', Some("*!*@host")),
+            change(true, 'b', None),
+        ]);
+    }
+
+    #[test]
+    fn user_prefixes() {
+        let changes = parse_modes("+ov-hn", &args("a b c"));
+        let isup = ISupport::default();
+        let prefixes = changes.iter().map(|c| c.user_prefix(&isup)).collect::<Vec<_>>();
+        assert_eq!(prefixes, vec![Some(('@', "a")), Some(('+', "b")), Some(('%', "c")), None]);
+    }
+
+    #[test]
+    fn advertised_modes() {
+        let mut isup = ISupport::default();
+        isup.parse_tokens(&args("PREFIX=(Yov)!@+ CHANMODES=beI,kfj,l,imnpst"));
+        let changes = super::parse_modes("+fYh-j", &args("#overflow bob"), &isup);
+        assert_eq!(changes, vec![
+            change(true, 'f', Some("#overflow")),
+            change(true, 'Y', Some("bob")),
+            change(true, 'h', None),
+            change(false, 'j', None),
+        ]);
+        assert_eq!(changes[1].user_prefix(&isup), Some(('!', "bob")));
+    }
+
+    #[test]
+    fn format_round_trip() {
+        let a = args("a b c");
+        let changes = parse_modes("+oo-v", &a);
+        assert_eq!(format_modes(&changes), ("+oo-v".to_owned(), a));
+    }
+}
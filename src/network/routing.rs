@@ -5,18 +5,23 @@
 use std::fmt;
 use std::str::FromStr;
 use std::ascii::AsciiExt;
+use time::{self, Tm};
 use rotor_irc::{Message, Command, Response};
 
-use common::line::{Sender, User};
+use common::line::{Sender, User, ChannelUser};
 use common::types::Nick;
 
+use super::mode::{ModeChange, parse_modes};
+use super::isupport::ISupport;
+
 /// Cleaned up forms of channel-specific IRC commands and response codes.
 #[derive(Debug, Clone)]
 // We allow this so we can match variant names to their associated IRC message
 // names.
 #[allow(non_camel_case_types)]
 pub enum BufferCmd {
-    JOIN(User),
+    /// A user joined, along with their account if the server told us.
+    JOIN(User, Option<String>),
     PART(User, Option<String>),
     KICK { by: User, targ: Nick, reason: Option<String> },
 
@@ -26,10 +31,32 @@ pub enum BufferCmd {
     /// `NOTICE` and `PRIVMSG` because they are handled differently.
     ACTION(User, String),
 
+    /// A user changed the channel's topic.
+    TOPIC(User, String),
+    /// The channel's current topic, sent when we join.
+    RPL_TOPIC(String),
+    /// Sent instead of `RPL_TOPIC` when the channel has no topic.
+    RPL_NOTOPIC,
+    /// Who set the channel's current topic and when, as a Unix timestamp.
+    /// Usually sent right after `RPL_TOPIC`, but not all servers send it.
+    RPL_TOPICWHOTIME(String, i64),
+
+    /// Channel mode changes made by the given user.
+    MODE(User, Vec<ModeChange>),
+
     RPL_NAMREPLY(String),
     RPL_ENDOFNAMES,
+    /// A user in a `WHO` reply. Only the nick, ident, host, away flag and,
+    /// for WHOX replies, account are filled in.
+    RPL_WHOREPLY(ChannelUser),
+    RPL_ENDOFWHO,
 
     RPL_MOTD(String),
+
+    /// A line of a `WHOIS` reply about the given nick. Replies are collected
+    /// until `RPL_ENDOFWHOIS`.
+    WhoisReply(Nick, String),
+    RPL_ENDOFWHOIS(Nick),
 }
 
 
@@ -41,9 +68,37 @@ pub enum BufferCmd {
 pub enum NetworkCmd {
     QUIT(User, Option<String>),
     NICK(User, String),
+    /// The user logged in to the given account, or logged out if `None`.
+    ACCOUNT(User, Option<String>),
+    /// A change to our own user modes.
+    UMODE(Vec<ModeChange>),
+    /// The given user invited us to a channel.
+    INVITE(User, String),
+    /// The server is closing our connection for the given reason.
+    ERROR(String),
 
     // The string is our nick.
     RPL_MYINFO(String),
+    /// Feature tokens advertised by the server, without our nick.
+    RPL_ISUPPORT(Vec<String>),
+    /// The server confirmed we're no longer away.
+    RPL_UNAWAY,
+    /// The server confirmed we're now away.
+    RPL_NOWAWAY,
+
+    /// Start of a `LIST` reply.
+    RPL_LISTSTART,
+    /// An entry in a `LIST` reply with the channel's name, visible user count
+    /// and topic.
+    RPL_LIST(String, usize, String),
+    RPL_LISTEND,
+
+    /// Which of the nicks in an `ISON` query are online.
+    RPL_ISON(Vec<String>),
+    /// Nicks on our `MONITOR` list which are online.
+    RPL_MONONLINE(Vec<String>),
+    /// Nicks on our `MONITOR` list which are offline.
+    RPL_MONOFFLINE(Vec<String>),
 
     /// A CTCP query from the given sender. The second arg is the destination it
     /// was sent to.
@@ -75,7 +130,9 @@ pub struct CtcpMsg {
 #[derive(Debug, Clone)]
 pub enum RoutedMsg {
     Channel(String, BufferCmd),
-    Private(User, BufferCmd),
+    /// A message from the given user to the given nick. The nick is ours,
+    /// unless this is the server echoing a message we sent.
+    Private(User, Nick, BufferCmd),
     NetBuffer(BufferCmd),
     Network(NetworkCmd),
 }
@@ -163,7 +220,7 @@ macro_rules! try_user {
 
 
 /// This function handles routing an IRC message to the proper destinations.
-pub fn route_message(msg: Message, cur_nick: &str) -> Option<RoutedMsg> {
+pub fn route_message(msg: Message, isup: &ISupport) -> Option<RoutedMsg> {
     use rotor_irc::Response::*;
 
     trace!("Routing {:?}", msg);
@@ -171,24 +228,30 @@ pub fn route_message(msg: Message, cur_nick: &str) -> Option<RoutedMsg> {
 
     match msg.command.clone() {
         Command::JOIN => {
-            check_args!(msg; if argc == 1, then {
-                let user = try_user!(sender, "JOIN").clone();
-                let chan = msg.args[0].clone();
-                let bc = BufferCmd::JOIN(user.clone());
-                route_target(chan.clone(), user, cur_nick, bc)
-            } else if has body {
-                let user = try_user!(sender, "JOIN").clone();
-                let chan = msg.body.unwrap();
-                let bc = BufferCmd::JOIN(user.clone());
-                route_target(chan.clone(), user, cur_nick, bc)
-            })
+            let user = try_user!(sender, "JOIN").clone();
+            // Some servers send the channel as the body. With `extended-join`,
+            // the channel is followed by the user's account, or `*` if they
+            // aren't logged in, and their real name is the body.
+            let chan = match msg.args.first().or(msg.body.as_ref()) {
+                Some(chan) => chan.clone(),
+                None => {
+                    error!("Expected a channel for JOIN. Got: {}", msg);
+                    return None;
+                },
+            };
+            let account = match msg.args.get(1) {
+                Some(acct) => parse_account(acct),
+                None => account_tag(&msg),
+            };
+            let bc = BufferCmd::JOIN(user.clone(), account);
+            route_target(chan, user, isup, bc)
         },
         Command::PART => {
             check_args!(msg; if argc == 1, then {
                 let user = try_user!(sender, "PART").clone();
                 let chan = msg.args[0].clone();
                 let bc = BufferCmd::PART(user.clone(), msg.body);
-                route_target(chan, user, cur_nick, bc)
+                route_target(chan, user, isup, bc)
             })
         },
         Command::KICK => {
@@ -201,7 +264,7 @@ pub fn route_message(msg: Message, cur_nick: &str) -> Option<RoutedMsg> {
                     targ: targ,
                     reason: msg.body,
                 };
-                route_target(chan, user, cur_nick, bc)
+                route_target(chan, user, isup, bc)
             })
         },
 
@@ -212,26 +275,26 @@ pub fn route_message(msg: Message, cur_nick: &str) -> Option<RoutedMsg> {
                 let message = msg.body.unwrap();
 
                 if message.starts_with("\u{1}") {
-                    route_ctcp_msg(dest, user, cur_nick, Command::PRIVMSG, message)
+                    route_ctcp_msg(dest, user, isup, Command::PRIVMSG, message)
                 } else {
                     let bc = BufferCmd::PRIVMSG(user.clone(), message);
-                    route_target(dest, user, cur_nick, bc)
+                    route_target(dest, user, isup, bc)
                 }
             })
         },
         Command::NOTICE => {
             check_args!(msg; if argc == 1, and has body, then {
-                let sender = if sender.is_none() {
-                    error!("Expected a prefix for NOTICE. Args: {:?}", msg.args);
-                    return None;
-                } else { sender.unwrap() };
+                // Some servers send notices without a prefix while we're
+                // connecting, like `NOTICE AUTH :*** Looking up your hostname`.
+                // Those come from the server itself.
+                let sender = sender.unwrap_or(Sender::Server("*".to_owned()));
 
                 let dest = msg.args[0].clone();
                 let message = msg.body.unwrap();
 
                 if message.starts_with("\u{1}") {
                     if let Sender::User(user) = sender {
-                        route_ctcp_msg(dest, user, cur_nick, Command::NOTICE, message)
+                        route_ctcp_msg(dest, user, isup, Command::NOTICE, message)
                     } else {
                         error!("Ignored CTCP reply from a server. This isn't supported");
                         return None;
@@ -240,19 +303,72 @@ pub fn route_message(msg: Message, cur_nick: &str) -> Option<RoutedMsg> {
                     let bc = BufferCmd::NOTICE(sender.clone(), message);
 
                     match sender {
-                        Sender::User(u) => route_target(dest, u, cur_nick, bc),
+                        Sender::User(u) => route_target(dest, u, isup, bc),
                         Sender::Server(_) => Some(RoutedMsg::NetBuffer(bc)),
                     }
                 }
             })
         },
 
+        Command::TOPIC => {
+            check_args!(msg; if argc == 1, and has body, then {
+                let user = try_user!(sender, "TOPIC").clone();
+                let chan = msg.args[0].clone();
+                let bc = BufferCmd::TOPIC(user, msg.body.unwrap());
+                Some(RoutedMsg::Channel(chan, bc))
+            })
+        },
+
+        Command::MODE => {
+            check_args!(msg; if argc >= 1, then {
+                let targ = msg.args[0].clone();
+                // The mode string and its parameters may be split between the
+                // args and the body, so we just combine them.
+                let mut params: Vec<String> = msg.args[1..].to_vec();
+                if let Some(body) = msg.body {
+                    params.extend(body.split(' ').filter(|s| !s.is_empty()).map(|s| s.to_owned()));
+                }
+                if params.is_empty() {
+                    error!("Expected a mode string for MODE. Got: {:?}", params);
+                    return None;
+                }
+                let changes = parse_modes(&params[0], &params[1..], isup);
+
+                if isup.is_channel(&targ) {
+                    // Servers can set channel modes too, so we turn server
+                    // prefixes into a user with no ident or host.
+                    let user = match sender {
+                        Some(Sender::User(user)) => user,
+                        Some(Sender::Server(name)) => User {
+                            nick: name,
+                            ident: String::new(),
+                            host: String::new(),
+                        },
+                        None => {
+                            error!("Expected a prefix for channel MODE command");
+                            return None;
+                        },
+                    };
+                    Some(RoutedMsg::Channel(targ, BufferCmd::MODE(user, changes)))
+                } else {
+                    Some(RoutedMsg::Network(NetworkCmd::UMODE(changes)))
+                }
+            })
+        },
+
         Command::QUIT => {
             let user = try_user!(sender, "QUIT").clone();
             // The network has to handle routing QUITs, as their routing depends
             // on which channels the quitting user is in.
             Some(RoutedMsg::Network(NetworkCmd::QUIT(user, msg.body)))
         },
+        Command::ERROR => {
+            let reason = match msg.body {
+                Some(body) => body,
+                None => msg.args.last().cloned().unwrap_or("No reason given".to_owned()),
+            };
+            Some(RoutedMsg::Network(NetworkCmd::ERROR(reason)))
+        },
         Command::NICK => {
             check_args!(msg; if argc == 1, then {
                 let user = try_user!(sender, "NICK").clone();
@@ -261,8 +377,90 @@ pub fn route_message(msg: Message, cur_nick: &str) -> Option<RoutedMsg> {
                 Some(RoutedMsg::Network(NetworkCmd::NICK(user, new)))
             })
         }
+        Command::Other(ref cmd) if cmd == "ACCOUNT" => {
+            check_args!(msg; if argc == 1, then {
+                let user = try_user!(sender, "ACCOUNT").clone();
+                let account = parse_account(&msg.args[0]);
+                // Like NICK, this applies to every channel the user is in.
+                Some(RoutedMsg::Network(NetworkCmd::ACCOUNT(user, account)))
+            })
+        },
+        Command::INVITE => {
+            // The first arg is our nick and the channel is either the second
+            // arg or the body.
+            check_args!(msg; if argc == 2, then {
+                let user = try_user!(sender, "INVITE").clone();
+                Some(RoutedMsg::Network(NetworkCmd::INVITE(user, msg.args[1].clone())))
+            } else if has body {
+                let user = try_user!(sender, "INVITE").clone();
+                Some(RoutedMsg::Network(NetworkCmd::INVITE(user, msg.body.unwrap())))
+            })
+        },
 
 
+        Command::Response(RPL_UNAWAY) => Some(RoutedMsg::Network(NetworkCmd::RPL_UNAWAY)),
+        Command::Response(RPL_NOWAWAY) => Some(RoutedMsg::Network(NetworkCmd::RPL_NOWAWAY)),
+
+        Command::Response(RPL_LISTSTART) => Some(RoutedMsg::Network(NetworkCmd::RPL_LISTSTART)),
+        Command::Response(RPL_LIST) => {
+            // The first arg is our nick. Channels without a topic may not
+            // have a body.
+            check_args!(msg; if argc >= 3, then {
+                let users = msg.args[2].parse().unwrap_or(0);
+                let topic = msg.body.unwrap_or(String::new());
+                Some(RoutedMsg::Network(NetworkCmd::RPL_LIST(msg.args[1].clone(), users, topic)))
+            })
+        },
+        Command::Response(RPL_LISTEND) => Some(RoutedMsg::Network(NetworkCmd::RPL_LISTEND)),
+
+        Command::Response(RPL_ISON) => {
+            // Some servers put the nicks in the args instead of the body.
+            let nicks = msg.body.as_ref().map_or(msg.args.iter().skip(1).cloned().collect(), |b| {
+                b.split_whitespace().map(|n| n.to_owned()).collect()
+            });
+            Some(RoutedMsg::Network(NetworkCmd::RPL_ISON(nicks)))
+        },
+        Command::Response(RPL_MONONLINE) => {
+            // Online targets come as full `nick!user@host` masks.
+            let nicks = monitor_targets(&msg).iter().map(|t| {
+                t.split('!').next().unwrap().to_owned()
+            }).collect();
+            Some(RoutedMsg::Network(NetworkCmd::RPL_MONONLINE(nicks)))
+        },
+        Command::Response(RPL_MONOFFLINE) => {
+            Some(RoutedMsg::Network(NetworkCmd::RPL_MONOFFLINE(monitor_targets(&msg))))
+        },
+
+        Command::Response(RPL_TOPIC) => {
+            check_args!(msg; if argc == 2, and has body, then {
+                // The first arg is our nick and the second is the channel.
+                let chan = msg.args[1].clone();
+                let bc = BufferCmd::RPL_TOPIC(msg.body.unwrap());
+                Some(RoutedMsg::Channel(chan, bc))
+            })
+        },
+        Command::Response(RPL_NOTOPIC) => {
+            check_args!(msg; if argc == 2, then {
+                let chan = msg.args[1].clone();
+                Some(RoutedMsg::Channel(chan, BufferCmd::RPL_NOTOPIC))
+            })
+        },
+        Command::Response(RPL_TOPICWHOTIME) => {
+            check_args!(msg; if argc >= 4, then {
+                // The args are our nick, the channel, the setter and the time.
+                // Some servers give the setter's full `nick!user@host`.
+                let chan = msg.args[1].clone();
+                let by = msg.args[2].split('!').next().unwrap().to_owned();
+                match msg.args[3].parse() {
+                    Ok(at) => Some(RoutedMsg::Channel(chan, BufferCmd::RPL_TOPICWHOTIME(by, at))),
+                    Err(_) => {
+                        warn!("Invalid topic time in RPL_TOPICWHOTIME: {}", msg.args[3]);
+                        None
+                    },
+                }
+            })
+        },
+
         Command::Response(RPL_NAMREPLY) => {
             check_args!(msg; if argc == 3, and has body, then {
                 // The channel is the third arg. I don't know what the other args
@@ -282,6 +480,52 @@ pub fn route_message(msg: Message, cur_nick: &str) -> Option<RoutedMsg> {
             })
         },
 
+        // We only send `WHO` for channels. Replies about anything else are
+        // from queries the user sent, so they're shown like any other reply.
+        Command::Response(RPL_WHOREPLY) if msg.args.len() >= 2 && !isup.is_channel(&msg.args[1]) => {
+            Some(RoutedMsg::Network(NetworkCmd::UnknownCode(RPL_WHOREPLY, msg.args, msg.body)))
+        },
+        Command::Response(RPL_WHOREPLY) => {
+            check_args!(msg; if argc >= 7, then {
+                // The args are our nick, the channel, ident, host, server,
+                // nick and flags.
+                let chan = msg.args[1].clone();
+                let cu = who_user(&msg.args[6], &msg.args[2], &msg.args[3], &msg.args[5], None);
+                Some(RoutedMsg::Channel(chan, BufferCmd::RPL_WHOREPLY(cu)))
+            })
+        },
+        Command::Response(RPL_WHOSPCRPL) => {
+            // We only send WHOX queries with our own token, so replies with
+            // any other fields aren't ours.
+            if msg.args.len() >= 8 && msg.args[1] == WHOX_TOKEN && isup.is_channel(&msg.args[2]) {
+                // The args are our nick, the token, the channel, ident, host,
+                // nick, flags and account.
+                let chan = msg.args[2].clone();
+                let acct = if msg.args[7] == "0" { None } else { Some(msg.args[7].clone()) };
+                let cu = who_user(&msg.args[6], &msg.args[3], &msg.args[4], &msg.args[5], acct);
+                Some(RoutedMsg::Channel(chan, BufferCmd::RPL_WHOREPLY(cu)))
+            } else {
+                debug!("Ignoring WHOX reply we didn't ask for: {}", msg);
+                None
+            }
+        },
+        Command::Response(RPL_ENDOFWHO) if msg.args.len() >= 2 && !isup.is_channel(&msg.args[1]) => {
+            Some(RoutedMsg::Network(NetworkCmd::UnknownCode(RPL_ENDOFWHO, msg.args, msg.body)))
+        },
+        Command::Response(RPL_ENDOFWHO) => {
+            check_args!(msg; if argc >= 2, then {
+                // The first arg is our nick and the second the channel.
+                Some(RoutedMsg::Channel(msg.args[1].clone(), BufferCmd::RPL_ENDOFWHO))
+            })
+        },
+
+        Command::Response(RPL_ISUPPORT) => {
+            check_args!(msg; if argc >= 2, then {
+                // The first arg is our nick and the body is just a
+                // human-readable "are supported by this server".
+                Some(RoutedMsg::Network(NetworkCmd::RPL_ISUPPORT(msg.args[1..].to_vec())))
+            })
+        },
         Command::Response(RPL_MYINFO) => {
             check_args!(msg; if argc >= 1, then {
                 Some(RoutedMsg::Network(NetworkCmd::RPL_MYINFO(msg.args[0].clone())))
@@ -298,6 +542,46 @@ pub fn route_message(msg: Message, cur_nick: &str) -> Option<RoutedMsg> {
             })
         },
 
+        // The first arg of all of the WHOIS replies is our nick and the second
+        // is the nick being queried.
+        Command::Response(RPL_WHOISUSER) => {
+            check_args!(msg; if argc >= 4, and has body, then {
+                let line = format!("{} is {}@{} ({})", msg.args[1], msg.args[2], msg.args[3],
+                                   msg.body.as_ref().unwrap());
+                Some(RoutedMsg::NetBuffer(BufferCmd::WhoisReply(msg.args[1].clone(), line)))
+            })
+        },
+        Command::Response(RPL_WHOISSERVER) => {
+            check_args!(msg; if argc >= 3, and has body, then {
+                let line = format!("{} is connected to {} ({})", msg.args[1], msg.args[2],
+                                   msg.body.as_ref().unwrap());
+                Some(RoutedMsg::NetBuffer(BufferCmd::WhoisReply(msg.args[1].clone(), line)))
+            })
+        },
+        Command::Response(RPL_WHOISOPERATOR) => {
+            check_args!(msg; if argc >= 2, then {
+                let line = format!("{} is an IRC operator", msg.args[1]);
+                Some(RoutedMsg::NetBuffer(BufferCmd::WhoisReply(msg.args[1].clone(), line)))
+            })
+        },
+        Command::Response(RPL_WHOISIDLE) => {
+            check_args!(msg; if argc >= 3, then {
+                let line = format!("{} has been idle for {} seconds", msg.args[1], msg.args[2]);
+                Some(RoutedMsg::NetBuffer(BufferCmd::WhoisReply(msg.args[1].clone(), line)))
+            })
+        },
+        Command::Response(RPL_WHOISCHANNELS) => {
+            check_args!(msg; if argc >= 2, and has body, then {
+                let line = format!("{} is in {}", msg.args[1], msg.body.as_ref().unwrap().trim());
+                Some(RoutedMsg::NetBuffer(BufferCmd::WhoisReply(msg.args[1].clone(), line)))
+            })
+        },
+        Command::Response(RPL_ENDOFWHOIS) => {
+            check_args!(msg; if argc >= 2, then {
+                Some(RoutedMsg::NetBuffer(BufferCmd::RPL_ENDOFWHOIS(msg.args[1].clone())))
+            })
+        },
+
         Command::Response(code) => {
             let cmd = NetworkCmd::UnknownCode(code.clone(), msg.args, msg.body);
             Some(RoutedMsg::Network(cmd))
@@ -315,37 +599,94 @@ pub fn route_message(msg: Message, cur_nick: &str) -> Option<RoutedMsg> {
     }
 }
 
+/// Parses an account name from `extended-join` or `ACCOUNT`, where `*` means
+/// the user isn't logged in.
+fn parse_account(acct: &str) -> Option<String> {
+    if acct == "*" { None } else { Some(acct.to_owned()) }
+}
+
+/// Token we tag our WHOX queries with, so we can recognize the replies.
+pub const WHOX_TOKEN: &'static str = "152";
+
+/// Builds a `ChannelUser` from the fields of a `WHO` reply. The flags start
+/// with `H` if the user is here or `G` if they're gone.
+fn who_user(flags: &str, ident: &str, host: &str, nick: &str, account: Option<String>) -> ChannelUser {
+    let mut cu = ChannelUser::new(nick.to_owned());
+    cu.ident = ident.to_owned();
+    cu.host = host.to_owned();
+    cu.away = flags.starts_with('G');
+    cu.account = account;
+    cu
+}
+
+/// Splits the comma separated targets in the body of a `MONITOR` reply.
+fn monitor_targets(msg: &Message) -> Vec<String> {
+    msg.body.as_ref().map_or(vec![], |b| {
+        b.split(',').filter(|t| !t.is_empty()).map(|t| t.to_owned()).collect()
+    })
+}
+
+/// Gets the sender's account from a message's `account` tag, which servers
+/// add with the `account-tag` capability if the sender is logged in.
+pub fn account_tag(msg: &Message) -> Option<String> {
+    msg.tags.as_ref()
+        .and_then(|tags| tags.get("account"))
+        .and_then(|acct| acct.clone())
+}
+
+/// Gets the time a message was sent from its `server-time` tag, if present.
+///
+/// The tag's value is an ISO 8601 UTC timestamp, such as
+/// `2023-01-02T03:04:05.000Z`. Fractional seconds are ignored.
+pub fn server_time(msg: &Message) -> Option<Tm> {
+    let val = match msg.tags.as_ref().and_then(|t| t.get("time")) {
+        Some(&Some(ref val)) => val,
+        _ => return None,
+    };
+    let end = val.find(|c| c == '.' || c == 'Z').unwrap_or(val.len());
+    match time::strptime(&val[..end], "%Y-%m-%dT%H:%M:%S") {
+        Ok(tm) => Some(time::at(tm.to_timespec())),
+        Err(e) => {
+            warn!("Invalid server-time tag {:?}: {}", val, e);
+            None
+        },
+    }
+}
+
 /// Routes a message to private or channel based on the given target string,
-/// sender, and current nick.
-fn route_target(targ: String, user: User, cur_nick: &str, msg: BufferCmd) -> Option<RoutedMsg> {
-    if cur_nick == targ  {
+/// sender, and the server's channel types.
+fn route_target(targ: String, user: User, isup: &ISupport, msg: BufferCmd) -> Option<RoutedMsg> {
+    if isup.is_channel(&targ) {
+        trace!("Routed channel message to {}", targ);
+        Some(RoutedMsg::Channel(targ, msg))
+    } else {
         // NOTE: We don't do anything about the case where the server sends us a
         // JOIN with our nick as target. This shouldn't happen, but it's not
         // inconceivable. Regardless, we'll continue to ignore it for now.
         trace!("Routed private message from {}", user.nick);
-        Some(RoutedMsg::Private(user.clone(), msg))
-    } else {
-        trace!("Routed channel message to {}", targ);
-        Some(RoutedMsg::Channel(targ, msg))
+        Some(RoutedMsg::Private(user.clone(), targ, msg))
     }
 }
 
 
 /// Routes a CTCP message.
-fn route_ctcp_msg(targ: String, user: User, cur_nick: &str, cmd: Command, msg: String) -> Option<RoutedMsg> {
+fn route_ctcp_msg(targ: String, user: User, isup: &ISupport, cmd: Command, msg: String) -> Option<RoutedMsg> {
     debug_assert!(msg.starts_with("\u{1}"));
     trace!("Parsing CTCP privmsg: {:?}", msg);
     match msg.parse::<CtcpMsg>() {
-        Ok(ref msg) if &msg.tag.to_ascii_uppercase() == "ACTION" => {
-            let bc = BufferCmd::ACTION(user.clone(), msg.args.join(" "));
-            route_target(targ, user, cur_nick, bc)
+        Ok(ref ctcp) if &ctcp.tag.to_ascii_uppercase() == "ACTION" => {
+            // Splitting and rejoining the args would collapse runs of spaces,
+            // so take the action's text as it was sent.
+            let text = split_ctcp(&msg).and_then(|(_, text)| text).unwrap_or("");
+            let bc = BufferCmd::ACTION(user.clone(), text.to_owned());
+            route_target(targ, user, isup, bc)
         },
         Ok(msg) => {
             match cmd {
                 Command::PRIVMSG =>
                     Some(RoutedMsg::Network(NetworkCmd::CtcpQuery(user, targ, msg))),
                 Command::NOTICE =>
-                    Some(RoutedMsg::Network(NetworkCmd::CtcpQuery(user, targ, msg))),
+                    Some(RoutedMsg::Network(NetworkCmd::CtcpReply(user, targ, msg))),
                 _ => unreachable!(),
             }
         },
@@ -371,28 +712,43 @@ impl FromStr for CtcpMsg {
     type Err = String;
 
     fn from_str(s: &str) -> Result<CtcpMsg, String> {
-        if !s.starts_with("\u{1}") {
-            return Err("Not a valid CTCP message".to_owned());
-        }
-        let s = &s[1..];
-        let end = s.find("\u{1}").unwrap_or(s.len());
-
-        let s = &s[..end];
-        let mut arg_iter = s.split(" ");
-        let tag = arg_iter.next().ok_or("Missing CTCP tag".to_owned());
-        let tag = try!(tag).to_ascii_uppercase();
-        let args: Vec<_> = arg_iter.map(|s| s.to_owned()).collect();
+        let (tag, rest) = try!(split_ctcp(s).ok_or("Not a valid CTCP message".to_owned()));
+        let args: Vec<_> = match rest {
+            Some(rest) => rest.split(" ").map(|s| s.to_owned()).collect(),
+            None => vec![],
+        };
         Ok(CtcpMsg {
-            tag: tag,
+            tag: tag.to_ascii_uppercase(),
             args: args,
         })
     }
 }
 
+/// Splits a CTCP message into its tag and the text after it, without the
+/// surrounding \u{1} chars.
+///
+/// The text is `None` if there is nothing after the tag, not even a space.
+fn split_ctcp(s: &str) -> Option<(&str, Option<&str>)> {
+    if !s.starts_with("\u{1}") {
+        return None;
+    }
+    let s = &s[1..];
+    let end = s.find("\u{1}").unwrap_or(s.len());
+    let s = &s[..end];
+    match s.find(' ') {
+        Some(i) => Some((&s[..i], Some(&s[i + 1..]))),
+        None => Some((s, None)),
+    }
+}
+
 
 #[cfg(test)]
 mod tests {
-    use super::CtcpMsg;
+    use std::collections::HashMap;
+    use rotor_irc::{Message, Command};
+    use common::line::Sender;
+    use super::{CtcpMsg, RoutedMsg, NetworkCmd, BufferCmd, server_time, route_message};
+    use super::super::isupport::ISupport;
 
     // Adapted from rotor_irc::message::tests
     macro_rules! parse_fmt_test {
@@ -428,4 +784,202 @@ mod tests {
         };
         (s, msg)
     });
+
+    fn tagged_msg(tag: Option<&str>) -> Message {
+        let mut msg = Message::new(None, Command::PING, vec!["irc.server.lol".to_owned()], None);
+        if let Some(val) = tag {
+            let mut tags = HashMap::new();
+            tags.insert("time".to_owned(), Some(val.to_owned()));
+            msg.tags = Some(tags);
+        }
+        msg
+    }
+
+    #[test]
+    fn server_time_parse() {
+        let msg = tagged_msg(Some("2023-01-02T03:04:05.000Z"));
+        assert_eq!(server_time(&msg).unwrap().to_timespec().sec, 1672628645);
+    }
+
+    #[test]
+    fn server_time_no_fraction() {
+        let msg = tagged_msg(Some("2023-01-02T03:04:05Z"));
+        assert_eq!(server_time(&msg).unwrap().to_timespec().sec, 1672628645);
+    }
+
+    #[test]
+    fn server_time_missing() {
+        assert!(server_time(&tagged_msg(None)).is_none());
+    }
+
+    #[test]
+    fn server_time_invalid() {
+        assert!(server_time(&tagged_msg(Some("yesterday"))).is_none());
+    }
+
+    #[test]
+    fn action_keeps_spaces() {
+        let msg = ":bob!b@host PRIVMSG #chan :\u{1}ACTION slaps   user  \u{1}".parse::<Message>().unwrap();
+        match route_message(msg, &ISupport::default()) {
+            Some(RoutedMsg::Channel(_, BufferCmd::ACTION(_, text))) => assert_eq!(text, "slaps   user  "),
+            m => panic!("Routed ACTION wrong: {:?}", m),
+        }
+    }
+
+    fn route_notice(line: &str) -> Option<RoutedMsg> {
+        route_message(line.parse::<Message>().unwrap(), &ISupport::default())
+    }
+
+    #[test]
+    fn server_notices() {
+        match route_notice("NOTICE AUTH :*** Looking up your hostname...") {
+            Some(RoutedMsg::NetBuffer(BufferCmd::NOTICE(Sender::Server(_), msg))) =>
+                assert_eq!(msg, "*** Looking up your hostname..."),
+            m => panic!("Routed prefix-less NOTICE wrong: {:?}", m),
+        }
+        match route_notice(":irc.example.net NOTICE * :*** Checking Ident") {
+            Some(RoutedMsg::NetBuffer(BufferCmd::NOTICE(Sender::Server(ref name), _))) =>
+                assert_eq!(name, "irc.example.net"),
+            m => panic!("Routed server NOTICE wrong: {:?}", m),
+        }
+    }
+
+    #[test]
+    fn echoed_private_message() {
+        match route_notice(":me!m@host PRIVMSG bob :hi") {
+            Some(RoutedMsg::Private(ref from, ref to, BufferCmd::PRIVMSG(_, _))) => {
+                assert_eq!(from.nick, "me");
+                assert_eq!(to, "bob");
+            },
+            m => panic!("Routed echoed PRIVMSG wrong: {:?}", m),
+        }
+    }
+
+    #[test]
+    fn ctcp_notice_is_reply() {
+        match route_notice(":bob!b@host NOTICE me :\u{1}VERSION some client\u{1}") {
+            Some(RoutedMsg::Network(NetworkCmd::CtcpReply(_, _, ref reply))) =>
+                assert_eq!(reply.tag, "VERSION"),
+            m => panic!("Routed CTCP reply wrong: {:?}", m),
+        }
+    }
+
+    #[test]
+    fn topic_who_time() {
+        let msg = ":irc.example.net 333 me #rust bob!b@host 1672628645".parse::<Message>().unwrap();
+        match route_message(msg, &ISupport::default()) {
+            Some(RoutedMsg::Channel(chan, BufferCmd::RPL_TOPICWHOTIME(by, at))) => {
+                assert_eq!(chan, "#rust");
+                assert_eq!(by, "bob");
+                assert_eq!(at, 1672628645);
+            },
+            m => panic!("Routed RPL_TOPICWHOTIME wrong: {:?}", m),
+        }
+    }
+
+    #[test]
+    fn who_replies() {
+        let route = |line: &str| route_message(line.parse::<Message>().unwrap(), &ISupport::default());
+        match route(":irc.example.net 352 me #rust ~b host.example irc.example.net bob G@ :0 Bob") {
+            Some(RoutedMsg::Channel(chan, BufferCmd::RPL_WHOREPLY(cu))) => {
+                assert_eq!(chan, "#rust");
+                assert_eq!((&cu.nick[..], &cu.ident[..], &cu.host[..]), ("bob", "~b", "host.example"));
+                assert!(cu.away);
+                assert_eq!(cu.account, None);
+            },
+            m => panic!("Routed RPL_WHOREPLY wrong: {:?}", m),
+        }
+        match route(":irc.example.net 354 me 152 #rust ~b host.example bob H bobacct") {
+            Some(RoutedMsg::Channel(_, BufferCmd::RPL_WHOREPLY(cu))) => {
+                assert!(!cu.away);
+                assert_eq!(cu.account, Some("bobacct".to_owned()));
+            },
+            m => panic!("Routed RPL_WHOSPCRPL wrong: {:?}", m),
+        }
+        assert!(route(":irc.example.net 354 me 42 #rust ~b host.example bob H 0").is_none());
+
+        // Replies to a WHO for a nick aren't about any channel.
+        match route(":irc.example.net 352 me * ~b host.example irc.example.net bob H :0 Bob") {
+            Some(RoutedMsg::Network(NetworkCmd::UnknownCode(..))) => {},
+            m => panic!("Routed RPL_WHOREPLY for a nick wrong: {:?}", m),
+        }
+        match route(":irc.example.net 315 me bob :End of WHO list") {
+            Some(RoutedMsg::Network(NetworkCmd::UnknownCode(..))) => {},
+            m => panic!("Routed RPL_ENDOFWHO for a nick wrong: {:?}", m),
+        }
+        match route(":irc.example.net 315 me #rust :End of WHO list") {
+            Some(RoutedMsg::Channel(chan, BufferCmd::RPL_ENDOFWHO)) => assert_eq!(chan, "#rust"),
+            m => panic!("Routed RPL_ENDOFWHO wrong: {:?}", m),
+        }
+    }
+
+    #[test]
+    fn presence_replies() {
+        let route = |line: &str| route_message(line.parse::<Message>().unwrap(), &ISupport::default());
+        match route(":irc.example.net 303 me :bob carol") {
+            Some(RoutedMsg::Network(NetworkCmd::RPL_ISON(nicks))) => assert_eq!(nicks, vec!["bob", "carol"]),
+            m => panic!("Routed RPL_ISON wrong: {:?}", m),
+        }
+        match route(":irc.example.net 730 me :bob!b@host,carol!c@host") {
+            Some(RoutedMsg::Network(NetworkCmd::RPL_MONONLINE(nicks))) => assert_eq!(nicks, vec!["bob", "carol"]),
+            m => panic!("Routed RPL_MONONLINE wrong: {:?}", m),
+        }
+        match route(":irc.example.net 731 me :bob") {
+            Some(RoutedMsg::Network(NetworkCmd::RPL_MONOFFLINE(nicks))) => assert_eq!(nicks, vec!["bob"]),
+            m => panic!("Routed RPL_MONOFFLINE wrong: {:?}", m),
+        }
+    }
+
+    #[test]
+    fn extended_join() {
+        let route = |line: &str| route_message(line.parse::<Message>().unwrap(), &ISupport::default());
+        match route(":bob!b@host JOIN #chan bobacct :Bob Smith") {
+            Some(RoutedMsg::Channel(chan, BufferCmd::JOIN(_, acct))) => {
+                assert_eq!(chan, "#chan");
+                assert_eq!(acct, Some("bobacct".to_owned()));
+            },
+            m => panic!("Routed extended JOIN wrong: {:?}", m),
+        }
+        match route(":bob!b@host JOIN #chan * :Bob Smith") {
+            Some(RoutedMsg::Channel(_, BufferCmd::JOIN(_, None))) => {},
+            m => panic!("Routed logged out JOIN wrong: {:?}", m),
+        }
+        match route("@account=bobacct :bob!b@host JOIN :#chan") {
+            Some(RoutedMsg::Channel(chan, BufferCmd::JOIN(_, acct))) => {
+                assert_eq!(chan, "#chan");
+                assert_eq!(acct, Some("bobacct".to_owned()));
+            },
+            m => panic!("Routed tagged JOIN wrong: {:?}", m),
+        }
+        match route(":bob!b@host ACCOUNT *") {
+            Some(RoutedMsg::Network(NetworkCmd::ACCOUNT(ref user, None))) => assert_eq!(user.nick, "bob"),
+            m => panic!("Routed ACCOUNT wrong: {:?}", m),
+        }
+    }
+
+    #[test]
+    fn error_reason() {
+        let msg = "ERROR :Closing Link: me (Killed)".parse::<Message>().unwrap();
+        match route_message(msg, &ISupport::default()) {
+            Some(RoutedMsg::Network(NetworkCmd::ERROR(reason))) =>
+                assert_eq!(reason, "Closing Link: me (Killed)"),
+            m => panic!("Routed ERROR wrong: {:?}", m),
+        }
+    }
+
+    fn route_list(line: &str) -> (String, usize, String) {
+        let msg = line.parse::<Message>().unwrap();
+        match route_message(msg, &ISupport::default()) {
+            Some(RoutedMsg::Network(NetworkCmd::RPL_LIST(chan, users, topic))) => (chan, users, topic),
+            m => panic!("Routed LIST reply wrong: {:?}", m),
+        }
+    }
+
+    #[test]
+    fn list_reply() {
+        assert_eq!(route_list(":irc.example.net 322 me #rust 42 :Rust talk"),
+                   ("#rust".to_owned(), 42, "Rust talk".to_owned()));
+        assert_eq!(route_list(":irc.example.net 322 me #quiet 3"),
+                   ("#quiet".to_owned(), 3, String::new()));
+    }
 }
@@ -0,0 +1,89 @@
+//! Splitting of outgoing messages that are too long for a single IRC line.
+
+/// Maximum length of an IRC line, including the trailing `\r\n`.
+const MAX_LINE_LEN: usize = 512;
+/// Length we assume for our ident and host when working out how much room the
+/// server's prefix takes up. We don't know our real host, so this is the
+/// longest host allowed plus a generous ident.
+const PREFIX_USERHOST_LEN: usize = 10 + 63;
+
+
+/// Returns the maximum length of a message body sent with the given command to
+/// `dest` such that the line relayed by the server, which will be prefixed with
+/// `:nick!ident@host`, still fits within the IRC line limit.
+pub fn max_body_len(nick: &str, cmd: &str, dest: &str) -> usize {
+    // `:nick!ident@host CMD dest :body\r\n`
+    let overhead = 1 + nick.len() + 2 + PREFIX_USERHOST_LEN + 1 +
+        cmd.len() + 1 + dest.len() + 2 + 2;
+    MAX_LINE_LEN.saturating_sub(overhead)
+}
+
+/// Splits `text` into chunks of at most `max` bytes.
+///
+/// Chunks are only split on UTF-8 character boundaries, and are split at the
+/// last space in the chunk if there is one. The space a chunk is split at is
+/// dropped.
+pub fn split_msg(text: &str, max: usize) -> Vec<String> {
+    let mut chunks = vec![];
+    let mut rest = text;
+    while rest.len() > max {
+        let mut end = max;
+        while end > 0 && !rest.is_char_boundary(end) { end -= 1; }
+        if end == 0 {
+            // `max` is smaller than the first character, so we have to send
+            // it on its own.
+            end = rest.chars().next().map_or(1, |c| c.len_utf8());
+        }
+        let (chunk, next) = match rest[..end].rfind(' ') {
+            Some(sp) if sp > 0 => (&rest[..sp], &rest[sp+1..]),
+            _ => (&rest[..end], &rest[end..]),
+        };
+        chunks.push(chunk.to_owned());
+        rest = next;
+    }
+    chunks.push(rest.to_owned());
+    chunks
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{split_msg, max_body_len, MAX_LINE_LEN};
+
+    #[test]
+    fn split_short() {
+        assert_eq!(split_msg("hello world", 20), vec!["hello world".to_owned()]);
+    }
+
+    #[test]
+    fn split_at_spaces() {
+        assert_eq!(split_msg("aaa bbb ccc ddd", 8),
+                   vec!["aaa bbb".to_owned(), "ccc ddd".to_owned()]);
+    }
+
+    #[test]
+    fn split_no_spaces() {
+        assert_eq!(split_msg("abcdefghij", 4),
+                   vec!["abcd".to_owned(), "efgh".to_owned(), "ij".to_owned()]);
+    }
+
+    #[test]
+    fn split_char_boundaries() {
+        // Each of these characters is three bytes long.
+        let chunks = split_msg("日本語のテキスト", 7);
+        for chunk in chunks.iter() {
+            assert!(chunk.len() <= 7);
+        }
+        assert_eq!(chunks.concat(), "日本語のテキスト");
+    }
+
+    #[test]
+    fn body_fits_line() {
+        let nick = "forkk";
+        let max = max_body_len(nick, "PRIVMSG", "#code");
+        let body: String = ::std::iter::repeat('a').take(max).collect();
+        let line = format!(":{}!{}@{} PRIVMSG #code :{}\r\n", nick,
+                           "~abcdefghi", ::std::iter::repeat('h').take(63).collect::<String>(), body);
+        assert!(line.len() <= MAX_LINE_LEN);
+    }
+}
@@ -0,0 +1,62 @@
+//! Catching the signals that ask the core to shut down.
+//!
+//! `SIGINT` and `SIGTERM` are blocked in every thread and picked up by a
+//! dedicated thread with `sigwait`, which then wakes the event loop. This way
+//! nothing has to happen inside a signal handler.
+
+use std::mem;
+use std::process;
+use std::ptr;
+use std::thread;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use libc;
+use rotor::Notifier;
+
+
+/// Blocks `SIGINT` and `SIGTERM` in this thread and any threads it spawns.
+///
+/// This must be called before any other threads are spawned, or they may end
+/// up handling the signals themselves.
+pub fn block_signals() {
+    unsafe {
+        let set = shutdown_signals();
+        libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut());
+    }
+}
+
+/// Spawns a thread which sets `flag` and wakes `notif` when `SIGINT` or
+/// `SIGTERM` arrives.
+///
+/// If another signal arrives while we're shutting down, we exit immediately.
+pub fn watch_signals(flag: Arc<AtomicBool>, notif: Notifier) {
+    thread::spawn(move || {
+        let set = unsafe { shutdown_signals() };
+        loop {
+            let mut sig = 0;
+            if unsafe { libc::sigwait(&set, &mut sig) } != 0 {
+                error!("Failed to wait for signals. Shutdown signals will be ignored");
+                return;
+            }
+            if flag.swap(true, Ordering::SeqCst) {
+                warn!("Received signal {} while shutting down. Exiting now", sig);
+                process::exit(1);
+            }
+            info!("Received signal {}. Shutting down", sig);
+            if notif.wakeup().is_err() {
+                error!("Failed to wake the event loop. Exiting now");
+                process::exit(1);
+            }
+        }
+    });
+}
+
+
+/// Returns a signal set with the signals we shut down on.
+unsafe fn shutdown_signals() -> libc::sigset_t {
+    let mut set = mem::zeroed();
+    libc::sigemptyset(&mut set);
+    libc::sigaddset(&mut set, libc::SIGINT);
+    libc::sigaddset(&mut set, libc::SIGTERM);
+    set
+}
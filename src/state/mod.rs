@@ -2,13 +2,17 @@
 
 use std::collections::hash_map;
 use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
 
 use user::{UserId, User};
 use config::UserConfig;
+use buffer::BufferConfig;
 
 mod user;
+pub mod session;
 
-pub use self::user::{UserHandle, UserClientHandle};
+pub use self::user::{UserHandle, UserClientHandle, MissedBuf};
 
 
 /// Container for the core's state.
@@ -17,20 +21,24 @@ pub use self::user::{UserHandle, UserClientHandle};
 /// provides a nice API for accessing them.
 pub struct Core {
     users: HashMap<UserId, UserHandle>,
+    /// Settings for all of the users' buffers.
+    buf_cfg: BufferConfig,
 }
 
 
 impl Core {
-    /// Creates a new core state with no users.
-    pub fn new() -> Core {
+    /// Creates a new core state with no users whose buffers use the given
+    /// settings.
+    pub fn new(buf_cfg: BufferConfig) -> Core {
         Core {
             users: HashMap::new(),
+            buf_cfg: buf_cfg,
         }
     }
 
     /// Adds a new user with the given ID and configuration to the core.
     pub fn add_user(&mut self, id: UserId, cfg: UserConfig) {
-        let user = User::from_cfg(cfg);
+        let user = User::from_cfg(cfg, self.buf_cfg.for_user(&id));
         let handle = UserHandle::new(user);
         self.users.insert(id, handle);
     }
@@ -41,6 +49,11 @@ impl Core {
         self.users.iter()
     }
 
+    /// Returns an iterator over mutable references to all of the users.
+    pub fn iter_users_mut(&mut self) -> IterUsersMut {
+        self.users.iter_mut()
+    }
+
     /// Gets a reference to a user handle for the user with the given ID if one
     /// exists.
     pub fn get_user(&self, id: &UserId) -> Option<&UserHandle> {
@@ -52,6 +65,84 @@ impl Core {
     pub fn get_user_mut(&mut self, id: &UserId) -> Option<&mut UserHandle> {
         self.users.get_mut(id)
     }
+
+    /// Checks a client's credentials and returns the user they authenticate
+    /// as.
+    pub fn authenticate(&mut self, id: &UserId, pass: &str) -> Result<&mut UserHandle, AuthError> {
+        match self.users.get_mut(id) {
+            Some(user) => {
+                if user.cfg.password == pass {
+                    Ok(user)
+                } else {
+                    Err(AuthError::BadPassword)
+                }
+            },
+            None => Err(AuthError::UnknownUser),
+        }
+    }
+}
+
+/// Reasons a client can fail to authenticate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthError {
+    /// There is no user with the given ID.
+    UnknownUser,
+    /// The password doesn't match the user's.
+    BadPassword,
+}
+
+impl Error for AuthError {
+    fn description(&self) -> &str {
+        match *self {
+            AuthError::UnknownUser => "Unknown user",
+            AuthError::BadPassword => "Wrong password",
+        }
+    }
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.description())
+    }
 }
 
 pub type IterUsers<'a> = hash_map::Iter<'a, UserId, UserHandle>;
+pub type IterUsersMut<'a> = hash_map::IterMut<'a, UserId, UserHandle>;
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use config::UserConfig;
+    use buffer::BufferConfig;
+    use super::{Core, AuthError};
+
+    fn core() -> Core {
+        let mut core = Core::new(BufferConfig {
+            log_dir: PathBuf::from("logs"),
+            max_lines: 2000,
+        });
+        let mut cfg = UserConfig::default();
+        cfg.password = "hunter2".to_owned();
+        cfg.global_bufs = Some(vec![]);
+        core.add_user("forkk".to_owned(), cfg);
+        core
+    }
+
+    #[test]
+    fn auth_ok() {
+        assert!(core().authenticate(&"forkk".to_owned(), "hunter2").is_ok());
+    }
+
+    #[test]
+    fn auth_bad_password() {
+        let res = core().authenticate(&"forkk".to_owned(), "hunter3").err();
+        assert_eq!(res, Some(AuthError::BadPassword));
+    }
+
+    #[test]
+    fn auth_unknown_user() {
+        let res = core().authenticate(&"Forkk".to_owned(), "hunter2").err();
+        assert_eq!(res, Some(AuthError::UnknownUser));
+    }
+}
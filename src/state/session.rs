@@ -0,0 +1,149 @@
+//! Saves what each network was doing when the core shuts down, so that after
+//! a restart it reconnects with the same nick and rejoins the same channels.
+//!
+//! The session is stored as JSON in `session.json` next to the config file.
+
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::collections::HashMap;
+use rustc_serialize::json;
+
+use common::types::NetId;
+
+use user::UserId;
+use super::Core;
+
+
+/// Snapshot of every user's networks.
+#[derive(Debug, Clone, Default, PartialEq, RustcEncodable, RustcDecodable)]
+pub struct Session {
+    pub users: HashMap<UserId, UserSession>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, RustcEncodable, RustcDecodable)]
+pub struct UserSession {
+    pub nets: HashMap<NetId, NetSession>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, RustcEncodable, RustcDecodable)]
+pub struct NetSession {
+    /// The nick the user last changed to. Empty if they've kept the
+    /// configured nick.
+    pub nick: String,
+    /// Channels we were in.
+    pub channels: Vec<ChanSession>,
+    /// ID of the newest read line in each buffer, keyed by buffer name.
+    pub read_ids: HashMap<String, u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, RustcEncodable, RustcDecodable)]
+pub struct ChanSession {
+    pub name: String,
+    /// The key we joined the channel with, if it needed one.
+    pub key: Option<String>,
+    pub topic: String,
+}
+
+
+impl Session {
+    /// Takes a snapshot of the networks in `core`.
+    pub fn capture(core: &Core) -> Session {
+        let mut users = HashMap::new();
+        for (uid, usr) in core.iter_users() {
+            let nets = usr.iter_nets().map(|(nid, net)| (nid.clone(), net.session())).collect();
+            users.insert(uid.clone(), UserSession { nets: nets });
+        }
+        Session { users: users }
+    }
+
+    /// Restores the saved state of each network in `core`. Users and networks
+    /// which have since been removed from the config are ignored.
+    pub fn restore(self, core: &mut Core) {
+        for (uid, usess) in self.users {
+            let usr = match core.get_user_mut(&uid) {
+                Some(usr) => usr,
+                None => continue,
+            };
+            for (nid, nsess) in usess.nets {
+                if let Some(net) = usr.get_net_mut(&nid) {
+                    debug!("Restoring user {}'s network {}", uid, nid);
+                    net.restore_session(nsess);
+                }
+            }
+        }
+    }
+}
+
+
+/// Returns the path of the session file for the config file at `cfg_path`.
+pub fn session_path(cfg_path: &Path) -> PathBuf {
+    cfg_path.with_file_name("session.json")
+}
+
+/// Reads the session file at `path`.
+///
+/// Returns an empty session if there is no session file or it can't be read,
+/// since the core can always fall back on the config.
+pub fn read_session(path: &Path) -> Session {
+    let mut s = String::new();
+    match File::open(path).and_then(|mut f| f.read_to_string(&mut s)) {
+        Ok(_) => {},
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Session::default(),
+        Err(e) => {
+            warn!("Failed to read session file {}: {}", path.display(), e);
+            return Session::default();
+        },
+    }
+    match json::decode(&s) {
+        Ok(session) => session,
+        Err(e) => {
+            warn!("Ignoring invalid session file {}: {}", path.display(), e);
+            Session::default()
+        },
+    }
+}
+
+/// Writes `session` to the session file at `path`.
+pub fn write_session(path: &Path, session: &Session) -> io::Result<()> {
+    let s = try!(json::encode(session).map_err(|e| io::Error::new(io::ErrorKind::Other, e)));
+    // Like the config, write to a temporary file first so a failed write
+    // doesn't leave us with half a session.
+    let tmp = path.with_extension("json.tmp");
+    {
+        let mut f = try!(File::create(&tmp));
+        try!(f.write_all(s.as_bytes()));
+    }
+    fs::rename(&tmp, path)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use common::tempdir::TempDir;
+    use super::{Session, UserSession, NetSession, ChanSession, read_session, write_session};
+
+    #[test]
+    fn round_trip() {
+        let dir = TempDir::new("session");
+        let path = dir.join("session.json");
+        assert_eq!(read_session(&path), Session::default());
+
+        let mut nets = HashMap::new();
+        nets.insert("freenode".to_owned(), NetSession {
+            nick: "Forkk".to_owned(),
+            channels: vec![ChanSession {
+                name: "#rust".to_owned(),
+                key: Some("hunter2".to_owned()),
+                topic: "Rust things".to_owned(),
+            }],
+            read_ids: vec![("#rust".to_owned(), 42)].into_iter().collect(),
+        });
+        let mut users = HashMap::new();
+        users.insert("forkk".to_owned(), UserSession { nets: nets });
+        let session = Session { users: users };
+        write_session(&path, &session).unwrap();
+        assert_eq!(read_session(&path), session);
+    }
+}
@@ -1,11 +1,16 @@
 use std::sync::mpsc::{channel, Sender, Receiver};
+use std::collections::HashMap;
+use std::collections::hash_map;
+use std::cmp;
 use std::ops::{Deref, DerefMut};
 use rotor::Notifier;
+use time::{self, Duration, Timespec};
 
-use common::messages::CoreMsg;
-use common::alert::Alert;
+use common::messages::{CoreMsg, CoreNetMsg, CoreBufMsg, NetId, BufId, BufInfo, BufTarget, BufferLine};
+use common::alert::{Alert, push_alert};
 
 use user::User;
+use buffer::Buffer;
 use handle::BaseUpdateHandle;
 
 
@@ -17,19 +22,66 @@ pub struct UserHandle {
     user: User,
     clients: Vec<UserClient>,
     alerts: Vec<Alert>,
+    /// Alerts we recently ran the alert command for, and when.
+    alert_cmd_times: Vec<(Alert, Timespec)>,
+    /// Buffers that belong to the user rather than to one of their networks.
+    global_bufs: HashMap<BufId, Buffer>,
+    /// Number of lines each buffer received while no clients were connected.
+    ///
+    /// Missed lines are always the most recent ones in a buffer, so this
+    /// tells us the index each buffer was at when the last client detached.
+    missed: HashMap<MissedBuf, usize>,
+    /// Index of the first missed line in each buffer which missed any since
+    /// the last client detached.
+    ///
+    /// `missed` is turned into this once a client attaches. It's kept so
+    /// every client that attaches gets the missed lines, not just the first,
+    /// until all of them have detached again.
+    missed_since: HashMap<MissedBuf, isize>,
+}
+
+/// Identifies a buffer that received lines while no clients were connected.
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+pub enum MissedBuf {
+    Net(NetId, BufTarget),
+    Global(BufId),
 }
 
 
 impl UserHandle {
     /// Wraps the given user in a new `UserHandle`.
     pub fn new(user: User) -> UserHandle {
+        let mut global_bufs = HashMap::new();
+        for id in user.cfg.global_bufs() {
+            global_bufs.insert(id.clone(), Buffer::new_global(id, &user.buf_cfg));
+        }
         UserHandle {
             user: user,
             clients: vec![],
             alerts: vec![],
+            alert_cmd_times: vec![],
+            global_bufs: global_bufs,
+            missed: HashMap::new(),
+            missed_since: HashMap::new(),
         }
     }
 
+    /// Returns an iterator over the user's global buffers.
+    pub fn iter_global_bufs(&self) -> IterGlobalBufs {
+        self.global_bufs.iter()
+    }
+
+    /// Gets a mutable reference to the global buffer with the given ID if it
+    /// exists.
+    pub fn get_global_buf_mut(&mut self, id: &BufId) -> Option<&mut Buffer> {
+        self.global_bufs.get_mut(id)
+    }
+
+    /// Gets `BufInfo` data for each of the user's global buffers.
+    pub fn global_buf_infos(&self) -> Vec<BufInfo> {
+        self.global_bufs.values().map(|b| b.as_info()).collect()
+    }
+
     /// Consumes an update handle, sending its messages and alerts to this
     /// user's clients.
     ///
@@ -46,16 +98,23 @@ impl UserHandle {
             self.broadcast(&CoreMsg::Alerts(alerts));
         } else if let Some(ref cmd) = self.cfg.alert_cmd.clone() {
             // Otherwise, run our alert command if there is one.
-            use std::process::Command;
+            let now = time::get_time();
+            let interval = Duration::seconds(self.cfg.alert_interval() as i64);
+            self.alert_cmd_times.retain(|&(_, at)| now - at < interval);
             for alert in self.take_alerts() {
-                let cmd = cmd.replace("%m", &alert.msg);
-                info!("Sending alert with command {}", cmd);
-                Command::new("/bin/sh").arg("-c").arg(cmd).spawn().expect("Failed to spawn alert command");
+                if self.alert_cmd_times.iter().any(|&(ref a, _)| a.same_source(&alert)) {
+                    debug!("Not running alert command for repeated alert: {}", alert.msg);
+                    continue;
+                }
+                run_alert_cmd(expand_alert_cmd(cmd, &alert));
+                self.alert_cmd_times.push((alert, now));
             }
         } else {
-            // If all else fails, store the alerts for sending later.
-            let mut alerts = self.take_alerts();
-            self.alerts.append(&mut alerts);
+            // If all else fails, store the alerts for sending later,
+            // collapsing repeats so they don't pile up.
+            for alert in self.take_alerts() {
+                push_alert(&mut self.alerts, alert);
+            }
         }
     }
 
@@ -68,11 +127,17 @@ impl UserHandle {
         alerts
     }
 
+    /// Returns the number of clients registered for this user.
+    pub fn client_count(&self) -> usize {
+        self.clients.len()
+    }
+
     /// Broadcasts the given message to all of this user's clients.
     ///
     /// As a side-effect, this function will also prune any disconnected clients
     /// (clients whose `Receiver`) has been `drop`ed.
     pub fn broadcast(&mut self, msg: &CoreMsg) {
+        let had_clients = !self.clients.is_empty();
         self.clients.retain(|client| {
             if let Err(_) = client.tx.send(msg.clone()) {
                 return false;
@@ -82,6 +147,63 @@ impl UserHandle {
             }
             true
         });
+        if self.clients.is_empty() {
+            if had_clients {
+                // Every client has seen the lines missed before they
+                // attached, so start over.
+                self.missed_since.clear();
+            }
+            self.count_missed(msg);
+        }
+    }
+
+    /// Records any new lines in `msg` as missed so they can be replayed to
+    /// the next client that connects.
+    fn count_missed(&mut self, msg: &CoreMsg) {
+        let (key, count) = match *msg {
+            CoreMsg::NetMsg(ref nid, CoreNetMsg::BufMsg(ref targ, CoreBufMsg::NewLines(ref lines))) =>
+                (MissedBuf::Net(nid.clone(), targ.clone()), lines.len()),
+            CoreMsg::BufMsg(ref bid, CoreBufMsg::NewLines(ref lines)) =>
+                (MissedBuf::Global(bid.clone()), lines.len()),
+            _ => return,
+        };
+        *self.missed.entry(key).or_insert(0) += count;
+    }
+
+    fn missed_buf(&self, key: &MissedBuf) -> Option<&Buffer> {
+        match *key {
+            MissedBuf::Net(ref nid, ref targ) =>
+                self.user.get_net(nid).and_then(|net| net.get_buf(targ)),
+            MissedBuf::Global(ref bid) => self.global_bufs.get(bid),
+        }
+    }
+
+    /// Gets the lines each buffer received since the last time no clients
+    /// were connected, for replaying to a client that's attaching.
+    ///
+    /// Returns each buffer along with the index of its first replayed line and
+    /// the replayed lines, oldest first.
+    ///
+    /// Only missed lines still in memory are replayed. Older ones come before
+    /// the first replayed line, so clients get them as scrollback.
+    pub fn missed_lines(&mut self) -> Vec<(MissedBuf, isize, Vec<BufferLine>)> {
+        // The counts are only right until a client attaches and lines stop
+        // being counted, so turn them into indices now.
+        for (key, count) in self.missed.drain().collect::<Vec<_>>() {
+            let start = self.missed_buf(&key)
+                .map(|buf| cmp::max(buf.front_len() - count as isize, 0));
+            if let Some(start) = start {
+                self.missed_since.insert(key, start);
+            }
+        }
+        let mut missed = vec![];
+        for (key, &start) in self.missed_since.iter() {
+            if let Some(buf) = self.missed_buf(key) {
+                let start = cmp::max(start, buf.front_start());
+                missed.push((key.clone(), start, buf.front_since(start)));
+            }
+        }
+        missed
     }
 
 
@@ -107,6 +229,8 @@ impl UserHandle {
     }
 }
 
+pub type IterGlobalBufs<'a> = hash_map::Iter<'a, BufId, Buffer>;
+
 impl Deref for UserHandle {
     type Target = User;
     fn deref(&self) -> &Self::Target { &self.user }
@@ -125,6 +249,68 @@ struct UserClient {
     tx: Sender<CoreMsg>,
 }
 
+/// Fills in the placeholders in an alert command.
+///
+/// `%m` is the message, `%n` the network, `%b` the buffer, `%s` the sender's
+/// nick, `%k` the kind of alert and `%%` a literal `%`. Values are quoted for
+/// the shell, so placeholders shouldn't be put in quotes.
+fn expand_alert_cmd(cmd: &str, alert: &Alert) -> String {
+    let (nid, bid) = alert.kind.source().map_or(("", ""), |(n, b)| (&n[..], &b[..]));
+    let from = alert.from.as_ref().map_or("", |f| &f[..]);
+    let mut out = String::new();
+    let mut chars = cmd.chars();
+    while let Some(ch) = chars.next() {
+        if ch != '%' {
+            out.push(ch);
+            continue;
+        }
+        match chars.next() {
+            Some('m') => out.push_str(&shell_quote(&alert.msg)),
+            Some('n') => out.push_str(&shell_quote(nid)),
+            Some('b') => out.push_str(&shell_quote(bid)),
+            Some('s') => out.push_str(&shell_quote(from)),
+            Some('k') => out.push_str(alert.kind.name()),
+            Some('%') => out.push('%'),
+            Some(c) => {
+                out.push('%');
+                out.push(c);
+            },
+            None => out.push('%'),
+        }
+    }
+    out
+}
+
+/// Quotes `s` so the shell treats it as a single word.
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// Runs an alert command in the background, logging it if it fails.
+fn run_alert_cmd(cmd: String) {
+    use std::process::Command;
+    use std::thread;
+
+    info!("Sending alert with command {}", cmd);
+    let mut child = match Command::new("/bin/sh").arg("-c").arg(&cmd).spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            error!("Failed to spawn alert command {}: {}", cmd, e);
+            return;
+        },
+    };
+    // Wait on another thread so we don't block the event loop, and so the
+    // child gets reaped.
+    thread::spawn(move || {
+        match child.wait() {
+            Ok(status) if !status.success() => warn!("Alert command {} exited with {}", cmd, status),
+            Ok(_) => {},
+            Err(e) => error!("Failed to wait for alert command {}: {}", cmd, e),
+        }
+    });
+}
+
+
 /// Handle for clients to receive messages broadcast to a user's clients.
 ///
 /// These are constructed by calling `UserHandle::register_client` with a
@@ -148,3 +334,87 @@ impl UserClientHandle {
         }
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use common::alert::Alert;
+    use common::messages::CoreMsg;
+    use common::tempdir::TempDir;
+    use common::line::LineData;
+    use buffer::BufferConfig;
+    use buffer::test_util::{test_cfg, msg};
+    use config::UserConfig;
+    use conn::test_util::TestScope;
+    use handle::{UpdateHandle, BaseUpdateHandle};
+    use user::User;
+    use super::{UserHandle, expand_alert_cmd};
+
+    #[test]
+    fn alert_cmd_placeholders() {
+        let alert = Alert::ping("freenode".to_owned(), "#rust".to_owned(),
+                                "bob".to_owned(), "it's %n".to_owned());
+        assert_eq!(expand_alert_cmd("notify %k %n%b from %s: %m 100%%", &alert),
+                   "notify ping 'freenode''#rust' from 'bob': 'it'\\''s %n' 100%");
+    }
+
+    fn push_note(usr: &mut UserHandle, i: usize) {
+        let bid = "notes".to_owned();
+        let mut u = BaseUpdateHandle::<CoreMsg>::new();
+        usr.get_global_buf_mut(&bid).unwrap()
+            .push_line(msg(i), &mut u.wrap(|m| CoreMsg::BufMsg(bid.clone(), m)));
+        usr.exec_update_handle(u);
+    }
+
+    #[test]
+    fn missed_lines_replayed_to_each_client() {
+        let dir = TempDir::new("missed");
+        let mut usr = UserHandle::new(User::from_cfg(UserConfig::default(), test_cfg(&dir)));
+        let mut ts = TestScope::new(test_cfg(&dir));
+        let notif = ts.scope().notifier();
+        for i in 0..3 {
+            push_note(&mut usr, i);
+        }
+
+        let a = usr.register_client(notif.clone());
+        assert_eq!(usr.missed_lines()[0].2.len(), 3);
+        push_note(&mut usr, 3);
+        // A second client gets the same lines, plus the ones since.
+        let b = usr.register_client(notif.clone());
+        let missed = usr.missed_lines();
+        assert_eq!(missed[0].1, 0);
+        assert_eq!(missed[0].2.len(), 4);
+
+        // Once every client has detached, only lines after that are missed.
+        drop(a);
+        drop(b);
+        push_note(&mut usr, 4);
+        let _c = usr.register_client(notif);
+        let missed = usr.missed_lines();
+        assert_eq!(missed[0].1, 4);
+        assert_eq!(missed[0].2.len(), 1);
+    }
+
+    #[test]
+    fn missed_lines_past_max_lines() {
+        let dir = TempDir::new("missed-max");
+        let cfg = BufferConfig { max_lines: 5, ..test_cfg(&dir) };
+        let mut usr = UserHandle::new(User::from_cfg(UserConfig::default(), cfg));
+        let mut ts = TestScope::new(test_cfg(&dir));
+        for i in 0..8 {
+            push_note(&mut usr, i);
+        }
+
+        let _a = usr.register_client(ts.scope().notifier());
+        let missed = usr.missed_lines();
+        // Only the lines still in memory are replayed...
+        assert_eq!(missed[0].1, 3);
+        assert_eq!(missed[0].2.len(), 5);
+        // ...and the ones before them are there as scrollback.
+        let buf = usr.get_global_buf_mut(&"notes".to_owned()).unwrap();
+        match buf.get_line(2).map(|l| &l.data) {
+            Some(&LineData::Message { ref msg, .. }) => assert_eq!(msg, "2"),
+            l => panic!("Expected a message, got {:?}", l),
+        }
+    }
+}
@@ -7,6 +7,7 @@ use std::default::Default;
 use common::types::NetId;
 
 use network::IrcNetwork;
+use buffer::{BufferConfig, GLOBAL_LOG_DIR};
 use config::{UserConfig, NetConfig};
 pub use config::UserId;
 
@@ -19,30 +20,61 @@ pub use config::UserId;
 // #[derive(Debug)]
 pub struct User {
     pub cfg: UserConfig,
+    /// Settings for this user's buffers.
+    pub buf_cfg: BufferConfig,
     networks: HashMap<NetId, IrcNetwork>,
 }
 
 impl User {
     /// Constructs a new user with a base config with no networks.
-    pub fn new() -> User {
+    pub fn new(buf_cfg: BufferConfig) -> User {
         User {
             cfg: UserConfig::default(),
+            buf_cfg: buf_cfg,
             networks: HashMap::new(),
         }
     }
 
     /// Constructs a new user from the given configuration object.
-    pub fn from_cfg(cfg: UserConfig) -> User {
-        let mut us = Self::new();
-        for (name, net_cfg) in cfg.net.iter() {
+    pub fn from_cfg(cfg: UserConfig, buf_cfg: BufferConfig) -> User {
+        let mut us = Self::new(buf_cfg);
+        us.cfg = cfg;
+        for (name, net_cfg) in us.cfg.net.clone().iter() {
+            if is_reserved_net_id(name) {
+                error!("Not loading network {}, since that name is reserved for global buffers", name);
+                continue;
+            }
             us.add_network(name.clone(), net_cfg);
         }
-        us.cfg = cfg;
         us
     }
 
     pub fn add_network(&mut self, name: String, cfg: &NetConfig) {
-        self.networks.insert(name.to_owned(), IrcNetwork::new(name, cfg));
+        let mut net = IrcNetwork::new(name.clone(), cfg, self.buf_cfg.clone());
+        net.set_ctcp_replies(self.cfg.ctcp_replies());
+        net.set_highlights(self.cfg.highlights());
+        self.networks.insert(name, net);
+    }
+
+    /// Adds a new network and records it in the user's config so it's kept
+    /// when the config is saved. Fails if the user already has a network with
+    /// that ID or the ID is reserved.
+    pub fn add_net_cfg(&mut self, name: NetId, cfg: NetConfig) -> Result<(), String> {
+        if self.networks.contains_key(&name) {
+            return Err(format!("There's already a network called {}", name));
+        }
+        if is_reserved_net_id(&name) {
+            return Err(format!("The name {} is reserved for global buffers", name));
+        }
+        self.add_network(name.clone(), &cfg);
+        self.cfg.net.insert(name, cfg);
+        Ok(())
+    }
+
+    /// Removes the given network from the user and their config.
+    pub fn remove_network(&mut self, id: &NetId) -> Option<IrcNetwork> {
+        self.cfg.net.remove(id);
+        self.networks.remove(id)
     }
 
 
@@ -51,6 +83,12 @@ impl User {
         self.networks.iter()
     }
 
+    /// Returns an iterator over mutable references to this user's IRC
+    /// networks.
+    pub fn iter_nets_mut(&mut self) -> IterNetsMut {
+        self.networks.iter_mut()
+    }
+
     /// Gets a reference to a network with the given ID if it exists.
     pub fn get_net(&self, id: &NetId) -> Option<&IrcNetwork> {
         self.networks.get(id)
@@ -62,4 +100,15 @@ impl User {
     }
 }
 
+
+/// Checks whether a network ID is reserved, because the network's logs would
+/// collide with the global buffers' logs.
+///
+/// This ignores case, since some filesystems do.
+fn is_reserved_net_id(id: &str) -> bool {
+    id.to_lowercase() == GLOBAL_LOG_DIR
+}
+
+
 pub type IterNets<'a> = hash_map::Iter<'a, NetId, IrcNetwork>;
+pub type IterNetsMut<'a> = hash_map::IterMut<'a, NetId, IrcNetwork>;